@@ -0,0 +1,34 @@
+//! Checks for the GraphiQL assets vendored in `assets/graphiql`, which are
+//! embedded in the binary (see `graph::api::ide_asset`), so that a missing
+//! file fails with instructions instead of an `include_bytes!` error.
+
+use std::path::Path;
+
+const GRAPHIQL_DIR: &str = "assets/graphiql";
+
+/// Files embedded by `graph::api`.
+const GRAPHIQL_FILES: &[&str] = &[
+	"es6-promise.auto.min.js",
+	"fetch.min.js",
+	"react.min.js",
+	"react-dom.min.js",
+	"graphiql.min.css",
+	"graphiql.min.js",
+];
+
+fn main() {
+	println!("cargo:rerun-if-changed={}", GRAPHIQL_DIR);
+	let dir = Path::new(GRAPHIQL_DIR);
+	let missing = GRAPHIQL_FILES
+		.iter()
+		.filter(|name| !dir.join(name).is_file())
+		.copied()
+		.collect::<Vec<_>>();
+	if !missing.is_empty() {
+		panic!(
+			"GraphiQL assets missing from {}: {}. Run `make graphiql` from the repository root.",
+			GRAPHIQL_DIR,
+			missing.join(", ")
+		);
+	}
+}
@@ -0,0 +1,155 @@
+//! Versions of the API, so that breaking changes can ship under a new prefix
+//! while the old one keeps working for a deprecation window.
+//!
+//! The routes for each version are mounted at `/api/<version>`. The routes
+//! at `/api` are an alias for `DEFAULT_VERSION`, which can be overridden with
+//! the `Accept-Version` header, as `v1` or `1`:
+//!
+//! ```text
+//! GET /api/v1/health
+//! GET /api/health
+//! GET /api/health                 Accept-Version: 1
+//! ```
+//!
+//! The responses have the version in `X-Api-Version`, and a `Deprecation`
+//! header if it is deprecated. Requests for an unknown version fail with
+//! `400 Bad Request`.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::{Data, Request, Response};
+
+use crate::server;
+
+/// Versions of the API, from the oldest.
+pub const VERSIONS: &[&str] = &["v1"];
+
+/// Version for the routes at `/api`.
+pub const DEFAULT_VERSION: &str = "v1";
+
+/// Versions that still work, but will be removed.
+const DEPRECATED: &[&str] = &[];
+
+/// Version used for a request, cached by the fairing.
+struct RequestVersion(Option<&'static str>);
+
+/// Fairing that routes the requests by API version.
+pub struct ApiVersion;
+
+impl Fairing for ApiVersion {
+	fn info(&self) -> Info {
+		Info {
+			name: "API version",
+			kind: Kind::Request | Kind::Response,
+		}
+	}
+
+	fn on_request(&self, request: &mut Request, _data: &Data) {
+		let path = request.uri().path();
+		if !is_api(path) {
+			request.local_cache(|| RequestVersion(None));
+			return;
+		}
+		if let Some((version, _)) = split_version(path) {
+			request.local_cache(|| RequestVersion(Some(version)));
+			return;
+		}
+
+		let version = match request.headers().get_one("Accept-Version") {
+			Some(value) => match parse_version(value) {
+				Some(version) => version,
+				None => {
+					// Fairings can't respond to a request, so it is routed to
+					// one that fails.
+					request.local_cache(|| RequestVersion(None));
+					request.set_method(Method::Get);
+					request.set_uri(Origin::parse(server::UNSUPPORTED_VERSION_URI).unwrap());
+					return;
+				}
+			},
+			None => DEFAULT_VERSION,
+		};
+		request.local_cache(|| RequestVersion(Some(version)));
+		if version != DEFAULT_VERSION {
+			let mut uri = format!("/api/{}{}", version, &path["/api".len()..]);
+			if let Some(query) = request.uri().query() {
+				uri = format!("{}?{}", uri, query);
+			}
+			if let Ok(uri) = Origin::parse_owned(uri) {
+				request.set_uri(uri);
+			}
+		}
+	}
+
+	fn on_response(&self, request: &Request, response: &mut Response) {
+		let version = request.local_cache(|| RequestVersion(None));
+		if let Some(version) = version.0 {
+			response.set_raw_header("X-Api-Version", version);
+			if DEPRECATED.contains(&version) {
+				response.set_raw_header("Deprecation", "true");
+			}
+		}
+	}
+}
+
+/// Returns true for the paths under `/api`.
+fn is_api(path: &str) -> bool {
+	path == "/api" || path.starts_with("/api/")
+}
+
+/// Splits a path under `/api/<version>` into the version and the path for
+/// the unversioned route.
+pub fn split_version(path: &str) -> Option<(&'static str, String)> {
+	let rest = path.strip_prefix("/api/")?;
+	let (version, rest) = match rest.find('/') {
+		Some(index) => rest.split_at(index),
+		None => (rest, ""),
+	};
+	let version = VERSIONS.iter().find(|it| **it == version)?;
+	Some((version, format!("/api{}", rest)))
+}
+
+/// Parses the value of `Accept-Version`.
+fn parse_version(value: &str) -> Option<&'static str> {
+	let value = value.trim();
+	let value = value
+		.strip_prefix('v')
+		.or_else(|| value.strip_prefix('V'))
+		.unwrap_or(value);
+	VERSIONS.iter().copied().find(|it| it[1..] == *value)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_split_version() {
+		assert_eq!(
+			split_version("/api/v1/health"),
+			Some(("v1", "/api/health".to_string()))
+		);
+		assert_eq!(split_version("/api/v1"), Some(("v1", "/api".to_string())));
+		assert_eq!(split_version("/api/health"), None);
+		assert_eq!(split_version("/api/v9/health"), None);
+		assert_eq!(split_version("/metrics"), None);
+	}
+
+	#[test]
+	fn test_parse_version() {
+		assert_eq!(parse_version("v1"), Some("v1"));
+		assert_eq!(parse_version(" 1 "), Some("v1"));
+		assert_eq!(parse_version("V1"), Some("v1"));
+		assert_eq!(parse_version("2"), None);
+		assert_eq!(parse_version(""), None);
+	}
+
+	#[test]
+	fn test_is_api() {
+		assert!(is_api("/api"));
+		assert!(is_api("/api/graphql"));
+		assert!(!is_api("/apidocs"));
+		assert!(!is_api("/"));
+	}
+}
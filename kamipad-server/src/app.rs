@@ -1,31 +1,98 @@
 //! Main application state for the server.
 
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::auth::Role;
+use crate::config::Config;
+use crate::export;
+use crate::import;
 use crate::logging;
-use crate::util::{Cache, CacheKey, CacheMap, CacheVal};
+use crate::metrics::Metrics;
+use crate::notes::NOTES_COLLECTION;
+use crate::util::time::unix_millis;
+use crate::util::{Cache, CacheKey, CacheMap, CacheStats, CacheVal};
 
 use kamipad_data as kd;
 
+/// Number of threads running database operations for async code.
+const DATABASE_THREADS: usize = 4;
+
+/// Number of prior versions kept for each record, such as the notes.
+pub const MAX_REVISIONS: usize = 50;
+
 /// Wraps the entire application state. The singleton instance for this can
-/// be retrieved through the `App::get()` method.
+/// be retrieved through the `App::get()` method, after `App::init`.
 pub struct App {
+	pub config: Config,
+
 	pub log: slog::Logger,
 	ring_log: logging::RingLogger,
 
-	cache_map: CacheMap,
+	pub(crate) cache_map: CacheMap,
+
+	database: kd::AsyncDatabase,
+
+	/// Start time in milliseconds since the UNIX epoch, and as an instant
+	/// for measuring the uptime.
+	pub(crate) started: u64,
+	pub(crate) start_time: Instant,
+
+	/// Number of requests being handled, see `App::request_started`.
+	pub(crate) active_requests: AtomicUsize,
+
+	/// Request metrics, see `App::metrics_text`.
+	pub(crate) metrics: Metrics,
+
+	/// Set once the reminder scheduler is running, see `App::readiness`.
+	pub(crate) scheduler_started: AtomicBool,
+
+	/// Set when the server is shutting down, see `App::shutdown`.
+	pub(crate) shutting_down: AtomicBool,
 
-	#[allow(dead_code)]
-	database: kd::Database,
+	/// Running and recently finished imports, see `App::import_notes`.
+	pub(crate) import_jobs: import::ImportJobs,
+
+	/// Running and recently finished exports, see `App::export_notebook`.
+	pub(crate) export_jobs: export::ExportJobs,
 
 	// This just resets the global logging when the App instance is discarded.
 	_compat_log_guard: slog_scope::GlobalLoggerGuard,
 }
 
+lazy_static! {
+	/// Configuration for the instance, set by `App::init`.
+	static ref CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+}
+
 impl App {
+	/// Initializes the global application state instance with the given
+	/// configuration, and returns it. This has no effect if the instance has
+	/// already been initialized.
+	pub fn init(config: Config) -> &'static App {
+		*CONFIG.lock().unwrap() = Some(config);
+		App::get()
+	}
+
 	/// Retrieves the global application state instance. This will initialize
-	/// the instance the first time is called.
+	/// the instance the first time is called, loading the configuration if
+	/// `App::init` was not called.
 	pub fn get() -> &'static App {
 		lazy_static! {
 			static ref APP: App = {
+				let config = CONFIG.lock().unwrap().take();
+				let config = match config {
+					Some(config) => config,
+					None => match Config::load(None) {
+						Ok(config) => config,
+						Err(err) => {
+							eprintln!("Failed to load the configuration: {}", err);
+							std::process::exit(1);
+						}
+					},
+				};
 
 				//============================================================//
 				// Logging setup
@@ -63,11 +130,13 @@ impl App {
 				let term = slog_term::term_compact();
 				let term = std::sync::Mutex::new(term);
 
-				// The root logger, outputting to the terminal
+				// The root logger, outputting to the terminal, filtered by the
+				// configured level.
+				let term = slog::LevelFilter::new(term.fuse(), config.log_level.to_slog());
 				let term = slog::Logger::root(term.fuse(), o!());
 
 				// Ring drain that keep all entries for `/api/logs`
-				let ring_log = logging::RingLogger::new(1000);
+				let ring_log = logging::RingLogger::new(config.cache.logs);
 
 				// Filter out debug/trace entries from libraries
 				let filter = slog::LevelFilter::new(term.clone(), slog::Level::Info);
@@ -92,12 +161,18 @@ impl App {
 				// Main database
 				//============================================================//
 
-				let db_path = std::env::current_exe().unwrap();
-				let db_path = db_path.parent().unwrap().join("database");
+				let db_path = config.database.clone();
 				info!(app_log, "opening database at {}", db_path.to_string_lossy());
-				let db = match kd::open(db_path, kd::OpenFlags::default()) {
+				let flags = kd::OpenFlags {
+					max_revisions: MAX_REVISIONS,
+					cache_size: config.cache.records,
+					read_only: config.read_only,
+					..Default::default()
+				};
+				let db = match kd::open(db_path, flags) {
 					Ok(db) => {
 						info!(app_log, "database opened successfully");
+						db.add_search_index(NOTES_COLLECTION, &["title", "text"]);
 						db
 					}
 					Err(err) => {
@@ -111,14 +186,41 @@ impl App {
 				//============================================================//
 
 				let app = App {
+					config,
 					log: app_log,
 					ring_log: ring_log,
 					cache_map: CacheMap::new(),
-					database: db,
+					database: kd::AsyncDatabase::new(db, DATABASE_THREADS),
+					started: unix_millis(),
+					start_time: Instant::now(),
+					active_requests: AtomicUsize::new(0),
+					metrics: Default::default(),
+					scheduler_started: AtomicBool::new(false),
+					shutting_down: AtomicBool::new(false),
+					import_jobs: Default::default(),
+					export_jobs: Default::default(),
 
 					_compat_log_guard: compat_log_guard,
 				};
 
+				//============================================================//
+				// Initial user
+				//============================================================//
+
+				// Setting `KAMIPAD_USER` and `KAMIPAD_PASSWORD` creates the
+				// user as an admin, or resets its password.
+				let user = std::env::var("KAMIPAD_USER");
+				let password = std::env::var("KAMIPAD_PASSWORD");
+				if let (Ok(user), Ok(password)) = (user, password) {
+					let result = app
+						.set_password(&user, &password)
+						.and_then(|_| app.set_roles(&user, &[Role::Admin]));
+					match result {
+						Ok(_) => info!(app.log, "set password for user {}", user),
+						Err(err) => error!(app.log, "failed to set password for {}: {}", user, err),
+					}
+				}
+
 				trace!(app.log, "application initialized"; t_init);
 
 				app
@@ -127,11 +229,38 @@ impl App {
 		&APP
 	}
 
+	/// Returns the main application database.
+	pub fn database(&self) -> &kd::Database {
+		self.database.database()
+	}
+
+	/// Runs blocking work with the application on the database threads,
+	/// returning a future that completes with its result. Async code, such
+	/// as the GraphQL resolvers, must use this for any database access.
+	pub fn run<T, F>(&'static self, callback: F) -> impl Future<Output = T>
+	where
+		T: Send + 'static,
+		F: FnOnce(&'static App) -> T + Send + 'static,
+	{
+		self.database.run(move |_| callback(self))
+	}
+
 	/// Returns a global cache instance for a given key and value types.
 	pub fn cache<K: CacheKey + 'static, V: CacheVal + 'static>(&self) -> Cache<K, V> {
 		self.cache_map.get()
 	}
 
+	/// Returns the name and statistics for each global cache instance.
+	pub fn cache_stats(&self) -> Vec<(String, CacheStats)> {
+		self.cache_map.stats()
+	}
+
+	/// Clears a global cache instance by name. Returns false if there is no
+	/// cache with the name.
+	pub fn clear_cache(&self, name: &str) -> bool {
+		self.cache_map.clear(name)
+	}
+
 	/// Creates a new [Logger] for a request.
 	///
 	/// A request logger will still log entries globally, but will also store
@@ -154,4 +283,26 @@ impl App {
 	pub fn all_logs(&self) -> Vec<logging::LogEntry> {
 		self.ring_log.entries()
 	}
+
+	/// Returns the number of application log entries ever logged, by level.
+	pub fn log_counts(&self) -> Vec<(slog::Level, u64)> {
+		self.ring_log.counts()
+	}
+
+	/// Returns a stream of the application log entries logged from now on.
+	pub fn subscribe_logs(&self) -> futures::channel::mpsc::UnboundedReceiver<logging::LogEntry> {
+		self.ring_log.subscribe()
+	}
+
+	/// Ends the streams returned by `subscribe_logs`, and any subscribed
+	/// later. Used when shutting down.
+	pub fn close_log_subscriptions(&self) {
+		self.ring_log.close_subscriptions()
+	}
+
+	/// Returns the log entries for a recent request, if still available.
+	pub fn request_logs(&self, id: &logging::RequestId) -> Option<Vec<logging::LogEntry>> {
+		let cache = self.cache::<logging::RequestId, Vec<logging::LogEntry>>();
+		cache.get(id).map(|entries| (*entries).clone())
+	}
 }
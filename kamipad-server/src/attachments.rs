@@ -0,0 +1,177 @@
+//! File attachments stored in the application database.
+//!
+//! The content of an attachment is stored as a blob, and its metadata as a
+//! JSON record in the `attachments` collection, keyed by its ID. Each
+//! attachment holds one reference to its blob.
+//!
+//! Attachments are attached to notes with a link from the note record.
+//! Deleting a note removes its links but keeps the attachments, and removing
+//! an attachment unlinks it from its notes.
+//!
+//! The content is downloaded from a signed URL, which is valid for
+//! `DOWNLOAD_URL_TTL`:
+//!
+//! ```text
+//! /api/attachments/<id>?expires=<unix millis>&signature=<signature>
+//! ```
+
+use std::time::Duration;
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::notes::NOTES_COLLECTION;
+use crate::util::time::unix_millis;
+use crate::util::Result;
+
+/// Collection for the attachments in the database.
+pub const ATTACHMENTS_COLLECTION: &'static str = "attachments";
+
+/// Time a signed download URL is valid.
+pub const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Metadata for an attachment.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Attachment {
+	#[serde(skip, default = "kd::ID::nil")]
+	pub id: kd::ID,
+	/// Blob with the content of the attachment.
+	pub blob: String,
+	pub filename: String,
+	pub content_type: String,
+	/// Size of the content in bytes.
+	pub size: u64,
+	/// Creation time in milliseconds since the UNIX epoch.
+	pub created: u64,
+}
+
+impl App {
+	/// Returns an attachment by its ID.
+	pub fn attachment(&self, id: &kd::ID) -> Result<Option<Attachment>> {
+		let attachments = self.database().collection(ATTACHMENTS_COLLECTION)?;
+		match attachments.get(&id.to_string())? {
+			Some(data) => {
+				let mut attachment: Attachment = serde_json::from_slice(&data)?;
+				attachment.id = *id;
+				Ok(Some(attachment))
+			}
+			None => Ok(None),
+		}
+	}
+
+	/// Creates an attachment for a blob already in the database, taking over
+	/// a reference to the blob from the caller.
+	pub fn create_attachment(
+		&self,
+		blob: &kd::BlobId,
+		filename: String,
+		content_type: String,
+		size: u64,
+	) -> Result<Attachment> {
+		let attachment = Attachment {
+			id: kd::ID::new_sortable(),
+			blob: blob.to_string(),
+			filename,
+			content_type,
+			size,
+			created: unix_millis(),
+		};
+		let attachments = self.database().collection(ATTACHMENTS_COLLECTION)?;
+		let data = serde_json::to_vec(&attachment)?;
+		attachments.put(&attachment.id.to_string(), &data)?;
+		Ok(attachment)
+	}
+
+	/// Returns the attachments of a note, oldest first. Returns `None` if the
+	/// note doesn't exist.
+	pub fn note_attachments(&self, note: &kd::ID) -> Result<Option<Vec<Attachment>>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let key = note.to_string();
+		if !notes.contains(&key)? {
+			return Ok(None);
+		}
+		let mut result = Vec::new();
+		for link in notes.links(&key)? {
+			if link.collection != ATTACHMENTS_COLLECTION {
+				continue;
+			}
+			let id = match kd::ID::parse(&link.key) {
+				Some(id) => id,
+				None => continue,
+			};
+			if let Some(attachment) = self.attachment(&id)? {
+				result.push(attachment);
+			}
+		}
+		result.sort_by(|a, b| a.created.cmp(&b.created).then(a.id.cmp(&b.id)));
+		Ok(Some(result))
+	}
+
+	/// Attaches an attachment to a note. Returns `None` if either doesn't
+	/// exist.
+	pub fn attach_attachment(&self, note: &kd::ID, id: &kd::ID) -> Result<Option<Attachment>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let target = kd::RecordId::new(ATTACHMENTS_COLLECTION, id.to_string());
+		match notes.link(&note.to_string(), &target) {
+			Ok(_) => self.attachment(id),
+			Err(kd::Error::NotFound(_)) => Ok(None),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	/// Deletes an attachment, removing it from its notes and releasing its
+	/// blob. Returns false if the attachment doesn't exist.
+	pub fn remove_attachment(&self, id: &kd::ID) -> Result<bool> {
+		let attachment = match self.attachment(id)? {
+			Some(attachment) => attachment,
+			None => return Ok(false),
+		};
+		let database = self.database();
+		let attachments = database.collection(ATTACHMENTS_COLLECTION)?;
+		let key = id.to_string();
+		let target = kd::RecordId::new(ATTACHMENTS_COLLECTION, key.as_str());
+		for from in attachments.backlinks(&key)? {
+			let collection = database.collection(from.collection.as_str())?;
+			collection.unlink(&from.key, &target)?;
+		}
+		if !attachments.delete(&key)? {
+			return Ok(false);
+		}
+		if let Some(blob) = kd::BlobId::parse(&attachment.blob) {
+			database.release_blob(&blob)?;
+		}
+		Ok(true)
+	}
+
+	/// Returns a signed URL to download an attachment.
+	pub fn download_url(&self, id: &kd::ID) -> Result<String> {
+		self.signed_url(&format!("/api/attachments/{}", id), DOWNLOAD_URL_TTL)
+	}
+
+	/// Returns an attachment with its content, for a signed download URL.
+	/// Returns `None` if the signature is not valid or expired, or if the
+	/// attachment doesn't exist.
+	pub fn download(
+		&self,
+		id: &kd::ID,
+		expires: u64,
+		signature: &str,
+	) -> Result<Option<(Attachment, Vec<u8>)>> {
+		let path = format!("/api/attachments/{}", id);
+		if !self.check_signed_url(&path, expires, signature)? {
+			return Ok(None);
+		}
+		let attachment = match self.attachment(id)? {
+			Some(attachment) => attachment,
+			None => return Ok(None),
+		};
+		let blob = match kd::BlobId::parse(&attachment.blob) {
+			Some(blob) => blob,
+			None => return Ok(None),
+		};
+		match self.database().get_blob(&blob)? {
+			Some(data) => Ok(Some((attachment, data))),
+			None => Ok(None),
+		}
+	}
+}
@@ -0,0 +1,330 @@
+//! Authentication of users.
+//!
+//! Users are stored in the `users` collection, keyed by name, with a salted
+//! PBKDF2 hash of their password. Logging in creates a session in the
+//! `sessions` collection, and returns a token for it:
+//!
+//! ```text
+//! <session ID>.<signature>
+//! ```
+//!
+//! The signature is an HMAC of the session ID, with a secret generated on
+//! first use and stored in the database, so that tokens can't be guessed
+//! from session IDs. Requests send the token in the `Authorization` header
+//! as `Bearer <token>`. Logging out deletes the session, which invalidates
+//! its token.
+//!
+//! Each user has a set of roles, which determine what they can access. See
+//! `Role`. Roles are read when authenticating each request, so changes apply
+//! to existing sessions.
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rocket::request::{FromRequest, Outcome, State};
+use rocket::Request;
+use sha2::Sha256;
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::util::time::unix_millis;
+use crate::util::{Error, Result};
+
+/// Collection for the users.
+pub const USERS_COLLECTION: &'static str = "users";
+
+/// Collection for the sessions from `App::login`.
+pub const SESSIONS_COLLECTION: &'static str = "sessions";
+
+/// Collection for server settings, which holds the token secret.
+pub const SETTINGS_COLLECTION: &'static str = "settings";
+
+const TOKEN_SECRET_KEY: &'static str = "token-secret";
+
+/// Time a session lasts after logging in.
+pub const SESSION_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Number of PBKDF2 rounds for new password hashes.
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// Role of a user. Each role includes the access of the roles before it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	/// Can read notes and attachments.
+	Reader,
+	/// Can also change notes and attachments.
+	Editor,
+	/// Can access everything.
+	Admin,
+}
+
+impl Role {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Role::Reader => "reader",
+			Role::Editor => "editor",
+			Role::Admin => "admin",
+		}
+	}
+}
+
+/// An authenticated user.
+#[derive(Clone, Debug)]
+pub struct User {
+	pub name: String,
+	/// Session the user authenticated with.
+	pub session: kd::ID,
+	pub roles: Vec<Role>,
+}
+
+impl User {
+	/// Returns true if the user has the role, or one that includes it.
+	pub fn has_role(&self, role: Role) -> bool {
+		self.roles.iter().any(|user_role| *user_role >= role)
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserRecord {
+	salt: String,
+	hash: String,
+	rounds: u32,
+	#[serde(default)]
+	roles: Vec<Role>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionRecord {
+	user: String,
+	/// Expiration time in milliseconds since the UNIX epoch.
+	expires: u64,
+}
+
+impl App {
+	/// Creates a user, or changes the password of an existing user. New
+	/// users have no roles.
+	pub fn set_password(&self, name: &str, password: &str) -> Result<()> {
+		let mut salt = [0; 16];
+		rand::thread_rng().fill_bytes(&mut salt);
+		let roles = self.user_record(name)?.map(|user| user.roles);
+		let record = UserRecord {
+			salt: base64::encode(&salt),
+			hash: base64::encode(&hash_password(password, &salt, PBKDF2_ROUNDS)),
+			rounds: PBKDF2_ROUNDS,
+			roles: roles.unwrap_or_default(),
+		};
+		let users = self.database().collection(USERS_COLLECTION)?;
+		users.put(name, &serde_json::to_vec(&record)?)?;
+		Ok(())
+	}
+
+	/// Replaces the roles of a user. Returns false if the user doesn't exist.
+	pub fn set_roles(&self, name: &str, roles: &[Role]) -> Result<bool> {
+		let mut record = match self.user_record(name)? {
+			Some(record) => record,
+			None => return Ok(false),
+		};
+		record.roles = roles.to_vec();
+		let users = self.database().collection(USERS_COLLECTION)?;
+		users.put(name, &serde_json::to_vec(&record)?)?;
+		Ok(true)
+	}
+
+	/// Checks the credentials for a user, and creates a session for them.
+	/// Returns the token for the session, or `None` if the credentials are
+	/// not valid.
+	pub fn login(&self, name: &str, password: &str) -> Result<Option<String>> {
+		let record = match self.user_record(name)? {
+			Some(record) => record,
+			None => return Ok(None),
+		};
+		let salt = base64::decode(&record.salt).map_err(Error::from)?;
+		let expected = base64::decode(&record.hash).map_err(Error::from)?;
+		let hash = hash_password(password, &salt, record.rounds);
+		if !constant_time_eq(&hash, &expected) {
+			return Ok(None);
+		}
+
+		let session = kd::ID::new();
+		let record = SessionRecord {
+			user: name.to_string(),
+			expires: unix_millis() + SESSION_TTL.as_millis() as u64,
+		};
+		let sessions = self.database().collection(SESSIONS_COLLECTION)?;
+		sessions.put(&session.to_string(), &serde_json::to_vec(&record)?)?;
+		let signature = base64::encode_config(&self.sign(&session)?, base64::URL_SAFE_NO_PAD);
+		Ok(Some(format!("{}.{}", session, signature)))
+	}
+
+	/// Ends the session for a user, invalidating its token.
+	pub fn logout(&self, user: &User) -> Result<()> {
+		let sessions = self.database().collection(SESSIONS_COLLECTION)?;
+		sessions.delete(&user.session.to_string())?;
+		Ok(())
+	}
+
+	/// Returns the user for a token, or `None` if the token is not valid or
+	/// its session ended.
+	pub fn authenticate(&self, token: &str) -> Result<Option<User>> {
+		let mut parts = token.splitn(2, '.');
+		let session = parts.next().and_then(kd::ID::parse);
+		let signature = parts
+			.next()
+			.and_then(|s| base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok());
+		let (session, signature) = match (session, signature) {
+			(Some(session), Some(signature)) => (session, signature),
+			_ => return Ok(None),
+		};
+		if !constant_time_eq(&self.sign(&session)?, &signature) {
+			return Ok(None);
+		}
+
+		let sessions = self.database().collection(SESSIONS_COLLECTION)?;
+		let record: SessionRecord = match sessions.get(&session.to_string())? {
+			Some(data) => serde_json::from_slice(&data)?,
+			None => return Ok(None),
+		};
+		if record.expires <= unix_millis() {
+			sessions.delete(&session.to_string())?;
+			return Ok(None);
+		}
+		let roles = match self.user_record(&record.user)? {
+			Some(user) => user.roles,
+			None => return Ok(None),
+		};
+		Ok(Some(User {
+			name: record.user,
+			session,
+			roles,
+		}))
+	}
+
+	fn user_record(&self, name: &str) -> Result<Option<UserRecord>> {
+		let users = self.database().collection(USERS_COLLECTION)?;
+		match users.get(name) {
+			Ok(Some(data)) => Ok(Some(serde_json::from_slice(&data)?)),
+			// Names that are not valid keys can't be users.
+			Ok(None) | Err(kd::Error::InvalidName(_)) => Ok(None),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	fn sign(&self, session: &kd::ID) -> Result<Vec<u8>> {
+		self.hmac(session.to_string().as_bytes())
+	}
+
+	/// Returns a URL for `path` that is valid for `ttl`, signed with the token
+	/// secret, as `<path>?expires=<unix millis>&signature=<signature>`.
+	pub fn signed_url(&self, path: &str, ttl: Duration) -> Result<String> {
+		let expires = unix_millis() + ttl.as_millis() as u64;
+		let signature = self.sign_url(path, expires)?;
+		Ok(format!(
+			"{}?expires={}&signature={}",
+			path, expires, signature
+		))
+	}
+
+	/// Returns true if the query of a URL from `signed_url` is valid for the
+	/// path and has not expired.
+	pub fn check_signed_url(&self, path: &str, expires: u64, signature: &str) -> Result<bool> {
+		let expected = self.sign_url(path, expires)?;
+		Ok(expires > unix_millis() && constant_time_eq(expected.as_bytes(), signature.as_bytes()))
+	}
+
+	fn sign_url(&self, path: &str, expires: u64) -> Result<String> {
+		let signature = self.hmac(format!("url:{}:{}", path, expires).as_bytes())?;
+		Ok(base64::encode_config(signature, base64::URL_SAFE_NO_PAD))
+	}
+
+	/// Returns an HMAC of the data with the token secret. Other signatures
+	/// must not be valid session IDs, so that they can't be used as tokens.
+	fn hmac(&self, data: &[u8]) -> Result<Vec<u8>> {
+		let secret = self.token_secret()?;
+		let mut mac = Hmac::<Sha256>::new_from_slice(&secret).map_err(Error::from)?;
+		mac.update(data);
+		Ok(mac.finalize().into_bytes().to_vec())
+	}
+
+	/// Returns the secret for signing tokens, generating it on first use.
+	fn token_secret(&self) -> Result<Vec<u8>> {
+		let settings = self.database().collection(SETTINGS_COLLECTION)?;
+		if let Some(secret) = settings.get(TOKEN_SECRET_KEY)? {
+			return Ok(secret);
+		}
+		let mut secret = vec![0; 32];
+		rand::thread_rng().fill_bytes(&mut secret);
+		match settings.put_if_version(TOKEN_SECRET_KEY, &secret, 0) {
+			Ok(_) => Ok(secret),
+			// Generated concurrently by another request.
+			Err(kd::Error::Conflict(_)) => self.token_secret(),
+			Err(err) => Err(err.into()),
+		}
+	}
+}
+
+fn hash_password(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+	let mut hash = [0; 32];
+	pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+	hash
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Request guard with the user authenticated by the `Authorization` header,
+/// if any. A missing or invalid token results in an anonymous request.
+pub struct Auth(pub Option<User>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Auth {
+	type Error = ();
+
+	fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+		// The user is cached, since the rate limit also needs it.
+		let auth = request.local_cache(|| Auth(authenticate(request)));
+		Outcome::Success(Auth(auth.0.clone()))
+	}
+}
+
+fn authenticate(request: &Request) -> Option<User> {
+	let token = request
+		.headers()
+		.get_one("Authorization")
+		.and_then(|value| value.strip_prefix("Bearer "))?
+		.trim();
+	let app: State<&'static App> = request.guard::<State<&App>>().unwrap();
+	match app.authenticate(token) {
+		Ok(user) => user,
+		Err(err) => {
+			warn!(app.log, "failed to authenticate request: {}", err);
+			None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_roles() {
+		let user = User {
+			name: "editor".into(),
+			session: kd::ID::nil(),
+			roles: vec![Role::Editor],
+		};
+		assert!(user.has_role(Role::Reader));
+		assert!(user.has_role(Role::Editor));
+		assert!(!user.has_role(Role::Admin));
+	}
+
+	#[test]
+	fn test_constant_time_eq() {
+		assert!(constant_time_eq(b"abc", b"abc"));
+		assert!(!constant_time_eq(b"abc", b"abd"));
+		assert!(!constant_time_eq(b"abc", b"ab"));
+	}
+}
@@ -0,0 +1,110 @@
+//! Command-line options for the server.
+//!
+//! The options override the settings from `kamipad.toml` and the `KAMIPAD_*`
+//! variables, so that multiple instances can run from the same setup:
+//!
+//! ```text
+//! kamipad --port 3002 --db-path ./backup --read-only
+//! ```
+
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::config::{Config, LogLevel};
+
+/// Kamipad server.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kamipad")]
+pub struct Options {
+	/// Port to listen on.
+	#[structopt(long)]
+	pub port: Option<u16>,
+
+	/// Address to listen on.
+	#[structopt(long)]
+	pub host: Option<String>,
+
+	/// Path of the database directory.
+	#[structopt(long, parse(from_os_str))]
+	pub db_path: Option<PathBuf>,
+
+	/// Directory with the frontend bundle to serve.
+	#[structopt(long, parse(from_os_str))]
+	pub frontend: Option<PathBuf>,
+
+	/// Configuration file to use instead of `kamipad.toml`.
+	#[structopt(long, parse(from_os_str))]
+	pub config: Option<PathBuf>,
+
+	/// Minimum level for the terminal log: critical, error, warning, info,
+	/// debug or trace.
+	#[structopt(long)]
+	pub log_level: Option<LogLevel>,
+
+	/// Opens the database read-only, failing any changes.
+	#[structopt(long)]
+	pub read_only: bool,
+
+	/// Prints the GraphQL schema and exits, without starting the server.
+	#[structopt(long)]
+	pub print_schema: bool,
+}
+
+impl Options {
+	/// Overrides the configuration with the options that were given.
+	pub fn apply(&self, config: &mut Config) {
+		if let Some(port) = self.port {
+			config.port = Some(port);
+		}
+		if let Some(host) = &self.host {
+			config.address = Some(host.clone());
+		}
+		if let Some(path) = &self.db_path {
+			config.database = path.clone();
+		}
+		if let Some(path) = &self.frontend {
+			config.frontend = Some(path.clone());
+		}
+		if let Some(level) = self.log_level {
+			config.log_level = level;
+		}
+		if self.read_only {
+			config.read_only = true;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_options() {
+		let args = [
+			"kamipad",
+			"--port",
+			"3002",
+			"--db-path",
+			"backup",
+			"--log-level",
+			"debug",
+			"--read-only",
+		];
+		let options = Options::from_iter_safe(&args).unwrap();
+		assert_eq!(options.port, Some(3002));
+		assert_eq!(options.host, None);
+		assert!(!options.print_schema);
+
+		let mut config = Config::default();
+		options.apply(&mut config);
+		assert_eq!(config.port, Some(3002));
+		assert_eq!(config.address, None);
+		assert_eq!(config.database, PathBuf::from("backup"));
+		assert_eq!(config.log_level, LogLevel::Debug);
+		assert!(config.read_only);
+
+		assert!(Options::from_iter_safe(&["kamipad", "--port", "http"]).is_err());
+		assert!(Options::from_iter_safe(&["kamipad", "--log-level", "loud"]).is_err());
+	}
+}
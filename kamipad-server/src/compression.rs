@@ -0,0 +1,203 @@
+//! Compression for the JSON responses, which include the GraphQL responses.
+//!
+//! The encoding is negotiated with the `Accept-Encoding` header, preferring
+//! brotli over gzip. Small responses and other content types, such as the
+//! attachments and the event streams for subscriptions, are sent as is.
+
+use std::io::{Cursor, Write};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::{Request, Response};
+
+/// Responses smaller than this are not worth compressing.
+const MIN_SIZE: usize = 1024;
+
+/// Brotli quality, from 0 to 11. Higher levels are too slow for responses
+/// that are compressed on every request.
+const BROTLI_QUALITY: u32 = 5;
+
+/// Brotli window size, as a power of two.
+const BROTLI_WINDOW: u32 = 22;
+
+/// Content encodings supported for the responses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+	Brotli,
+	Gzip,
+}
+
+impl Encoding {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Encoding::Brotli => "br",
+			Encoding::Gzip => "gzip",
+		}
+	}
+
+	/// Chooses the encoding from the value of `Accept-Encoding`. Returns
+	/// `None` if neither encoding is accepted.
+	pub fn negotiate(accept: &str) -> Option<Encoding> {
+		let brotli = quality(accept, "br");
+		let gzip = quality(accept, "gzip");
+		if brotli > 0.0 && brotli >= gzip {
+			Some(Encoding::Brotli)
+		} else if gzip > 0.0 {
+			Some(Encoding::Gzip)
+		} else {
+			None
+		}
+	}
+
+	/// Compresses the data with this encoding.
+	pub fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+		match self {
+			Encoding::Brotli => {
+				let mut writer =
+					brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+				writer.write_all(data)?;
+				Ok(writer.into_inner())
+			}
+			Encoding::Gzip => {
+				let mut writer =
+					flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+				writer.write_all(data)?;
+				writer.finish()
+			}
+		}
+	}
+}
+
+/// Returns the quality value for the encoding in `Accept-Encoding`, falling
+/// back to the `*` entry. Encodings that are not listed have zero quality.
+fn quality(accept: &str, encoding: &str) -> f32 {
+	let mut any = 0.0;
+	for item in accept.split(',') {
+		let mut params = item.split(';');
+		let name = params.next().unwrap_or("").trim();
+		let value = params
+			.map(|it| it.trim())
+			.find(|it| it.starts_with("q="))
+			.map(|it| it[2..].trim().parse().unwrap_or(0.0))
+			.unwrap_or(1.0);
+		if name.eq_ignore_ascii_case(encoding) {
+			return value;
+		} else if name == "*" {
+			any = value;
+		}
+	}
+	any
+}
+
+/// Returns true for the JSON content types, including the `+json` ones.
+fn is_compressible(content_type: &ContentType) -> bool {
+	content_type.top() == "application"
+		&& (content_type.sub() == "json" || content_type.sub().as_str().ends_with("+json"))
+}
+
+/// Fairing that compresses the JSON responses.
+pub struct Compression;
+
+impl Fairing for Compression {
+	fn info(&self) -> Info {
+		Info {
+			name: "Compression",
+			kind: Kind::Response,
+		}
+	}
+
+	fn on_response(&self, request: &Request, response: &mut Response) {
+		match response.content_type() {
+			Some(content_type) if is_compressible(&content_type) => {}
+			_ => return,
+		}
+		if response.status() == Status::NoContent || response.headers().contains("Content-Encoding")
+		{
+			return;
+		}
+		let accept = request
+			.headers()
+			.get("Accept-Encoding")
+			.collect::<Vec<_>>()
+			.join(",");
+		let encoding = match Encoding::negotiate(&accept) {
+			Some(encoding) => encoding,
+			None => return,
+		};
+
+		// Responses vary by encoding even when they are not compressed.
+		response.adjoin_raw_header("Vary", "Accept-Encoding");
+		let body = match response.body_bytes() {
+			Some(body) => body,
+			None => return,
+		};
+		if body.len() < MIN_SIZE {
+			response.set_sized_body(Cursor::new(body));
+			return;
+		}
+		match encoding.compress(&body) {
+			Ok(compressed) => {
+				response.set_raw_header("Content-Encoding", encoding.as_str());
+				response.set_sized_body(Cursor::new(compressed));
+			}
+			Err(_) => response.set_sized_body(Cursor::new(body)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::io::Read;
+
+	#[test]
+	fn test_negotiate() {
+		assert_eq!(Encoding::negotiate(""), None);
+		assert_eq!(Encoding::negotiate("identity"), None);
+		assert_eq!(Encoding::negotiate("gzip"), Some(Encoding::Gzip));
+		assert_eq!(
+			Encoding::negotiate("gzip, deflate, br"),
+			Some(Encoding::Brotli)
+		);
+		assert_eq!(
+			Encoding::negotiate("br;q=0.5, gzip;q=0.8"),
+			Some(Encoding::Gzip)
+		);
+		assert_eq!(Encoding::negotiate("br;q=0, GZIP"), Some(Encoding::Gzip));
+		assert_eq!(Encoding::negotiate("*"), Some(Encoding::Brotli));
+		assert_eq!(Encoding::negotiate("*;q=0.1, br;q=0"), Some(Encoding::Gzip));
+		assert_eq!(Encoding::negotiate("gzip;q=0, *;q=0"), None);
+	}
+
+	#[test]
+	fn test_compress() {
+		let data = "{\"data\":{\"notes\":[]}}".repeat(100);
+
+		let compressed = Encoding::Gzip.compress(data.as_bytes()).unwrap();
+		assert!(compressed.len() < data.len());
+		let mut text = String::new();
+		flate2::read::GzDecoder::new(&compressed[..])
+			.read_to_string(&mut text)
+			.unwrap();
+		assert_eq!(text, data);
+
+		let compressed = Encoding::Brotli.compress(data.as_bytes()).unwrap();
+		assert!(compressed.len() < data.len());
+		let mut text = String::new();
+		brotli::Decompressor::new(&compressed[..], 4096)
+			.read_to_string(&mut text)
+			.unwrap();
+		assert_eq!(text, data);
+	}
+
+	#[test]
+	fn test_is_compressible() {
+		assert!(is_compressible(&ContentType::JSON));
+		assert!(is_compressible(
+			&ContentType::parse_flexible("application/problem+json").unwrap()
+		));
+		assert!(!is_compressible(&ContentType::HTML));
+		assert!(!is_compressible(&ContentType::new("text", "event-stream")));
+	}
+}
@@ -0,0 +1,407 @@
+//! Server configuration.
+//!
+//! The configuration is read from `kamipad.toml` in the current directory,
+//! or from the file given with `--config` or the `KAMIPAD_CONFIG` variable.
+//! All settings are optional, and can be overridden by a `KAMIPAD_*`
+//! variable or a command-line option (see `cli`):
+//!
+//! ```text
+//! address = "0.0.0.0"        # KAMIPAD_ADDRESS
+//! port = 3001                # KAMIPAD_PORT
+//! database = "database"      # KAMIPAD_DATABASE
+//! frontend = "dist"          # KAMIPAD_FRONTEND
+//! read_only = false          # KAMIPAD_READ_ONLY
+//! log_level = "info"         # KAMIPAD_LOG_LEVEL
+//! request_timeout = 30       # KAMIPAD_REQUEST_TIMEOUT
+//!
+//! [cache]
+//! records = 0                # KAMIPAD_CACHE_RECORDS
+//! logs = 1000                # KAMIPAD_CACHE_LOGS
+//!
+//! [tls]
+//! certs = "certs.pem"        # KAMIPAD_TLS_CERTS
+//! key = "key.pem"            # KAMIPAD_TLS_KEY
+//!
+//! [rate_limit]
+//! burst = 100
+//! per_second = 20
+//!
+//! [cors]
+//! origins = ["https://notes.example.com"]
+//! ```
+//!
+//! The server uses HTTPS when the `tls` table is set, with the certificate
+//! chain and private key in PEM format. The `rate_limit` table limits the
+//! requests for each client, see `rate_limit`. Requests that take longer
+//! than `request_timeout` seconds fail with 503, unless it is zero (see
+//! `timeout`). The `cors` table allows a frontend hosted on other origins to
+//! call the API, see `cors`.
+//!
+//! The address and port override the ones in `Rocket.toml`, which holds the
+//! settings for the web server and GraphQL. Relative paths in the file are
+//! relative to its directory. Without a file, the database is next to the
+//! server executable.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::cors::Cors;
+use crate::graph::rate_limit::RateLimit;
+use crate::util::{Error, Result};
+
+/// Default name of the configuration file.
+pub const CONFIG_FILE: &str = "kamipad.toml";
+
+/// Server configuration, see the module documentation.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// Address and port to listen on, if not the ones in `Rocket.toml`.
+	pub address: Option<String>,
+	pub port: Option<u16>,
+	/// Path of the database directory.
+	pub database: PathBuf,
+	/// Opens the database read-only, failing any changes.
+	pub read_only: bool,
+	/// Directory with the frontend bundle to serve at `/`, if any.
+	pub frontend: Option<PathBuf>,
+	/// Minimum level for the log entries in the terminal. Every entry is
+	/// kept in memory for the `logs` query, regardless of the level.
+	pub log_level: LogLevel,
+	/// Seconds for a request to finish before it fails, disabled with zero.
+	pub request_timeout: u64,
+	pub cache: CacheConfig,
+	pub tls: Option<TlsConfig>,
+	pub rate_limit: Option<RateLimit>,
+	pub cors: Option<Cors>,
+}
+
+/// Sizes of the in-memory caches.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+	/// Maximum size in bytes of the database cache for records, disabled
+	/// with zero.
+	pub records: usize,
+	/// Number of log entries kept in memory.
+	pub logs: usize,
+}
+
+/// Paths of the certificate chain and private key for HTTPS.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+	pub certs: PathBuf,
+	pub key: PathBuf,
+}
+
+/// Level for the terminal log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+	Critical,
+	Error,
+	Warning,
+	Info,
+	Debug,
+	Trace,
+}
+
+impl Default for Config {
+	fn default() -> Config {
+		Config {
+			address: None,
+			port: None,
+			database: PathBuf::from("database"),
+			read_only: false,
+			frontend: None,
+			log_level: LogLevel::Info,
+			request_timeout: 30,
+			cache: Default::default(),
+			tls: None,
+			rate_limit: None,
+			cors: None,
+		}
+	}
+}
+
+impl Default for CacheConfig {
+	fn default() -> CacheConfig {
+		CacheConfig {
+			records: 0,
+			logs: 1000,
+		}
+	}
+}
+
+impl LogLevel {
+	pub fn to_slog(self) -> slog::Level {
+		match self {
+			LogLevel::Critical => slog::Level::Critical,
+			LogLevel::Error => slog::Level::Error,
+			LogLevel::Warning => slog::Level::Warning,
+			LogLevel::Info => slog::Level::Info,
+			LogLevel::Debug => slog::Level::Debug,
+			LogLevel::Trace => slog::Level::Trace,
+		}
+	}
+}
+
+impl FromStr for LogLevel {
+	type Err = Error;
+
+	fn from_str(value: &str) -> Result<LogLevel> {
+		let level = match value.to_lowercase().as_str() {
+			"critical" => LogLevel::Critical,
+			"error" => LogLevel::Error,
+			"warning" => LogLevel::Warning,
+			"info" => LogLevel::Info,
+			"debug" => LogLevel::Debug,
+			"trace" => LogLevel::Trace,
+			_ => return Err(Error::from(format!("invalid log level `{}`", value))),
+		};
+		Ok(level)
+	}
+}
+
+impl Config {
+	/// Returns the `request_timeout`, if it is enabled.
+	pub fn request_timeout(&self) -> Option<Duration> {
+		match self.request_timeout {
+			0 => None,
+			secs => Some(Duration::from_secs(secs)),
+		}
+	}
+
+	/// Loads the configuration from the file and the environment. The file
+	/// defaults to `KAMIPAD_CONFIG` or `kamipad.toml`, and is not required
+	/// to exist in the latter case.
+	pub fn load(path: Option<PathBuf>) -> Result<Config> {
+		let path = path.or_else(|| std::env::var_os("KAMIPAD_CONFIG").map(PathBuf::from));
+		let required = path.is_some();
+		let path = path.unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+		let mut config = match std::fs::read_to_string(&path) {
+			Ok(text) => Config::parse(&text, path.parent())?,
+			Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+				let exe = std::env::current_exe()?;
+				let mut config = Config::default();
+				config.database = exe.parent().unwrap().join(&config.database);
+				config
+			}
+			Err(err) => {
+				let path = path.to_string_lossy();
+				return Err(Error::from(format!("failed to read {}: {}", path, err)));
+			}
+		};
+		config.apply_env(|name| std::env::var(name).ok())?;
+		Ok(config)
+	}
+
+	/// Parses the configuration file, resolving the paths relative to the
+	/// directory of the file.
+	pub fn parse(text: &str, dir: Option<&Path>) -> Result<Config> {
+		let mut config: Config = toml::from_str(text)
+			.map_err(|err| Error::from(format!("invalid {}: {}", CONFIG_FILE, err)))?;
+		if let Some(limit) = config.rate_limit {
+			if limit.burst == 0 || limit.per_second <= 0.0 {
+				let message = format!("invalid {}: rate_limit must be positive", CONFIG_FILE);
+				return Err(Error::from(message));
+			}
+		}
+		if let Some(cors) = &config.cors {
+			if cors.origins.is_empty() {
+				let message = format!("invalid {}: cors.origins can't be empty", CONFIG_FILE);
+				return Err(Error::from(message));
+			}
+		}
+		if let Some(dir) = dir {
+			config.database = dir.join(&config.database);
+			config.frontend = config.frontend.map(|path| dir.join(path));
+			if let Some(tls) = &mut config.tls {
+				tls.certs = dir.join(&tls.certs);
+				tls.key = dir.join(&tls.key);
+			}
+		}
+		Ok(config)
+	}
+
+	/// Overrides the settings from the `KAMIPAD_*` variables, read with the
+	/// given function.
+	pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<()> {
+		fn parse<T: FromStr>(name: &str, value: String) -> Result<T> {
+			value
+				.parse()
+				.map_err(|_| Error::from(format!("invalid {}: `{}`", name, value)))
+		}
+
+		if let Some(address) = var("KAMIPAD_ADDRESS") {
+			self.address = Some(address);
+		}
+		if let Some(port) = var("KAMIPAD_PORT") {
+			self.port = Some(parse("KAMIPAD_PORT", port)?);
+		}
+		if let Some(database) = var("KAMIPAD_DATABASE") {
+			self.database = PathBuf::from(database);
+		}
+		if let Some(frontend) = var("KAMIPAD_FRONTEND") {
+			self.frontend = Some(PathBuf::from(frontend));
+		}
+		if let Some(read_only) = var("KAMIPAD_READ_ONLY") {
+			self.read_only = parse("KAMIPAD_READ_ONLY", read_only)?;
+		}
+		if let Some(level) = var("KAMIPAD_LOG_LEVEL") {
+			self.log_level = level.parse()?;
+		}
+		if let Some(secs) = var("KAMIPAD_REQUEST_TIMEOUT") {
+			self.request_timeout = parse("KAMIPAD_REQUEST_TIMEOUT", secs)?;
+		}
+		if let Some(size) = var("KAMIPAD_CACHE_RECORDS") {
+			self.cache.records = parse("KAMIPAD_CACHE_RECORDS", size)?;
+		}
+		if let Some(size) = var("KAMIPAD_CACHE_LOGS") {
+			self.cache.logs = parse("KAMIPAD_CACHE_LOGS", size)?;
+		}
+
+		// Either path can be overridden if the file sets both.
+		let certs = var("KAMIPAD_TLS_CERTS").map(PathBuf::from);
+		let key = var("KAMIPAD_TLS_KEY").map(PathBuf::from);
+		match (certs, key, &mut self.tls) {
+			(Some(certs), Some(key), _) => self.tls = Some(TlsConfig { certs, key }),
+			(None, None, _) => {}
+			(certs, key, Some(tls)) => {
+				tls.certs = certs.unwrap_or_else(|| tls.certs.clone());
+				tls.key = key.unwrap_or_else(|| tls.key.clone());
+			}
+			_ => {
+				let message = "KAMIPAD_TLS_CERTS and KAMIPAD_TLS_KEY must be set together";
+				return Err(Error::from(message));
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_config() {
+		let config = Config::parse("", None).unwrap();
+		assert_eq!(config, Config::default());
+
+		let text = r#"
+			port = 8000
+			database = "data/kamipad"
+			frontend = "/srv/kamipad"
+			log_level = "debug"
+			request_timeout = 0
+
+			[cache]
+			logs = 50
+
+			[tls]
+			certs = "certs.pem"
+			key = "/etc/ssl/key.pem"
+
+			[rate_limit]
+			burst = 100
+			per_second = 20.0
+
+			[cors]
+			origins = ["https://notes.example.com"]
+		"#;
+		let config = Config::parse(text, Some(Path::new("/etc/kamipad"))).unwrap();
+		assert_eq!(config.address, None);
+		assert_eq!(config.port, Some(8000));
+		assert_eq!(config.database, Path::new("/etc/kamipad/data/kamipad"));
+		assert_eq!(config.frontend.unwrap(), Path::new("/srv/kamipad"));
+		assert_eq!(config.log_level, LogLevel::Debug);
+		assert_eq!(config.request_timeout(), None);
+		assert_eq!(config.cache.logs, 50);
+		assert_eq!(config.cache.records, CacheConfig::default().records);
+		let tls = config.tls.unwrap();
+		assert_eq!(tls.certs, Path::new("/etc/kamipad/certs.pem"));
+		assert_eq!(tls.key, Path::new("/etc/ssl/key.pem"));
+		let limit = config.rate_limit.unwrap();
+		assert_eq!(limit.burst, 100);
+		assert_eq!(limit.per_second, 20.0);
+		let cors = config.cors.unwrap();
+		assert_eq!(cors.origins, ["https://notes.example.com"]);
+		assert_eq!(cors.methods, ["GET", "POST", "OPTIONS"]);
+		assert_eq!(
+			cors.headers,
+			["Authorization", "Content-Type", "Accept-Version"]
+		);
+		assert_eq!(cors.max_age, None);
+
+		assert!(Config::parse("prot = 8000", None).is_err());
+		assert!(Config::parse("log_level = \"loud\"", None).is_err());
+		assert!(Config::parse("[tls]\ncerts = \"certs.pem\"", None).is_err());
+		assert!(Config::parse("[rate_limit]\nburst = 0\nper_second = 1.0", None).is_err());
+	}
+
+	#[test]
+	fn test_parse_cors() {
+		let text = "[cors]\norigins = [\"*\"]\nmethods = [\"GET\"]\nmax_age = 600";
+		let cors = Config::parse(text, None).unwrap().cors.unwrap();
+		assert_eq!(cors.origins, ["*"]);
+		assert_eq!(cors.methods, ["GET"]);
+		assert_eq!(cors.max_age, Some(600));
+
+		assert!(Config::parse("[cors]\norigins = []", None).is_err());
+		assert!(Config::parse("[cors]\norigins = \"*\"", None).is_err());
+		assert!(Config::parse("[cors]\norigins = [\"*\"]\nmax_age = -1", None).is_err());
+		assert!(Config::parse("[cors]\norigin = [\"*\"]", None).is_err());
+	}
+
+	#[test]
+	fn test_apply_env() {
+		let vars = |name: &str| match name {
+			"KAMIPAD_PORT" => Some("9000".to_string()),
+			"KAMIPAD_LOG_LEVEL" => Some("Warning".to_string()),
+			"KAMIPAD_CACHE_RECORDS" => Some("65536".to_string()),
+			"KAMIPAD_READ_ONLY" => Some("true".to_string()),
+			"KAMIPAD_REQUEST_TIMEOUT" => Some("5".to_string()),
+			_ => None,
+		};
+		let mut config = Config::default();
+		config.apply_env(vars).unwrap();
+		assert_eq!(config.port, Some(9000));
+		assert_eq!(config.log_level, LogLevel::Warning);
+		assert_eq!(config.cache.records, 65536);
+		assert!(config.read_only);
+		assert_eq!(config.request_timeout(), Some(Duration::from_secs(5)));
+		assert_eq!(config.database, Config::default().database);
+
+		let mut config = Config::default();
+		let result = config.apply_env(|name| match name {
+			"KAMIPAD_PORT" => Some("port".to_string()),
+			_ => None,
+		});
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn test_apply_env_tls() {
+		let certs = |name: &str| match name {
+			"KAMIPAD_TLS_CERTS" => Some("certs.pem".to_string()),
+			_ => None,
+		};
+		let mut config = Config::default();
+		assert!(config.apply_env(certs).is_err());
+
+		config
+			.apply_env(|name| match name {
+				"KAMIPAD_TLS_CERTS" => Some("a.pem".to_string()),
+				"KAMIPAD_TLS_KEY" => Some("b.pem".to_string()),
+				_ => None,
+			})
+			.unwrap();
+		config.apply_env(certs).unwrap();
+		let tls = config.tls.unwrap();
+		assert_eq!(tls.certs, Path::new("certs.pem"));
+		assert_eq!(tls.key, Path::new("b.pem"));
+	}
+}
@@ -0,0 +1,138 @@
+//! Cross-origin resource sharing, so that a frontend hosted on another origin
+//! can call the API from the browser.
+//!
+//! CORS is enabled by the `cors` table of `kamipad.toml` (see `config`), with
+//! the allowed origins, or `"*"` for any origin. The methods and headers are
+//! optional:
+//!
+//! ```text
+//! [cors]
+//! origins = ["https://notes.example.com"]
+//! methods = ["GET", "POST", "OPTIONS"]
+//! headers = ["Authorization", "Content-Type", "Accept-Version"]
+//! max_age = 86400
+//! ```
+//!
+//! Preflight requests from an allowed origin are answered with no content.
+//! Requests from other origins get no CORS headers, so the browser blocks
+//! them. Credentials are not allowed, since the API authenticates with the
+//! `Authorization` header instead of cookies.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::{Request, Response};
+
+/// Methods allowed by default.
+const DEFAULT_METHODS: &[&str] = &["GET", "POST", "OPTIONS"];
+
+/// Request headers allowed by default.
+const DEFAULT_HEADERS: &[&str] = &["Authorization", "Content-Type", "Accept-Version"];
+
+/// Response headers that the browser exposes to the frontend.
+const EXPOSE_HEADERS: &str = "X-Request-Id, X-Response-Time, X-Api-Version, Deprecation";
+
+/// Fairing that adds the CORS headers and answers preflight requests, with
+/// the settings from the `cors` table.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cors {
+	pub origins: Vec<String>,
+	#[serde(default = "default_methods")]
+	pub methods: Vec<String>,
+	#[serde(default = "default_headers")]
+	pub headers: Vec<String>,
+	/// Seconds the browser can cache a preflight response.
+	pub max_age: Option<u64>,
+}
+
+fn default_methods() -> Vec<String> {
+	DEFAULT_METHODS.iter().map(|it| it.to_string()).collect()
+}
+
+fn default_headers() -> Vec<String> {
+	DEFAULT_HEADERS.iter().map(|it| it.to_string()).collect()
+}
+
+impl Cors {
+	/// Returns the value for `Access-Control-Allow-Origin` if the origin is
+	/// allowed.
+	fn allowed_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+		if self.origins.iter().any(|it| it == "*") {
+			Some("*")
+		} else if self
+			.origins
+			.iter()
+			.any(|it| it.eq_ignore_ascii_case(origin))
+		{
+			Some(origin)
+		} else {
+			None
+		}
+	}
+}
+
+impl Fairing for Cors {
+	fn info(&self) -> Info {
+		Info {
+			name: "CORS",
+			kind: Kind::Response,
+		}
+	}
+
+	fn on_response(&self, request: &Request, response: &mut Response) {
+		let origin = match request.headers().get_one("Origin") {
+			Some(origin) => origin,
+			None => return,
+		};
+		let allowed = match self.allowed_origin(origin) {
+			Some(allowed) => allowed.to_string(),
+			None => return,
+		};
+		if allowed != "*" {
+			response.adjoin_raw_header("Vary", "Origin");
+		}
+		response.set_raw_header("Access-Control-Allow-Origin", allowed);
+
+		let preflight = request.method() == Method::Options
+			&& request.headers().contains("Access-Control-Request-Method");
+		if !preflight {
+			response.set_raw_header("Access-Control-Expose-Headers", EXPOSE_HEADERS);
+			return;
+		}
+		response.set_raw_header("Access-Control-Allow-Methods", self.methods.join(", "));
+		response.set_raw_header("Access-Control-Allow-Headers", self.headers.join(", "));
+		if let Some(max_age) = self.max_age {
+			response.set_raw_header("Access-Control-Max-Age", max_age.to_string());
+		}
+
+		// There are no routes for OPTIONS, so preflight requests end up in the
+		// not found catcher.
+		if response.status() == Status::NotFound {
+			response.set_status(Status::NoContent);
+			response.remove_header("Content-Type");
+			response.take_body();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(table: &str) -> Cors {
+		toml::from_str(table).unwrap()
+	}
+
+	#[test]
+	fn test_allowed_origin() {
+		let cors = parse(r#"origins = ["https://notes.example.com"]"#);
+		assert_eq!(
+			cors.allowed_origin("https://notes.example.com"),
+			Some("https://notes.example.com")
+		);
+		assert_eq!(cors.allowed_origin("https://evil.example.com"), None);
+
+		let cors = parse(r#"origins = ["*"]"#);
+		assert_eq!(cors.allowed_origin("https://evil.example.com"), Some("*"));
+	}
+}
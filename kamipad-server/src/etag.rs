@@ -0,0 +1,82 @@
+//! Entity tags for the responses that don't change often, so that clients
+//! can revalidate them with `If-None-Match` instead of downloading them again.
+//!
+//! The tag is the SHA-256 hash of the response body. It is a weak tag, since
+//! the body may be compressed on the way out (see `compression`).
+
+use std::io::Cursor;
+
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+use sha2::{Digest, Sha256};
+
+/// Responder that adds an `ETag` to a successful response, and responds with
+/// `304 Not Modified` if the request has a matching `If-None-Match`.
+pub struct ETagged<R>(pub R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for ETagged<R> {
+	fn respond_to(self, request: &Request) -> response::Result<'r> {
+		let mut response = self.0.respond_to(request)?;
+		if response.status() != Status::Ok {
+			return Ok(response);
+		}
+		let body = match response.body_bytes() {
+			Some(body) => body,
+			None => return Ok(response),
+		};
+		let etag = compute(&body);
+		let if_none_match = request.headers().get("If-None-Match");
+		if if_none_match.into_iter().any(|it| matches(it, &etag)) {
+			return Response::build()
+				.status(Status::NotModified)
+				.raw_header("ETag", etag)
+				.ok();
+		}
+		response.set_raw_header("ETag", etag);
+		response.set_sized_body(Cursor::new(body));
+		Ok(response)
+	}
+}
+
+/// Returns the weak entity tag for a response body.
+fn compute(body: &[u8]) -> String {
+	let hash = Sha256::digest(body)
+		.iter()
+		.take(16)
+		.map(|byte| format!("{:02x}", byte))
+		.collect::<String>();
+	format!("W/\"{}\"", hash)
+}
+
+/// Returns true if the value of `If-None-Match` matches the entity tag, with
+/// the weak comparison.
+fn matches(if_none_match: &str, etag: &str) -> bool {
+	let etag = etag.trim_start_matches("W/");
+	if_none_match
+		.split(',')
+		.map(|it| it.trim())
+		.any(|it| it == "*" || it.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_etag() {
+		let etag = compute(b"{\"name\":\"kamipad\"}");
+		assert!(etag.starts_with("W/\""));
+		assert_eq!(etag.len(), 36);
+		assert_eq!(etag, compute(b"{\"name\":\"kamipad\"}"));
+		assert_ne!(etag, compute(b"{\"name\":\"kamipad2\"}"));
+
+		let tag = etag.trim_start_matches("W/");
+		assert!(matches(&etag, &etag));
+		assert!(matches(tag, &etag));
+		assert!(matches(&format!("\"other\", {}", tag), &etag));
+		assert!(matches("*", &etag));
+		assert!(!matches("\"other\"", &etag));
+		assert!(!matches("", &etag));
+	}
+}
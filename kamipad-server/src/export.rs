@@ -0,0 +1,276 @@
+//! Export of a notebook as a zip archive.
+//!
+//! A notebook is the set of notes with a tag, or all notes without one. The
+//! archive has a markdown file for each note, in a folder named after the
+//! tag, and the attachments of the notes, linked from their files:
+//!
+//! ```text
+//! <tag>/<title>-<id>.md
+//! attachments/<attachment id>/<filename>
+//! ```
+//!
+//! The folder is `notes` when exporting all notes. Importing the archive
+//! with `App::import_notes` tags the notes with the folder name again.
+//!
+//! Exports run in a background thread, as an `ExportJob`. The archive is
+//! kept in the blob store until `JOB_TTL` after the job finishes, and is
+//! downloaded from a signed URL.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::Mutex;
+
+use kamipad_data as kd;
+use zip::write::{FileOptions, ZipWriter};
+
+use crate::app::App;
+use crate::import::{JobStatus, JOB_TTL};
+use crate::notes::Note;
+use crate::util::time::unix_millis;
+use crate::util::{Error, Result};
+
+/// Export jobs, by ID.
+pub type ExportJobs = Mutex<HashMap<kd::ID, ExportJob>>;
+
+/// An export running in the background, see `App::export_notebook`.
+#[derive(Clone, Debug)]
+pub struct ExportJob {
+	pub id: kd::ID,
+	pub status: JobStatus,
+	/// Tag of the exported notes, or `None` for all notes.
+	pub tag: Option<String>,
+	/// Number of exported notes, once done.
+	pub notes: usize,
+	/// Blob with the archive, once done.
+	pub blob: Option<kd::BlobId>,
+	/// Size of the archive in bytes, once done.
+	pub size: u64,
+	/// Reason the job failed.
+	pub error: Option<String>,
+	/// Start and end time, in milliseconds since the UNIX epoch.
+	pub started: u64,
+	pub finished: Option<u64>,
+}
+
+impl App {
+	/// Starts exporting the notes with a tag, or all notes for `None`.
+	/// Returns the job, which runs in the background.
+	pub fn export_notebook(&'static self, tag: Option<String>) -> ExportJob {
+		let job = ExportJob {
+			id: kd::ID::new_sortable(),
+			status: JobStatus::Running,
+			tag,
+			notes: 0,
+			blob: None,
+			size: 0,
+			error: None,
+			started: unix_millis(),
+			finished: None,
+		};
+		self.remove_expired_exports();
+		self.export_jobs.lock().unwrap().insert(job.id, job.clone());
+
+		let (id, tag) = (job.id, job.tag.clone());
+		std::thread::spawn(move || {
+			let result = self.run_export(tag.as_deref());
+			let mut jobs = self.export_jobs.lock().unwrap();
+			let job = match jobs.get_mut(&id) {
+				Some(job) => job,
+				None => return,
+			};
+			job.finished = Some(unix_millis());
+			match result {
+				Ok((blob, notes, size)) => {
+					info!(self.log, "exported {} notes to {}", notes, blob; "job" => %id);
+					job.status = JobStatus::Done;
+					job.blob = Some(blob);
+					job.notes = notes;
+					job.size = size;
+				}
+				Err(err) => {
+					error!(self.log, "failed to export notes: {}", err; "job" => %id);
+					job.status = JobStatus::Failed;
+					job.error = Some(err.to_string());
+				}
+			}
+		});
+		job
+	}
+
+	/// Returns an export job by its ID.
+	pub fn export_job(&self, id: &kd::ID) -> Option<ExportJob> {
+		self.export_jobs.lock().unwrap().get(id).cloned()
+	}
+
+	/// Returns a signed URL to download the archive of a finished export.
+	/// The URL is valid until the export expires.
+	pub fn export_download_url(&self, job: &ExportJob) -> Result<Option<String>> {
+		let finished = match (job.blob, job.finished) {
+			(Some(_), Some(finished)) => finished,
+			_ => return Ok(None),
+		};
+		let expires = finished + JOB_TTL.as_millis() as u64;
+		let ttl = std::time::Duration::from_millis(expires.saturating_sub(unix_millis()));
+		let url = self.signed_url(&format!("/api/exports/{}", job.id), ttl)?;
+		Ok(Some(url))
+	}
+
+	/// Returns the tag and archive of an export, for a signed download URL.
+	/// Returns `None` if the signature is not valid or expired, or if the
+	/// export is not available.
+	pub fn download_export(
+		&self,
+		id: &kd::ID,
+		expires: u64,
+		signature: &str,
+	) -> Result<Option<(Option<String>, Vec<u8>)>> {
+		let path = format!("/api/exports/{}", id);
+		if !self.check_signed_url(&path, expires, signature)? {
+			return Ok(None);
+		}
+		let job = match self.export_job(id) {
+			Some(job) => job,
+			None => return Ok(None),
+		};
+		match job.blob {
+			Some(blob) => Ok(self.database().get_blob(&blob)?.map(|data| (job.tag, data))),
+			None => Ok(None),
+		}
+	}
+
+	/// Writes the archive for the notes to the blob store. Returns the blob,
+	/// the number of notes and the size of the archive.
+	fn run_export(&self, tag: Option<&str>) -> Result<(kd::BlobId, usize, u64)> {
+		let notes = match tag {
+			Some(tag) => self.notes_by_tag(tag)?,
+			None => self.notes()?,
+		};
+		let folder = tag.unwrap_or("notes");
+		let options = FileOptions::default();
+		let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+		let mut exported_attachments = Vec::new();
+		for note in &notes {
+			let mut text = note_markdown(note);
+			let attachments = self.note_attachments(&note.id)?.unwrap_or_default();
+			if !attachments.is_empty() {
+				text.push_str("\n\n## Attachments\n\n");
+			}
+			for attachment in attachments {
+				let path = format!(
+					"attachments/{}/{}",
+					attachment.id,
+					safe_filename(&attachment.filename)
+				);
+				text.push_str(&format!("- [{}](../{})\n", attachment.filename, path));
+				if !exported_attachments.contains(&attachment.id) {
+					let blob = kd::BlobId::parse(&attachment.blob).ok_or_else(|| {
+						Error::from(format!("invalid blob for {}", attachment.id))
+					})?;
+					let data = self.database().get_blob(&blob)?.unwrap_or_default();
+					zip.start_file(path, options).map_err(Error::from)?;
+					zip.write_all(&data)?;
+					exported_attachments.push(attachment.id);
+				}
+			}
+			zip.start_file(format!("{}/{}", folder, note_filename(note)), options)
+				.map_err(Error::from)?;
+			zip.write_all(text.as_bytes())?;
+		}
+		let data = zip.finish().map_err(Error::from)?.into_inner();
+		let blob = self.database().put_blob(&data)?;
+		Ok((blob, notes.len(), data.len() as u64))
+	}
+
+	/// Removes the exports that finished more than `JOB_TTL` ago, releasing
+	/// their archives.
+	fn remove_expired_exports(&self) {
+		let expired = unix_millis().saturating_sub(JOB_TTL.as_millis() as u64);
+		let mut jobs = self.export_jobs.lock().unwrap();
+		let ids = jobs
+			.values()
+			.filter(|job| job.finished.map_or(false, |time| time <= expired))
+			.map(|job| job.id)
+			.collect::<Vec<_>>();
+		for id in ids {
+			let blob = jobs.remove(&id).and_then(|job| job.blob);
+			if let Some(blob) = blob {
+				if let Err(err) = self.database().release_blob(&blob) {
+					warn!(self.log, "failed to release export {}: {}", blob, err);
+				}
+			}
+		}
+	}
+}
+
+/// Returns the markdown for a note, with the title as heading.
+fn note_markdown(note: &Note) -> String {
+	format!("# {}\n\n{}\n", note.title, note.text.trim_end())
+}
+
+/// Returns the file name for a note, from its title and ID.
+fn note_filename(note: &Note) -> String {
+	let mut slug = String::new();
+	for c in note.title.to_lowercase().chars() {
+		if c.is_alphanumeric() {
+			slug.push(c);
+		} else if !slug.is_empty() && !slug.ends_with('-') {
+			slug.push('-');
+		}
+	}
+	let slug = slug
+		.trim_end_matches('-')
+		.chars()
+		.take(50)
+		.collect::<String>();
+	let id = note.id.to_string();
+	if slug.is_empty() {
+		format!("{}.md", &id[..8])
+	} else {
+		format!("{}-{}.md", slug, &id[..8])
+	}
+}
+
+/// Returns a file name without path separators or control characters.
+fn safe_filename(name: &str) -> String {
+	let name = name.replace(|c: char| c == '/' || c == '\\' || c.is_control(), "_");
+	match name.as_str() {
+		"" | "." | ".." => String::from("file"),
+		_ => name,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn note(title: &str) -> Note {
+		Note {
+			id: kd::ID::parse("645a9c23-9590-49d0-879e-250bff5b621a").unwrap(),
+			title: title.to_string(),
+			text: "Some text\n".to_string(),
+			created: 0,
+			updated: 0,
+			metadata: Default::default(),
+			reminder: None,
+			archived: false,
+		}
+	}
+
+	#[test]
+	fn test_note_filename() {
+		assert_eq!(note_filename(&note("My List")), "my-list-645a9c23.md");
+		assert_eq!(note_filename(&note(" A / B? ")), "a-b-645a9c23.md");
+		assert_eq!(note_filename(&note("?")), "645a9c23.md");
+	}
+
+	#[test]
+	fn test_note_markdown() {
+		assert_eq!(note_markdown(&note("List")), "# List\n\nSome text\n");
+	}
+
+	#[test]
+	fn test_safe_filename() {
+		assert_eq!(safe_filename("a/b\\c.txt"), "a_b_c.txt");
+		assert_eq!(safe_filename(".."), "file");
+	}
+}
@@ -1,11 +1,32 @@
 //! Implementation for the GraphQL endpoints.
 
-use rocket::response::content::Html;
-use rocket::State;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use regex::Regex;
+use rocket::http::{ContentType, Status};
+use rocket::request::LenientForm;
+use rocket::response::content::{Content, Html};
+use rocket::response::Stream;
+use rocket::{Data, State};
+use rocket_contrib::json::Json;
+use tokio::runtime::Handle;
 
 use crate::app::App;
+use crate::auth::Auth;
+use crate::etag::ETagged;
 use crate::graph;
-use crate::logging::RequestLog;
+use crate::graph::rate_limit::{client_key, ClientIp, RateLimiter};
+use crate::graph::upload::Uploads;
+use crate::logging::{RequestId, RequestLog};
+use crate::status::ActiveRequest;
+use crate::timeout;
+
+/// Interval between the comments sent to keep an idle subscription stream
+/// open through proxies, and to notice when the client disconnects.
+const STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
 
 /// This endpoint just servers the static HTML for the GraphiQL interface.
 #[get("/graphiql")]
@@ -13,16 +34,652 @@ pub fn ide() -> Html<String> {
 	Html(graphiql_source("Kamipad - GraphiQL", "/api/graphql"))
 }
 
+/// Serves the scripts and styles used by the GraphiQL interface. These are
+/// embedded in the binary so the IDE works without internet access.
+///
+/// The files are vendored in `assets/graphiql` (see `make graphiql`), and the
+/// build fails without them (see `build.rs`).
+#[get("/graphiql/<file>")]
+pub fn ide_asset(file: String) -> Option<Content<&'static [u8]>> {
+	GRAPHIQL_ASSETS
+		.iter()
+		.find(|(name, _)| *name == file)
+		.map(|(name, data)| {
+			let content_type = if name.ends_with(".css") {
+				ContentType::CSS
+			} else {
+				ContentType::JavaScript
+			};
+			Content(content_type, *data)
+		})
+}
+
+macro_rules! graphiql_asset {
+	($name:literal) => {
+		(
+			$name,
+			&include_bytes!(concat!("../../assets/graphiql/", $name))[..],
+		)
+	};
+}
+
+/// Vendored GraphiQL assets, by file name, in the order they are loaded.
+const GRAPHIQL_ASSETS: &[(&str, &[u8])] = &[
+	graphiql_asset!("es6-promise.auto.min.js"),
+	graphiql_asset!("fetch.min.js"),
+	graphiql_asset!("react.min.js"),
+	graphiql_asset!("react-dom.min.js"),
+	graphiql_asset!("graphiql.min.css"),
+	graphiql_asset!("graphiql.min.js"),
+];
+
+/// Returns the tags that load the vendored GraphiQL scripts and styles.
+fn graphiql_links() -> String {
+	GRAPHIQL_ASSETS
+		.iter()
+		.map(|(name, _)| {
+			let url = format!("/api/graphiql/{}", name);
+			if name.ends_with(".css") {
+				format!(r#"<link rel="stylesheet" href="{}" />"#, url)
+			} else {
+				format!(r#"<script src="{}"></script>"#, url)
+			}
+		})
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+/// This endpoint returns the schema in the GraphQL SDL, for code generation.
+/// Like the GraphiQL interface, it is only available with introspection.
+#[get("/graphql/schema")]
+pub fn schema(schema: State<&graph::Schema>) -> ETagged<Content<String>> {
+	ETagged(Content(ContentType::Plain, schema.as_schema_language()))
+}
+
+/// Parameters for a GraphQL request sent with GET. The `variables` and
+/// `extensions` are JSON strings.
+#[derive(FromForm)]
+pub struct GetRequest {
+	query: Option<String>,
+	variables: Option<String>,
+	#[form(field = "operationName")]
+	operation_name: Option<String>,
+	extensions: Option<String>,
+}
+
+/// This endpoint executes a GraphQL query sent with GET, so that responses
+/// can be cached and linked to. Mutations are rejected, as GET requests must
+/// not have side effects.
+#[get("/graphql?<request..>")]
+pub fn get_query(
+	app: State<&App>,
+	log: RequestLog,
+	request_id: RequestId,
+	auth: Auth,
+	ip: ClientIp,
+	limiter: State<RateLimiter>,
+	request: LenientForm<GetRequest>,
+	schema: State<&graph::Schema>,
+	config: State<graph::GraphConfig>,
+	runtime: State<Handle>,
+) -> juniper_rocket::GraphQLResponse {
+	if let Err(response) = check_rate_limit(&limiter, &auth, &ip, request_id) {
+		return response;
+	}
+	let app = *app.inner();
+	let request = request.into_inner();
+	let (status, body) = match get_request_body(app, request) {
+		Ok(body) => {
+			let context = new_context(app, log, request_id, auth, Uploads::default(), &config);
+			execute(context, body, &schema, &config, &runtime)
+		}
+		Err(err) => (Status::BadRequest, err.to_response()),
+	};
+	let body = graph::error::add_request_id(body, request_id);
+	juniper_rocket::GraphQLResponse(status, body)
+}
+
+/// This endpoint executes a GraphQL subscription sent with GET, streaming
+/// each result as a server-sent event with the JSON response as data, such as
+/// `data: {"data":{"logStream":{...}}}`.
+///
+/// Rocket has no support for websockets, so this uses a response body that
+/// never ends. The subscription runs as a task on the runtime, and sends a
+/// `: keep-alive` comment every `STREAM_KEEP_ALIVE` while idle. It stops at
+/// the first event after the client disconnects, or when the subscription
+/// ends, which `App::shutdown` does for the log streams. Open streams count
+/// as active requests until then, so the shutdown waits for them.
+///
+/// Each stream holds a Rocket worker while open, so they are limited by the
+/// `StreamLimit`, and fail with 503 over it.
+#[get("/graphql/stream?<request..>")]
+pub fn stream(
+	app: State<&App>,
+	log: RequestLog,
+	request_id: RequestId,
+	auth: Auth,
+	ip: ClientIp,
+	limiter: State<RateLimiter>,
+	streams: State<StreamLimit>,
+	request: LenientForm<GetRequest>,
+	schema: State<&graph::Schema>,
+	config: State<graph::GraphConfig>,
+	runtime: State<Handle>,
+) -> Result<Content<Stream<EventStream>>, juniper_rocket::GraphQLResponse> {
+	check_rate_limit(&limiter, &auth, &ip, request_id)?;
+	let app = *app.inner();
+	let request = get_request_body(app, request.into_inner())
+		.and_then(|body| {
+			serde_json::from_value::<juniper::http::GraphQLRequest>(body).map_err(|err| {
+				graph::error::Error::bad_request(format!("invalid request: {}", err))
+			})
+		})
+		.map_err(|err| {
+			let body = graph::error::add_request_id(err.to_response(), request_id);
+			juniper_rocket::GraphQLResponse(Status::BadRequest, body)
+		})?;
+	let slot = streams.acquire().ok_or_else(|| {
+		let err = graph::error::Error::new(
+			graph::error::ErrorCode::Unavailable,
+			"too many subscription streams, try again later",
+		);
+		let body = graph::error::add_request_id(err.to_response(), request_id);
+		juniper_rocket::GraphQLResponse(Status::ServiceUnavailable, body)
+	})?;
+
+	let active = app.active_request();
+
+	let context = new_context(app, log, request_id, auth, Uploads::default(), &config);
+	let schema: &'static graph::Schema = *schema.inner();
+	let (sender, receiver) = std::sync::mpsc::channel();
+	runtime.spawn(async move {
+		let data = |response: String| format!("data: {}\n\n", response);
+		let resolved = juniper::http::resolve_into_stream(&request, schema, &context).await;
+		let mut results = match resolved {
+			Ok((stream, errors)) => juniper_subscriptions::Connection::from_stream(stream, errors),
+			Err(err) => {
+				let response = juniper::http::GraphQLResponse::error(err);
+				let _ = sender.send(data(serde_json::to_string(&response).unwrap()));
+				return;
+			}
+		};
+		let start = tokio::time::Instant::now() + STREAM_KEEP_ALIVE;
+		let mut keep_alive = tokio::time::interval_at(start, STREAM_KEEP_ALIVE);
+		loop {
+			let event = tokio::select! {
+				result = results.next() => match result {
+					Some(result) => data(serde_json::to_string(&result).unwrap()),
+					None => break,
+				},
+				_ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+			};
+			if sender.send(event).is_err() {
+				break;
+			}
+		}
+	});
+
+	let content_type = ContentType::new("text", "event-stream");
+	Ok(Content(
+		content_type,
+		Stream::from(EventStream::new(receiver, slot, active)),
+	))
+}
+
+/// Response body for `stream`, with the events sent by the subscription
+/// task. The body ends when the subscription does.
+pub struct EventStream {
+	events: std::sync::mpsc::Receiver<String>,
+	buffer: std::io::Cursor<Vec<u8>>,
+	_slot: StreamSlot,
+	// Keeps the stream in the requests `App::shutdown` waits for.
+	_active: ActiveRequest,
+}
+
+impl EventStream {
+	fn new(
+		events: std::sync::mpsc::Receiver<String>,
+		slot: StreamSlot,
+		active: ActiveRequest,
+	) -> EventStream {
+		EventStream {
+			events,
+			buffer: Default::default(),
+			_slot: slot,
+			_active: active,
+		}
+	}
+}
+
+/// Limit for the subscription streams open at once, see `stream`.
+pub struct StreamLimit {
+	max: usize,
+	open: Arc<AtomicUsize>,
+}
+
+impl StreamLimit {
+	pub fn new(max: usize) -> StreamLimit {
+		StreamLimit {
+			max,
+			open: Default::default(),
+		}
+	}
+
+	/// Takes a slot for a stream, which is released when dropped. Returns
+	/// `None` if all the slots are taken.
+	fn acquire(&self) -> Option<StreamSlot> {
+		let max = self.max;
+		self.open
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+				if open < max {
+					Some(open + 1)
+				} else {
+					None
+				}
+			})
+			.ok()
+			.map(|_| StreamSlot(self.open.clone()))
+	}
+}
+
+/// Slot for an open stream in a `StreamLimit`.
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+impl std::io::Read for EventStream {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		if self.buffer.position() as usize >= self.buffer.get_ref().len() {
+			match self.events.recv() {
+				Ok(event) => self.buffer = std::io::Cursor::new(event.into_bytes()),
+				Err(_) => return Ok(0),
+			}
+		}
+		std::io::Read::read(&mut self.buffer, buf)
+	}
+}
+
+/// Returns the JSON body for a GET request, with persisted queries resolved.
+fn get_request_body(app: &App, request: GetRequest) -> graph::error::Result<serde_json::Value> {
+	let parse = |name: &str, value: Option<String>| match value {
+		Some(value) => serde_json::from_str(&value).map_err(|err| {
+			graph::error::Error::bad_request(format!("invalid `{}`: {}", name, err))
+		}),
+		None => Ok(serde_json::Value::Null),
+	};
+	let mut body = serde_json::json!({
+		"query": request.query,
+		"variables": parse("variables", request.variables)?,
+		"operationName": request.operation_name,
+		"extensions": parse("extensions", request.extensions)?,
+	});
+	graph::persisted::resolve_persisted_queries(app, &mut body)?;
+	let query = body.get("query").and_then(|query| query.as_str());
+	if query.map(has_mutation) == Some(true) {
+		return Err(graph::error::Error::bad_request(
+			"mutations are not allowed with GET",
+		));
+	}
+	Ok(body)
+}
+
+/// This endpoint executes a GraphQL query with file uploads, sent as a
+/// multipart request. See `graph::upload`.
+#[post("/graphql", format = "multipart/form-data", data = "<data>", rank = 1)]
+pub fn upload(
+	app: State<&App>,
+	log: RequestLog,
+	request_id: RequestId,
+	auth: Auth,
+	ip: ClientIp,
+	limiter: State<RateLimiter>,
+	content_type: &ContentType,
+	data: Data,
+	schema: State<&graph::Schema>,
+	config: State<graph::GraphConfig>,
+	runtime: State<Handle>,
+) -> juniper_rocket::GraphQLResponse {
+	if let Err(response) = check_rate_limit(&limiter, &auth, &ip, request_id) {
+		return response;
+	}
+	let app = *app.inner();
+	let boundary = content_type
+		.params()
+		.find(|(name, _)| *name == "boundary")
+		.map(|(_, value)| value.to_string());
+	let parsed = match boundary {
+		Some(boundary) => graph::upload::parse_multipart(app, data.open(), &boundary),
+		None => Err(graph::error::Error::bad_request(
+			"missing multipart boundary",
+		)),
+	};
+	let (status, body) = match parsed {
+		Ok((body, uploads)) => {
+			let context = new_context(app, log, request_id, auth, uploads, &config);
+			execute(context, body, &schema, &config, &runtime)
+		}
+		Err(err) => (Status::BadRequest, err.to_response()),
+	};
+	let body = graph::error::add_request_id(body, request_id);
+	juniper_rocket::GraphQLResponse(status, body)
+}
+
 /// This endpoint is responsible for executing a GraphQL query.
-#[post("/graphql", data = "<request>")]
+///
+/// The body may also be an array of requests, which are executed concurrently
+/// and answered with an array of responses in the same order. Requests in a
+/// batch share the context, including the loaders, so data loaded for one
+/// request is reused by the others. Batches are limited to `MAX_BATCH_SIZE`
+/// requests.
+///
+/// The request is parsed here instead of using `juniper_rocket`, so that
+/// automatic persisted queries can fill in the query text before parsing.
+/// See `graph::persisted`.
+#[post("/graphql", data = "<body>", rank = 2)]
 pub fn query(
 	app: State<&App>,
 	log: RequestLog,
-	request: juniper_rocket::GraphQLRequest,
-	schema: State<graph::Schema>,
+	request_id: RequestId,
+	auth: Auth,
+	ip: ClientIp,
+	limiter: State<RateLimiter>,
+	body: Json<serde_json::Value>,
+	schema: State<&graph::Schema>,
+	config: State<graph::GraphConfig>,
+	runtime: State<Handle>,
 ) -> juniper_rocket::GraphQLResponse {
-	let context = graph::Context { app: &app, log };
-	request.execute(&schema, &context)
+	if let Err(response) = check_rate_limit(&limiter, &auth, &ip, request_id) {
+		return response;
+	}
+	let mut body = body.into_inner();
+	let (status, body) = match graph::persisted::resolve_persisted_queries(&app, &mut body) {
+		Ok(()) => {
+			let context = new_context(
+				*app.inner(),
+				log,
+				request_id,
+				auth,
+				Uploads::default(),
+				&config,
+			);
+			execute(context, body, &schema, &config, &runtime)
+		}
+		Err(err) => (Status::Ok, err.to_response()),
+	};
+	let body = graph::error::add_request_id(body, request_id);
+	juniper_rocket::GraphQLResponse(status, body)
+}
+
+/// Checks the rate limit for the client, returning the response with the
+/// `TOO_MANY_REQUESTS` error if it was exceeded.
+fn check_rate_limit(
+	limiter: &RateLimiter,
+	auth: &Auth,
+	ip: &ClientIp,
+	request_id: RequestId,
+) -> Result<(), juniper_rocket::GraphQLResponse> {
+	limiter.check(&client_key(auth, ip)).map_err(|err| {
+		let body = graph::error::add_request_id(err.to_response(), request_id);
+		juniper_rocket::GraphQLResponse(Status::TooManyRequests, body)
+	})
+}
+
+fn new_context(
+	app: &'static App,
+	log: RequestLog,
+	request_id: RequestId,
+	auth: Auth,
+	uploads: Uploads,
+	config: &graph::GraphConfig,
+) -> graph::Context {
+	graph::Context {
+		app,
+		log,
+		request_id,
+		loaders: graph::loader::Loaders::new(app),
+		uploads,
+		user: auth.0,
+		cache_ttl: config.cache_ttl.clone(),
+		tracer: if config.tracing {
+			Some(graph::tracing::Tracer::new())
+		} else {
+			None
+		},
+	}
+}
+
+/// Executes a GraphQL request body, which may be a batch.
+///
+/// The resolvers run in the context of the tokio `runtime`, so that they can
+/// use its I/O. Rocket handlers are synchronous, so the request thread waits
+/// here for the resolvers, which run any blocking work on the database
+/// threads (see `App::run`).
+///
+/// Requests that take longer than the configured timeout fail with 503. Any
+/// blocking work already started still runs to the end on the database
+/// threads, but the request thread is released.
+fn execute(
+	context: graph::Context,
+	body: serde_json::Value,
+	schema: &graph::Schema,
+	config: &graph::GraphConfig,
+	runtime: &Handle,
+) -> (Status, String) {
+	let result = check_batch(&body)
+		.and_then(|_| check_introspection(&body, config))
+		.and_then(|_| {
+			serde_json::from_value::<juniper::http::GraphQLBatchRequest>(body).map_err(|err| {
+				graph::error::Error::bad_request(format!("invalid request: {}", err))
+			})
+		});
+	let request = match result {
+		Ok(request) => request,
+		Err(err) => {
+			context.uploads.release_unused(context.app);
+			return (Status::BadRequest, err.to_response());
+		}
+	};
+	let response = timeout::wait(runtime, config.timeout, request.execute(schema, &context));
+	context.uploads.release_unused(context.app);
+	let response = match response {
+		Some(response) => response,
+		None => {
+			let timeout = config.timeout.unwrap_or_default();
+			warn!(context.log, "request timed out after {:?}", timeout);
+			let err = graph::error::Error::new(
+				graph::error::ErrorCode::Timeout,
+				format!("request timed out after {} seconds", timeout.as_secs()),
+			);
+			return (Status::ServiceUnavailable, err.to_response());
+		}
+	};
+	let status = if response.is_ok() {
+		Status::Ok
+	} else {
+		Status::BadRequest
+	};
+	let body = serde_json::to_string(&response).unwrap();
+	match &context.tracer {
+		Some(tracer) => (status, graph::tracing::add_tracing(body, tracer)),
+		None => (status, body),
+	}
+}
+
+/// Maximum number of requests in a batch.
+pub const MAX_BATCH_SIZE: usize = 20;
+
+/// Rejects empty batches and batches with more than `MAX_BATCH_SIZE`
+/// requests.
+fn check_batch(body: &serde_json::Value) -> graph::error::Result<()> {
+	match body {
+		serde_json::Value::Array(requests) if requests.is_empty() => {
+			Err(graph::error::Error::bad_request("empty batch"))
+		}
+		serde_json::Value::Array(requests) if requests.len() > MAX_BATCH_SIZE => {
+			Err(graph::error::Error::bad_request(format!(
+				"batch has more than {} requests",
+				MAX_BATCH_SIZE
+			)))
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Returns true if a GraphQL document has a mutation operation.
+///
+/// This only scans the tokens at the top level of the document, skipping
+/// strings, comments and everything inside braces and parentheses, to find
+/// the keyword that starts each operation.
+fn has_mutation(query: &str) -> bool {
+	let mut chars = query.chars().peekable();
+	let mut depth = 0;
+	let mut operation_start = true;
+	while let Some(c) = chars.next() {
+		match c {
+			'#' => {
+				while chars.peek().map(|&c| c != '\n' && c != '\r') == Some(true) {
+					chars.next();
+				}
+			}
+			'"' => {
+				// Block strings are skipped as a sequence of regular strings.
+				while let Some(c) = chars.next() {
+					match c {
+						'\\' => {
+							chars.next();
+						}
+						'"' => break,
+						_ => {}
+					}
+				}
+			}
+			'{' | '(' | '[' => depth += 1,
+			'}' | ')' | ']' => {
+				depth -= 1;
+				if depth == 0 && c == '}' {
+					operation_start = true;
+				}
+			}
+			c if depth == 0 && (c.is_ascii_alphabetic() || c == '_') => {
+				let mut word = c.to_string();
+				while let Some(&c) = chars.peek() {
+					if !c.is_ascii_alphanumeric() && c != '_' {
+						break;
+					}
+					word.push(c);
+					chars.next();
+				}
+				if operation_start && word == "mutation" {
+					return true;
+				}
+				operation_start = false;
+			}
+			_ => {}
+		}
+	}
+	false
+}
+
+/// Rejects introspection queries if disabled in the configuration. This
+/// includes the `_service` query for federation, which returns the schema.
+///
+/// This looks for the introspection fields in the query text, so it also
+/// rejects queries that only mention them in strings or comments.
+fn check_introspection(
+	body: &serde_json::Value,
+	config: &graph::GraphConfig,
+) -> graph::error::Result<()> {
+	lazy_static! {
+		static ref RE_INTROSPECTION: Regex = Regex::new(r"\b(__schema|__type|_service)\b").unwrap();
+	}
+	if config.introspection {
+		return Ok(());
+	}
+	let requests = match body {
+		serde_json::Value::Array(requests) => requests.iter().collect(),
+		body => vec![body],
+	};
+	for request in requests {
+		let query = request.get("query").and_then(|query| query.as_str());
+		if query.map(|query| RE_INTROSPECTION.is_match(query)) == Some(true) {
+			return Err(graph::error::Error::bad_request(
+				"introspection is disabled",
+			));
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_stream_limit() {
+		let limit = StreamLimit::new(2);
+		let first = limit.acquire().unwrap();
+		let second = limit.acquire().unwrap();
+		assert!(limit.acquire().is_none());
+		drop(first);
+		let third = limit.acquire().unwrap();
+		assert!(limit.acquire().is_none());
+		drop(second);
+		drop(third);
+		assert_eq!(limit.open.load(Ordering::SeqCst), 0);
+		assert!(StreamLimit::new(0).acquire().is_none());
+	}
+
+	#[test]
+	fn test_graphiql_links() {
+		let page = graphiql_source("GraphiQL", "/api/graphql");
+		assert_eq!(GRAPHIQL_ASSETS.len(), 6);
+		for (name, data) in GRAPHIQL_ASSETS {
+			assert!(!data.is_empty(), "{}", name);
+			assert!(
+				page.contains(&format!("\"/api/graphiql/{}\"", name)),
+				"{}",
+				name
+			);
+			assert!(ide_asset(name.to_string()).is_some(), "{}", name);
+		}
+		assert!(ide_asset("other.js".to_string()).is_none());
+
+		// Nothing is loaded from a CDN, so the IDE works offline.
+		assert!(!page.contains("http://") && !page.contains("https://"));
+		assert!(!page.contains("//cdn"));
+	}
+
+	#[test]
+	fn test_check_batch() {
+		let request = serde_json::json!({ "query": "{ appName }" });
+		assert!(check_batch(&request).is_ok());
+		assert!(check_batch(&serde_json::json!([request.clone()])).is_ok());
+		assert!(check_batch(&serde_json::json!([])).is_err());
+		let batch = vec![request; MAX_BATCH_SIZE + 1];
+		assert!(check_batch(&serde_json::Value::Array(batch)).is_err());
+	}
+
+	#[test]
+	fn test_has_mutation() {
+		assert!(has_mutation("mutation { noOp }"));
+		assert!(has_mutation("mutation NoOp($a: Int) { noOp }"));
+		assert!(has_mutation("query A { appName } mutation B { noOp }"));
+		assert!(has_mutation("# comment\n mutation { noOp }"));
+
+		assert!(!has_mutation("{ appName }"));
+		assert!(!has_mutation("query { mutation: appName }"));
+		assert!(!has_mutation(
+			"query ($a: String = \"mutation\") { appName }"
+		));
+		assert!(!has_mutation("# mutation\n query { appName }"));
+		assert!(!has_mutation(
+			"fragment F on Query { appName } query { ...F }"
+		));
+	}
 }
 
 // spell-checker: disable
@@ -35,12 +692,7 @@ fn graphiql_source(title: &str, url: &str) -> String {
 				<head>
 					<title>{title}</title>
 					{style}
-					<script src="https://cdn.jsdelivr.net/es6-promise/4.0.5/es6-promise.auto.min.js"></script>
-					<script src="https://cdn.jsdelivr.net/fetch/0.9.0/fetch.min.js"></script>
-					<script src="https://cdn.jsdelivr.net/react/15.4.2/react.min.js"></script>
-					<script src="https://cdn.jsdelivr.net/react/15.4.2/react-dom.min.js"></script>
-					<link rel="stylesheet" href="https://cdnjs.cloudflare.com/ajax/libs/graphiql/0.11.11/graphiql.min.css" />
-					<script src="https://cdnjs.cloudflare.com/ajax/libs/graphiql/0.11.11/graphiql.min.js"></script>
+					{links}
 				</head>
 				<body>
 				<div id="graphiql">Loading...</div>
@@ -51,6 +703,7 @@ fn graphiql_source(title: &str, url: &str) -> String {
 		"#,
 		title = title,
 		url = url,
+		links = graphiql_links(),
 		style = STYLE,
 		script = SCRIPT,
 	);
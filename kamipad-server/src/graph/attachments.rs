@@ -0,0 +1,47 @@
+//! GraphQL types for attachments.
+
+use crate::attachments::Attachment;
+
+use super::error::Result;
+use super::scalars::{DateTime, ID};
+use super::Context;
+
+#[juniper::graphql_object(context = Context)]
+impl Attachment {
+	/// Unique ID for the attachment.
+	fn id(&self) -> ID {
+		ID(self.id)
+	}
+
+	/// ID of the blob with the content of the attachment.
+	fn blob_id(&self) -> &str {
+		&self.blob
+	}
+
+	/// Original name of the uploaded file.
+	fn filename(&self) -> &str {
+		&self.filename
+	}
+
+	/// MIME type of the content.
+	fn content_type(&self) -> &str {
+		&self.content_type
+	}
+
+	/// Size of the content in bytes.
+	fn size(&self) -> f64 {
+		self.size as f64
+	}
+
+	/// Time the attachment was uploaded.
+	fn created(&self) -> DateTime {
+		DateTime::from_millis(self.created)
+	}
+
+	/// Signed URL to download the content, which expires after an hour.
+	async fn download_url(&self, context: &Context) -> Result<String> {
+		let _trace = context.trace("Attachment.downloadUrl");
+		let id = self.id;
+		Ok(context.app.run(move |app| app.download_url(&id)).await?)
+	}
+}
@@ -0,0 +1,120 @@
+//! Caching of resolver results in the application `Cache`.
+//!
+//! Expensive resolvers wrap their work with `Context::cached`, which keeps
+//! the result for the TTL configured for the field, so that repeated
+//! identical queries are resolved from memory:
+//!
+//! ```text
+//! context.cached("Query.notes", &filter, || async { ... }).await
+//! ```
+//!
+//! Results are cached by field, arguments and user, so users never see
+//! results resolved for another user. The TTL for each field is set in the
+//! `graphql_cache_ttl` table of the configuration, in seconds:
+//!
+//! ```text
+//! [global.graphql_cache_ttl]
+//! "Query.notes" = 5
+//! ```
+//!
+//! Fields without a TTL are not cached. Cached results are not invalidated
+//! by mutations, so a field should only be cached if results up to its TTL
+//! old are acceptable.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::util::CacheStats;
+
+use super::error::Result;
+use super::Context;
+
+/// TTL for the cached fields, by `Type.field` name.
+pub type CacheTtl = HashMap<String, Duration>;
+
+/// Statistics for one of the application caches, for the `cacheStats`
+/// query.
+#[derive(juniper::GraphQLObject)]
+pub struct CacheInfo {
+	/// Name of the cache, from its key and value types. This is the name
+	/// used by `clearCache`.
+	pub name: String,
+	/// Number of entries currently in the cache.
+	pub entries: i32,
+	/// Number of lookups that found an entry.
+	pub hits: f64,
+	/// Number of lookups that did not find an entry.
+	pub misses: f64,
+}
+
+impl From<(String, CacheStats)> for CacheInfo {
+	fn from((name, stats): (String, CacheStats)) -> CacheInfo {
+		CacheInfo {
+			name,
+			entries: stats.entries as i32,
+			hits: stats.hits as f64,
+			misses: stats.misses as f64,
+		}
+	}
+}
+
+/// Cache key for the result of a field.
+#[derive(Clone, Eq, PartialEq, Hash)]
+struct FieldKey {
+	field: &'static str,
+	args: String,
+	user: Option<String>,
+}
+
+impl Context {
+	/// Returns the cached result for a field with the given arguments, or
+	/// resolves and caches it. See the module documentation.
+	///
+	/// The `field` is the `Type.field` name used in the configuration, and
+	/// `args` is anything identifying the arguments the result depends on.
+	pub async fn cached<V, A, F, R>(&self, field: &'static str, args: &A, resolve: F) -> Result<V>
+	where
+		V: Clone + Send + Sync + 'static,
+		A: Debug + ?Sized,
+		F: FnOnce() -> R,
+		R: Future<Output = Result<V>>,
+	{
+		let ttl = match self.cache_ttl.get(field) {
+			Some(ttl) => *ttl,
+			None => return resolve().await,
+		};
+		let key = FieldKey {
+			field,
+			args: format!("{:?}", args),
+			user: self.user.as_ref().map(|user| user.name.clone()),
+		};
+		if let Some(value) = self.app.cache::<FieldKey, V>().get(&key) {
+			return Ok((*value).clone());
+		}
+
+		let value = resolve().await?;
+		self.app
+			.cache::<FieldKey, V>()
+			.save(key, value.clone(), ttl);
+		Ok(value)
+	}
+}
+
+/// Reads the TTL for each field from the `graphql_cache_ttl` table in the
+/// configuration. Invalid entries are ignored with a warning.
+pub fn read_cache_ttl(config: &rocket::Config, log: &slog::Logger) -> CacheTtl {
+	let mut ttl = CacheTtl::new();
+	if let Ok(table) = config.get_table("graphql_cache_ttl") {
+		for (field, value) in table {
+			match value.as_integer() {
+				Some(secs) if secs > 0 => {
+					ttl.insert(field.clone(), Duration::from_secs(secs as u64));
+				}
+				_ => warn!(log, "invalid cache TTL for {}: {}", field, value),
+			}
+		}
+	}
+	ttl
+}
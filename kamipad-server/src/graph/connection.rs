@@ -0,0 +1,180 @@
+//! Relay-style pagination for list fields.
+//!
+//! List fields return a connection instead of an array, and take the usual
+//! `first`, `after`, `last` and `before` arguments:
+//!
+//! ```text
+//! notes(first: 10, after: "...") {
+//!     edges { cursor node { id title } }
+//!     pageInfo { hasNextPage endCursor }
+//!     totalCount
+//! }
+//! ```
+//!
+//! The connection and edge types for a node type are declared with the
+//! `connection!` macro, and built from the full list of nodes by `paginate`.
+//!
+//! Cursors are opaque strings encoding the key of the node, so they stay
+//! valid when nodes are added or removed elsewhere in the list.
+
+use super::error::{Error, Result};
+
+/// Number of nodes returned when neither `first` nor `last` is given.
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// Maximum number of nodes returned in a single page.
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// Information about the page returned in a connection.
+#[derive(juniper::GraphQLObject)]
+pub struct PageInfo {
+	/// True if there are nodes after this page.
+	pub has_next_page: bool,
+	/// True if there are nodes before this page.
+	pub has_previous_page: bool,
+	/// Cursor for the first node in the page.
+	pub start_cursor: Option<String>,
+	/// Cursor for the last node in the page.
+	pub end_cursor: Option<String>,
+}
+
+/// Pagination arguments for a list field.
+#[derive(Default)]
+pub struct PageArgs {
+	pub first: Option<i32>,
+	pub after: Option<String>,
+	pub last: Option<i32>,
+	pub before: Option<String>,
+}
+
+/// A page of nodes, with their cursors. This is converted into the concrete
+/// connection type declared with `connection!`.
+pub struct Page<T> {
+	pub edges: Vec<(String, T)>,
+	pub page_info: PageInfo,
+	pub total_count: usize,
+}
+
+/// Returns the page of `nodes` selected by the arguments. The key for each
+/// node, returned by `key`, must be unique in the list.
+pub fn paginate<T, F>(nodes: Vec<T>, key: F, args: PageArgs) -> Result<Page<T>>
+where
+	F: Fn(&T) -> String,
+{
+	let total_count = nodes.len();
+	let keys = nodes.iter().map(|node| key(node)).collect::<Vec<_>>();
+	let position = |cursor: &str| -> Result<usize> {
+		let key = decode_cursor(cursor)?;
+		keys.iter()
+			.position(|k| *k == key)
+			.ok_or_else(|| invalid_cursor(cursor))
+	};
+
+	let mut start = 0;
+	let mut end = total_count;
+	if let Some(after) = &args.after {
+		start = position(after)? + 1;
+	}
+	if let Some(before) = &args.before {
+		end = position(before)?;
+	}
+	end = end.max(start);
+
+	let count = |value: Option<i32>, name: &str| -> Result<Option<usize>> {
+		match value {
+			Some(value) if value < 0 => Err(Error::bad_request(format!(
+				"`{}` must not be negative",
+				name
+			))),
+			Some(value) => Ok(Some((value as usize).min(MAX_PAGE_SIZE))),
+			None => Ok(None),
+		}
+	};
+	let first = count(args.first, "first")?;
+	let last = count(args.last, "last")?;
+	if first.is_none() && last.is_none() {
+		end = end.min(start + DEFAULT_PAGE_SIZE);
+	}
+	if let Some(first) = first {
+		end = end.min(start + first);
+	}
+	if let Some(last) = last {
+		start = start.max(end.saturating_sub(last));
+	}
+
+	let edges = nodes
+		.into_iter()
+		.zip(keys)
+		.skip(start)
+		.take(end - start)
+		.map(|(node, key)| (encode_cursor(&key), node))
+		.collect::<Vec<_>>();
+	let page_info = PageInfo {
+		has_next_page: end < total_count,
+		has_previous_page: start > 0,
+		start_cursor: edges.first().map(|(cursor, _)| cursor.clone()),
+		end_cursor: edges.last().map(|(cursor, _)| cursor.clone()),
+	};
+	Ok(Page {
+		edges,
+		page_info,
+		total_count,
+	})
+}
+
+fn encode_cursor(key: &str) -> String {
+	base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(cursor: &str) -> Result<String> {
+	base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+		.ok()
+		.and_then(|key| String::from_utf8(key).ok())
+		.ok_or_else(|| invalid_cursor(cursor))
+}
+
+fn invalid_cursor(cursor: &str) -> Error {
+	Error::bad_request(format!("invalid cursor `{}`", cursor))
+}
+
+/// Declares the connection and edge GraphQL types for a node type:
+///
+/// ```text
+/// connection!(NoteConnection, NoteEdge, Note);
+/// ```
+macro_rules! connection {
+	($connection:ident, $edge:ident, $node:ty) => {
+		/// An edge in a connection, with the node and its cursor.
+		#[derive(juniper::GraphQLObject)]
+		#[graphql(context = $crate::graph::Context)]
+		pub struct $edge {
+			/// Cursor for the node, to use in `after` and `before`.
+			pub cursor: String,
+			pub node: $node,
+		}
+
+		/// A page of nodes from a list field.
+		#[derive(juniper::GraphQLObject)]
+		#[graphql(context = $crate::graph::Context)]
+		pub struct $connection {
+			pub edges: Vec<$edge>,
+			pub page_info: $crate::graph::connection::PageInfo,
+			/// Total number of nodes in the list, across all pages.
+			pub total_count: i32,
+		}
+
+		impl From<$crate::graph::connection::Page<$node>> for $connection {
+			fn from(page: $crate::graph::connection::Page<$node>) -> $connection {
+				$connection {
+					edges: page
+						.edges
+						.into_iter()
+						.map(|(cursor, node)| $edge { cursor, node })
+						.collect(),
+					page_info: page.page_info,
+					total_count: page.total_count as i32,
+				}
+			}
+		}
+	};
+}
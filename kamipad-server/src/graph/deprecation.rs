@@ -0,0 +1,118 @@
+//! Versioning and deprecation of the schema.
+//!
+//! `SCHEMA_VERSION` is bumped on every change to the schema: the minor
+//! version for additions, and the major version when fields are removed.
+//!
+//! Fields are deprecated with the `deprecated` attribute of juniper, with a
+//! reason that ends with the sunset date, after which the field may be
+//! removed. The field is also listed in `DEPRECATED_FIELDS`, and its
+//! resolver calls `Context::deprecated` to log the usage:
+//!
+//! ```text
+//! #[graphql(deprecated = "Use `other` instead. Sunset on 2021-06-30.")]
+//! fn field(context: &Context) -> i32 {
+//!     context.deprecated("Query.field");
+//!     ...
+//! }
+//! ```
+
+use super::Context;
+
+/// Version of the GraphQL schema, for the `schemaVersion` query.
+pub const SCHEMA_VERSION: &str = "1.5.0";
+
+/// A deprecated field of the schema.
+pub struct DeprecatedField {
+	/// Field name, as `Type.field`.
+	pub field: &'static str,
+	/// Date after which the field may be removed, as `YYYY-MM-DD`.
+	pub sunset: &'static str,
+}
+
+/// All the deprecated fields of the schema.
+pub const DEPRECATED_FIELDS: &[DeprecatedField] = &[DeprecatedField {
+	field: "Mutation.noOp",
+	sunset: "2021-06-30",
+}];
+
+/// Returns the deprecation for a field, if deprecated.
+pub fn find_deprecated(field: &str) -> Option<&'static DeprecatedField> {
+	DEPRECATED_FIELDS.iter().find(|it| it.field == field)
+}
+
+#[juniper::graphql_object(context = Context)]
+impl DeprecatedField {
+	/// Field name, as `Type.field`.
+	fn field(&self) -> &str {
+		self.field
+	}
+
+	/// Date after which the field may be removed, as `YYYY-MM-DD`.
+	fn sunset(&self) -> &str {
+		self.sunset
+	}
+}
+
+/// Version of the schema, for the `schemaVersion` query.
+pub struct SchemaVersion;
+
+#[juniper::graphql_object(context = Context)]
+impl SchemaVersion {
+	/// Version of the schema. The major version changes when fields are
+	/// removed.
+	fn version(&self) -> &str {
+		SCHEMA_VERSION
+	}
+
+	/// Deprecated fields, which will be removed after their sunset date.
+	fn deprecated_fields(&self) -> &[DeprecatedField] {
+		DEPRECATED_FIELDS
+	}
+}
+
+impl Context {
+	/// Logs the usage of a deprecated field, by `Type.field` name. Called
+	/// by the resolvers of the fields in `DEPRECATED_FIELDS`.
+	pub fn deprecated(&self, field: &'static str) {
+		match find_deprecated(field) {
+			Some(deprecated) => warn!(
+				self.log,
+				"deprecated field {} used", field;
+				"field" => field,
+				"sunset" => deprecated.sunset,
+			),
+			None => warn!(self.log, "field {} is not listed as deprecated", field),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_deprecated_fields_in_schema() {
+		let sdl = crate::graph::schema_sdl();
+		for deprecated in DEPRECATED_FIELDS {
+			let name = deprecated.field.splitn(2, '.').nth(1).unwrap();
+			let line = sdl
+				.lines()
+				.map(|line| line.trim())
+				.find(|line| {
+					line.starts_with(&format!("{}:", name))
+						|| line.starts_with(&format!("{}(", name))
+				})
+				.unwrap_or_else(|| panic!("{} is not in the schema", deprecated.field));
+			assert!(
+				line.contains("@deprecated"),
+				"{} is not deprecated in the schema",
+				deprecated.field
+			);
+			assert!(
+				line.contains(deprecated.sunset),
+				"{} has no sunset date in the deprecation reason",
+				deprecated.field
+			);
+		}
+	}
+}
@@ -0,0 +1,214 @@
+//! Errors returned by the GraphQL resolvers.
+//!
+//! Every error in a GraphQL response carries an `extensions` object with a
+//! machine-readable `code` and the `requestId`, so that the client can act
+//! on the error type and find the request in the logs:
+//!
+//! ```text
+//! {
+//!     "message": "note 645a9c23-9590-49d0-879e-250bff5b621a not found",
+//!     "path": ["updateNote"],
+//!     "extensions": { "code": "NOT_FOUND", "requestId": "..." }
+//! }
+//! ```
+//!
+//! Resolvers return an `Error` with the code. Errors without a code, which
+//! come from juniper itself, get `BAD_REQUEST` if they are about the query
+//! and `INTERNAL` otherwise. The request ID is added by `add_request_id`
+//! when sending the response.
+
+use juniper::{FieldError, IntoFieldError, Object, Value};
+use kamipad_data as kd;
+
+use crate::logging::RequestId;
+use crate::util;
+
+use super::validate::InvalidField;
+
+/// Machine-readable code for an error.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+	/// The input to the query or mutation is not valid.
+	BadRequest,
+	/// The requested entity does not exist.
+	NotFound,
+	/// The change conflicts with the current state of the entity.
+	Conflict,
+	/// The request requires authentication or permissions it doesn't have.
+	Unauthorized,
+	/// Any other error on the server.
+	Internal,
+	/// The hash for an automatic persisted query is not known.
+	PersistedQueryNotFound,
+	/// The automatic persisted query version is not supported.
+	PersistedQueryNotSupported,
+	/// The client sent too many requests, see `graph::rate_limit`.
+	TooManyRequests,
+	/// Fields in the input are not valid, see `graph::validate`.
+	InvalidInput,
+	/// The request took longer than `GraphConfig::timeout`.
+	Timeout,
+	/// The server is at capacity, such as for `api::StreamLimit`.
+	Unavailable,
+}
+
+impl ErrorCode {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ErrorCode::BadRequest => "BAD_REQUEST",
+			ErrorCode::NotFound => "NOT_FOUND",
+			ErrorCode::Conflict => "CONFLICT",
+			ErrorCode::Unauthorized => "UNAUTHORIZED",
+			ErrorCode::Internal => "INTERNAL",
+			ErrorCode::PersistedQueryNotFound => "PERSISTED_QUERY_NOT_FOUND",
+			ErrorCode::PersistedQueryNotSupported => "PERSISTED_QUERY_NOT_SUPPORTED",
+			ErrorCode::TooManyRequests => "TOO_MANY_REQUESTS",
+			ErrorCode::InvalidInput => "INVALID_INPUT",
+			ErrorCode::Timeout => "TIMEOUT",
+			ErrorCode::Unavailable => "UNAVAILABLE",
+		}
+	}
+}
+
+/// Error from a resolver, with its code.
+#[derive(Debug)]
+pub struct Error {
+	pub code: ErrorCode,
+	pub message: String,
+	/// Invalid fields for `INVALID_INPUT`, sent in the `fields` extension.
+	pub fields: Vec<InvalidField>,
+}
+
+/// Result for resolvers.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+	pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> Error {
+		Error {
+			code,
+			message: message.into(),
+			fields: Vec::new(),
+		}
+	}
+
+	/// Returns an `INVALID_INPUT` error for the fields.
+	pub fn invalid_input(fields: Vec<InvalidField>) -> Error {
+		let names = fields
+			.iter()
+			.map(|field| field.field.as_str())
+			.collect::<Vec<_>>();
+		let message = format!("invalid input: {}", names.join(", "));
+		Error {
+			fields,
+			..Error::new(ErrorCode::InvalidInput, message)
+		}
+	}
+
+	pub fn bad_request<S: Into<String>>(message: S) -> Error {
+		Error::new(ErrorCode::BadRequest, message)
+	}
+
+	pub fn not_found<S: Into<String>>(message: S) -> Error {
+		Error::new(ErrorCode::NotFound, message)
+	}
+
+	/// Returns a serialized GraphQL response with only this error, for errors
+	/// before executing the request.
+	pub fn to_response(&self) -> String {
+		let mut extensions = serde_json::json!({ "code": self.code.as_str() });
+		if !self.fields.is_empty() {
+			let fields = self
+				.fields
+				.iter()
+				.map(|field| serde_json::json!({ "field": field.field, "reason": field.reason }))
+				.collect::<Vec<_>>();
+			extensions["fields"] = fields.into();
+		}
+		let error = serde_json::json!({
+			"message": self.message,
+			"extensions": extensions,
+		});
+		serde_json::json!({ "errors": [error] }).to_string()
+	}
+}
+
+impl From<util::Error> for Error {
+	fn from(err: util::Error) -> Error {
+		Error::new(ErrorCode::Internal, err.to_string())
+	}
+}
+
+impl From<kd::Error> for Error {
+	fn from(err: kd::Error) -> Error {
+		let code = match &err {
+			kd::Error::NotFound(_) => ErrorCode::NotFound,
+			kd::Error::Conflict(_) | kd::Error::Linked(_) => ErrorCode::Conflict,
+			kd::Error::InvalidName(_)
+			| kd::Error::InvalidCursor(_)
+			| kd::Error::Validation(_)
+			| kd::Error::QuotaExceeded(_) => ErrorCode::BadRequest,
+			kd::Error::WrongPassphrase => ErrorCode::Unauthorized,
+			_ => ErrorCode::Internal,
+		};
+		Error::new(code, err.to_string())
+	}
+}
+
+impl IntoFieldError for Error {
+	fn into_field_error(self) -> FieldError {
+		let mut extensions = Object::with_capacity(2);
+		extensions.add_field("code", Value::scalar(self.code.as_str()));
+		if !self.fields.is_empty() {
+			let fields = self
+				.fields
+				.into_iter()
+				.map(|field| {
+					let mut object = Object::with_capacity(2);
+					object.add_field("field", Value::scalar(field.field));
+					object.add_field("reason", Value::scalar(field.reason));
+					Value::Object(object)
+				})
+				.collect();
+			extensions.add_field("fields", Value::List(fields));
+		}
+		FieldError::new(self.message, Value::Object(extensions))
+	}
+}
+
+/// Adds the request ID to the errors in a serialized GraphQL response,
+/// along with the code for errors that don't have one. This also handles
+/// batched responses.
+pub fn add_request_id(response: String, request_id: RequestId) -> String {
+	let mut json: serde_json::Value = match serde_json::from_str(&response) {
+		Ok(json) => json,
+		Err(_) => return response,
+	};
+	let request_id = request_id.to_string();
+	let responses = match &mut json {
+		serde_json::Value::Array(responses) => responses.iter_mut().collect(),
+		json => vec![json],
+	};
+	for response in responses {
+		let errors = match response.get_mut("errors") {
+			Some(serde_json::Value::Array(errors)) => errors,
+			_ => continue,
+		};
+		for error in errors.iter_mut().filter_map(|error| error.as_object_mut()) {
+			let code = if error.contains_key("path") {
+				ErrorCode::Internal
+			} else {
+				ErrorCode::BadRequest
+			};
+			let extensions = error
+				.entry("extensions")
+				.or_insert_with(|| serde_json::json!({}));
+			if let Some(extensions) = extensions.as_object_mut() {
+				extensions
+					.entry("code")
+					.or_insert_with(|| code.as_str().into());
+				extensions.insert("requestId".into(), request_id.clone().into());
+			}
+		}
+	}
+	json.to_string()
+}
@@ -0,0 +1,59 @@
+//! GraphQL types for exporting notebooks.
+
+use crate::export::ExportJob;
+
+use super::error::Result;
+use super::import::JobStatus;
+use super::scalars::{DateTime, ID};
+use super::Context;
+
+#[juniper::graphql_object(context = Context)]
+impl ExportJob {
+	/// ID to query the job with `exportJob`.
+	fn id(&self) -> ID {
+		ID(self.id)
+	}
+
+	fn status(&self) -> JobStatus {
+		self.status.into()
+	}
+
+	/// Tag of the exported notes, or null for all notes.
+	fn tag(&self) -> Option<&str> {
+		self.tag.as_deref()
+	}
+
+	/// Number of exported notes, once done.
+	fn note_count(&self) -> i32 {
+		self.notes as i32
+	}
+
+	/// Size of the archive in bytes, once done.
+	fn size(&self) -> f64 {
+		self.size as f64
+	}
+
+	/// Signed URL to download the zip archive, once done. The archive is
+	/// removed an hour after the export finishes.
+	async fn download_url(&self, context: &Context) -> Result<Option<String>> {
+		let _trace = context.trace("ExportJob.downloadUrl");
+		let job = self.clone();
+		Ok(context
+			.app
+			.run(move |app| app.export_download_url(&job))
+			.await?)
+	}
+
+	/// Reason the job failed, if it did.
+	fn error(&self) -> Option<&str> {
+		self.error.as_deref()
+	}
+
+	fn started(&self) -> DateTime {
+		DateTime::from_millis(self.started)
+	}
+
+	fn finished(&self) -> Option<DateTime> {
+		self.finished.map(DateTime::from_millis)
+	}
+}
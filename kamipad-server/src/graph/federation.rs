@@ -0,0 +1,166 @@
+//! Support for composing the schema into a federated gateway.
+//!
+//! This implements the subgraph side of Apollo Federation: the `_service`
+//! query returns the schema with the `@key` directive on the entity types,
+//! and the `_entities` query resolves entities by their key, from the
+//! representations sent by the gateway:
+//!
+//! ```text
+//! { "__typename": "Note", "id": "645a9c23-9590-49d0-879e-250bff5b621a" }
+//! ```
+//!
+//! All entities are keyed by `id`. The `_service` query is an introspection
+//! query, and is disabled with it.
+
+use kamipad_data as kd;
+
+use crate::attachments::Attachment;
+use crate::notes::Note;
+use crate::templates::Template;
+
+use super::error::{Error, Result};
+use super::scalars::Any;
+use super::Context;
+
+/// Types that can be resolved with `_entities`, all keyed by `id`.
+pub const ENTITY_TYPES: &[&str] = &["Attachment", "Note", "Template"];
+
+/// Federation types and fields, which are not part of the `_service` SDL.
+const FEDERATION_TYPES: &[&str] = &["scalar _Any", "union _Entity", "type _Service"];
+const FEDERATION_FIELDS: &[&str] = &["_service", "_entities"];
+
+/// An entity resolved by `_entities`.
+#[derive(juniper::GraphQLUnion)]
+#[graphql(name = "_Entity", context = Context)]
+pub enum Entity {
+	Attachment(Attachment),
+	Note(Note),
+	Template(Template),
+}
+
+/// Result for the `_service` query.
+pub struct Service;
+
+#[juniper::graphql_object(name = "_Service", context = Context)]
+impl Service {
+	/// Schema of the service, with federation directives.
+	fn sdl(&self) -> &str {
+		lazy_static! {
+			static ref SDL: String = federation_sdl(&super::schema_sdl());
+		}
+		&SDL
+	}
+}
+
+/// Resolves an entity from its representation. Returns `None` if it doesn't
+/// exist.
+pub async fn resolve_entity(context: &Context, representation: Any) -> Result<Option<Entity>> {
+	let field = |name: &str| representation.0.get(name).and_then(|value| value.as_str());
+	let typename = field("__typename")
+		.ok_or_else(|| Error::bad_request("entity representation has no `__typename`"))?;
+	let id = field("id")
+		.and_then(kd::ID::parse)
+		.ok_or_else(|| Error::bad_request(format!("invalid `id` for {} entity", typename)))?;
+	let entity = match typename {
+		"Attachment" => context
+			.app
+			.run(move |app| app.attachment(&id))
+			.await?
+			.map(Entity::Attachment),
+		"Note" => context.loaders.notes.load(&id).await?.map(Entity::Note),
+		"Template" => context
+			.app
+			.run(move |app| app.template(&id))
+			.await?
+			.map(Entity::Template),
+		_ => {
+			let message = format!("`{}` is not an entity type", typename);
+			return Err(Error::bad_request(message));
+		}
+	};
+	Ok(entity)
+}
+
+/// Returns the SDL for `_service`, adding `@key` to the entity types and
+/// removing the federation types and fields, which the gateway provides.
+pub fn federation_sdl(sdl: &str) -> String {
+	let mut definitions = Vec::new();
+	for definition in sdl.split("\n\n") {
+		let header = definition
+			.lines()
+			.find(|line| !line.starts_with(' ') && !line.starts_with('"'))
+			.unwrap_or_default();
+		if FEDERATION_TYPES.iter().any(|it| is_definition(header, it)) {
+			continue;
+		}
+		let entity = ENTITY_TYPES
+			.iter()
+			.find(|name| is_definition(header, &format!("type {}", name)));
+		let mut lines = Vec::new();
+		for line in definition.lines() {
+			let field = line.trim_start();
+			let is_federation_field = line.starts_with(' ')
+				&& FEDERATION_FIELDS.iter().any(|name| {
+					field.starts_with(&format!("{}:", name))
+						|| field.starts_with(&format!("{}(", name))
+				});
+			if is_federation_field {
+				continue;
+			}
+			match entity {
+				Some(_) if line == header => {
+					let (name, rest) = line.split_at(line.find(" {").unwrap_or(line.len()));
+					lines.push(format!("{} @key(fields: \"id\"){}", name, rest));
+				}
+				_ => lines.push(line.to_string()),
+			}
+		}
+		definitions.push(lines.join("\n"));
+	}
+	definitions.join("\n\n")
+}
+
+/// Returns true if the header line of a definition starts with the kind and
+/// name, such as `type Note`.
+fn is_definition(header: &str, kind_and_name: &str) -> bool {
+	match header.strip_prefix(kind_and_name) {
+		Some(rest) => rest.is_empty() || rest.starts_with(' '),
+		None => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_federation_sdl() {
+		let sdl = [
+			"type Query {\n  _service: _Service!\n  _entities(representations: [_Any!]!): [_Entity]!\n  note(id: UUID!): Note\n}",
+			"\"Entity representation.\"\nscalar _Any",
+			"union _Entity = Attachment | Note | Template",
+			"type _Service {\n  sdl: String!\n}",
+			"type Note {\n  id: UUID!\n}",
+			"type NoteEdge {\n  node: Note!\n}",
+		]
+		.join("\n\n");
+		let expected = [
+			"type Query {\n  note(id: UUID!): Note\n}",
+			"type Note @key(fields: \"id\") {\n  id: UUID!\n}",
+			"type NoteEdge {\n  node: Note!\n}",
+		]
+		.join("\n\n");
+		assert_eq!(federation_sdl(&sdl), expected);
+	}
+
+	#[test]
+	fn test_entity_types_in_schema() {
+		let sdl = federation_sdl(&crate::graph::schema_sdl());
+		for name in ENTITY_TYPES {
+			let header = format!("type {} @key(fields: \"id\")", name);
+			assert!(sdl.contains(&header), "{} has no @key in the SDL", name);
+		}
+		assert!(!sdl.contains("_Service"));
+		assert!(!sdl.contains("_entities"));
+	}
+}
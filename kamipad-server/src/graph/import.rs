@@ -0,0 +1,87 @@
+//! GraphQL types for importing notes.
+
+use crate::import::{self, ImportItem, ImportJob};
+
+use super::scalars::{DateTime, ID};
+use super::Context;
+
+/// State of an import or export job.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum JobStatus {
+	Running,
+	Done,
+	Failed,
+}
+
+impl From<import::JobStatus> for JobStatus {
+	fn from(status: import::JobStatus) -> JobStatus {
+		match status {
+			import::JobStatus::Running => JobStatus::Running,
+			import::JobStatus::Done => JobStatus::Done,
+			import::JobStatus::Failed => JobStatus::Failed,
+		}
+	}
+}
+
+#[juniper::graphql_object(context = Context)]
+impl ImportJob {
+	/// ID to query the job with `importJob`.
+	fn id(&self) -> ID {
+		ID(self.id)
+	}
+
+	fn status(&self) -> JobStatus {
+		self.status.into()
+	}
+
+	/// Name of the imported file.
+	fn filename(&self) -> &str {
+		&self.filename
+	}
+
+	/// Number of items in the file, or zero until the file is read.
+	fn total(&self) -> i32 {
+		self.total as i32
+	}
+
+	/// Number of items processed so far.
+	fn processed(&self) -> i32 {
+		self.items.len() as i32
+	}
+
+	/// Results for the items processed so far, in order.
+	fn items(&self) -> &[ImportItem] {
+		&self.items
+	}
+
+	/// Reason the job failed, if it did.
+	fn error(&self) -> Option<&str> {
+		self.error.as_deref()
+	}
+
+	fn started(&self) -> DateTime {
+		DateTime::from_millis(self.started)
+	}
+
+	fn finished(&self) -> Option<DateTime> {
+		self.finished.map(DateTime::from_millis)
+	}
+}
+
+#[juniper::graphql_object(context = Context)]
+impl ImportItem {
+	/// Line number or file name of the item in the imported file.
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// ID of the created note, or null if the item was not imported.
+	fn note_id(&self) -> Option<ID> {
+		self.note.map(ID)
+	}
+
+	/// Reason the item was not imported.
+	fn error(&self) -> Option<&str> {
+		self.error.as_deref()
+	}
+}
@@ -0,0 +1,119 @@
+//! Batched loading of data for the resolvers.
+//!
+//! Resolving a field for each node in a list would otherwise read the data
+//! for each node separately. Instead, the resolver for the list calls
+//! `Loader::defer` with the keys for all nodes, and the first `Loader::load`
+//! for any of them loads all deferred keys with a single batch call:
+//!
+//! ```text
+//! // In the list resolver:
+//! context.loaders.note_tags.defer(notes.iter().map(|note| note.id));
+//! // In the node resolver:
+//! context.loaders.note_tags.load(&note.id).await
+//! ```
+//!
+//! The batch call runs on the database threads with `App::run`. Fields
+//! resolved concurrently may each load their key before the other batch
+//! completes, which just loads these keys again.
+//!
+//! Loaded values are cached for the rest of the request, so a `Loaders`
+//! instance must not outlive its request.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::notes::Note;
+use crate::util::Result;
+
+/// Loads the values for a set of keys. Keys without a value are left out of
+/// the result.
+pub type BatchFn<K, V> = fn(&App, &[K]) -> Result<HashMap<K, V>>;
+
+/// Loader for values of one type, by key. See the module documentation.
+pub struct Loader<K, V> {
+	app: &'static App,
+	batch: BatchFn<K, V>,
+	state: Mutex<LoaderState<K, V>>,
+}
+
+struct LoaderState<K, V> {
+	loaded: HashMap<K, Option<V>>,
+	deferred: Vec<K>,
+}
+
+impl<K, V> Loader<K, V>
+where
+	K: Clone + Eq + Hash + Send + 'static,
+	V: Clone + Send + 'static,
+{
+	pub fn new(app: &'static App, batch: BatchFn<K, V>) -> Loader<K, V> {
+		Loader {
+			app,
+			batch,
+			state: Mutex::new(LoaderState {
+				loaded: HashMap::new(),
+				deferred: Vec::new(),
+			}),
+		}
+	}
+
+	/// Adds keys to load with the next call to `load`.
+	pub fn defer<I: IntoIterator<Item = K>>(&self, keys: I) {
+		let mut state = self.state.lock().unwrap();
+		let keys = keys
+			.into_iter()
+			.filter(|key| !state.loaded.contains_key(key))
+			.collect::<Vec<_>>();
+		state.deferred.extend(keys);
+	}
+
+	/// Returns the value for a key, loading it along with any deferred keys
+	/// if it is not loaded yet.
+	pub async fn load(&self, key: &K) -> Result<Option<V>> {
+		let keys = {
+			let mut state = self.state.lock().unwrap();
+			if let Some(value) = state.loaded.get(key) {
+				return Ok(value.clone());
+			}
+			let mut keys = std::mem::replace(&mut state.deferred, Vec::new());
+			keys.push(key.clone());
+			keys.retain(|key| !state.loaded.contains_key(key));
+			keys
+		};
+
+		let batch = self.batch;
+		let (keys, values) = self
+			.app
+			.run(move |app| {
+				let values = batch(app, &keys);
+				(keys, values)
+			})
+			.await;
+		let mut values = values?;
+		let mut state = self.state.lock().unwrap();
+		for key in keys {
+			let value = values.remove(&key);
+			state.loaded.insert(key, value);
+		}
+		Ok(state.loaded[key].clone())
+	}
+}
+
+/// Loaders available to the resolvers through `Context::loaders`.
+pub struct Loaders {
+	pub notes: Loader<kd::ID, Note>,
+	pub note_tags: Loader<kd::ID, Vec<String>>,
+}
+
+impl Loaders {
+	pub fn new(app: &'static App) -> Loaders {
+		Loaders {
+			notes: Loader::new(app, App::notes_by_id),
+			note_tags: Loader::new(app, App::note_tags),
+		}
+	}
+}
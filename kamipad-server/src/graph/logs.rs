@@ -0,0 +1,203 @@
+//! GraphQL types for the application and request logs.
+
+use crate::logging::LogEntry;
+
+use super::scalars::DateTime;
+use super::Context;
+
+/// Number of entries returned by `logs` without a `limit`.
+pub const DEFAULT_LOG_LIMIT: usize = 100;
+
+/// Severity of a log entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum LogLevel {
+	Critical,
+	Error,
+	Warning,
+	Info,
+	Debug,
+	Trace,
+}
+
+impl From<slog::Level> for LogLevel {
+	fn from(level: slog::Level) -> LogLevel {
+		match level {
+			slog::Level::Critical => LogLevel::Critical,
+			slog::Level::Error => LogLevel::Error,
+			slog::Level::Warning => LogLevel::Warning,
+			slog::Level::Info => LogLevel::Info,
+			slog::Level::Debug => LogLevel::Debug,
+			slog::Level::Trace => LogLevel::Trace,
+		}
+	}
+}
+
+impl From<LogLevel> for slog::Level {
+	fn from(level: LogLevel) -> slog::Level {
+		match level {
+			LogLevel::Critical => slog::Level::Critical,
+			LogLevel::Error => slog::Level::Error,
+			LogLevel::Warning => slog::Level::Warning,
+			LogLevel::Info => slog::Level::Info,
+			LogLevel::Debug => slog::Level::Debug,
+			LogLevel::Trace => slog::Level::Trace,
+		}
+	}
+}
+
+/// Key and value attached to a log entry.
+#[derive(juniper::GraphQLObject)]
+pub struct LogField {
+	pub key: String,
+	pub value: String,
+}
+
+#[juniper::graphql_object(context = Context)]
+impl LogEntry {
+	/// Severity of the entry.
+	fn level(&self) -> LogLevel {
+		self.level.into()
+	}
+
+	/// Log message.
+	fn message(&self) -> &str {
+		&self.msg
+	}
+
+	/// Time the entry was logged.
+	fn time(&self) -> DateTime {
+		DateTime::from_millis(self.time)
+	}
+
+	/// Source module that logged the entry.
+	fn module(&self) -> &str {
+		self.module
+	}
+
+	/// Source file and line that logged the entry, as `file:line`.
+	fn location(&self) -> String {
+		format!("{}:{}", self.file, self.line)
+	}
+
+	/// Values from the logger and the entry, sorted by key. Entry values take
+	/// precedence over logger values with the same key.
+	fn fields(&self) -> Vec<LogField> {
+		let mut fields = self.values.clone();
+		fields.extend(self.keys.iter().map(|(key, value)| (*key, value.clone())));
+		let mut fields = fields
+			.into_iter()
+			.map(|(key, value)| LogField {
+				key: key.to_string(),
+				value,
+			})
+			.collect::<Vec<_>>();
+		fields.sort_by(|a, b| a.key.cmp(&b.key));
+		fields
+	}
+}
+
+/// Returns true if the entry is at `level` or more severe, and was logged by
+/// `module` or one of its submodules.
+pub fn log_matches(entry: &LogEntry, level: Option<LogLevel>, module: Option<&str>) -> bool {
+	let level_matches = match level {
+		Some(level) => entry.level.is_at_least(level.into()),
+		None => true,
+	};
+	let module_matches = match module {
+		Some(module) => {
+			entry.module == module
+				|| (entry.module.starts_with(module)
+					&& entry.module[module.len()..].starts_with("::"))
+		}
+		None => true,
+	};
+	level_matches && module_matches
+}
+
+/// Returns the last `limit` entries at `level` or more severe, and logged
+/// after the given time, oldest first.
+pub fn filter_logs(
+	entries: Vec<LogEntry>,
+	level: Option<LogLevel>,
+	after: Option<DateTime>,
+	limit: usize,
+) -> Vec<LogEntry> {
+	let mut entries = entries
+		.into_iter()
+		.filter(|entry| log_matches(entry, level, None))
+		.filter(|entry| match after {
+			Some(after) => entry.time > after.to_millis(),
+			None => true,
+		})
+		.collect::<Vec<_>>();
+	let skip = entries.len().saturating_sub(limit);
+	entries.drain(..skip);
+	entries
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(level: slog::Level, time: u64) -> LogEntry {
+		module_entry(level, time, "module")
+	}
+
+	fn module_entry(level: slog::Level, time: u64, module: &'static str) -> LogEntry {
+		LogEntry {
+			level,
+			msg: format!("at {}", time),
+			time,
+			line: 1,
+			column: 1,
+			file: "file.rs",
+			module,
+			keys: Default::default(),
+			values: Default::default(),
+		}
+	}
+
+	#[test]
+	fn test_filter_logs() {
+		let entries = vec![
+			entry(slog::Level::Info, 1),
+			entry(slog::Level::Error, 2),
+			entry(slog::Level::Debug, 3),
+			entry(slog::Level::Warning, 4),
+		];
+		let times = |entries: Vec<LogEntry>| entries.iter().map(|e| e.time).collect::<Vec<_>>();
+
+		assert_eq!(
+			times(filter_logs(entries.clone(), None, None, 10)),
+			[1, 2, 3, 4]
+		);
+		assert_eq!(times(filter_logs(entries.clone(), None, None, 2)), [3, 4]);
+		assert_eq!(
+			times(filter_logs(entries.clone(), Some(LogLevel::Info), None, 10)),
+			[1, 2, 4]
+		);
+		let after = Some(DateTime::from_millis(2));
+		assert_eq!(
+			times(filter_logs(entries, Some(LogLevel::Warning), after, 10)),
+			[4]
+		);
+	}
+
+	#[test]
+	fn test_log_matches() {
+		let entry = module_entry(slog::Level::Warning, 1, "kamipad::graph::api");
+		assert!(log_matches(&entry, None, None));
+		assert!(log_matches(&entry, Some(LogLevel::Warning), None));
+		assert!(!log_matches(&entry, Some(LogLevel::Error), None));
+		assert!(log_matches(&entry, None, Some("kamipad")));
+		assert!(log_matches(&entry, None, Some("kamipad::graph")));
+		assert!(log_matches(&entry, None, Some("kamipad::graph::api")));
+		assert!(!log_matches(&entry, None, Some("kamipad::gra")));
+		assert!(!log_matches(&entry, None, Some("kamipad::graph::api::x")));
+		assert!(!log_matches(
+			&entry,
+			Some(LogLevel::Error),
+			Some("kamipad::graph")
+		));
+	}
+}
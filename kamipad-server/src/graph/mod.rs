@@ -1,16 +1,96 @@
 //! Contains the GraphQL API support code and resolvers.
 //!
-//! The main types in this module are `Context`, `Query` and `Mutation`.
+//! The main types in this module are `Context`, `Query`, `Mutation` and
+//! `Subscription`.
 //!
 //! The submodule `api` contains the API interfaces for resolving GraphQL and
 //! the GraphiQL endpoint.
+//!
+//! Resolvers are async, and must not block on the database or any other I/O.
+//! Blocking work with the `App` runs on the database threads with `App::run`.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
 
 use crate::app::App;
+use crate::attachments::Attachment;
+use crate::auth::{Role, User};
 use crate::common;
-use crate::logging::RequestLog;
+use crate::logging::{LogEntry, RequestId, RequestLog};
+use crate::notes::Note;
+use crate::reminders::Reminder;
+use crate::searches::{SavedSearch, SearchFilter, SearchSort};
+use crate::status::ServerStatus;
+use crate::templates::Template;
+use crate::util;
 
 pub mod api;
 
+pub mod cache;
+use self::cache::{CacheInfo, CacheTtl};
+
+pub mod deprecation;
+use self::deprecation::SchemaVersion;
+
+pub mod error;
+
+pub mod federation;
+use self::federation::{Entity, Service};
+
+pub mod loader;
+use self::loader::Loaders;
+
+pub mod logs;
+use self::logs::LogLevel;
+
+pub mod persisted;
+
+pub mod rate_limit;
+use self::error::{Error, ErrorCode, Result};
+
+#[macro_use]
+pub mod connection;
+use self::connection::PageArgs;
+use self::scalars::{Any, DateTime, ID};
+
+pub mod scalars;
+
+pub mod tracing;
+use self::tracing::Tracer;
+
+pub mod upload;
+use self::upload::{Upload, Uploads};
+
+pub mod validate;
+
+mod attachments;
+
+mod export;
+use crate::export::ExportJob;
+
+mod import;
+use crate::import::ImportJob;
+
+mod notes;
+use self::notes::{
+	BulkNoteUpdate, BulkUpdateResult, LinkGraph, NewNote, NoteConnection, NoteDiff, NoteFilter,
+	NoteSort, NoteUpdate, SearchResult, TagConnection,
+};
+
+mod reminders;
+use self::reminders::RepeatRule;
+
+mod searches;
+use self::searches::{NewSavedSearch, SavedSearchUpdate};
+
+mod status;
+
+mod templates;
+use self::templates::{NewTemplate, PlaceholderValue, TemplateUpdate};
+
 /// Context for GraphQL. This wraps all the data available to a GraphQL
 /// resolver, which basically boils down to the `App` instance and the
 /// request log.
@@ -20,10 +100,57 @@ pub mod api;
 pub struct Context {
 	pub app: &'static App,
 	pub log: RequestLog,
+	pub request_id: RequestId,
+	pub loaders: Loaders,
+	pub uploads: Uploads,
+	/// User authenticated for the request, if any.
+	pub user: Option<User>,
+	/// TTL for the cached fields, see `Context::cached`.
+	pub cache_ttl: Arc<CacheTtl>,
+	/// Timing of the resolvers, if tracing is enabled.
+	pub tracer: Option<Tracer>,
 }
 
 impl juniper::Context for Context {}
 
+impl Context {
+	/// Returns the authenticated user, failing with `UNAUTHORIZED` for
+	/// anonymous requests.
+	pub fn require_user(&self) -> Result<&User> {
+		self.user
+			.as_ref()
+			.ok_or_else(|| Error::new(ErrorCode::Unauthorized, "authentication required"))
+	}
+
+	/// Returns the authenticated user if they have the role, failing with
+	/// `UNAUTHORIZED` otherwise.
+	///
+	/// Resolvers call this before accessing any data, so that the field is
+	/// resolved as null with an error for users without the role.
+	pub fn require(&self, role: Role) -> Result<&User> {
+		let user = self.require_user()?;
+		if user.has_role(role) {
+			Ok(user)
+		} else {
+			let message = format!("requires the {} role", role.as_str());
+			Err(Error::new(ErrorCode::Unauthorized, message))
+		}
+	}
+}
+
+/// Configuration for executing GraphQL requests.
+pub struct GraphConfig {
+	/// Allow introspection queries, which describe the schema.
+	pub introspection: bool,
+	/// TTL for the cached fields, by `Type.field` name.
+	pub cache_ttl: Arc<CacheTtl>,
+	/// Add the timing of the resolvers to the responses.
+	pub tracing: bool,
+	/// Maximum time to execute a request, if any. This is the
+	/// `request_timeout` from the configuration, see `timeout`.
+	pub timeout: Option<Duration>,
+}
+
 /// Root for GraphQL queries. Any method implemented here will be available
 /// to the GraphQL interface.
 pub struct Query;
@@ -32,7 +159,11 @@ pub struct Query;
 /// to the GraphQL interface.
 pub struct Mutation;
 
-#[juniper::object(Context = Context)]
+/// Root for GraphQL subscriptions. These are served by the
+/// `/api/graphql/stream` endpoint, see `api::stream`.
+pub struct Subscription;
+
+#[juniper::graphql_object(context = Context)]
 impl Query {
 	/// Server application name.
 	fn app_name() -> &'static str {
@@ -48,14 +179,743 @@ impl Query {
 	fn app_version() -> &'static str {
 		common::VERSION
 	}
+
+	// Federation fields, see `federation`. These have no description, so that
+	// they are removed cleanly from the `_service` SDL.
+
+	#[graphql(name = "_service")]
+	fn service() -> Service {
+		Service
+	}
+
+	#[graphql(name = "_entities")]
+	async fn entities(context: &Context, representations: Vec<Any>) -> Result<Vec<Option<Entity>>> {
+		let _trace = context.trace("Query._entities");
+		context.require(Role::Reader)?;
+		let entities = representations
+			.into_iter()
+			.map(|representation| federation::resolve_entity(context, representation));
+		futures::future::try_join_all(entities).await
+	}
+
+	/// Version of the GraphQL schema and its deprecated fields.
+	fn schema_version() -> SchemaVersion {
+		SchemaVersion
+	}
+
+	/// Returns a note by its ID, or null if it doesn't exist.
+	async fn note(context: &Context, id: ID) -> Result<Option<Note>> {
+		let _trace = context.trace("Query.note");
+		context.require(Role::Reader)?;
+		Ok(context.loaders.notes.load(&id.0).await?)
+	}
+
+	/// Returns the notes matching the filter, most recently created first
+	/// unless sorted otherwise.
+	async fn notes(
+		context: &Context,
+		filter: Option<NoteFilter>,
+		sort: Option<NoteSort>,
+		first: Option<i32>,
+		after: Option<String>,
+		last: Option<i32>,
+		before: Option<String>,
+	) -> Result<NoteConnection> {
+		let _trace = context.trace("Query.notes");
+		context.require(Role::Reader)?;
+		let notes = context
+			.cached("Query.notes", &(&filter, sort), || async {
+				let filter = SearchFilter::from(filter.clone().unwrap_or_default());
+				let sort = sort.map_or(SearchSort::Created, SearchSort::from);
+				let notes = context
+					.app
+					.run(move |app| app.find_notes(&filter, sort))
+					.await?;
+				Ok(notes)
+			})
+			.await?;
+		let args = PageArgs {
+			first,
+			after,
+			last,
+			before,
+		};
+		let page = connection::paginate(notes, |note| note.id.to_string(), args)?;
+		let ids = page.edges.iter().map(|(_, note)| note.id);
+		context.loaders.note_tags.defer(ids);
+		Ok(page.into())
+	}
+
+	/// Returns the notes with a tag, most recently created first.
+	async fn notes_by_tag(
+		context: &Context,
+		tag: String,
+		first: Option<i32>,
+		after: Option<String>,
+		last: Option<i32>,
+		before: Option<String>,
+	) -> Result<NoteConnection> {
+		let _trace = context.trace("Query.notesByTag");
+		context.require(Role::Reader)?;
+		notes::check_tag(&tag)?;
+		let notes = context.app.run(move |app| app.notes_by_tag(&tag)).await?;
+		let args = PageArgs {
+			first,
+			after,
+			last,
+			before,
+		};
+		let page = connection::paginate(notes, |note| note.id.to_string(), args)?;
+		let ids = page.edges.iter().map(|(_, note)| note.id);
+		context.loaders.note_tags.defer(ids);
+		Ok(page.into())
+	}
+
+	/// Returns the notes containing all the words in the query, best matches
+	/// first.
+	async fn search(
+		context: &Context,
+		query: String,
+		limit: Option<i32>,
+	) -> Result<Vec<SearchResult>> {
+		let _trace = context.trace("Query.search");
+		context.require(Role::Reader)?;
+		let limit = limit
+			.map_or(notes::DEFAULT_SEARCH_LIMIT, |limit| limit.max(0) as usize)
+			.min(connection::MAX_PAGE_SIZE);
+		let results = context
+			.app
+			.run(move |app| app.search_notes(&query, limit))
+			.await?;
+		let ids = results.iter().map(|(note, _)| note.id);
+		context.loaders.note_tags.defer(ids);
+		Ok(results.into_iter().map(SearchResult::from).collect())
+	}
+
+	/// Returns the saved searches of the current user, sorted by name.
+	async fn saved_searches(context: &Context) -> Result<Vec<SavedSearch>> {
+		let _trace = context.trace("Query.savedSearches");
+		let user = context.require(Role::Reader)?.name.clone();
+		Ok(context
+			.app
+			.run(move |app| app.saved_searches(&user))
+			.await?)
+	}
+
+	/// Returns all note templates, sorted by name.
+	async fn templates(context: &Context) -> Result<Vec<Template>> {
+		let _trace = context.trace("Query.templates");
+		context.require(Role::Reader)?;
+		Ok(context.app.run(|app| app.templates()).await?)
+	}
+
+	/// Returns a note template by its ID, or null if it doesn't exist.
+	async fn template(context: &Context, id: ID) -> Result<Option<Template>> {
+		let _trace = context.trace("Query.template");
+		context.require(Role::Reader)?;
+		Ok(context.app.run(move |app| app.template(&id.0)).await?)
+	}
+
+	/// Returns the tags used by notes, sorted by name.
+	async fn tags(
+		context: &Context,
+		first: Option<i32>,
+		after: Option<String>,
+		last: Option<i32>,
+		before: Option<String>,
+	) -> Result<TagConnection> {
+		let _trace = context.trace("Query.tags");
+		context.require(Role::Reader)?;
+		let tags = context.app.run(|app| app.tags()).await?;
+		let args = PageArgs {
+			first,
+			after,
+			last,
+			before,
+		};
+		notes::tag_connection(tags, args)
+	}
+
+	/// Returns the latest entries from the application log, oldest first,
+	/// optionally only those at `level` or more severe and logged after the
+	/// given time.
+	fn logs(
+		context: &Context,
+		level: Option<LogLevel>,
+		after: Option<DateTime>,
+		limit: Option<i32>,
+	) -> Result<Vec<LogEntry>> {
+		context.require(Role::Admin)?;
+		let limit = limit.map_or(logs::DEFAULT_LOG_LIMIT, |limit| limit.max(0) as usize);
+		Ok(logs::filter_logs(
+			context.app.all_logs(),
+			level,
+			after,
+			limit,
+		))
+	}
+
+	/// Returns the log entries for a recent request, by the ID in the
+	/// `X-Request-Id` header, or null if they are no longer available.
+	fn request_log(context: &Context, id: String) -> Result<Option<Vec<LogEntry>>> {
+		context.require(Role::Admin)?;
+		let id = RequestId::parse(&id)
+			.map_err(|_| Error::bad_request(format!("invalid request ID `{}`", id)))?;
+		Ok(context.app.request_logs(&id))
+	}
+
+	/// Returns the line-based diff of the text of a note from a revision to
+	/// another, or to the current version if `to` is not given. Returns null
+	/// if the note or either revision doesn't exist.
+	async fn note_diff(
+		context: &Context,
+		id: ID,
+		from: i32,
+		to: Option<i32>,
+	) -> Result<Option<NoteDiff>> {
+		let _trace = context.trace("Query.noteDiff");
+		context.require(Role::Reader)?;
+		let revision = |revision: i32| -> Result<u64> {
+			if revision > 0 {
+				Ok(revision as u64)
+			} else {
+				Err(Error::bad_request(format!("invalid revision {}", revision)))
+			}
+		};
+		let from = revision(from)?;
+		let to = to.map(revision).transpose()?;
+		let notes = context
+			.app
+			.run(move |app| -> util::Result<_> {
+				let from = app.note_revision(&id.0, Some(from))?;
+				let to = app.note_revision(&id.0, to)?;
+				Ok((from, to))
+			})
+			.await?;
+		match notes {
+			(Some(from), Some(to)) => Ok(Some(NoteDiff::new(&from, &to))),
+			_ => Ok(None),
+		}
+	}
+
+	/// Returns the notes connected to a note by links in either direction, up
+	/// to `depth` links away (1 by default, at most 5), along with the links
+	/// between them. Returns null if the note doesn't exist.
+	async fn link_graph(
+		context: &Context,
+		root_id: ID,
+		depth: Option<i32>,
+	) -> Result<Option<LinkGraph>> {
+		let _trace = context.trace("Query.linkGraph");
+		context.require(Role::Reader)?;
+		let depth = depth.map_or(notes::DEFAULT_LINK_DEPTH, |depth| depth.max(0) as usize);
+		if depth > notes::MAX_LINK_DEPTH {
+			return Err(Error::bad_request(format!(
+				"`depth` must be at most {}",
+				notes::MAX_LINK_DEPTH
+			)));
+		}
+		let graph = context
+			.app
+			.run(move |app| app.link_graph(&root_id.0, depth))
+			.await?;
+		Ok(graph.map(LinkGraph::from))
+	}
+
+	/// Returns the attachments of a note, oldest first, or null if the note
+	/// doesn't exist.
+	async fn attachments(context: &Context, note_id: ID) -> Result<Option<Vec<Attachment>>> {
+		let _trace = context.trace("Query.attachments");
+		context.require(Role::Reader)?;
+		let attachments = context
+			.app
+			.run(move |app| app.note_attachments(&note_id.0))
+			.await?;
+		Ok(attachments)
+	}
+
+	/// Returns an import job started by `importNotes`, or null if it doesn't
+	/// exist or finished more than an hour ago.
+	fn import_job(context: &Context, id: ID) -> Result<Option<ImportJob>> {
+		context.require(Role::Editor)?;
+		Ok(context.app.import_job(&id.0))
+	}
+
+	/// Returns an export job started by `exportNotebook`, or null if it
+	/// doesn't exist or finished more than an hour ago.
+	fn export_job(context: &Context, id: ID) -> Result<Option<ExportJob>> {
+		context.require(Role::Editor)?;
+		Ok(context.app.export_job(&id.0))
+	}
+
+	/// Returns the current status of the server and its database.
+	async fn server_status(context: &Context) -> Result<ServerStatus> {
+		let _trace = context.trace("Query.serverStatus");
+		context.require(Role::Admin)?;
+		Ok(context.app.run(|app| app.status()).await?)
+	}
+
+	/// Returns the number of entries, hits and misses for each of the server
+	/// caches.
+	fn cache_stats(context: &Context) -> Result<Vec<CacheInfo>> {
+		context.require(Role::Admin)?;
+		let stats = context.app.cache_stats();
+		Ok(stats.into_iter().map(CacheInfo::from).collect())
+	}
 }
 
-#[juniper::object(Context = Context)]
+#[juniper::graphql_object(context = Context)]
 impl Mutation {
 	/// A no-op operation to test mutations.
+	#[graphql(deprecated = "Not needed to test mutations. Sunset on 2021-06-30.")]
 	fn no_op(context: &Context) -> i32 {
+		context.deprecated("Mutation.noOp");
 		42
 	}
+
+	/// Logs in with a user name and password, returning the token to send in
+	/// the `Authorization` header as `Bearer <token>`.
+	async fn login(context: &Context, username: String, password: String) -> Result<String> {
+		let _trace = context.trace("Mutation.login");
+		let name = username.clone();
+		let result = context
+			.app
+			.run(move |app| app.login(&name, &password))
+			.await?;
+		match result {
+			Some(token) => {
+				info!(context.log, "user {} logged in", username);
+				Ok(token)
+			}
+			None => {
+				warn!(context.log, "failed login for user {}", username);
+				Err(Error::new(
+					ErrorCode::Unauthorized,
+					"invalid user name or password",
+				))
+			}
+		}
+	}
+
+	/// Ends the session for the token used in the request.
+	async fn logout(context: &Context) -> Result<bool> {
+		let _trace = context.trace("Mutation.logout");
+		let user = context.require_user()?.clone();
+		let name = user.name.clone();
+		context.app.run(move |app| app.logout(&user)).await?;
+		info!(context.log, "user {} logged out", name);
+		Ok(true)
+	}
+
+	/// Creates a new note.
+	async fn create_note(context: &Context, mut input: NewNote) -> Result<Note> {
+		let _trace = context.trace("Mutation.createNote");
+		context.require(Role::Editor)?;
+		validate::validate(&input)?;
+		let metadata = input.metadata()?;
+		let (title, text) = (input.title, input.text.unwrap_or_default());
+		let note = context
+			.app
+			.run(move |app| app.create_note(title, text, metadata))
+			.await?;
+		info!(context.log, "created note {}", note.id);
+		Ok(note)
+	}
+
+	/// Changes an existing note.
+	async fn update_note(context: &Context, id: ID, input: NoteUpdate) -> Result<Note> {
+		let _trace = context.trace("Mutation.updateNote");
+		context.require(Role::Editor)?;
+		validate::validate(&input)?;
+		let changes = input.into_changes()?;
+		let result = context
+			.app
+			.run(move |app| app.update_note(&id.0, changes))
+			.await?;
+		match result {
+			Some(note) => Ok(note),
+			None => Err(Error::not_found(format!("note {} not found", id))),
+		}
+	}
+
+	/// Deletes a note. Returns false if the note doesn't exist.
+	async fn delete_note(context: &Context, id: ID) -> Result<bool> {
+		let _trace = context.trace("Mutation.deleteNote");
+		context.require(Role::Editor)?;
+		let deleted = context.app.run(move |app| app.delete_note(&id.0)).await?;
+		if deleted {
+			info!(context.log, "deleted note {}", id);
+		}
+		Ok(deleted)
+	}
+
+	/// Applies the same change to many notes at once, in a single
+	/// transaction. Notebooks are tags, so notes are moved between notebooks
+	/// by adding and removing tags. Returns the result for each ID, in order.
+	/// Notes that fail, such as when they don't exist, are left unchanged
+	/// without failing the others.
+	async fn bulk_update_notes(
+		context: &Context,
+		ids: Vec<ID>,
+		patch: BulkNoteUpdate,
+	) -> Result<Vec<BulkUpdateResult>> {
+		let _trace = context.trace("Mutation.bulkUpdateNotes");
+		context.require(Role::Editor)?;
+		if ids.len() > notes::MAX_BULK_UPDATE {
+			let message = format!(
+				"cannot update more than {} notes at once",
+				notes::MAX_BULK_UPDATE
+			);
+			return Err(Error::bad_request(message));
+		}
+		validate::validate(&patch)?;
+		let patch = patch.into_patch();
+		let ids = ids.into_iter().map(|id| id.0).collect::<Vec<_>>();
+		let results = context
+			.app
+			.run(move |app| app.bulk_update_notes(&ids, &patch))
+			.await?;
+		let failed = results.iter().filter(|(_, error)| error.is_some()).count();
+		info!(
+			context.log,
+			"bulk updated {} notes, {} failed",
+			results.len() - failed,
+			failed
+		);
+		Ok(results
+			.into_iter()
+			.map(|(id, error)| BulkUpdateResult {
+				id: ID(id),
+				ok: error.is_none(),
+				error,
+			})
+			.collect())
+	}
+
+	/// Adds a tag to a note.
+	async fn tag_note(context: &Context, id: ID, tag: String) -> Result<Note> {
+		let _trace = context.trace("Mutation.tagNote");
+		context.require(Role::Editor)?;
+		notes::check_tag(&tag)?;
+		let result = context
+			.app
+			.run(move |app| app.tag_note(&id.0, &tag))
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("note {} not found", id)))
+	}
+
+	/// Removes a tag from a note.
+	async fn untag_note(context: &Context, id: ID, tag: String) -> Result<Note> {
+		let _trace = context.trace("Mutation.untagNote");
+		context.require(Role::Editor)?;
+		notes::check_tag(&tag)?;
+		let result = context
+			.app
+			.run(move |app| app.untag_note(&id.0, &tag))
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("note {} not found", id)))
+	}
+
+	/// Adds a link from a note to another note. A note can't be deleted while
+	/// other notes link to it.
+	async fn link_note(context: &Context, id: ID, to: ID) -> Result<Note> {
+		let _trace = context.trace("Mutation.linkNote");
+		context.require(Role::Editor)?;
+		let result = context
+			.app
+			.run(move |app| app.link_note(&id.0, &to.0))
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("note {} or {} not found", id, to)))
+	}
+
+	/// Removes a link from a note to another note.
+	async fn unlink_note(context: &Context, id: ID, to: ID) -> Result<Note> {
+		let _trace = context.trace("Mutation.unlinkNote");
+		context.require(Role::Editor)?;
+		let result = context
+			.app
+			.run(move |app| app.unlink_note(&id.0, &to.0))
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("note {} not found", id)))
+	}
+
+	/// Sets the reminder for a note, replacing any existing one. When due,
+	/// the reminder is logged and posted to the configured webhook, then
+	/// moves to its next occurrence if it repeats.
+	async fn set_reminder(
+		context: &Context,
+		id: ID,
+		due: DateTime,
+		repeat: Option<RepeatRule>,
+	) -> Result<Note> {
+		let _trace = context.trace("Mutation.setReminder");
+		context.require(Role::Editor)?;
+		let reminder = Reminder {
+			due: due.to_millis(),
+			repeat: repeat.map(Into::into),
+		};
+		let result = context
+			.app
+			.run(move |app| app.set_reminder(&id.0, Some(reminder)))
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("note {} not found", id)))
+	}
+
+	/// Removes the reminder from a note.
+	async fn clear_reminder(context: &Context, id: ID) -> Result<Note> {
+		let _trace = context.trace("Mutation.clearReminder");
+		context.require(Role::Editor)?;
+		let result = context
+			.app
+			.run(move |app| app.set_reminder(&id.0, None))
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("note {} not found", id)))
+	}
+
+	/// Saves a search for the current user, to run again with the `notes`
+	/// field of `savedSearches`.
+	async fn create_saved_search(context: &Context, input: NewSavedSearch) -> Result<SavedSearch> {
+		let _trace = context.trace("Mutation.createSavedSearch");
+		let user = context.require(Role::Reader)?.name.clone();
+		validate::validate(&input)?;
+		let filter = SearchFilter::from(input.filter.unwrap_or_default());
+		let sort = input.sort.map_or(SearchSort::Created, SearchSort::from);
+		let name = input.name;
+		let search = context
+			.app
+			.run(move |app| app.create_saved_search(&user, name, filter, sort))
+			.await?;
+		info!(context.log, "created saved search {}", search.id);
+		Ok(search)
+	}
+
+	/// Changes a saved search of the current user.
+	async fn update_saved_search(
+		context: &Context,
+		id: ID,
+		input: SavedSearchUpdate,
+	) -> Result<SavedSearch> {
+		let _trace = context.trace("Mutation.updateSavedSearch");
+		let user = context.require(Role::Reader)?.name.clone();
+		validate::validate(&input)?;
+		let changes = input.into_changes();
+		let result = context
+			.app
+			.run(move |app| match app.saved_search(&id.0)? {
+				Some(search) if search.user == user => app.update_saved_search(&id.0, changes),
+				_ => Ok(None),
+			})
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("saved search {} not found", id)))
+	}
+
+	/// Deletes a saved search of the current user. Returns false if the
+	/// search doesn't exist.
+	async fn delete_saved_search(context: &Context, id: ID) -> Result<bool> {
+		let _trace = context.trace("Mutation.deleteSavedSearch");
+		let user = context.require(Role::Reader)?.name.clone();
+		let deleted = context
+			.app
+			.run(move |app| match app.saved_search(&id.0)? {
+				Some(search) if search.user == user => app.delete_saved_search(&id.0),
+				_ => Ok(false),
+			})
+			.await?;
+		if deleted {
+			info!(context.log, "deleted saved search {}", id);
+		}
+		Ok(deleted)
+	}
+
+	/// Creates a note template.
+	async fn create_template(context: &Context, input: NewTemplate) -> Result<Template> {
+		let _trace = context.trace("Mutation.createTemplate");
+		context.require(Role::Editor)?;
+		validate::validate(&input)?;
+		let (name, title, text) = (input.name, input.title, input.text.unwrap_or_default());
+		let template = context
+			.app
+			.run(move |app| app.create_template(name, title, text))
+			.await?;
+		info!(context.log, "created template {}", template.id);
+		Ok(template)
+	}
+
+	/// Changes a note template.
+	async fn update_template(context: &Context, id: ID, input: TemplateUpdate) -> Result<Template> {
+		let _trace = context.trace("Mutation.updateTemplate");
+		context.require(Role::Editor)?;
+		validate::validate(&input)?;
+		let changes = input.into_changes();
+		let result = context
+			.app
+			.run(move |app| app.update_template(&id.0, changes))
+			.await?;
+		result.ok_or_else(|| Error::not_found(format!("template {} not found", id)))
+	}
+
+	/// Deletes a note template. Returns false if the template doesn't exist.
+	async fn delete_template(context: &Context, id: ID) -> Result<bool> {
+		let _trace = context.trace("Mutation.deleteTemplate");
+		context.require(Role::Editor)?;
+		let deleted = context
+			.app
+			.run(move |app| app.delete_template(&id.0))
+			.await?;
+		if deleted {
+			info!(context.log, "deleted template {}", id);
+		}
+		Ok(deleted)
+	}
+
+	/// Creates a note from a template, replacing the `{{name}}` placeholders
+	/// in its title and text with the given values. The placeholders `date`,
+	/// `time` and `datetime` are replaced with the current UTC time, and
+	/// placeholders without a value are kept as is.
+	async fn create_note_from_template(
+		context: &Context,
+		template_id: ID,
+		values: Option<Vec<PlaceholderValue>>,
+	) -> Result<Note> {
+		let _trace = context.trace("Mutation.createNoteFromTemplate");
+		context.require(Role::Editor)?;
+		let values = templates::placeholder_values(values.unwrap_or_default());
+		let result = context
+			.app
+			.run(move |app| app.create_note_from_template(&template_id.0, values))
+			.await?;
+		let note = result
+			.ok_or_else(|| Error::not_found(format!("template {} not found", template_id)))?;
+		info!(
+			context.log,
+			"created note {} from template {}", note.id, template_id
+		);
+		Ok(note)
+	}
+
+	/// Removes all entries from a server cache, by the name returned from
+	/// `cacheStats`. Returns false if there is no cache with the name.
+	fn clear_cache(context: &Context, name: String) -> Result<bool> {
+		context.require(Role::Admin)?;
+		let cleared = context.app.clear_cache(&name);
+		if cleared {
+			info!(context.log, "cleared cache {}", name);
+		}
+		Ok(cleared)
+	}
+
+	/// Stores a file uploaded with the request as an attachment.
+	async fn upload_attachment(context: &Context, file: Upload) -> Result<Attachment> {
+		let _trace = context.trace("Mutation.uploadAttachment");
+		context.require(Role::Editor)?;
+		let file = context.uploads.take(&file)?;
+		let attachment = context
+			.app
+			.run(move |app| {
+				let result =
+					app.create_attachment(&file.blob, file.filename, file.content_type, file.size);
+				if result.is_err() {
+					let _ = app.database().release_blob(&file.blob);
+				}
+				result
+			})
+			.await?;
+		info!(context.log, "uploaded attachment {}", attachment.id);
+		Ok(attachment)
+	}
+
+	/// Starts importing notes from an uploaded JSON Lines file or zip archive
+	/// of markdown files. The import runs in the background, and its progress
+	/// and results are returned by `importJob`.
+	async fn import_notes(context: &Context, file: Upload) -> Result<ImportJob> {
+		let _trace = context.trace("Mutation.importNotes");
+		context.require(Role::Editor)?;
+		let file = context.uploads.take(&file)?;
+		let job = context.app.import_notes(file.blob, file.filename);
+		info!(context.log, "started import job {}", job.id);
+		Ok(job)
+	}
+
+	/// Starts exporting the notes with a tag, or all notes without one, as a
+	/// zip archive of markdown files and attachments. The export runs in the
+	/// background, and its download URL is returned by `exportJob`.
+	fn export_notebook(context: &Context, tag: Option<String>) -> Result<ExportJob> {
+		context.require(Role::Editor)?;
+		if let Some(tag) = &tag {
+			notes::check_tag(tag)?;
+		}
+		let job = context.app.export_notebook(tag);
+		info!(context.log, "started export job {}", job.id);
+		Ok(job)
+	}
+
+	/// Attaches an uploaded attachment to a note.
+	async fn attach_attachment(context: &Context, note_id: ID, id: ID) -> Result<Attachment> {
+		let _trace = context.trace("Mutation.attachAttachment");
+		context.require(Role::Editor)?;
+		let result = context
+			.app
+			.run(move |app| app.attach_attachment(&note_id.0, &id.0))
+			.await?;
+		result.ok_or_else(|| {
+			Error::not_found(format!("note {} or attachment {} not found", note_id, id))
+		})
+	}
+
+	/// Deletes an attachment, removing it from its notes. Returns false if the
+	/// attachment doesn't exist.
+	async fn remove_attachment(context: &Context, id: ID) -> Result<bool> {
+		let _trace = context.trace("Mutation.removeAttachment");
+		context.require(Role::Editor)?;
+		let removed = context
+			.app
+			.run(move |app| app.remove_attachment(&id.0))
+			.await?;
+		if removed {
+			info!(context.log, "removed attachment {}", id);
+		}
+		Ok(removed)
+	}
+}
+
+/// Stream of log entries for `Subscription.logStream`.
+type LogStream = Pin<Box<dyn Stream<Item = LogEntry> + Send>>;
+
+#[juniper::graphql_subscription(context = Context)]
+impl Subscription {
+	/// Streams the entries logged to the application log from now on,
+	/// optionally only those at `level` or more severe and logged by `module`
+	/// or its submodules.
+	async fn log_stream(
+		context: &Context,
+		level: Option<LogLevel>,
+		module: Option<String>,
+	) -> Result<LogStream> {
+		context.require(Role::Admin)?;
+		let entries = context.app.subscribe_logs().filter(move |entry| {
+			futures::future::ready(logs::log_matches(entry, level, module.as_deref()))
+		});
+		Ok(Box::pin(entries))
+	}
+}
+
+pub type Schema = juniper::RootNode<'static, Query, Mutation, Subscription>;
+
+/// Returns a new schema instance.
+pub fn new_schema() -> Schema {
+	Schema::new(Query, Mutation, Subscription)
 }
 
-pub type Schema = juniper::RootNode<'static, Query, Mutation>;
+/// Returns the schema instance managed by the server, which outlives the
+/// requests so that subscriptions can run as tasks on the runtime.
+pub fn schema() -> &'static Schema {
+	lazy_static! {
+		static ref SCHEMA: Schema = new_schema();
+	}
+	&SCHEMA
+}
+
+/// Returns the GraphQL schema definition language (SDL) for the schema.
+pub fn schema_sdl() -> String {
+	new_schema().as_schema_language()
+}
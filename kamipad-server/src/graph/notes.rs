@@ -0,0 +1,561 @@
+//! GraphQL types for notes.
+
+use kamipad_data as kd;
+
+use crate::notes::{Note, NoteChanges, NoteGraph, NotePatch};
+use crate::reminders::Reminder;
+use crate::searches::{SearchFilter, SearchSort};
+use crate::util::{diff_lines, LineChange};
+
+use super::connection::{self, PageArgs};
+use super::error::Result;
+use super::scalars::{DateTime, Json, ID};
+use super::validate::{Validate, Validator};
+use super::Context;
+
+connection!(NoteConnection, NoteEdge, Note);
+
+#[juniper::graphql_object(context = Context)]
+impl Note {
+	/// Unique ID for the note.
+	fn id(&self) -> ID {
+		ID(self.id)
+	}
+
+	/// Title for the note.
+	fn title(&self) -> &str {
+		&self.title
+	}
+
+	/// Full text of the note.
+	fn text(&self) -> &str {
+		&self.text
+	}
+
+	/// Tags for the note, sorted.
+	async fn tags(&self, context: &Context) -> Result<Vec<String>> {
+		let _trace = context.trace("Note.tags");
+		Ok(context
+			.loaders
+			.note_tags
+			.load(&self.id)
+			.await?
+			.unwrap_or_default())
+	}
+
+	/// Time the note was created.
+	fn created(&self) -> DateTime {
+		DateTime::from_millis(self.created)
+	}
+
+	/// Time the note was last changed.
+	fn updated(&self) -> DateTime {
+		DateTime::from_millis(self.updated)
+	}
+
+	/// Free-form metadata object for clients.
+	fn metadata(&self) -> Json {
+		Json(serde_json::Value::Object(self.metadata.clone()))
+	}
+
+	/// Archived notes are kept, but should be hidden by clients.
+	fn archived(&self) -> bool {
+		self.archived
+	}
+
+	/// Reminder for the note, or null if it has none.
+	fn reminder(&self) -> Option<&Reminder> {
+		self.reminder.as_ref()
+	}
+
+	/// Prior versions of the note, newest first.
+	async fn revisions(&self, context: &Context) -> Result<Vec<NoteRevision>> {
+		let _trace = context.trace("Note.revisions");
+		let id = self.id;
+		let revisions = context.app.run(move |app| app.note_revisions(&id)).await?;
+		Ok(revisions
+			.into_iter()
+			.map(|(revision, note)| NoteRevision {
+				revision: revision as i32,
+				title: note.title,
+				text: note.text,
+				updated: DateTime::from_millis(note.updated),
+			})
+			.collect())
+	}
+
+	/// Notes linked from this note, most recently created first.
+	async fn links(&self, context: &Context) -> Result<Vec<Note>> {
+		let _trace = context.trace("Note.links");
+		let id = self.id;
+		Ok(context.app.run(move |app| app.note_links(&id)).await?)
+	}
+
+	/// Notes linking to this note, most recently created first.
+	async fn backlinks(&self, context: &Context) -> Result<Vec<Note>> {
+		let _trace = context.trace("Note.backlinks");
+		let id = self.id;
+		Ok(context.app.run(move |app| app.note_backlinks(&id)).await?)
+	}
+}
+
+/// A prior version of a note.
+#[derive(juniper::GraphQLObject)]
+pub struct NoteRevision {
+	/// Revision number, increasing with each change to the note.
+	pub revision: i32,
+	pub title: String,
+	pub text: String,
+	/// Time the version was saved.
+	pub updated: DateTime,
+}
+
+/// Change to a line in a `NoteDiff`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum DiffChange {
+	Same,
+	Added,
+	Removed,
+}
+
+/// A line in a `NoteDiff`.
+#[derive(juniper::GraphQLObject)]
+pub struct DiffLine {
+	pub change: DiffChange,
+	pub text: String,
+}
+
+/// Line-based diff between two versions of a note, for the `noteDiff`
+/// query.
+#[derive(juniper::GraphQLObject)]
+pub struct NoteDiff {
+	/// Title of the older version.
+	pub from_title: String,
+	/// Title of the newer version.
+	pub to_title: String,
+	/// Lines of the text, in order.
+	pub lines: Vec<DiffLine>,
+}
+
+impl NoteDiff {
+	pub fn new(from: &Note, to: &Note) -> NoteDiff {
+		let lines = diff_lines(&from.text, &to.text)
+			.into_iter()
+			.map(|(change, text)| DiffLine {
+				change: match change {
+					LineChange::Same => DiffChange::Same,
+					LineChange::Added => DiffChange::Added,
+					LineChange::Removed => DiffChange::Removed,
+				},
+				text: text.to_string(),
+			})
+			.collect();
+		NoteDiff {
+			from_title: from.title.clone(),
+			to_title: to.title.clone(),
+			lines,
+		}
+	}
+}
+
+/// Default depth for the `linkGraph` query.
+pub const DEFAULT_LINK_DEPTH: usize = 1;
+
+/// Maximum depth for the `linkGraph` query.
+pub const MAX_LINK_DEPTH: usize = 5;
+
+/// A link between two notes in a `LinkGraph`.
+#[derive(juniper::GraphQLObject)]
+pub struct NoteLink {
+	pub from: ID,
+	pub to: ID,
+}
+
+/// Notes connected by links, for the `linkGraph` query.
+#[derive(juniper::GraphQLObject)]
+#[graphql(context = Context)]
+pub struct LinkGraph {
+	/// Connected notes, most recently created first.
+	pub notes: Vec<Note>,
+	/// Links between the notes.
+	pub links: Vec<NoteLink>,
+}
+
+impl From<NoteGraph> for LinkGraph {
+	fn from(graph: NoteGraph) -> LinkGraph {
+		LinkGraph {
+			notes: graph.notes,
+			links: graph
+				.links
+				.into_iter()
+				.map(|(from, to)| NoteLink {
+					from: ID(from),
+					to: ID(to),
+				})
+				.collect(),
+		}
+	}
+}
+
+/// Default number of results for the `search` query.
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+/// Note matching a full-text search.
+#[derive(juniper::GraphQLObject)]
+#[graphql(context = Context)]
+pub struct SearchResult {
+	pub note: Note,
+	/// Relevance of the note for the query, only comparable between results
+	/// of the same search.
+	pub score: f64,
+	/// Snippets of the fields that matched.
+	pub snippets: Vec<SearchSnippet>,
+}
+
+/// Part of a note field matching a search.
+#[derive(juniper::GraphQLObject)]
+pub struct SearchSnippet {
+	/// Name of the field, either `title` or `text`.
+	pub field: String,
+	/// Plain text of the snippet.
+	pub text: String,
+	/// Snippet as HTML, with the matching words in `<mark>` elements.
+	pub highlighted: String,
+}
+
+impl From<(Note, kd::SearchHit)> for SearchResult {
+	fn from((note, hit): (Note, kd::SearchHit)) -> SearchResult {
+		SearchResult {
+			note,
+			score: hit.score,
+			snippets: hit.snippets.into_iter().map(SearchSnippet::from).collect(),
+		}
+	}
+}
+
+impl From<kd::Snippet> for SearchSnippet {
+	fn from(snippet: kd::Snippet) -> SearchSnippet {
+		let mut highlighted = String::new();
+		let mut last = 0;
+		for range in snippet.highlights.iter() {
+			highlighted.push_str(&escape_html(&snippet.text[last..range.start]));
+			highlighted.push_str("<mark>");
+			highlighted.push_str(&escape_html(&snippet.text[range.clone()]));
+			highlighted.push_str("</mark>");
+			last = range.end;
+		}
+		highlighted.push_str(&escape_html(&snippet.text[last..]));
+		SearchSnippet {
+			field: snippet.field,
+			text: snippet.text,
+			highlighted,
+		}
+	}
+}
+
+fn escape_html(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&#39;")
+}
+
+/// A tag used by notes.
+#[derive(juniper::GraphQLObject)]
+pub struct Tag {
+	pub name: String,
+	/// Number of notes with the tag.
+	pub note_count: i32,
+}
+
+connection!(TagConnection, TagEdge, Tag);
+
+/// Returns the page of tags selected by the arguments, from the names and
+/// note counts sorted by name.
+pub fn tag_connection(tags: Vec<(String, usize)>, args: PageArgs) -> Result<TagConnection> {
+	let tags = tags
+		.into_iter()
+		.map(|(name, count)| Tag {
+			name,
+			note_count: count as i32,
+		})
+		.collect();
+	let page = connection::paginate(tags, |tag: &Tag| tag.name.clone(), args)?;
+	Ok(page.into())
+}
+
+/// Fails with `INVALID_INPUT` if the tag is not a valid name.
+pub fn check_tag(tag: &str) -> Result<()> {
+	let mut validator = Validator::new();
+	validator.check("tag", kd::is_valid_name(tag), "must be a valid name");
+	validator.finish()
+}
+
+/// Maximum length of a note title, in characters.
+pub const MAX_TITLE_LENGTH: usize = 500;
+
+pub fn validate_title(validator: &mut Validator, title: &str) {
+	validator.check("title", !title.trim().is_empty(), "must not be empty");
+	validator.check(
+		"title",
+		title.chars().count() <= MAX_TITLE_LENGTH,
+		format!("must have at most {} characters", MAX_TITLE_LENGTH),
+	);
+}
+
+fn validate_metadata(validator: &mut Validator, metadata: &Option<Json>) {
+	let valid = match metadata {
+		Some(metadata) => metadata.0.is_object(),
+		None => true,
+	};
+	validator.check("metadata", valid, "must be an object");
+}
+
+/// Maximum number of notes for the `bulkUpdateNotes` mutation.
+pub const MAX_BULK_UPDATE: usize = 500;
+
+/// Input for the `bulkUpdateNotes` mutation. Fields that are null are not
+/// changed.
+#[derive(juniper::GraphQLInputObject)]
+pub struct BulkNoteUpdate {
+	/// Tags to add to the notes, such as to move them to a notebook.
+	pub add_tags: Option<Vec<String>>,
+	/// Tags to remove from the notes.
+	pub remove_tags: Option<Vec<String>>,
+	/// Archives or unarchives the notes.
+	pub archived: Option<bool>,
+}
+
+impl Validate for BulkNoteUpdate {
+	fn validate(&self, validator: &mut Validator) {
+		let fields = [
+			("addTags", &self.add_tags),
+			("removeTags", &self.remove_tags),
+		];
+		for (field, tags) in fields.iter() {
+			for tag in tags.iter().flatten() {
+				let valid = kd::is_valid_name(tag);
+				validator.check(field, valid, format!("`{}` is not a valid name", tag));
+			}
+		}
+	}
+}
+
+impl BulkNoteUpdate {
+	pub fn into_patch(self) -> NotePatch {
+		NotePatch {
+			add_tags: self.add_tags.unwrap_or_default(),
+			remove_tags: self.remove_tags.unwrap_or_default(),
+			archived: self.archived,
+		}
+	}
+}
+
+/// Result for a note in `bulkUpdateNotes`.
+#[derive(juniper::GraphQLObject)]
+pub struct BulkUpdateResult {
+	pub id: ID,
+	/// True if the note was changed.
+	pub ok: bool,
+	/// Reason the note was not changed.
+	pub error: Option<String>,
+}
+
+/// Filter for the `notes` query.
+#[derive(Clone, Debug, Default, juniper::GraphQLInputObject)]
+pub struct NoteFilter {
+	/// Only return notes containing this text in the title or text, ignoring
+	/// case.
+	pub text: Option<String>,
+	/// Only return notes changed after this time.
+	pub updated_after: Option<DateTime>,
+	/// Only return archived notes if true, or notes not archived if false.
+	pub archived: Option<bool>,
+}
+
+impl From<NoteFilter> for SearchFilter {
+	fn from(filter: NoteFilter) -> SearchFilter {
+		SearchFilter {
+			text: filter.text,
+			updated_after: filter.updated_after.map(|time| time.to_millis()),
+			archived: filter.archived,
+		}
+	}
+}
+
+/// Order of the notes for the `notes` query and saved searches.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum NoteSort {
+	/// Most recently created first.
+	Created,
+	/// Most recently updated first.
+	Updated,
+	/// By title, ignoring case.
+	Title,
+}
+
+impl From<NoteSort> for SearchSort {
+	fn from(sort: NoteSort) -> SearchSort {
+		match sort {
+			NoteSort::Created => SearchSort::Created,
+			NoteSort::Updated => SearchSort::Updated,
+			NoteSort::Title => SearchSort::Title,
+		}
+	}
+}
+
+impl From<SearchSort> for NoteSort {
+	fn from(sort: SearchSort) -> NoteSort {
+		match sort {
+			SearchSort::Created => NoteSort::Created,
+			SearchSort::Updated => NoteSort::Updated,
+			SearchSort::Title => NoteSort::Title,
+		}
+	}
+}
+
+/// Input for the `createNote` mutation.
+#[derive(juniper::GraphQLInputObject)]
+pub struct NewNote {
+	pub title: String,
+	pub text: Option<String>,
+	/// Free-form metadata, which must be an object.
+	pub metadata: Option<Json>,
+}
+
+impl Validate for NewNote {
+	fn validate(&self, validator: &mut Validator) {
+		validate_title(validator, &self.title);
+		validate_metadata(validator, &self.metadata);
+	}
+}
+
+impl NewNote {
+	/// Returns the metadata for the note, failing if it is not an object.
+	pub fn metadata(&mut self) -> Result<serde_json::Map<String, serde_json::Value>> {
+		match self.metadata.take() {
+			Some(metadata) => metadata.into_object("metadata"),
+			None => Ok(Default::default()),
+		}
+	}
+}
+
+/// Input for the `updateNote` mutation. Fields that are not given are not
+/// changed.
+#[derive(juniper::GraphQLInputObject)]
+pub struct NoteUpdate {
+	pub title: Option<String>,
+	pub text: Option<String>,
+	/// Replaces the metadata, which must be an object.
+	pub metadata: Option<Json>,
+}
+
+impl Validate for NoteUpdate {
+	fn validate(&self, validator: &mut Validator) {
+		if let Some(title) = &self.title {
+			validate_title(validator, title);
+		}
+		validate_metadata(validator, &self.metadata);
+	}
+}
+
+impl NoteUpdate {
+	/// Returns the changes to apply, failing if the metadata is not an
+	/// object.
+	pub fn into_changes(self) -> Result<NoteChanges> {
+		let metadata = match self.metadata {
+			Some(metadata) => Some(metadata.into_object("metadata")?),
+			None => None,
+		};
+		Ok(NoteChanges {
+			title: self.title,
+			text: self.text,
+			metadata,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_tag_connection() {
+		fn tags() -> Vec<(String, usize)> {
+			vec![("a".into(), 3), ("b".into(), 1), ("c".into(), 2)]
+		}
+		fn names(page: &TagConnection) -> Vec<&str> {
+			page.edges.iter().map(|it| it.node.name.as_str()).collect()
+		}
+
+		let args = PageArgs {
+			first: Some(2),
+			..Default::default()
+		};
+		let page = tag_connection(tags(), args).unwrap();
+		assert_eq!(names(&page), ["a", "b"]);
+		assert_eq!(page.edges[0].node.note_count, 3);
+		assert_eq!(page.total_count, 3);
+		assert!(page.page_info.has_next_page);
+
+		let args = PageArgs {
+			first: Some(2),
+			after: page.page_info.end_cursor.clone(),
+			..Default::default()
+		};
+		let page = tag_connection(tags(), args).unwrap();
+		assert_eq!(names(&page), ["c"]);
+		assert!(!page.page_info.has_next_page);
+		assert!(page.page_info.has_previous_page);
+
+		let args = PageArgs {
+			last: Some(1),
+			before: page.page_info.start_cursor.clone(),
+			..Default::default()
+		};
+		let page = tag_connection(tags(), args).unwrap();
+		assert_eq!(names(&page), ["b"]);
+		assert!(page.page_info.has_next_page);
+		assert!(page.page_info.has_previous_page);
+	}
+
+	#[test]
+	fn test_highlighted_snippet() {
+		let snippet = kd::Snippet {
+			field: "text".to_string(),
+			text: "a <b> & needle, or needle".to_string(),
+			highlights: vec![8..14, 19..25],
+		};
+		let snippet = SearchSnippet::from(snippet);
+		assert_eq!(
+			snippet.highlighted,
+			"a &lt;b&gt; &amp; <mark>needle</mark>, or <mark>needle</mark>"
+		);
+	}
+
+	#[test]
+	fn test_validate_new_note() {
+		use crate::graph::validate::validate;
+
+		let note = NewNote {
+			title: "Title".into(),
+			text: None,
+			metadata: Some(Json(serde_json::json!({ "pinned": true }))),
+		};
+		assert!(validate(&note).is_ok());
+
+		let note = NewNote {
+			title: " ".into(),
+			text: None,
+			metadata: Some(Json(serde_json::json!([1, 2]))),
+		};
+		let err = validate(&note).unwrap_err();
+		let fields = err
+			.fields
+			.iter()
+			.map(|f| f.field.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(fields, ["title", "metadata"]);
+	}
+}
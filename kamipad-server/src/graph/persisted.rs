@@ -0,0 +1,118 @@
+//! Support for automatic persisted queries (APQ).
+//!
+//! Instead of the full query text, the client can send its SHA-256 hash in
+//! the request extensions:
+//!
+//! ```text
+//! { "extensions": { "persistedQuery": { "version": 1, "sha256Hash": "..." } } }
+//! ```
+//!
+//! If the server knows the hash, the query text is taken from the cache.
+//! Otherwise the request fails with `PERSISTED_QUERY_NOT_FOUND`, and the
+//! client sends the request again with both the hash and the query, which
+//! is then stored for the next requests.
+//!
+//! Queries are kept in the application `Cache`, and expire if not used for
+//! `PERSISTED_QUERY_TTL`.
+
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::app::App;
+
+use super::error::{Error, ErrorCode, Result};
+
+/// Time a persisted query is kept after it was last used.
+pub const PERSISTED_QUERY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Cache key for a persisted query, as the lowercase hex SHA-256 hash.
+#[derive(Clone, Eq, PartialEq, Hash)]
+struct QueryHash(String);
+
+/// Cache value for a persisted query.
+struct QueryText(String);
+
+/// Resolves the persisted queries in a GraphQL request body, which may be a
+/// single request or a batch, filling in the query text for requests that
+/// only have the hash.
+pub fn resolve_persisted_queries(app: &App, body: &mut serde_json::Value) -> Result<()> {
+	let requests = match body {
+		serde_json::Value::Array(requests) => requests.iter_mut().collect(),
+		body => vec![body],
+	};
+	for request in requests {
+		if let Some(request) = request.as_object_mut() {
+			resolve_query(app, request)?;
+		}
+	}
+	Ok(())
+}
+
+fn resolve_query(
+	app: &App,
+	request: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<()> {
+	let persisted = match request
+		.get("extensions")
+		.and_then(|extensions| extensions.get("persistedQuery"))
+	{
+		Some(persisted) => persisted,
+		None => return Ok(()),
+	};
+	if persisted.get("version").and_then(|v| v.as_u64()) != Some(1) {
+		return Err(Error::new(
+			ErrorCode::PersistedQueryNotSupported,
+			"unsupported persisted query version",
+		));
+	}
+	let hash = match persisted.get("sha256Hash").and_then(|v| v.as_str()) {
+		Some(hash) => QueryHash(hash.to_lowercase()),
+		None => return Err(Error::bad_request("missing persisted query hash")),
+	};
+
+	let cache = app.cache::<QueryHash, QueryText>();
+	match request.get("query").and_then(|query| query.as_str()) {
+		Some(query) => {
+			if query_hash(query) != hash.0 {
+				return Err(Error::bad_request(
+					"provided sha256Hash does not match query",
+				));
+			}
+			cache.save(hash, QueryText(query.to_string()), PERSISTED_QUERY_TTL);
+		}
+		None => match cache.get_and_renew(&hash, PERSISTED_QUERY_TTL) {
+			Some(query) => {
+				request.insert("query".into(), query.0.clone().into());
+			}
+			None => {
+				return Err(Error::new(
+					ErrorCode::PersistedQueryNotFound,
+					"PersistedQueryNotFound",
+				))
+			}
+		},
+	}
+	Ok(())
+}
+
+/// Returns the lowercase hex SHA-256 hash for a query.
+fn query_hash(query: &str) -> String {
+	Sha256::digest(query.as_bytes())
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_query_hash() {
+		assert_eq!(
+			query_hash("{ appName }"),
+			"c27dd85be6fb9158f807be470a442cbfa968a7e286b9fd42745fd7f2a28f2c10"
+		);
+	}
+}
@@ -0,0 +1,212 @@
+//! Per-client rate limiting for the GraphQL endpoints.
+//!
+//! Each client has a token bucket that holds up to `burst` requests and
+//! refills at `per_second` requests per second. Clients are identified by
+//! their session when authenticated, and by their IP address otherwise, so
+//! that sending made up tokens doesn't give a client a new bucket.
+//!
+//! The limit is set in the `graphql_rate_limit` table of the configuration,
+//! and is disabled by default:
+//!
+//! ```text
+//! [global.graphql_rate_limit]
+//! burst = 20
+//! per_second = 5
+//! ```
+//!
+//! Requests over the limit fail with `TOO_MANY_REQUESTS`. The same limiter
+//! is used for every request by the `rate_limit` fairing.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use crate::auth::Auth;
+
+use super::error::{Error, ErrorCode, Result};
+
+/// Number of client buckets kept before pruning the full ones.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Limits for each client.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+	/// Maximum number of requests in a burst.
+	pub burst: u32,
+	/// Sustained number of requests per second.
+	pub per_second: f64,
+}
+
+/// Token bucket for a client.
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+/// Rate limiter for the GraphQL requests, by client key.
+pub struct RateLimiter {
+	limit: Option<RateLimit>,
+	buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+	/// Returns a new limiter. Requests are not limited without a `limit`.
+	pub fn new(limit: Option<RateLimit>) -> RateLimiter {
+		RateLimiter {
+			limit,
+			buckets: Default::default(),
+		}
+	}
+
+	/// Takes a request from the bucket for the client, failing with
+	/// `TOO_MANY_REQUESTS` if it is empty.
+	pub fn check(&self, client: &str) -> Result<()> {
+		self.check_at(client, Instant::now())
+	}
+
+	fn check_at(&self, client: &str, now: Instant) -> Result<()> {
+		self.take_at(client, now).map_err(|retry| {
+			Error::new(
+				ErrorCode::TooManyRequests,
+				format!("too many requests, retry in {:.1}s", retry.as_secs_f64()),
+			)
+		})
+	}
+
+	/// Takes a request from the bucket for the client. If it is empty,
+	/// returns the time until the next request is allowed.
+	pub fn take(&self, client: &str) -> std::result::Result<(), Duration> {
+		self.take_at(client, Instant::now())
+	}
+
+	fn take_at(&self, client: &str, now: Instant) -> std::result::Result<(), Duration> {
+		let limit = match self.limit {
+			Some(limit) => limit,
+			None => return Ok(()),
+		};
+		let burst = limit.burst as f64;
+		let refill = |bucket: &Bucket| {
+			let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+			(bucket.tokens + elapsed * limit.per_second).min(burst)
+		};
+
+		let mut buckets = self.buckets.lock().unwrap();
+		if buckets.len() >= MAX_CLIENTS {
+			buckets.retain(|_, bucket| refill(bucket) < burst);
+		}
+		let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+			tokens: burst,
+			updated: now,
+		});
+		bucket.tokens = refill(bucket);
+		bucket.updated = now;
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			Ok(())
+		} else {
+			let retry = (1.0 - bucket.tokens) / limit.per_second;
+			Err(Duration::from_secs_f64(retry))
+		}
+	}
+}
+
+/// Reads the `graphql_rate_limit` table from the configuration. Invalid
+/// limits are logged and ignored.
+pub fn read_rate_limit(config: &rocket::Config, log: &slog::Logger) -> Option<RateLimit> {
+	let table = config.get_table("graphql_rate_limit").ok()?;
+	let burst = table.get("burst").and_then(|value| value.as_integer());
+	let per_second = table.get("per_second").and_then(|value| {
+		value
+			.as_float()
+			.or_else(|| value.as_integer().map(|value| value as f64))
+	});
+	match (burst, per_second) {
+		(Some(burst), Some(per_second)) if burst > 0 && per_second > 0.0 => Some(RateLimit {
+			burst: burst as u32,
+			per_second,
+		}),
+		_ => {
+			warn!(log, "invalid GraphQL rate limit: {:?}", table);
+			None
+		}
+	}
+}
+
+/// Request guard with the client IP address, if known.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+	type Error = ();
+
+	fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+		Outcome::Success(ClientIp(request.client_ip()))
+	}
+}
+
+/// Returns the key for the rate limit of a client.
+pub fn client_key(auth: &Auth, ip: &ClientIp) -> String {
+	match (&auth.0, ip.0) {
+		(Some(user), _) => format!("session:{}", user.session),
+		(None, Some(ip)) => format!("ip:{}", ip),
+		(None, None) => String::from("unknown"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rate_limiter() {
+		let limiter = RateLimiter::new(Some(RateLimit {
+			burst: 2,
+			per_second: 1.0,
+		}));
+		let start = Instant::now();
+		assert!(limiter.check_at("a", start).is_ok());
+		assert!(limiter.check_at("a", start).is_ok());
+		let err = limiter.check_at("a", start).unwrap_err();
+		assert_eq!(err.code, ErrorCode::TooManyRequests);
+
+		// Clients have separate buckets.
+		assert!(limiter.check_at("b", start).is_ok());
+
+		// The bucket refills over time, up to the burst.
+		let later = start + Duration::from_millis(1500);
+		assert!(limiter.check_at("a", later).is_ok());
+		assert!(limiter.check_at("a", later).is_err());
+		let much_later = later + Duration::from_secs(60);
+		assert!(limiter.check_at("a", much_later).is_ok());
+		assert!(limiter.check_at("a", much_later).is_ok());
+		assert!(limiter.check_at("a", much_later).is_err());
+	}
+
+	#[test]
+	fn test_rate_limiter_retry() {
+		let limiter = RateLimiter::new(Some(RateLimit {
+			burst: 1,
+			per_second: 2.0,
+		}));
+		let start = Instant::now();
+		assert!(limiter.take_at("a", start).is_ok());
+		let retry = limiter.take_at("a", start).unwrap_err();
+		assert_eq!(retry, Duration::from_millis(500));
+		let retry = limiter
+			.take_at("a", start + Duration::from_millis(200))
+			.unwrap_err();
+		assert!(retry > Duration::from_millis(299) && retry < Duration::from_millis(301));
+	}
+
+	#[test]
+	fn test_rate_limiter_disabled() {
+		let limiter = RateLimiter::new(None);
+		for _ in 0..100 {
+			assert!(limiter.check("a").is_ok());
+		}
+	}
+}
@@ -0,0 +1,51 @@
+//! GraphQL types for note reminders.
+
+use crate::reminders::{self, Reminder};
+
+use super::scalars::DateTime;
+use super::Context;
+
+/// Rule to repeat a reminder, from its due time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum RepeatRule {
+	Daily,
+	Weekly,
+	/// On the same day of the month, or the last day for shorter months.
+	Monthly,
+	Yearly,
+}
+
+impl From<reminders::RepeatRule> for RepeatRule {
+	fn from(rule: reminders::RepeatRule) -> RepeatRule {
+		match rule {
+			reminders::RepeatRule::Daily => RepeatRule::Daily,
+			reminders::RepeatRule::Weekly => RepeatRule::Weekly,
+			reminders::RepeatRule::Monthly => RepeatRule::Monthly,
+			reminders::RepeatRule::Yearly => RepeatRule::Yearly,
+		}
+	}
+}
+
+impl From<RepeatRule> for reminders::RepeatRule {
+	fn from(rule: RepeatRule) -> reminders::RepeatRule {
+		match rule {
+			RepeatRule::Daily => reminders::RepeatRule::Daily,
+			RepeatRule::Weekly => reminders::RepeatRule::Weekly,
+			RepeatRule::Monthly => reminders::RepeatRule::Monthly,
+			RepeatRule::Yearly => reminders::RepeatRule::Yearly,
+		}
+	}
+}
+
+#[juniper::graphql_object(context = Context)]
+impl Reminder {
+	/// Next time the reminder fires.
+	fn due(&self) -> DateTime {
+		DateTime::from_millis(self.due)
+	}
+
+	/// Rule to repeat the reminder, or null if it fires once.
+	fn repeat(&self) -> Option<RepeatRule> {
+		self.repeat.map(RepeatRule::from)
+	}
+}
@@ -0,0 +1,224 @@
+//! Custom scalar types for the GraphQL schema.
+
+use chrono::{TimeZone, Utc};
+use juniper::{
+	InputValue, Object, ParseScalarResult, ParseScalarValue, ScalarToken, ScalarValue, Value,
+};
+use kamipad_data as kd;
+
+use super::error::{Error, Result};
+
+/// Date and time in UTC, represented in GraphQL as an RFC 3339 string.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct DateTime(pub chrono::DateTime<Utc>);
+
+impl DateTime {
+	/// Returns the time for a timestamp in milliseconds since the UNIX
+	/// epoch, as stored in the database.
+	pub fn from_millis(millis: u64) -> DateTime {
+		DateTime(Utc.timestamp_millis(millis as i64))
+	}
+
+	/// Returns the timestamp in milliseconds since the UNIX epoch, or zero
+	/// for times before the epoch.
+	pub fn to_millis(&self) -> u64 {
+		self.0.timestamp_millis().max(0) as u64
+	}
+
+	/// Parses an RFC 3339 string, with any offset.
+	pub fn parse(input: &str) -> Option<DateTime> {
+		chrono::DateTime::parse_from_rfc3339(input)
+			.ok()
+			.map(|time| DateTime(time.with_timezone(&Utc)))
+	}
+}
+
+/// Unique ID from the database, represented in GraphQL as a hyphenated
+/// lowercase UUID string.
+///
+/// This is named `UUID` in the schema to not clash with the built-in `ID`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ID(pub kd::ID);
+
+impl From<kd::ID> for ID {
+	fn from(id: kd::ID) -> ID {
+		ID(id)
+	}
+}
+
+impl From<ID> for kd::ID {
+	fn from(id: ID) -> kd::ID {
+		id.0
+	}
+}
+
+impl std::fmt::Display for ID {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.0.fmt(f)
+	}
+}
+
+/// Arbitrary JSON value, represented in GraphQL as the value itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Json(pub serde_json::Value);
+
+impl Json {
+	/// Returns the value if it is an object, failing with `BAD_REQUEST`
+	/// otherwise. The `what` describes the value in the error message.
+	pub fn into_object(self, what: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+		match self.0 {
+			serde_json::Value::Object(object) => Ok(object),
+			_ => Err(Error::bad_request(format!("{} must be an object", what))),
+		}
+	}
+}
+
+/// Representation of an entity for `_entities` in federation, as a JSON
+/// object with the `__typename` and key fields.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Any(pub serde_json::Value);
+
+/// Converts a GraphQL input value to JSON. Returns `None` for enum values
+/// and unresolved variables, which are not valid JSON.
+fn input_to_json<S: ScalarValue>(input: &InputValue<S>) -> Option<serde_json::Value> {
+	let json = match input {
+		InputValue::Null => serde_json::Value::Null,
+		InputValue::Scalar(value) => {
+			if let Some(value) = value.as_int() {
+				value.into()
+			} else if let Some(value) = value.as_float() {
+				serde_json::Number::from_f64(value)?.into()
+			} else if let Some(value) = value.as_boolean() {
+				value.into()
+			} else {
+				value.as_string()?.into()
+			}
+		}
+		InputValue::List(items) => serde_json::Value::Array(
+			items
+				.iter()
+				.map(|item| input_to_json(&item.item))
+				.collect::<Option<_>>()?,
+		),
+		InputValue::Object(fields) => serde_json::Value::Object(
+			fields
+				.iter()
+				.map(|(key, value)| Some((key.item.clone(), input_to_json(&value.item)?)))
+				.collect::<Option<_>>()?,
+		),
+		InputValue::Enum(_) | InputValue::Variable(_) => return None,
+	};
+	Some(json)
+}
+
+/// Converts JSON to a GraphQL output value. Integers that don't fit in
+/// 32 bits are returned as floats.
+fn json_to_value<S: ScalarValue>(json: &serde_json::Value) -> Value<S> {
+	match json {
+		serde_json::Value::Null => Value::null(),
+		serde_json::Value::Bool(value) => Value::scalar(*value),
+		serde_json::Value::Number(number) => match number.as_i64() {
+			Some(value) if value >= i32::MIN as i64 && value <= i32::MAX as i64 => {
+				Value::scalar(value as i32)
+			}
+			_ => Value::scalar(number.as_f64().unwrap_or_default()),
+		},
+		serde_json::Value::String(value) => Value::scalar(value.clone()),
+		serde_json::Value::Array(items) => Value::list(items.iter().map(json_to_value).collect()),
+		serde_json::Value::Object(fields) => {
+			let mut object = Object::with_capacity(fields.len());
+			for (key, value) in fields {
+				object.add_field(key.as_str(), json_to_value(value));
+			}
+			Value::object(object)
+		}
+	}
+}
+
+#[juniper::graphql_scalar(
+	name = "Json",
+	description = "Arbitrary JSON value, such as an object with free-form metadata."
+)]
+impl<S> GraphQLScalar for Json
+where
+	S: ScalarValue,
+{
+	fn resolve(&self) -> Value {
+		json_to_value(&self.0)
+	}
+
+	fn from_input_value(v: &InputValue) -> Option<Json> {
+		input_to_json(v).map(Json)
+	}
+
+	fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+		match value {
+			ScalarToken::Int(_) => <i32 as ParseScalarValue<S>>::from_str(value),
+			ScalarToken::Float(_) => <f64 as ParseScalarValue<S>>::from_str(value),
+			ScalarToken::String(_) => <String as ParseScalarValue<S>>::from_str(value),
+		}
+	}
+}
+
+#[juniper::graphql_scalar(
+	name = "_Any",
+	description = "Entity representation for federation, with the `__typename` and key fields."
+)]
+impl<S> GraphQLScalar for Any
+where
+	S: ScalarValue,
+{
+	fn resolve(&self) -> Value {
+		json_to_value(&self.0)
+	}
+
+	fn from_input_value(v: &InputValue) -> Option<Any> {
+		input_to_json(v).map(Any)
+	}
+
+	fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+		<String as ParseScalarValue<S>>::from_str(value)
+	}
+}
+
+#[juniper::graphql_scalar(
+	name = "UUID",
+	description = "Unique ID as a lowercase hyphenated UUID, e.g. `645a9c23-9590-49d0-879e-250bff5b621a`."
+)]
+impl<S> GraphQLScalar for ID
+where
+	S: ScalarValue,
+{
+	fn resolve(&self) -> Value {
+		Value::scalar(self.0.to_string())
+	}
+
+	fn from_input_value(v: &InputValue) -> Option<ID> {
+		v.as_string_value().and_then(kd::ID::parse).map(ID)
+	}
+
+	fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+		<String as ParseScalarValue<S>>::from_str(value)
+	}
+}
+
+#[juniper::graphql_scalar(
+	name = "DateTime",
+	description = "Date and time as an RFC 3339 string, e.g. `2020-08-01T12:30:00.000Z`."
+)]
+impl<S> GraphQLScalar for DateTime
+where
+	S: ScalarValue,
+{
+	fn resolve(&self) -> Value {
+		Value::scalar(self.0.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+	}
+
+	fn from_input_value(v: &InputValue) -> Option<DateTime> {
+		v.as_string_value().and_then(DateTime::parse)
+	}
+
+	fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+		<String as ParseScalarValue<S>>::from_str(value)
+	}
+}
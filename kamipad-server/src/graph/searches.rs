@@ -0,0 +1,138 @@
+//! GraphQL types for saved searches.
+
+use crate::searches::{SavedSearch, SavedSearchChanges, SearchFilter};
+
+use super::connection::{self, PageArgs};
+use super::error::Result;
+use super::notes::{NoteConnection, NoteFilter, NoteSort};
+use super::scalars::{DateTime, ID};
+use super::validate::{Validate, Validator};
+use super::Context;
+
+/// Maximum length of the name of a saved search, in characters.
+pub const MAX_SEARCH_NAME_LENGTH: usize = 100;
+
+#[juniper::graphql_object(context = Context)]
+impl SavedSearch {
+	fn id(&self) -> ID {
+		ID(self.id)
+	}
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn filter(&self) -> &SearchFilter {
+		&self.filter
+	}
+
+	fn sort(&self) -> NoteSort {
+		self.sort.into()
+	}
+
+	fn created(&self) -> DateTime {
+		DateTime::from_millis(self.created)
+	}
+
+	fn updated(&self) -> DateTime {
+		DateTime::from_millis(self.updated)
+	}
+
+	/// Runs the search, returning the notes as the `notes` query would.
+	async fn notes(
+		&self,
+		context: &Context,
+		first: Option<i32>,
+		after: Option<String>,
+		last: Option<i32>,
+		before: Option<String>,
+	) -> Result<NoteConnection> {
+		let _trace = context.trace("SavedSearch.notes");
+		let (filter, sort) = (self.filter.clone(), self.sort);
+		let notes = context
+			.app
+			.run(move |app| app.find_notes(&filter, sort))
+			.await?;
+		let args = PageArgs {
+			first,
+			after,
+			last,
+			before,
+		};
+		let page = connection::paginate(notes, |note| note.id.to_string(), args)?;
+		let ids = page.edges.iter().map(|(_, note)| note.id);
+		context.loaders.note_tags.defer(ids);
+		Ok(page.into())
+	}
+}
+
+#[juniper::graphql_object(context = Context)]
+impl SearchFilter {
+	/// Only matches notes containing this text in the title or text, ignoring
+	/// case.
+	fn text(&self) -> Option<&str> {
+		self.text.as_deref()
+	}
+
+	/// Only matches notes changed after this time.
+	fn updated_after(&self) -> Option<DateTime> {
+		self.updated_after.map(DateTime::from_millis)
+	}
+
+	/// Only matches archived notes if true, or notes not archived if false.
+	fn archived(&self) -> Option<bool> {
+		self.archived
+	}
+}
+
+/// Input for the `createSavedSearch` mutation.
+#[derive(juniper::GraphQLInputObject)]
+pub struct NewSavedSearch {
+	pub name: String,
+	/// Filter for the notes, matching all notes if null.
+	pub filter: Option<NoteFilter>,
+	/// Order of the notes, most recently created first if null.
+	pub sort: Option<NoteSort>,
+}
+
+impl Validate for NewSavedSearch {
+	fn validate(&self, validator: &mut Validator) {
+		validate_name(validator, &self.name);
+	}
+}
+
+/// Input for the `updateSavedSearch` mutation. Fields that are null are not
+/// changed.
+#[derive(juniper::GraphQLInputObject)]
+pub struct SavedSearchUpdate {
+	pub name: Option<String>,
+	pub filter: Option<NoteFilter>,
+	pub sort: Option<NoteSort>,
+}
+
+impl Validate for SavedSearchUpdate {
+	fn validate(&self, validator: &mut Validator) {
+		if let Some(name) = &self.name {
+			validate_name(validator, name);
+		}
+	}
+}
+
+impl SavedSearchUpdate {
+	pub fn into_changes(self) -> SavedSearchChanges {
+		SavedSearchChanges {
+			name: self.name,
+			filter: self.filter.map(SearchFilter::from),
+			sort: self.sort.map(Into::into),
+		}
+	}
+}
+
+fn validate_name(validator: &mut Validator, name: &str) {
+	validator.check("name", !name.trim().is_empty(), "must not be empty");
+	validator.check(
+		"name",
+		name.chars().count() <= MAX_SEARCH_NAME_LENGTH,
+		format!("must have at most {} characters", MAX_SEARCH_NAME_LENGTH),
+	);
+}
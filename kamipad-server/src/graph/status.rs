@@ -0,0 +1,49 @@
+//! GraphQL types for the server status.
+
+use crate::status::ServerStatus;
+
+use super::scalars::DateTime;
+use super::Context;
+
+#[juniper::graphql_object(context = Context)]
+impl ServerStatus {
+	/// Time the server started.
+	fn started(&self) -> DateTime {
+		DateTime::from_millis(self.started)
+	}
+
+	/// Time since the server started, in seconds.
+	fn uptime(&self) -> f64 {
+		self.uptime as f64 / 1000.0
+	}
+
+	/// Resident memory of the server process in bytes, if available.
+	fn memory(&self) -> Option<f64> {
+		self.memory.map(|memory| memory as f64)
+	}
+
+	/// Number of requests being handled, including this one.
+	fn active_requests(&self) -> i32 {
+		self.active_requests as i32
+	}
+
+	/// Path to the database directory.
+	fn database_path(&self) -> String {
+		self.database.path.to_string_lossy().into_owned()
+	}
+
+	/// Size in bytes of the records and blobs in the database.
+	fn database_size(&self) -> f64 {
+		self.database.usage.bytes as f64
+	}
+
+	/// Number of records in the database.
+	fn database_records(&self) -> f64 {
+		self.database.usage.records as f64
+	}
+
+	/// True if the database is open in read-only mode.
+	fn database_read_only(&self) -> bool {
+		self.database.read_only
+	}
+}
@@ -0,0 +1,113 @@
+//! GraphQL types for note templates.
+
+use std::collections::HashMap;
+
+use crate::templates::{Template, TemplateChanges};
+
+use super::notes::validate_title;
+use super::scalars::{DateTime, ID};
+use super::validate::{Validate, Validator};
+use super::Context;
+
+/// Maximum length of the name of a template, in characters.
+pub const MAX_TEMPLATE_NAME_LENGTH: usize = 100;
+
+#[juniper::graphql_object(context = Context)]
+impl Template {
+	fn id(&self) -> ID {
+		ID(self.id)
+	}
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Title for new notes, with `{{name}}` placeholders.
+	fn title(&self) -> &str {
+		&self.title
+	}
+
+	/// Text for new notes, with `{{name}}` placeholders.
+	fn text(&self) -> &str {
+		&self.text
+	}
+
+	fn created(&self) -> DateTime {
+		DateTime::from_millis(self.created)
+	}
+
+	fn updated(&self) -> DateTime {
+		DateTime::from_millis(self.updated)
+	}
+}
+
+/// Input for the `createTemplate` mutation.
+#[derive(juniper::GraphQLInputObject)]
+pub struct NewTemplate {
+	pub name: String,
+	pub title: String,
+	pub text: Option<String>,
+}
+
+impl Validate for NewTemplate {
+	fn validate(&self, validator: &mut Validator) {
+		validate_name(validator, &self.name);
+		validate_title(validator, &self.title);
+	}
+}
+
+/// Input for the `updateTemplate` mutation. Fields that are null are not
+/// changed.
+#[derive(juniper::GraphQLInputObject)]
+pub struct TemplateUpdate {
+	pub name: Option<String>,
+	pub title: Option<String>,
+	pub text: Option<String>,
+}
+
+impl Validate for TemplateUpdate {
+	fn validate(&self, validator: &mut Validator) {
+		if let Some(name) = &self.name {
+			validate_name(validator, name);
+		}
+		if let Some(title) = &self.title {
+			validate_title(validator, title);
+		}
+	}
+}
+
+impl TemplateUpdate {
+	pub fn into_changes(self) -> TemplateChanges {
+		TemplateChanges {
+			name: self.name,
+			title: self.title,
+			text: self.text,
+		}
+	}
+}
+
+/// Value for a placeholder in `createNoteFromTemplate`.
+#[derive(juniper::GraphQLInputObject)]
+pub struct PlaceholderValue {
+	/// Name of the placeholder, without the braces.
+	pub name: String,
+	pub value: String,
+}
+
+/// Returns the placeholder values by name. Later values replace earlier ones
+/// with the same name.
+pub fn placeholder_values(values: Vec<PlaceholderValue>) -> HashMap<String, String> {
+	values
+		.into_iter()
+		.map(|it| (it.name.trim().to_string(), it.value))
+		.collect()
+}
+
+fn validate_name(validator: &mut Validator, name: &str) {
+	validator.check("name", !name.trim().is_empty(), "must not be empty");
+	validator.check(
+		"name",
+		name.chars().count() <= MAX_TEMPLATE_NAME_LENGTH,
+		format!("must have at most {} characters", MAX_TEMPLATE_NAME_LENGTH),
+	);
+}
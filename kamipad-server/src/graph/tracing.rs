@@ -0,0 +1,152 @@
+//! Timing of the resolvers, in the Apollo tracing format.
+//!
+//! With `graphql_tracing` enabled in the configuration, every response has
+//! the time spent by the resolvers in `extensions.tracing`:
+//!
+//! ```text
+//! "extensions": { "tracing": {
+//!     "version": 1, "startTime": "...", "endTime": "...", "duration": 1234567,
+//!     "execution": { "resolvers": [{
+//!         "path": ["notes"], "parentType": "Query", "fieldName": "notes",
+//!         "startOffset": 1234, "duration": 123456
+//!     }] }
+//! } }
+//! ```
+//!
+//! Durations are in nanoseconds. Only resolvers that call `Context::trace`
+//! are timed, which are those that do any work beyond returning a value.
+//! The path of a resolver only has its own field name, and the return type
+//! is not included. Batched requests have the same tracing data in every
+//! response.
+//!
+//! Resolvers are timed with the same `PerfTimer` as the `time!` macro.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::util::time::{unix_millis, PerfTimer};
+
+use super::scalars::DateTime;
+use super::Context;
+
+/// Timing data for a request.
+pub struct Tracer {
+	start: PerfTimer,
+	start_time: u64,
+	resolvers: Mutex<Vec<ResolverTrace>>,
+}
+
+struct ResolverTrace {
+	field: &'static str,
+	start_offset: Duration,
+	duration: Duration,
+}
+
+/// Records the time for a resolver when dropped. See `Context::trace`.
+pub struct TraceGuard<'a> {
+	tracer: Option<&'a Tracer>,
+	field: &'static str,
+	start_offset: Duration,
+	start: PerfTimer,
+}
+
+impl Tracer {
+	pub fn new() -> Tracer {
+		time!(start);
+		Tracer {
+			start,
+			start_time: unix_millis(),
+			resolvers: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Returns the tracing data up to now, for the response extensions.
+	pub fn to_json(&self) -> serde_json::Value {
+		let duration = self.start.elapsed();
+		let time = |millis: u64| {
+			let time = DateTime::from_millis(millis).0;
+			time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+		};
+		let resolvers = self.resolvers.lock().unwrap();
+		let resolvers = resolvers
+			.iter()
+			.map(|resolver| {
+				let mut parts = resolver.field.splitn(2, '.');
+				let parent_type = parts.next().unwrap_or_default();
+				let field_name = parts.next().unwrap_or_default();
+				serde_json::json!({
+					"path": [field_name],
+					"parentType": parent_type,
+					"fieldName": field_name,
+					"startOffset": resolver.start_offset.as_nanos() as u64,
+					"duration": resolver.duration.as_nanos() as u64,
+				})
+			})
+			.collect::<Vec<_>>();
+		serde_json::json!({
+			"version": 1,
+			"startTime": time(self.start_time),
+			"endTime": time(self.start_time + duration.as_millis() as u64),
+			"duration": duration.as_nanos() as u64,
+			"execution": { "resolvers": resolvers },
+		})
+	}
+}
+
+impl Drop for TraceGuard<'_> {
+	fn drop(&mut self) {
+		if let Some(tracer) = self.tracer {
+			tracer.resolvers.lock().unwrap().push(ResolverTrace {
+				field: self.field,
+				start_offset: self.start_offset,
+				duration: self.start.elapsed(),
+			});
+		}
+	}
+}
+
+impl Context {
+	/// Times a resolver until the returned guard is dropped, if tracing is
+	/// enabled. The `field` is the `Type.field` name of the resolver:
+	///
+	/// ```text
+	/// let _trace = context.trace("Query.notes");
+	/// ```
+	pub fn trace(&self, field: &'static str) -> TraceGuard<'_> {
+		let tracer = self.tracer.as_ref();
+		time!(start);
+		TraceGuard {
+			tracer,
+			field,
+			start_offset: tracer
+				.map(|tracer| tracer.start.elapsed())
+				.unwrap_or_default(),
+			start,
+		}
+	}
+}
+
+/// Adds the tracing data to the extensions of a serialized GraphQL response,
+/// which may be a batch.
+pub fn add_tracing(response: String, tracer: &Tracer) -> String {
+	let mut json: serde_json::Value = match serde_json::from_str(&response) {
+		Ok(json) => json,
+		Err(_) => return response,
+	};
+	let tracing = tracer.to_json();
+	let responses = match &mut json {
+		serde_json::Value::Array(responses) => responses.iter_mut().collect(),
+		json => vec![json],
+	};
+	for response in responses {
+		if let Some(response) = response.as_object_mut() {
+			let extensions = response
+				.entry("extensions")
+				.or_insert_with(|| serde_json::json!({}));
+			if let Some(extensions) = extensions.as_object_mut() {
+				extensions.insert("tracing".into(), tracing.clone());
+			}
+		}
+	}
+	json.to_string()
+}
@@ -0,0 +1,205 @@
+//! File uploads with the GraphQL multipart request spec.
+//!
+//! A request with files is sent as `multipart/form-data`, with the GraphQL
+//! request in the `operations` field, followed by a `map` field assigning
+//! each file to the variables it is for, and then the files:
+//!
+//! ```text
+//! operations: { "query": "mutation ($file: Upload!) { ... }", "variables": { "file": null } }
+//! map: { "0": ["variables.file"] }
+//! 0: <file content>
+//! ```
+//!
+//! Files are stored in the blob store while parsing the request, and the
+//! variables replaced by a reference to the file, which resolvers receive as
+//! the `Upload` scalar and claim with `Uploads::take`. Blobs for files that
+//! no resolver claimed are released after the request.
+//!
+//! See https://github.com/jaydenseric/graphql-multipart-request-spec
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Mutex;
+
+use juniper::{InputValue, ParseScalarResult, ParseScalarValue, ScalarToken, ScalarValue, Value};
+use kamipad_data as kd;
+use multipart::server::Multipart;
+
+use crate::app::App;
+
+use super::error::{Error, Result};
+
+/// Maximum size for an uploaded file.
+pub const MAX_UPLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A file uploaded with the request, stored as a blob.
+#[derive(Clone, Debug)]
+pub struct UploadedFile {
+	pub blob: kd::BlobId,
+	pub filename: String,
+	pub content_type: String,
+	pub size: u64,
+}
+
+/// Reference to a file uploaded with the request, given to resolvers as the
+/// `Upload` scalar.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Upload(pub String);
+
+#[juniper::graphql_scalar(
+	name = "Upload",
+	description = "A file uploaded with the request, see the GraphQL multipart request spec."
+)]
+impl<S> GraphQLScalar for Upload
+where
+	S: ScalarValue,
+{
+	fn resolve(&self) -> Value {
+		Value::scalar(self.0.clone())
+	}
+
+	fn from_input_value(v: &InputValue) -> Option<Upload> {
+		v.as_string_value().map(|s| Upload(s.to_string()))
+	}
+
+	fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+		<String as ParseScalarValue<S>>::from_str(value)
+	}
+}
+
+/// Files uploaded with a request.
+#[derive(Default)]
+pub struct Uploads {
+	files: Mutex<HashMap<String, UploadedFile>>,
+}
+
+impl Uploads {
+	/// Claims an uploaded file, which then holds a reference to its blob
+	/// that the caller must keep or release.
+	pub fn take(&self, upload: &Upload) -> Result<UploadedFile> {
+		let mut files = self.files.lock().unwrap();
+		files
+			.remove(&upload.0)
+			.ok_or_else(|| Error::bad_request(format!("no file for upload `{}`", upload.0)))
+	}
+
+	/// Releases the blobs for the files that were not claimed.
+	pub fn release_unused(&self, app: &App) {
+		let mut files = self.files.lock().unwrap();
+		for (_, file) in files.drain() {
+			if let Err(err) = app.database().release_blob(&file.blob) {
+				warn!(
+					app.log,
+					"failed to release unused upload {}: {}", file.blob, err
+				);
+			}
+		}
+	}
+}
+
+/// Parses a multipart GraphQL request, storing the files in the blob store.
+/// Returns the GraphQL request, with the file variables replaced by their
+/// `Upload` reference, and the files.
+pub fn parse_multipart<R: Read>(
+	app: &App,
+	body: R,
+	boundary: &str,
+) -> Result<(serde_json::Value, Uploads)> {
+	let mut multipart = Multipart::with_body(body, boundary);
+	let mut operations = None;
+	let mut map = None;
+	let uploads = Uploads::default();
+	let result = parse_fields(app, &mut multipart, &mut operations, &mut map, &uploads);
+	if let Err(err) = result {
+		uploads.release_unused(app);
+		return Err(err);
+	}
+
+	let mut operations = operations.ok_or_else(|| Error::bad_request("missing `operations`"))?;
+	let map = map.unwrap_or_default();
+	for (key, paths) in map {
+		for path in paths {
+			let slot = value_at(&mut operations, &path);
+			match slot {
+				Some(slot) => *slot = serde_json::Value::String(key.clone()),
+				None => {
+					uploads.release_unused(app);
+					return Err(Error::bad_request(format!(
+						"invalid upload path `{}`",
+						path
+					)));
+				}
+			}
+		}
+	}
+	Ok((operations, uploads))
+}
+
+/// Returns the value at a dot-separated path, such as `variables.file` or
+/// `0.variables.files.1`.
+fn value_at<'a>(value: &'a mut serde_json::Value, path: &str) -> Option<&'a mut serde_json::Value> {
+	let mut value = value;
+	for part in path.split('.') {
+		value = match value {
+			serde_json::Value::Array(items) => items.get_mut(part.parse::<usize>().ok()?)?,
+			serde_json::Value::Object(fields) => fields.get_mut(part)?,
+			_ => return None,
+		};
+	}
+	Some(value)
+}
+
+fn parse_fields<R: Read>(
+	app: &App,
+	multipart: &mut Multipart<R>,
+	operations: &mut Option<serde_json::Value>,
+	map: &mut Option<HashMap<String, Vec<String>>>,
+	uploads: &Uploads,
+) -> Result<()> {
+	let invalid =
+		|err: std::io::Error| Error::bad_request(format!("invalid multipart request: {}", err));
+	while let Some(mut field) = multipart.read_entry().map_err(invalid)? {
+		let name = field.headers.name.to_string();
+		let mut data = Vec::new();
+		let size = (&mut field.data)
+			.take(MAX_UPLOAD_SIZE + 1)
+			.read_to_end(&mut data)
+			.map_err(invalid)?;
+		if size as u64 > MAX_UPLOAD_SIZE {
+			return Err(Error::bad_request(format!("file `{}` is too large", name)));
+		}
+
+		match name.as_str() {
+			"operations" => {
+				let json = serde_json::from_slice(&data)
+					.map_err(|err| Error::bad_request(format!("invalid `operations`: {}", err)))?;
+				*operations = Some(json);
+			}
+			"map" => {
+				let json = serde_json::from_slice(&data)
+					.map_err(|err| Error::bad_request(format!("invalid `map`: {}", err)))?;
+				*map = Some(json);
+			}
+			_ => {
+				let known = map.as_ref().map(|map| map.contains_key(&name));
+				if known != Some(true) {
+					return Err(Error::bad_request(format!("unexpected field `{}`", name)));
+				}
+				let blob = app.database().put_blob(&data)?;
+				let file = UploadedFile {
+					blob,
+					filename: field.headers.filename.clone().unwrap_or_default(),
+					content_type: field
+						.headers
+						.content_type
+						.as_ref()
+						.map(|mime| mime.to_string())
+						.unwrap_or_else(|| "application/octet-stream".to_string()),
+					size: data.len() as u64,
+				};
+				uploads.files.lock().unwrap().insert(name, file);
+			}
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,120 @@
+//! Validation of mutation inputs.
+//!
+//! Inputs implement `Validate` to check all their fields at once, so that a
+//! mutation with invalid input fails with an `INVALID_INPUT` error listing
+//! every invalid field and the reason, which forms can show inline:
+//!
+//! ```text
+//! {
+//!     "message": "invalid input: title",
+//!     "path": ["createNote"],
+//!     "extensions": {
+//!         "code": "INVALID_INPUT",
+//!         "fields": [{ "field": "title", "reason": "must not be empty" }]
+//!     }
+//! }
+//! ```
+//!
+//! Fields are named as in the input object.
+
+use super::error::{Error, Result};
+
+/// An invalid field in an input, with the reason.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidField {
+	pub field: String,
+	pub reason: String,
+}
+
+/// Collects the invalid fields of an input.
+#[derive(Default)]
+pub struct Validator {
+	invalid: Vec<InvalidField>,
+}
+
+impl Validator {
+	pub fn new() -> Validator {
+		Default::default()
+	}
+
+	/// Marks the field as invalid with the reason, unless `valid`.
+	pub fn check<S: Into<String>>(&mut self, field: &str, valid: bool, reason: S) {
+		if !valid {
+			self.invalid.push(InvalidField {
+				field: field.to_string(),
+				reason: reason.into(),
+			});
+		}
+	}
+
+	/// Fails with `INVALID_INPUT` if any field is invalid.
+	pub fn finish(self) -> Result<()> {
+		if self.invalid.is_empty() {
+			Ok(())
+		} else {
+			Err(Error::invalid_input(self.invalid))
+		}
+	}
+}
+
+/// Input that can be validated before a mutation.
+pub trait Validate {
+	/// Checks each field of the input.
+	fn validate(&self, validator: &mut Validator);
+}
+
+/// Validates an input, failing with `INVALID_INPUT` if any field is invalid.
+pub fn validate<T: Validate>(input: &T) -> Result<()> {
+	let mut validator = Validator::new();
+	input.validate(&mut validator);
+	validator.finish()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::graph::error::ErrorCode;
+
+	struct Input {
+		name: String,
+		age: i32,
+	}
+
+	impl Validate for Input {
+		fn validate(&self, validator: &mut Validator) {
+			validator.check("name", !self.name.is_empty(), "must not be empty");
+			validator.check("age", self.age >= 0, "must not be negative");
+		}
+	}
+
+	#[test]
+	fn test_validate() {
+		let input = Input {
+			name: "name".into(),
+			age: 1,
+		};
+		assert!(validate(&input).is_ok());
+
+		let input = Input {
+			name: "".into(),
+			age: -1,
+		};
+		let err = validate(&input).unwrap_err();
+		assert_eq!(err.code, ErrorCode::InvalidInput);
+		assert_eq!(err.message, "invalid input: name, age");
+		assert_eq!(
+			err.fields,
+			[
+				InvalidField {
+					field: "name".into(),
+					reason: "must not be empty".into(),
+				},
+				InvalidField {
+					field: "age".into(),
+					reason: "must not be negative".into(),
+				},
+			]
+		);
+	}
+}
@@ -0,0 +1,339 @@
+//! Import of notes from an uploaded file.
+//!
+//! Two formats are supported:
+//!
+//! - A JSON Lines file, either an export of the database, of which only the
+//!   records in the `notes` collection are imported, or one note per line as
+//!   `{"title": "...", "text": "...", "tags": [...]}`;
+//! - A zip archive with a folder of markdown files. The title of each note
+//!   is the first `# ` heading, or the file name without one, and the note
+//!   is tagged with the name of its folder, if it is a valid tag.
+//!
+//! Imports run in a background thread, as an `ImportJob` that can be polled
+//! by its ID with `App::import_job` while it runs, and until `JOB_TTL` after
+//! it finishes. Each item in the file is imported separately, so that an
+//! invalid item doesn't fail the whole import.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::util::time::unix_millis;
+use crate::util::{Error, Result};
+
+/// Time an import job is kept after it finishes.
+pub const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum size of a single imported note, in bytes.
+pub const MAX_NOTE_SIZE: u64 = 1024 * 1024;
+
+/// Import jobs, by ID.
+pub type ImportJobs = Mutex<HashMap<kd::ID, ImportJob>>;
+
+/// State of an import or export job.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum JobStatus {
+	Running,
+	Done,
+	/// The job failed, such as when the imported file could not be read.
+	/// Items imported before the failure are kept.
+	Failed,
+}
+
+/// Result of importing an item from the file.
+#[derive(Clone, Debug)]
+pub struct ImportItem {
+	/// Line number or file name of the item.
+	pub name: String,
+	/// ID of the created note, if imported.
+	pub note: Option<kd::ID>,
+	/// Reason the item was not imported.
+	pub error: Option<String>,
+}
+
+/// An import running in the background, see `App::import_notes`.
+#[derive(Clone, Debug)]
+pub struct ImportJob {
+	pub id: kd::ID,
+	pub status: JobStatus,
+	/// Name of the imported file.
+	pub filename: String,
+	/// Number of items in the file, once read.
+	pub total: usize,
+	/// Results for the items processed so far, in order.
+	pub items: Vec<ImportItem>,
+	/// Reason the job failed.
+	pub error: Option<String>,
+	/// Start and end time, in milliseconds since the UNIX epoch.
+	pub started: u64,
+	pub finished: Option<u64>,
+}
+
+/// A note read from the imported file.
+#[derive(Debug, Default, Deserialize)]
+struct ImportedNote {
+	title: String,
+	#[serde(default)]
+	text: String,
+	#[serde(default)]
+	tags: Vec<String>,
+	#[serde(default)]
+	metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+impl App {
+	/// Starts importing notes from a blob with the content of the named file,
+	/// taking over a reference to the blob from the caller. Returns the job,
+	/// which runs in the background.
+	pub fn import_notes(&'static self, blob: kd::BlobId, filename: String) -> ImportJob {
+		let job = ImportJob {
+			id: kd::ID::new_sortable(),
+			status: JobStatus::Running,
+			filename,
+			total: 0,
+			items: Vec::new(),
+			error: None,
+			started: unix_millis(),
+			finished: None,
+		};
+		{
+			let mut jobs = self.import_jobs.lock().unwrap();
+			let expired = unix_millis().saturating_sub(JOB_TTL.as_millis() as u64);
+			jobs.retain(|_, job| job.finished.map_or(true, |time| time > expired));
+			jobs.insert(job.id, job.clone());
+		}
+
+		let (id, filename) = (job.id, job.filename.clone());
+		std::thread::spawn(move || {
+			info!(self.log, "importing notes from {}", filename; "job" => %id);
+			let result = self.run_import(&id, &blob, &filename);
+			if let Err(err) = self.database().release_blob(&blob) {
+				warn!(self.log, "failed to release import {}: {}", blob, err);
+			}
+			self.update_import(&id, |job| {
+				job.finished = Some(unix_millis());
+				match result {
+					Ok(()) => job.status = JobStatus::Done,
+					Err(err) => {
+						job.status = JobStatus::Failed;
+						job.error = Some(err.to_string());
+					}
+				}
+			});
+			info!(self.log, "finished importing notes from {}", filename; "job" => %id);
+		});
+		job
+	}
+
+	/// Returns an import job by its ID.
+	pub fn import_job(&self, id: &kd::ID) -> Option<ImportJob> {
+		self.import_jobs.lock().unwrap().get(id).cloned()
+	}
+
+	fn run_import(&self, id: &kd::ID, blob: &kd::BlobId, filename: &str) -> Result<()> {
+		let data = self
+			.database()
+			.get_blob(blob)?
+			.ok_or_else(|| Error::from("uploaded file not found"))?;
+		let notes = if is_zip(filename, &data) {
+			read_markdown_zip(data)?
+		} else {
+			read_jsonl(&data)?
+		};
+		self.update_import(id, |job| job.total = notes.len());
+		for (name, note) in notes {
+			let item = match note.and_then(|note| self.import_note(note)) {
+				Ok(note) => ImportItem {
+					name,
+					note: Some(note),
+					error: None,
+				},
+				Err(err) => ImportItem {
+					name,
+					note: None,
+					error: Some(err.to_string()),
+				},
+			};
+			self.update_import(id, |job| job.items.push(item));
+		}
+		Ok(())
+	}
+
+	fn import_note(&self, note: ImportedNote) -> Result<kd::ID> {
+		if note.title.trim().is_empty() {
+			return Err(Error::from("note has no title"));
+		}
+		if let Some(tag) = note.tags.iter().find(|tag| !kd::is_valid_name(tag)) {
+			return Err(Error::from(format!("invalid tag `{}`", tag)));
+		}
+		let created = self.create_note(note.title, note.text, note.metadata)?;
+		for tag in note.tags {
+			self.tag_note(&created.id, &tag)?;
+		}
+		Ok(created.id)
+	}
+
+	fn update_import<F: FnOnce(&mut ImportJob)>(&self, id: &kd::ID, update: F) {
+		if let Some(job) = self.import_jobs.lock().unwrap().get_mut(id) {
+			update(job);
+		}
+	}
+}
+
+/// Returns true if the file is a zip archive, by its name or content.
+fn is_zip(filename: &str, data: &[u8]) -> bool {
+	filename.to_lowercase().ends_with(".zip") || data.starts_with(b"PK\x03\x04")
+}
+
+/// Reads the notes from a JSON Lines file, by line number.
+fn read_jsonl(data: &[u8]) -> Result<Vec<(String, Result<ImportedNote>)>> {
+	let text = std::str::from_utf8(data).map_err(|_| Error::from("file is not valid UTF-8"))?;
+	let mut notes = Vec::new();
+	for (index, line) in text.lines().enumerate() {
+		if line.trim().is_empty() {
+			continue;
+		}
+		let name = format!("line {}", index + 1);
+		let value = match serde_json::from_str::<serde_json::Value>(line) {
+			Ok(value) => value,
+			Err(err) => {
+				notes.push((name, Err(err.into())));
+				continue;
+			}
+		};
+		// Database exports have a line for each record and blob.
+		let note = match value.get("collection") {
+			Some(collection) if collection == crate::notes::NOTES_COLLECTION => {
+				match value.get("value").and_then(|value| value.as_str()) {
+					Some(value) => serde_json::from_str(value).map_err(Error::from),
+					None => Err(Error::from("note record has no value")),
+				}
+			}
+			Some(_) => continue,
+			None if value.get("blob").is_some() => continue,
+			None => serde_json::from_value(value).map_err(Error::from),
+		};
+		notes.push((name, note));
+	}
+	Ok(notes)
+}
+
+/// Reads the notes from a zip archive of markdown files, by file name.
+fn read_markdown_zip(data: Vec<u8>) -> Result<Vec<(String, Result<ImportedNote>)>> {
+	let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(Error::from)?;
+	let mut notes = Vec::new();
+	for index in 0..archive.len() {
+		let file = archive.by_index(index).map_err(Error::from)?;
+		let name = file.name().to_string();
+		let path = Path::new(&name);
+		let is_markdown = path
+			.extension()
+			.and_then(|ext| ext.to_str())
+			.map_or(false, |ext| ext == "md" || ext == "markdown");
+		if file.is_dir() || !is_markdown || name.starts_with("__MACOSX/") {
+			continue;
+		}
+		if file.size() > MAX_NOTE_SIZE {
+			notes.push((name, Err(Error::from("file is too large"))));
+			continue;
+		}
+		let mut text = String::new();
+		let note = match file.take(MAX_NOTE_SIZE).read_to_string(&mut text) {
+			Ok(_) => Ok(parse_markdown(path, text)),
+			Err(err) => Err(err.into()),
+		};
+		notes.push((name, note));
+	}
+	Ok(notes)
+}
+
+/// Returns the note for a markdown file.
+fn parse_markdown(path: &Path, text: String) -> ImportedNote {
+	let heading = text
+		.lines()
+		.enumerate()
+		.find(|(_, line)| !line.trim().is_empty())
+		.filter(|(_, line)| line.starts_with("# "));
+	let (title, text) = match heading {
+		Some((index, line)) => {
+			let rest = text.lines().skip(index + 1).collect::<Vec<_>>();
+			(
+				line[2..].trim().to_string(),
+				rest.join("\n").trim().to_string(),
+			)
+		}
+		None => {
+			let stem = path.file_stem().and_then(|stem| stem.to_str());
+			(stem.unwrap_or_default().to_string(), text)
+		}
+	};
+	let folder = path
+		.parent()
+		.and_then(|parent| parent.file_name())
+		.and_then(|name| name.to_str())
+		.map(|name| name.to_lowercase());
+	ImportedNote {
+		title,
+		text,
+		tags: folder
+			.into_iter()
+			.filter(|tag| kd::is_valid_name(tag))
+			.collect(),
+		..Default::default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_read_jsonl() {
+		let data = concat!(
+			r#"{"collection":"notes","key":"a","value":"{\"title\":\"A\",\"text\":\"a\"}"}"#,
+			"\n",
+			r#"{"collection":"users","key":"b","value":"{}"}"#,
+			"\n\n",
+			r#"{"title":"C","tags":["x"]}"#,
+			"\n",
+			r#"{"text":"no title"}"#,
+			"\n",
+			"not json\n",
+		);
+		let notes = read_jsonl(data.as_bytes()).unwrap();
+		let names = notes
+			.iter()
+			.map(|(name, _)| name.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(names, ["line 1", "line 4", "line 5", "line 6"]);
+
+		let note = notes[0].1.as_ref().unwrap();
+		assert_eq!((note.title.as_str(), note.text.as_str()), ("A", "a"));
+		let note = notes[1].1.as_ref().unwrap();
+		assert_eq!(note.title, "C");
+		assert_eq!(note.tags, ["x"]);
+		assert!(notes[2].1.is_err());
+		assert!(notes[3].1.is_err());
+	}
+
+	#[test]
+	fn test_parse_markdown() {
+		let note = parse_markdown(
+			Path::new("notes/Ideas/list.md"),
+			"\n# The list\n\nSome text\n".into(),
+		);
+		assert_eq!(note.title, "The list");
+		assert_eq!(note.text, "Some text");
+		assert_eq!(note.tags, ["ideas"]);
+
+		let note = parse_markdown(Path::new("list.md"), "Some text".into());
+		assert_eq!(note.title, "list");
+		assert_eq!(note.text, "Some text");
+		assert!(note.tags.is_empty());
+	}
+}
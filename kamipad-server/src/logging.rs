@@ -3,14 +3,20 @@
 use slog::*;
 use std::collections::HashMap;
 use std::collections::LinkedList;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::RawStr;
+use rocket::http::uri::Origin;
+use rocket::http::{Method, RawStr};
 use rocket::request::{FromFormValue, FromParam, FromRequest, Outcome, State};
 use rocket::{Data, Request, Response};
 
 use crate::app::App;
+use crate::metrics;
+use crate::server;
 use crate::util;
 
 /// Wrapper for a [slog::Logger] that can be used as a `rocket` request
@@ -153,6 +159,14 @@ impl Fairing for ServerLogger {
 		};
 
 		request.local_cache(|| request_id);
+		app.request_started();
+
+		// Fairings can't respond to a request, so requests during shutdown are
+		// routed to one that fails.
+		if app.is_shutting_down() {
+			request.set_method(Method::Get);
+			request.set_uri(Origin::parse(server::UNAVAILABLE_URI).unwrap());
+		}
 
 		// Create a logger for the request
 		let (logger, store) = app.request_log(o!("client" => client, "target" => target));
@@ -181,6 +195,17 @@ impl Fairing for ServerLogger {
 		// Store log entries by the request id:
 
 		let app: State<&'static App> = request.guard::<State<&App>>().unwrap();
+		app.request_finished();
+
+		if t_request != t_none {
+			let route = request.route().map(|route| route.uri.path());
+			app.metrics.record_request(
+				request.method().as_str(),
+				route.unwrap_or(metrics::UNMATCHED_ROUTE),
+				response.status().code,
+				t_request.elapsed(),
+			);
+		}
 
 		let entries = request.local_cache(|| RequestLogStore::new());
 		let entries = entries.iter().into_iter().cloned().collect::<Vec<_>>();
@@ -244,6 +269,8 @@ pub struct LogEntry {
 	pub level: Level,
 
 	pub msg: String,
+	/// Time of the entry, in milliseconds since the UNIX epoch.
+	pub time: u64,
 	pub line: u32,
 	pub column: u32,
 	pub file: &'static str,
@@ -261,6 +288,7 @@ impl LogEntry {
 		let mut entry = LogEntry {
 			level: record.level(),
 			msg: format!("{}", record.msg()),
+			time: util::time::unix_millis(),
 			line: record.line(),
 			column: record.column(),
 			file: record.file(),
@@ -357,10 +385,16 @@ impl<'a, 'b: 'a, T: 'a> IntoIterator for &'b RequestLogStoreIter<'a, T> {
 }
 
 /// Implement a ring logger drain that keeps the last N entries.
+///
+/// New entries are also sent to the subscribers, see `subscribe`.
 #[derive(Clone)]
 pub struct RingLogger {
 	keep_n: usize,
 	entries: Arc<Mutex<LinkedList<LogEntry>>>,
+	// `None` once the subscriptions are closed, see `close_subscriptions`.
+	subscribers: Arc<Mutex<Option<Vec<UnboundedSender<LogEntry>>>>>,
+	// Number of entries ever logged, by level from `Critical` to `Trace`.
+	counts: Arc<[AtomicU64; 6]>,
 }
 
 impl RingLogger {
@@ -368,9 +402,42 @@ impl RingLogger {
 		RingLogger {
 			keep_n: keep_n,
 			entries: Default::default(),
+			subscribers: Arc::new(Mutex::new(Some(Vec::new()))),
+			counts: Default::default(),
 		}
 	}
 
+	/// Returns the number of entries ever logged for each level, including
+	/// the ones no longer kept.
+	pub fn counts(&self) -> Vec<(Level, u64)> {
+		(1..=self.counts.len())
+			.filter_map(|n| {
+				Some((
+					Level::from_usize(n)?,
+					self.counts[n - 1].load(Ordering::Relaxed),
+				))
+			})
+			.collect()
+	}
+
+	/// Returns a receiver for the entries logged from now on. The subscriber
+	/// is removed once the receiver is dropped.
+	///
+	/// After `close_subscriptions` the receiver ends right away.
+	pub fn subscribe(&self) -> UnboundedReceiver<LogEntry> {
+		let (sender, receiver) = futures::channel::mpsc::unbounded();
+		if let Some(subscribers) = self.subscribers.lock().unwrap().as_mut() {
+			subscribers.push(sender);
+		}
+		receiver
+	}
+
+	/// Drops the senders for all the subscribers, ending their receivers,
+	/// and refuses any new subscriptions.
+	pub fn close_subscriptions(&self) {
+		self.subscribers.lock().unwrap().take();
+	}
+
 	/// Returns a copy of all entries current in the logger.
 	pub fn entries(&self) -> Vec<LogEntry> {
 		let mut out = Vec::new();
@@ -382,7 +449,13 @@ impl RingLogger {
 	}
 
 	fn push(&self, record: &Record, values: &OwnedKVList) {
+		self.counts[record.level().as_usize() - 1].fetch_add(1, Ordering::Relaxed);
 		let entry = LogEntry::from_record(record, values);
+		{
+			if let Some(subscribers) = self.subscribers.lock().unwrap().as_mut() {
+				subscribers.retain(|sender| sender.unbounded_send(entry.clone()).is_ok());
+			}
+		}
 		let mut entries = self.entries.lock().unwrap();
 		entries.push_back(entry);
 		if self.keep_n > 0 {
@@ -16,37 +16,104 @@ extern crate rocket;
 #[macro_use]
 mod util;
 
+mod api_version;
 mod app;
+mod attachments;
+mod auth;
+mod cli;
 mod common;
+mod compression;
+mod config;
+mod cors;
+mod etag;
+mod export;
 mod graph;
+mod import;
 mod logging;
+mod metrics;
+mod notes;
+mod openapi;
+mod rate_limit;
+mod reminders;
+mod searches;
 mod server;
+mod status;
+mod templates;
+mod timeout;
+
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+/// Time for the requests being handled to finish when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() {
+	let options = cli::Options::from_args();
+
+	// Prints the GraphQL schema for code generation in the frontend build,
+	// without starting the server.
+	if options.print_schema {
+		print!("{}", graph::schema_sdl());
+		return;
+	}
+
 	let mut rt = tokio::runtime::Runtime::new().unwrap();
-	let exit_code = rt.block_on(run());
+	let exit_code = rt.block_on(run(options));
 	std::process::exit(exit_code);
 }
 
-async fn run() -> i32 {
+async fn run(options: cli::Options) -> i32 {
 	println!("\nStarting Kamipad server - v{}...\n", common::VERSION);
 
-	let (mut tx, mut rx) = tokio::sync::mpsc::channel::<i32>(16);
+	let mut config = match config::Config::load(options.config.clone()) {
+		Ok(config) => config,
+		Err(err) => {
+			eprintln!("Failed to load the configuration: {}", err);
+			return 1;
+		}
+	};
+	options.apply(&mut config);
+
+	let app = app::App::init(config);
+	let (tx, mut rx) = tokio::sync::mpsc::channel::<i32>(16);
 
+	let mut signal_tx = tx.clone();
 	tokio::spawn(async move {
 		if let Ok(_) = tokio::signal::ctrl_c().await {
 			println!("\nReceived interrupt signal...\n");
-			tx.send(0).await.ok();
+			signal_tx.send(0).await.ok();
 		}
 	});
 
-	tokio::spawn(async {
-		let app = app::App::get();
-		server::launch(app);
-	});
+	// Rocket 0.4 blocks the thread serving requests, so it gets its own
+	// instead of a runtime worker.
+	let mut launch_tx = tx;
+	let runtime = tokio::runtime::Handle::current();
+	std::thread::Builder::new()
+		.name("rocket".to_string())
+		.spawn(move || {
+			server::launch(app, runtime);
+			launch_tx.try_send(1).ok();
+		})
+		.expect("failed to start the server thread");
 
-	let exit_code = rx.recv().await;
+	let mut exit_code = rx.recv().await.unwrap_or(0);
+	println!("Shutting down...");
+	let shutdown = tokio::task::spawn_blocking(move || app.shutdown(SHUTDOWN_TIMEOUT));
+	match shutdown.await {
+		Ok(Ok(true)) => (),
+		Ok(Ok(false)) => exit_code = 1,
+		Ok(Err(err)) => {
+			eprintln!("Failed to flush the database: {}", err);
+			exit_code = 1;
+		}
+		Err(err) => {
+			eprintln!("Failed to shut down: {}", err);
+			exit_code = 1;
+		}
+	}
 	println!("Shutdown complete!");
 
-	exit_code.unwrap_or(0)
+	exit_code
 }
@@ -0,0 +1,317 @@
+//! Metrics for monitoring, in the Prometheus text format.
+//!
+//! The `/metrics` route exports:
+//!
+//! - `kamipad_requests_total` and `kamipad_request_duration_seconds`, by
+//!   method and route, counted by `ServerLogger` (see `logging`);
+//! - the hits, misses, entries and hit ratio of each cache in the `CacheMap`;
+//! - the number of application log entries by level;
+//! - the database statistics from `kd::Database::stats`.
+//!
+//! Requests that match no route are counted with the `unmatched` route, so
+//! that made up paths don't add labels.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::util::{CacheStats, Result};
+
+/// Upper bounds in seconds of the buckets for the request durations.
+pub const LATENCY_BUCKETS: &[f64] = &[
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Route label for requests that match no route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Request metrics, by method and route.
+#[derive(Default)]
+pub struct Metrics {
+	routes: Mutex<BTreeMap<(String, String), RouteMetrics>>,
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+	/// Number of requests by status code.
+	statuses: BTreeMap<u16, u64>,
+	/// Number of requests in each bucket of `LATENCY_BUCKETS`, not
+	/// cumulative.
+	buckets: [u64; LATENCY_BUCKETS.len()],
+	/// Total duration in seconds, and number of requests.
+	sum: f64,
+	count: u64,
+}
+
+impl Metrics {
+	/// Counts a finished request.
+	pub fn record_request(&self, method: &str, route: &str, status: u16, duration: Duration) {
+		let seconds = duration.as_secs_f64();
+		let mut routes = self.routes.lock().unwrap();
+		let metrics = routes
+			.entry((method.to_string(), route.to_string()))
+			.or_default();
+		*metrics.statuses.entry(status).or_default() += 1;
+		if let Some(index) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+			metrics.buckets[index] += 1;
+		}
+		metrics.sum += seconds;
+		metrics.count += 1;
+	}
+
+	/// Writes the request metrics.
+	fn write(&self, out: &mut String) -> std::fmt::Result {
+		let routes = self.routes.lock().unwrap();
+
+		header(
+			out,
+			"kamipad_requests_total",
+			"counter",
+			"Number of requests handled.",
+		)?;
+		for ((method, route), metrics) in routes.iter() {
+			for (status, count) in &metrics.statuses {
+				let labels = [
+					("method", method.as_str()),
+					("route", route),
+					("status", &status.to_string()),
+				];
+				sample(out, "kamipad_requests_total", &labels, *count as f64)?;
+			}
+		}
+
+		let name = "kamipad_request_duration_seconds";
+		header(out, name, "histogram", "Time to handle the requests.")?;
+		for ((method, route), metrics) in routes.iter() {
+			let mut cumulative = 0;
+			for (le, count) in LATENCY_BUCKETS.iter().zip(&metrics.buckets) {
+				cumulative += count;
+				let labels = [
+					("method", method.as_str()),
+					("route", route),
+					("le", &le.to_string()),
+				];
+				sample(out, &format!("{}_bucket", name), &labels, cumulative as f64)?;
+			}
+			let labels = [
+				("method", method.as_str()),
+				("route", route),
+				("le", "+Inf"),
+			];
+			sample(
+				out,
+				&format!("{}_bucket", name),
+				&labels,
+				metrics.count as f64,
+			)?;
+			let labels = [("method", method.as_str()), ("route", route)];
+			sample(out, &format!("{}_sum", name), &labels, metrics.sum)?;
+			sample(
+				out,
+				&format!("{}_count", name),
+				&labels,
+				metrics.count as f64,
+			)?;
+		}
+		Ok(())
+	}
+}
+
+impl App {
+	/// Returns all metrics in the Prometheus text format.
+	pub fn metrics_text(&self) -> Result<String> {
+		let mut out = String::new();
+		self.metrics.write(&mut out)?;
+		write_caches(&mut out, &self.cache_map.stats())?;
+		write_log_counts(&mut out, &self.log_counts())?;
+		write_database(&mut out, &self.database().stats()?)?;
+		Ok(out)
+	}
+}
+
+fn write_caches(out: &mut String, caches: &[(String, CacheStats)]) -> std::fmt::Result {
+	let metrics: &[(&str, &str, &str, fn(&CacheStats) -> f64)] = &[
+		(
+			"kamipad_cache_hits_total",
+			"counter",
+			"Number of cache lookups that found an entry.",
+			|it| it.hits as f64,
+		),
+		(
+			"kamipad_cache_misses_total",
+			"counter",
+			"Number of cache lookups that found no entry.",
+			|it| it.misses as f64,
+		),
+		(
+			"kamipad_cache_entries",
+			"gauge",
+			"Number of entries in the cache.",
+			|it| it.entries as f64,
+		),
+		(
+			"kamipad_cache_hit_ratio",
+			"gauge",
+			"Ratio of cache lookups that found an entry.",
+			|it| {
+				let lookups = it.hits + it.misses;
+				if lookups == 0 {
+					0.0
+				} else {
+					it.hits as f64 / lookups as f64
+				}
+			},
+		),
+	];
+	for (name, kind, help, value) in metrics {
+		header(out, name, kind, help)?;
+		for (cache, stats) in caches {
+			sample(out, name, &[("cache", cache)], value(stats))?;
+		}
+	}
+	Ok(())
+}
+
+fn write_log_counts(out: &mut String, counts: &[(slog::Level, u64)]) -> std::fmt::Result {
+	let name = "kamipad_log_entries_total";
+	header(
+		out,
+		name,
+		"counter",
+		"Number of application log entries, by level.",
+	)?;
+	for (level, count) in counts {
+		let level = level.as_str().to_lowercase();
+		sample(out, name, &[("level", &level)], *count as f64)?;
+	}
+	Ok(())
+}
+
+fn write_database(out: &mut String, stats: &kd::DatabaseStats) -> std::fmt::Result {
+	let metrics = [
+		(
+			"kamipad_database_collections",
+			"Number of collections in the database.",
+			stats.collections as f64,
+		),
+		(
+			"kamipad_database_records",
+			"Number of records in the database.",
+			stats.usage.records as f64,
+		),
+		(
+			"kamipad_database_bytes",
+			"Size in bytes of the record and blob files.",
+			stats.usage.bytes as f64,
+		),
+		(
+			"kamipad_database_sequence",
+			"Sequence number of the last change.",
+			stats.sequence as f64,
+		),
+		(
+			"kamipad_database_read_only",
+			"1 if the database is open read-only.",
+			if stats.read_only { 1.0 } else { 0.0 },
+		),
+	];
+	for (name, help, value) in &metrics {
+		header(out, name, "gauge", help)?;
+		sample(out, name, &[], *value)?;
+	}
+	Ok(())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) -> std::fmt::Result {
+	writeln!(out, "# HELP {} {}", name, help)?;
+	writeln!(out, "# TYPE {} {}", name, kind)
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) -> std::fmt::Result {
+	out.push_str(name);
+	if !labels.is_empty() {
+		let labels = labels
+			.iter()
+			.map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+			.collect::<Vec<_>>();
+		write!(out, "{{{}}}", labels.join(","))?;
+	}
+	writeln!(out, " {}", value)
+}
+
+fn escape_label(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_request_metrics() {
+		let metrics = Metrics::default();
+		let ms = Duration::from_millis;
+		metrics.record_request("POST", "/api/graphql", 200, ms(3));
+		metrics.record_request("POST", "/api/graphql", 200, ms(30));
+		metrics.record_request("POST", "/api/graphql", 400, ms(20_000));
+
+		let mut out = String::new();
+		metrics.write(&mut out).unwrap();
+		let lines = out.lines().collect::<Vec<_>>();
+		let labels = "method=\"POST\",route=\"/api/graphql\"";
+		assert!(lines.contains(&"# TYPE kamipad_requests_total counter"));
+		assert!(lines
+			.contains(&format!("kamipad_requests_total{{{},status=\"200\"}} 2", labels).as_str()));
+		assert!(lines
+			.contains(&format!("kamipad_requests_total{{{},status=\"400\"}} 1", labels).as_str()));
+
+		let bucket = |le: &str, count: u64| {
+			format!(
+				"kamipad_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+				labels, le, count
+			)
+		};
+		assert!(lines.contains(&bucket("0.005", 1).as_str()));
+		assert!(lines.contains(&bucket("0.025", 1).as_str()));
+		assert!(lines.contains(&bucket("0.05", 2).as_str()));
+		assert!(lines.contains(&bucket("10", 2).as_str()));
+		assert!(lines.contains(&bucket("+Inf", 3).as_str()));
+		assert!(lines
+			.contains(&format!("kamipad_request_duration_seconds_count{{{}}} 3", labels).as_str()));
+	}
+
+	#[test]
+	fn test_write_caches() {
+		let stats = CacheStats {
+			entries: 2,
+			hits: 3,
+			misses: 1,
+		};
+		let mut out = String::new();
+		write_caches(&mut out, &[("u32/\"u32\"".to_string(), stats)]).unwrap();
+		let lines = out.lines().collect::<Vec<_>>();
+		assert!(lines.contains(&"kamipad_cache_hits_total{cache=\"u32/\\\"u32\\\"\"} 3"));
+		assert!(lines.contains(&"kamipad_cache_entries{cache=\"u32/\\\"u32\\\"\"} 2"));
+		assert!(lines.contains(&"kamipad_cache_hit_ratio{cache=\"u32/\\\"u32\\\"\"} 0.75"));
+	}
+
+	#[test]
+	fn test_write_log_counts() {
+		let mut out = String::new();
+		write_log_counts(
+			&mut out,
+			&[(slog::Level::Error, 4), (slog::Level::Warning, 0)],
+		)
+		.unwrap();
+		let lines = out.lines().collect::<Vec<_>>();
+		assert!(lines.contains(&"kamipad_log_entries_total{level=\"error\"} 4"));
+		assert!(lines.contains(&"kamipad_log_entries_total{level=\"warn\"} 0"));
+	}
+}
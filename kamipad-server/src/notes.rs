@@ -0,0 +1,456 @@
+//! Notes stored in the application database.
+//!
+//! Each note is a JSON record in the `notes` collection, keyed by its ID.
+//!
+//! Notes link to other notes with the record links of the database, so a
+//! note can't be deleted while other notes link to it.
+//!
+//! Prior versions of a note are kept in the revision history of the
+//! database, up to `app::MAX_REVISIONS`.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::reminders::Reminder;
+use crate::util::time::unix_millis;
+use crate::util::{Error, Result};
+
+/// Collection for the notes in the database.
+pub const NOTES_COLLECTION: &'static str = "notes";
+
+/// A single note.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Note {
+	#[serde(skip, default = "kd::ID::nil")]
+	pub id: kd::ID,
+	pub title: String,
+	pub text: String,
+	/// Creation time in milliseconds since the UNIX epoch.
+	pub created: u64,
+	/// Last update time in milliseconds since the UNIX epoch.
+	pub updated: u64,
+	/// Free-form metadata for clients.
+	#[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+	pub metadata: serde_json::Map<String, serde_json::Value>,
+	/// Reminder for the note, see `reminders`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub reminder: Option<Reminder>,
+	/// Archived notes are kept, but hidden by clients.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub archived: bool,
+}
+
+/// Notes connected by links, from `App::link_graph`.
+pub struct NoteGraph {
+	pub notes: Vec<Note>,
+	/// Links between the notes, as `(from, to)`.
+	pub links: Vec<(kd::ID, kd::ID)>,
+}
+
+/// Changes to apply to a note. Fields that are `None` are not changed.
+#[derive(Clone, Debug, Default)]
+pub struct NoteChanges {
+	pub title: Option<String>,
+	pub text: Option<String>,
+	/// Replaces the metadata.
+	pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Changes to apply to many notes with `App::bulk_update_notes`.
+#[derive(Clone, Debug, Default)]
+pub struct NotePatch {
+	pub add_tags: Vec<String>,
+	pub remove_tags: Vec<String>,
+	/// Archives or unarchives the notes, if set.
+	pub archived: Option<bool>,
+}
+
+impl App {
+	/// Returns a note by its ID.
+	pub fn note(&self, id: &kd::ID) -> Result<Option<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		match notes.get(&id.to_string())? {
+			Some(data) => Ok(Some(parse_note(id, &data)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Returns the notes with the given IDs. Notes that don't exist are left
+	/// out of the result.
+	pub fn notes_by_id(&self, ids: &[kd::ID]) -> Result<HashMap<kd::ID, Note>> {
+		let mut notes = HashMap::new();
+		for id in ids {
+			if let Some(note) = self.note(id)? {
+				notes.insert(*id, note);
+			}
+		}
+		Ok(notes)
+	}
+
+	/// Returns the tags for the notes with the given IDs.
+	pub fn note_tags(&self, ids: &[kd::ID]) -> Result<HashMap<kd::ID, Vec<String>>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let keys = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+		let mut tags = notes.tags_of(&keys)?;
+		Ok(ids
+			.iter()
+			.zip(keys)
+			.map(|(id, key)| (*id, tags.remove(&key).unwrap_or_default()))
+			.collect())
+	}
+
+	/// Returns all notes, most recently created first.
+	pub fn notes(&self) -> Result<Vec<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		self.read_notes(notes.keys()?)
+	}
+
+	/// Returns the notes with a tag, most recently created first.
+	pub fn notes_by_tag(&self, tag: &str) -> Result<Vec<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		self.read_notes(notes.find_by_tag(tag)?)
+	}
+
+	/// Returns up to `limit` notes matching a full-text search, best matches
+	/// first, with the search hit for each.
+	pub fn search_notes(&self, query: &str, limit: usize) -> Result<Vec<(Note, kd::SearchHit)>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let mut result = Vec::new();
+		for hit in notes.search(query, limit)? {
+			let id = match kd::ID::parse(&hit.key) {
+				Some(id) => id,
+				None => continue,
+			};
+			if let Some(data) = notes.get(&hit.key)? {
+				result.push((parse_note(&id, &data)?, hit));
+			}
+		}
+		Ok(result)
+	}
+
+	/// Returns all tags used by notes, sorted, with the number of notes with
+	/// each tag.
+	pub fn tags(&self) -> Result<Vec<(String, usize)>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		Ok(notes.tag_counts()?)
+	}
+
+	/// Adds a tag to a note. Returns `None` if the note doesn't exist.
+	pub fn tag_note(&self, id: &kd::ID, tag: &str) -> Result<Option<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		match notes.tag(&id.to_string(), tag) {
+			Ok(_) => self.note(id),
+			Err(kd::Error::NotFound(_)) => Ok(None),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	/// Removes a tag from a note. Returns `None` if the note doesn't exist.
+	pub fn untag_note(&self, id: &kd::ID, tag: &str) -> Result<Option<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		notes.untag(&id.to_string(), tag)?;
+		self.note(id)
+	}
+
+	/// Adds a link from a note to another. Returns `None` if either note
+	/// doesn't exist.
+	pub fn link_note(&self, id: &kd::ID, to: &kd::ID) -> Result<Option<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let target = kd::RecordId::new(NOTES_COLLECTION, to.to_string());
+		match notes.link(&id.to_string(), &target) {
+			Ok(_) => self.note(id),
+			Err(kd::Error::NotFound(_)) => Ok(None),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	/// Removes a link from a note to another. Returns `None` if the note
+	/// doesn't exist.
+	pub fn unlink_note(&self, id: &kd::ID, to: &kd::ID) -> Result<Option<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let target = kd::RecordId::new(NOTES_COLLECTION, to.to_string());
+		notes.unlink(&id.to_string(), &target)?;
+		self.note(id)
+	}
+
+	/// Returns the notes linked from a note, most recently created first.
+	pub fn note_links(&self, id: &kd::ID) -> Result<Vec<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		self.read_notes(note_keys(notes.links(&id.to_string())?))
+	}
+
+	/// Returns the notes linking to a note, most recently created first.
+	pub fn note_backlinks(&self, id: &kd::ID) -> Result<Vec<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		self.read_notes(note_keys(notes.backlinks(&id.to_string())?))
+	}
+
+	/// Returns the notes connected to a note by links in either direction, up
+	/// to `depth` links away, along with the links between them. Returns
+	/// `None` if the note doesn't exist.
+	pub fn link_graph(&self, root: &kd::ID, depth: usize) -> Result<Option<NoteGraph>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		if !notes.contains(&root.to_string())? {
+			return Ok(None);
+		}
+		let (ids, links) = walk_links(*root, depth, |id| {
+			let key = id.to_string();
+			let links = note_keys(notes.links(&key)?);
+			let backlinks = note_keys(notes.backlinks(&key)?);
+			let parse = |keys: Vec<String>| keys.iter().filter_map(kd::ID::parse).collect();
+			Ok((parse(links), parse(backlinks)))
+		})?;
+		let notes = self.read_notes(ids.iter().map(|id| id.to_string()).collect())?;
+		// Notes deleted while walking the links are left out.
+		let found = notes.iter().map(|note| note.id).collect::<HashSet<_>>();
+		let links = links
+			.into_iter()
+			.filter(|(from, to)| found.contains(from) && found.contains(to))
+			.collect();
+		Ok(Some(NoteGraph { notes, links }))
+	}
+
+	/// Returns the prior versions of a note, newest first, with the revision
+	/// number of each.
+	pub fn note_revisions(&self, id: &kd::ID) -> Result<Vec<(u64, Note)>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let key = id.to_string();
+		let mut result = Vec::new();
+		for revision in notes.revisions(&key)?.into_iter().rev() {
+			if let Some(data) = notes.get_revision(&key, revision)? {
+				result.push((revision, parse_note(id, &data)?));
+			}
+		}
+		Ok(result)
+	}
+
+	/// Returns a prior version of a note, or the current version for `None`.
+	/// Returns `None` if the note or revision doesn't exist.
+	pub fn note_revision(&self, id: &kd::ID, revision: Option<u64>) -> Result<Option<Note>> {
+		let revision = match revision {
+			Some(revision) => revision,
+			None => return self.note(id),
+		};
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		match notes.get_revision(&id.to_string(), revision)? {
+			Some(data) => Ok(Some(parse_note(id, &data)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Reads the notes with the given keys, most recently created first.
+	fn read_notes(&self, keys: Vec<String>) -> Result<Vec<Note>> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let mut result = Vec::new();
+		for key in keys {
+			let id = match kd::ID::parse(&key) {
+				Some(id) => id,
+				None => continue,
+			};
+			// The note may have been deleted since listing the keys.
+			if let Some(data) = notes.get(&key)? {
+				result.push(parse_note(&id, &data)?);
+			}
+		}
+		result.sort_by(|a, b| b.created.cmp(&a.created).then(b.id.cmp(&a.id)));
+		Ok(result)
+	}
+
+	/// Creates a new note.
+	pub fn create_note(
+		&self,
+		title: String,
+		text: String,
+		metadata: serde_json::Map<String, serde_json::Value>,
+	) -> Result<Note> {
+		let now = unix_millis();
+		let note = Note {
+			id: kd::ID::new_sortable(),
+			title,
+			text,
+			created: now,
+			updated: now,
+			metadata,
+			reminder: None,
+			archived: false,
+		};
+		self.save_note(&note)?;
+		Ok(note)
+	}
+
+	/// Changes an existing note. Returns `None` if the note doesn't exist.
+	pub fn update_note(&self, id: &kd::ID, changes: NoteChanges) -> Result<Option<Note>> {
+		let mut note = match self.note(id)? {
+			Some(note) => note,
+			None => return Ok(None),
+		};
+		if let Some(title) = changes.title {
+			note.title = title;
+		}
+		if let Some(text) = changes.text {
+			note.text = text;
+		}
+		if let Some(metadata) = changes.metadata {
+			note.metadata = metadata;
+		}
+		note.updated = unix_millis();
+		self.save_note(&note)?;
+		Ok(Some(note))
+	}
+
+	/// Applies a patch to the notes with the given IDs in a single
+	/// transaction. Returns the result for each ID, in order, with the reason
+	/// it failed, if it did. Notes that failed are left unchanged, and the
+	/// others are changed together.
+	pub fn bulk_update_notes(
+		&self,
+		ids: &[kd::ID],
+		patch: &NotePatch,
+	) -> Result<Vec<(kd::ID, Option<String>)>> {
+		let mut tx = self.database().transaction();
+		let mut results = Vec::new();
+		for id in ids {
+			let savepoint = tx.savepoint();
+			match patch_note(&mut tx, id, patch) {
+				Ok(()) => results.push((*id, None)),
+				Err(err) => {
+					tx.rollback_to(savepoint)?;
+					results.push((*id, Some(err.to_string())));
+				}
+			}
+		}
+		tx.commit()?;
+		Ok(results)
+	}
+
+	/// Deletes a note. Returns false if the note doesn't exist.
+	pub fn delete_note(&self, id: &kd::ID) -> Result<bool> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		Ok(notes.delete(&id.to_string())?)
+	}
+
+	pub(crate) fn save_note(&self, note: &Note) -> Result<()> {
+		let notes = self.database().collection(NOTES_COLLECTION)?;
+		let data = serde_json::to_vec(note)?;
+		notes.put(&note.id.to_string(), &data)?;
+		Ok(())
+	}
+}
+
+/// Returns the keys of the notes in a list of records.
+fn note_keys(records: Vec<kd::RecordId>) -> Vec<String> {
+	records
+		.into_iter()
+		.filter(|record| record.collection == NOTES_COLLECTION)
+		.map(|record| record.key)
+		.collect()
+}
+
+/// Walks the links from `root` in both directions, up to `depth` links away.
+/// The `neighbors` callback returns the IDs linked from and to an ID.
+///
+/// Returns the IDs found, including `root`, and the links between them as
+/// `(from, to)`, sorted.
+fn walk_links<F>(
+	root: kd::ID,
+	depth: usize,
+	mut neighbors: F,
+) -> Result<(Vec<kd::ID>, Vec<(kd::ID, kd::ID)>)>
+where
+	F: FnMut(&kd::ID) -> Result<(Vec<kd::ID>, Vec<kd::ID>)>,
+{
+	let mut found = vec![root];
+	let mut visited = HashSet::new();
+	visited.insert(root);
+	let mut links = BTreeSet::new();
+	let mut frontier = vec![root];
+	for _ in 0..depth {
+		let mut next = Vec::new();
+		for id in frontier {
+			let (to, from) = neighbors(&id)?;
+			let edges = to
+				.into_iter()
+				.map(|to| ((id, to), to))
+				.chain(from.into_iter().map(|from| ((from, id), from)));
+			for (link, other) in edges {
+				links.insert(link);
+				if visited.insert(other) {
+					found.push(other);
+					next.push(other);
+				}
+			}
+		}
+		frontier = next;
+	}
+	Ok((found, links.into_iter().collect()))
+}
+
+/// Applies a patch to a note in a transaction.
+fn patch_note(tx: &mut kd::Transaction, id: &kd::ID, patch: &NotePatch) -> Result<()> {
+	let key = id.to_string();
+	let data = tx
+		.get(NOTES_COLLECTION, &key)?
+		.ok_or_else(|| Error::from(format!("note {} not found", id)))?;
+	if let Some(archived) = patch.archived {
+		let mut note = parse_note(id, &data)?;
+		if note.archived != archived {
+			note.archived = archived;
+			tx.put(NOTES_COLLECTION, &key, &serde_json::to_vec(&note)?)?;
+		}
+	}
+	for tag in &patch.remove_tags {
+		tx.untag(NOTES_COLLECTION, &key, tag)?;
+	}
+	for tag in &patch.add_tags {
+		tx.tag(NOTES_COLLECTION, &key, tag)?;
+	}
+	Ok(())
+}
+
+fn parse_note(id: &kd::ID, data: &[u8]) -> Result<Note> {
+	let mut note: Note = serde_json::from_slice(data)?;
+	note.id = *id;
+	Ok(note)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_walk_links() {
+		let ids = (0..5).map(|_| kd::ID::new_sortable()).collect::<Vec<_>>();
+		// 0 -> 1 -> 2 -> 3, and 4 -> 0
+		let links = [(0, 1), (1, 2), (2, 3), (4, 0)];
+		let neighbors = |id: &kd::ID| {
+			let index = ids.iter().position(|it| it == id).unwrap();
+			let to = links
+				.iter()
+				.filter(|(from, _)| *from == index)
+				.map(|(_, to)| ids[*to])
+				.collect();
+			let from = links
+				.iter()
+				.filter(|(_, to)| *to == index)
+				.map(|(from, _)| ids[*from])
+				.collect();
+			Ok((to, from))
+		};
+
+		let (found, found_links) = walk_links(ids[0], 0, neighbors).unwrap();
+		assert_eq!(found, [ids[0]]);
+		assert!(found_links.is_empty());
+
+		let (found, found_links) = walk_links(ids[1], 1, neighbors).unwrap();
+		assert_eq!(found, [ids[1], ids[2], ids[0]]);
+		let mut expected = vec![(ids[0], ids[1]), (ids[1], ids[2])];
+		expected.sort();
+		assert_eq!(found_links, expected);
+
+		let (found, found_links) = walk_links(ids[1], 2, neighbors).unwrap();
+		assert_eq!(found, [ids[1], ids[2], ids[0], ids[3], ids[4]]);
+		assert_eq!(found_links.len(), 4);
+	}
+}
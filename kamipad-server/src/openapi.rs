@@ -0,0 +1,370 @@
+//! OpenAPI 3 document for the REST routes, served at `/api/openapi.json`.
+//!
+//! The GraphQL routes are described by the GraphQL schema instead, which also
+//! covers the logs and the administration. Each operation here is matched to
+//! its Rocket route by the name of the handler, and the tests check that the
+//! methods, paths and parameters agree with the route definitions.
+
+use serde_json::{json, Map, Value};
+
+use crate::api_version;
+use crate::common;
+
+/// Operation for a REST route.
+struct Operation {
+	/// Name of the route handler.
+	route: &'static str,
+	method: &'static str,
+	/// Path relative to the API version, with `{name}` for parameters.
+	path: &'static str,
+	tag: &'static str,
+	summary: &'static str,
+	parameters: &'static [Parameter],
+	responses: &'static [Reply],
+}
+
+/// Required parameter in the path or query.
+struct Parameter {
+	name: &'static str,
+	location: &'static str,
+	kind: &'static str,
+	description: &'static str,
+}
+
+/// Response for a status code, with the content type of the body, if any.
+struct Reply {
+	status: u16,
+	description: &'static str,
+	content: Option<&'static str>,
+}
+
+const JSON: Option<&str> = Some("application/json");
+const TEXT: Option<&str> = Some("text/plain");
+
+/// Parameters of the signed download URLs.
+const SIGNED_URL: &[Parameter] = &[
+	Parameter {
+		name: "id",
+		location: "path",
+		kind: "string",
+		description: "ID of the entity to download.",
+	},
+	Parameter {
+		name: "expires",
+		location: "query",
+		kind: "integer",
+		description: "Expiration of the URL, in milliseconds since the epoch.",
+	},
+	Parameter {
+		name: "signature",
+		location: "query",
+		kind: "string",
+		description: "Signature of the URL.",
+	},
+];
+
+/// Responses for the signed download URLs, besides the download itself.
+const EXPIRED: Reply = Reply {
+	status: 403,
+	description: "The URL expired or the signature is invalid.",
+	content: None,
+};
+const INVALID_ID: Reply = Reply {
+	status: 404,
+	description: "The ID is invalid.",
+	content: None,
+};
+
+/// Response for the routes that time out, see `timeout`.
+const TIMED_OUT: Reply = Reply {
+	status: 503,
+	description: "The request timed out.",
+	content: None,
+};
+
+const OPERATIONS: &[Operation] = &[
+	Operation {
+		route: "index",
+		method: "GET",
+		path: "/",
+		tag: "server",
+		summary: "Returns the name, version and description of the server.",
+		parameters: &[],
+		responses: &[Reply {
+			status: 200,
+			description: "Server information.",
+			content: JSON,
+		}],
+	},
+	Operation {
+		route: "openapi_document",
+		method: "GET",
+		path: "/openapi.json",
+		tag: "server",
+		summary: "Returns this document. Only available with introspection.",
+		parameters: &[],
+		responses: &[Reply {
+			status: 200,
+			description: "OpenAPI document.",
+			content: JSON,
+		}],
+	},
+	Operation {
+		route: "schema",
+		method: "GET",
+		path: "/graphql/schema",
+		tag: "server",
+		summary: "Returns the GraphQL schema. Only available with introspection.",
+		parameters: &[],
+		responses: &[Reply {
+			status: 200,
+			description: "Schema in the GraphQL SDL.",
+			content: TEXT,
+		}],
+	},
+	Operation {
+		route: "health",
+		method: "GET",
+		path: "/health",
+		tag: "health",
+		summary: "Checks the database lock, the journal and the caches.",
+		parameters: &[],
+		responses: &[
+			Reply {
+				status: 200,
+				description: "Every check passed.",
+				content: JSON,
+			},
+			Reply {
+				status: 503,
+				description: "A check failed, or the request timed out.",
+				content: JSON,
+			},
+		],
+	},
+	Operation {
+		route: "healthz",
+		method: "GET",
+		path: "/healthz",
+		tag: "health",
+		summary: "Liveness probe, which only checks that the server responds.",
+		parameters: &[],
+		responses: &[Reply {
+			status: 200,
+			description: "The server is alive.",
+			content: TEXT,
+		}],
+	},
+	Operation {
+		route: "readyz",
+		method: "GET",
+		path: "/readyz",
+		tag: "health",
+		summary: "Readiness probe, for the database and the scheduler.",
+		parameters: &[],
+		responses: &[
+			Reply {
+				status: 200,
+				description: "The server is ready.",
+				content: JSON,
+			},
+			Reply {
+				status: 503,
+				description: "The server is not ready.",
+				content: JSON,
+			},
+		],
+	},
+	Operation {
+		route: "download",
+		method: "GET",
+		path: "/attachments/{id}",
+		tag: "attachments",
+		summary: "Downloads an attachment, from the `downloadUrl` in GraphQL.",
+		parameters: SIGNED_URL,
+		responses: &[
+			Reply {
+				status: 200,
+				description: "Content of the attachment, with its content type.",
+				content: Some("application/octet-stream"),
+			},
+			EXPIRED,
+			INVALID_ID,
+			TIMED_OUT,
+		],
+	},
+	Operation {
+		route: "download_export",
+		method: "GET",
+		path: "/exports/{id}",
+		tag: "exports",
+		summary: "Downloads an export, from the `downloadUrl` in GraphQL.",
+		parameters: SIGNED_URL,
+		responses: &[
+			Reply {
+				status: 200,
+				description: "Archive with the exported notes.",
+				content: Some("application/zip"),
+			},
+			EXPIRED,
+			INVALID_ID,
+			TIMED_OUT,
+		],
+	},
+];
+
+/// Routes that are not in the document: the GraphQL routes, and the ones that
+/// requests are routed to by the fairings.
+const UNDOCUMENTED: &[&str] = &[
+	"query",
+	"get_query",
+	"stream",
+	"upload",
+	"ide",
+	"ide_asset",
+	"unavailable",
+	"rate_limited",
+	"unsupported_version",
+];
+
+/// Returns the OpenAPI document.
+pub fn document() -> Value {
+	let mut paths = Map::new();
+	for operation in OPERATIONS {
+		let path = paths
+			.entry(operation.path)
+			.or_insert_with(|| Value::Object(Map::new()));
+		path[operation.method.to_lowercase()] = operation.to_json();
+	}
+	json!({
+		"openapi": "3.0.3",
+		"info": {
+			"title": common::PACKAGE_NAME,
+			"description": common::PACKAGE_DESCRIPTION,
+			"version": common::VERSION,
+		},
+		"servers": [{ "url": format!("/api/{}", api_version::DEFAULT_VERSION) }],
+		"paths": paths,
+	})
+}
+
+impl Operation {
+	fn to_json(&self) -> Value {
+		let parameters = self
+			.parameters
+			.iter()
+			.map(|it| {
+				json!({
+					"name": it.name,
+					"in": it.location,
+					"required": true,
+					"description": it.description,
+					"schema": { "type": it.kind },
+				})
+			})
+			.collect::<Vec<_>>();
+
+		let mut responses = Map::new();
+		for reply in self.responses {
+			let mut response = json!({ "description": reply.description });
+			if let Some(content) = reply.content {
+				response["content"] = json!({ content: { "schema": schema(content) } });
+			}
+			responses.insert(reply.status.to_string(), response);
+		}
+
+		json!({
+			"operationId": self.route,
+			"tags": [self.tag],
+			"summary": self.summary,
+			"parameters": parameters,
+			"responses": responses,
+		})
+	}
+}
+
+/// Returns the schema for a body with the content type.
+fn schema(content: &str) -> Value {
+	if content.ends_with("json") {
+		json!({ "type": "object" })
+	} else if content.starts_with("text/") {
+		json!({ "type": "string" })
+	} else {
+		json!({ "type": "string", "format": "binary" })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::server;
+
+	/// Returns the names of the dynamic segments, such as `id` for `<id>`.
+	fn dynamic<'a>(segments: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+		segments
+			.filter(|it| it.starts_with('<') && it.ends_with('>'))
+			.map(|it| it[1..it.len() - 1].trim_end_matches(".."))
+			.collect()
+	}
+
+	#[test]
+	fn test_operations_match_routes() {
+		let routes = server::api_routes(true);
+		for route in &routes {
+			let name = route.name.unwrap();
+			if UNDOCUMENTED.contains(&name) {
+				continue;
+			}
+			let operation = OPERATIONS
+				.iter()
+				.find(|it| it.route == name)
+				.unwrap_or_else(|| panic!("route {} is not in the document", name));
+			assert_eq!(operation.method, route.method.as_str(), "{}", name);
+
+			let path = route.uri.path();
+			let template = path.replace('<', "{").replace('>', "}");
+			assert_eq!(operation.path, template, "{}", name);
+
+			let params = |location: &str| {
+				operation
+					.parameters
+					.iter()
+					.filter(|it| it.location == location)
+					.map(|it| it.name)
+					.collect::<Vec<_>>()
+			};
+			let query = route.uri.query().unwrap_or("");
+			assert_eq!(params("path"), dynamic(path.split('/')), "{}", name);
+			assert_eq!(params("query"), dynamic(query.split('&')), "{}", name);
+		}
+
+		for operation in OPERATIONS {
+			assert!(
+				routes.iter().any(|it| it.name == Some(operation.route)),
+				"no route for {}",
+				operation.route
+			);
+		}
+	}
+
+	#[test]
+	fn test_document() {
+		let document = document();
+		assert_eq!(document["openapi"], "3.0.3");
+		assert_eq!(document["servers"][0]["url"], "/api/v1");
+
+		let health = &document["paths"]["/health"]["get"];
+		assert_eq!(health["operationId"], "health");
+		assert!(health["responses"]["503"].is_object());
+
+		let download = &document["paths"]["/attachments/{id}"]["get"];
+		assert_eq!(download["parameters"].as_array().unwrap().len(), 3);
+		assert!(download["responses"]["403"].is_object());
+		let content = &download["responses"]["200"]["content"];
+		assert_eq!(
+			content["application/octet-stream"]["schema"]["format"],
+			"binary"
+		);
+	}
+}
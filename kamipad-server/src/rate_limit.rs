@@ -0,0 +1,90 @@
+//! Server-wide rate limiting, for every request.
+//!
+//! The limit is set in the `rate_limit` table of `kamipad.toml` (see
+//! `config`), and is disabled by default. Like the GraphQL limit, each client
+//! has a token bucket, by session or IP address (see `graph::rate_limit`).
+//!
+//! Requests over the limit fail with `429 Too Many Requests` and a
+//! `Retry-After` header. The health probes and the metrics are not limited.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request};
+
+use crate::api_version;
+use crate::auth::Auth;
+use crate::graph::rate_limit::{client_key, ClientIp, RateLimit, RateLimiter};
+use crate::logging::RequestLog;
+use crate::server;
+
+/// Paths that are not limited, so that monitoring keeps working. The paths
+/// for each API version are also matched.
+const EXEMPT_PATHS: &[&str] = &[
+	"/api/health",
+	"/api/healthz",
+	"/api/readyz",
+	"/metrics",
+	server::UNAVAILABLE_URI,
+];
+
+/// Fairing that limits the requests for each client.
+pub struct RateLimiting {
+	limiter: RateLimiter,
+}
+
+impl RateLimiting {
+	pub fn new(limit: RateLimit) -> RateLimiting {
+		RateLimiting {
+			limiter: RateLimiter::new(Some(limit)),
+		}
+	}
+}
+
+impl Fairing for RateLimiting {
+	fn info(&self) -> Info {
+		Info {
+			name: "Rate limit",
+			kind: Kind::Request,
+		}
+	}
+
+	fn on_request(&self, request: &mut Request, _data: &Data) {
+		let path = request.uri().path();
+		let path = match api_version::split_version(path) {
+			Some((_, path)) => path,
+			None => path.to_string(),
+		};
+		if EXEMPT_PATHS.contains(&path.as_str()) {
+			return;
+		}
+		let auth = request.guard::<Auth>().succeeded().unwrap_or(Auth(None));
+		let key = client_key(&auth, &ClientIp(request.client_ip()));
+		if let Err(retry) = self.limiter.take(&key) {
+			let retry = RetryAfter(retry.as_secs_f64().ceil().max(1.0) as u64);
+			if let Some(log) = request.guard::<RequestLog>().succeeded() {
+				info!(log, "rate limited, retry in {}s", retry.0);
+			}
+
+			// Fairings can't respond to a request, so it is routed to one that
+			// fails with the `Retry-After` cached here.
+			request.local_cache(|| retry);
+			request.set_method(Method::Get);
+			request.set_uri(Origin::parse(server::RATE_LIMITED_URI).unwrap());
+		}
+	}
+}
+
+/// Request guard with the seconds for the `Retry-After` of a rate limited
+/// request.
+#[derive(Copy, Clone, Debug)]
+pub struct RetryAfter(pub u64);
+
+impl<'a, 'r> FromRequest<'a, 'r> for RetryAfter {
+	type Error = ();
+
+	fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+		Outcome::Success(*request.local_cache(|| RetryAfter(1)))
+	}
+}
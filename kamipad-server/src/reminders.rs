@@ -0,0 +1,236 @@
+//! Reminders for notes.
+//!
+//! A note can have a `Reminder` with a due time, and optionally a rule to
+//! repeat it. The scheduler started by `App::start_scheduler` checks the
+//! notes every `SCHEDULER_INTERVAL`, and for each reminder that came due
+//! logs an entry and posts an event to the webhook, if configured:
+//!
+//! ```text
+//! {"event": "reminder", "note": "<id>", "title": "...", "due": "<RFC 3339>"}
+//! ```
+//!
+//! The reminder is then advanced to its next occurrence after the current
+//! time, or removed if it doesn't repeat. Occurrences missed while the
+//! server was down fire only once.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::notes::Note;
+use crate::util::time::unix_millis;
+use crate::util::{Error, Result};
+
+/// Interval between checks for reminders that came due.
+pub const SCHEDULER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Timeout for posting an event to the webhook.
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rule to repeat a reminder, from its due time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatRule {
+	Daily,
+	Weekly,
+	/// On the same day of the month, or the last day for shorter months.
+	Monthly,
+	Yearly,
+}
+
+/// Reminder for a note.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Reminder {
+	/// Due time in milliseconds since the UNIX epoch.
+	pub due: u64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub repeat: Option<RepeatRule>,
+}
+
+impl RepeatRule {
+	/// Returns the occurrence after a time, in milliseconds since the UNIX
+	/// epoch.
+	pub fn next(&self, time: u64) -> u64 {
+		const DAY: u64 = 24 * 60 * 60 * 1000;
+		match self {
+			RepeatRule::Daily => time + DAY,
+			RepeatRule::Weekly => time + 7 * DAY,
+			RepeatRule::Monthly => add_months(time, 1),
+			RepeatRule::Yearly => add_months(time, 12),
+		}
+	}
+}
+
+impl Reminder {
+	/// Returns the reminder for the first occurrence after `now`, or `None`
+	/// if it doesn't repeat.
+	pub fn next_after(&self, now: u64) -> Option<Reminder> {
+		let repeat = self.repeat?;
+		let mut due = self.due;
+		while due <= now {
+			due = repeat.next(due);
+		}
+		Some(Reminder {
+			due,
+			repeat: Some(repeat),
+		})
+	}
+}
+
+impl App {
+	/// Sets or removes the reminder for a note. This doesn't change the
+	/// update time of the note. Returns `None` if the note doesn't exist.
+	pub fn set_reminder(&self, id: &kd::ID, reminder: Option<Reminder>) -> Result<Option<Note>> {
+		let mut note = match self.note(id)? {
+			Some(note) => note,
+			None => return Ok(None),
+		};
+		note.reminder = reminder;
+		self.save_note(&note)?;
+		Ok(Some(note))
+	}
+
+	/// Starts the background thread that fires the reminders, posting them
+	/// to the webhook URL, if any.
+	pub fn start_scheduler(&'static self, webhook: Option<String>) {
+		std::thread::spawn(move || loop {
+			std::thread::sleep(SCHEDULER_INTERVAL);
+			if self.is_shutting_down() {
+				break;
+			}
+			if let Err(err) = self.fire_reminders(webhook.as_deref()) {
+				error!(self.log, "failed to check reminders: {}", err);
+			}
+		});
+		self.scheduler_started.store(true, Ordering::Relaxed);
+	}
+
+	/// Fires the reminders that came due, and advances them to their next
+	/// occurrence. Returns the number of reminders fired.
+	pub fn fire_reminders(&self, webhook: Option<&str>) -> Result<usize> {
+		let now = unix_millis();
+		let mut count = 0;
+		for note in self.notes()? {
+			let reminder = match &note.reminder {
+				Some(reminder) if reminder.due <= now => reminder,
+				_ => continue,
+			};
+			info!(
+				self.log,
+				"reminder due for note {}", note.id;
+				"title" => &note.title,
+				"due" => reminder.due,
+			);
+			if let Some(url) = webhook {
+				if let Err(err) = post_event(url, &note, reminder) {
+					warn!(self.log, "failed to post reminder for {}: {}", note.id, err);
+				}
+			}
+			count += 1;
+
+			// The note may have changed since listing the notes.
+			if let Some(mut current) = self.note(&note.id)? {
+				if current.reminder == note.reminder {
+					current.reminder = reminder.next_after(now);
+					self.save_note(&current)?;
+				}
+			}
+		}
+		Ok(count)
+	}
+}
+
+/// Posts the event for a due reminder to the webhook.
+fn post_event(url: &str, note: &Note, reminder: &Reminder) -> Result<()> {
+	let due = Utc.timestamp_millis(reminder.due as i64);
+	let event = serde_json::json!({
+		"event": "reminder",
+		"note": note.id.to_string(),
+		"title": note.title,
+		"due": due.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+	});
+	let response = ureq::post(url).timeout(WEBHOOK_TIMEOUT).send_json(event);
+	if response.ok() {
+		Ok(())
+	} else if let Some(err) = response.synthetic_error() {
+		Err(Error::from(err))
+	} else {
+		Err(Error::from(format!(
+			"webhook returned {}",
+			response.status()
+		)))
+	}
+}
+
+/// Adds months to a time, keeping the day of the month unless the month is
+/// shorter.
+fn add_months(time: u64, months: u32) -> u64 {
+	let time = Utc.timestamp_millis(time as i64).naive_utc();
+	let month = time.month0() + months;
+	let (year, month) = (time.year() + (month / 12) as i32, month % 12 + 1);
+	let date = (1..=time.day())
+		.rev()
+		.find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+		.unwrap();
+	date.and_time(time.time()).timestamp_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn millis(time: &str) -> u64 {
+		chrono::DateTime::parse_from_rfc3339(time)
+			.unwrap()
+			.timestamp_millis() as u64
+	}
+
+	#[test]
+	fn test_repeat_rule() {
+		let time = millis("2020-01-31T09:30:00Z");
+		assert_eq!(RepeatRule::Daily.next(time), millis("2020-02-01T09:30:00Z"));
+		assert_eq!(
+			RepeatRule::Weekly.next(time),
+			millis("2020-02-07T09:30:00Z")
+		);
+		assert_eq!(
+			RepeatRule::Monthly.next(time),
+			millis("2020-02-29T09:30:00Z")
+		);
+		assert_eq!(
+			RepeatRule::Yearly.next(time),
+			millis("2021-01-31T09:30:00Z")
+		);
+
+		let time = millis("2020-12-15T00:00:00Z");
+		assert_eq!(
+			RepeatRule::Monthly.next(time),
+			millis("2021-01-15T00:00:00Z")
+		);
+		let time = millis("2020-02-29T00:00:00Z");
+		assert_eq!(
+			RepeatRule::Yearly.next(time),
+			millis("2021-02-28T00:00:00Z")
+		);
+	}
+
+	#[test]
+	fn test_reminder_next_after() {
+		let once = Reminder {
+			due: millis("2020-08-01T12:00:00Z"),
+			repeat: None,
+		};
+		assert_eq!(once.next_after(once.due), None);
+
+		let daily = Reminder {
+			due: millis("2020-08-01T12:00:00Z"),
+			repeat: Some(RepeatRule::Daily),
+		};
+		let next = daily.next_after(millis("2020-08-03T12:00:00Z")).unwrap();
+		assert_eq!(next.due, millis("2020-08-04T12:00:00Z"));
+		assert_eq!(next.repeat, Some(RepeatRule::Daily));
+	}
+}
@@ -0,0 +1,296 @@
+//! Saved searches of notes.
+//!
+//! A saved search is a named `SearchFilter` and `SearchSort` that a user
+//! stores to run again later. Each search is a JSON record in the
+//! `saved_searches` collection, keyed by its ID, and belongs to the user
+//! that created it.
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::notes::Note;
+use crate::util::time::unix_millis;
+use crate::util::Result;
+
+/// Collection for the saved searches.
+pub const SEARCHES_COLLECTION: &'static str = "saved_searches";
+
+/// Filter for notes. Fields that are `None` match all notes.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SearchFilter {
+	/// Only match notes containing this text in the title or text, ignoring
+	/// case.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub text: Option<String>,
+	/// Only match notes changed after this time, in milliseconds since the
+	/// UNIX epoch.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub updated_after: Option<u64>,
+	/// Only match archived notes if true, or notes not archived if false.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub archived: Option<bool>,
+}
+
+impl SearchFilter {
+	pub fn matches(&self, note: &Note) -> bool {
+		if let Some(time) = self.updated_after {
+			if note.updated <= time {
+				return false;
+			}
+		}
+		if let Some(archived) = self.archived {
+			if note.archived != archived {
+				return false;
+			}
+		}
+		if let Some(text) = &self.text {
+			let text = text.to_lowercase();
+			if !note.title.to_lowercase().contains(&text)
+				&& !note.text.to_lowercase().contains(&text)
+			{
+				return false;
+			}
+		}
+		true
+	}
+}
+
+/// Order of the notes in a search.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchSort {
+	/// Most recently created first.
+	Created,
+	/// Most recently updated first.
+	Updated,
+	/// By title, ignoring case.
+	Title,
+}
+
+impl Default for SearchSort {
+	fn default() -> SearchSort {
+		SearchSort::Created
+	}
+}
+
+impl SearchSort {
+	/// Sorts the notes. Notes that compare equal are sorted by ID, newest
+	/// first, so that the order is stable for pagination.
+	pub fn sort(&self, notes: &mut [Note]) {
+		match self {
+			SearchSort::Created => {
+				notes.sort_by(|a, b| b.created.cmp(&a.created).then(b.id.cmp(&a.id)))
+			}
+			SearchSort::Updated => {
+				notes.sort_by(|a, b| b.updated.cmp(&a.updated).then(b.id.cmp(&a.id)))
+			}
+			SearchSort::Title => notes
+				.sort_by_cached_key(|note| (note.title.to_lowercase(), std::cmp::Reverse(note.id))),
+		}
+	}
+}
+
+/// A search saved by a user.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedSearch {
+	#[serde(skip, default = "kd::ID::nil")]
+	pub id: kd::ID,
+	/// Name of the user that owns the search.
+	pub user: String,
+	pub name: String,
+	#[serde(default)]
+	pub filter: SearchFilter,
+	#[serde(default)]
+	pub sort: SearchSort,
+	/// Creation time in milliseconds since the UNIX epoch.
+	pub created: u64,
+	/// Last update time in milliseconds since the UNIX epoch.
+	pub updated: u64,
+}
+
+/// Changes to apply to a saved search. Fields that are `None` are not
+/// changed.
+#[derive(Clone, Debug, Default)]
+pub struct SavedSearchChanges {
+	pub name: Option<String>,
+	pub filter: Option<SearchFilter>,
+	pub sort: Option<SearchSort>,
+}
+
+impl App {
+	/// Returns the notes matching the filter, in order.
+	pub fn find_notes(&self, filter: &SearchFilter, sort: SearchSort) -> Result<Vec<Note>> {
+		let mut notes = self.notes()?;
+		notes.retain(|note| filter.matches(note));
+		sort.sort(&mut notes);
+		Ok(notes)
+	}
+
+	/// Returns a saved search by its ID.
+	pub fn saved_search(&self, id: &kd::ID) -> Result<Option<SavedSearch>> {
+		let searches = self.database().collection(SEARCHES_COLLECTION)?;
+		match searches.get(&id.to_string())? {
+			Some(data) => Ok(Some(parse_search(id, &data)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Returns the saved searches of a user, sorted by name.
+	pub fn saved_searches(&self, user: &str) -> Result<Vec<SavedSearch>> {
+		let searches = self.database().collection(SEARCHES_COLLECTION)?;
+		let mut result = Vec::new();
+		for key in searches.keys()? {
+			let id = match kd::ID::parse(&key) {
+				Some(id) => id,
+				None => continue,
+			};
+			// The search may have been deleted since listing the keys.
+			if let Some(data) = searches.get(&key)? {
+				let search = parse_search(&id, &data)?;
+				if search.user == user {
+					result.push(search);
+				}
+			}
+		}
+		result.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+		Ok(result)
+	}
+
+	/// Saves a new search for a user.
+	pub fn create_saved_search(
+		&self,
+		user: &str,
+		name: String,
+		filter: SearchFilter,
+		sort: SearchSort,
+	) -> Result<SavedSearch> {
+		let now = unix_millis();
+		let search = SavedSearch {
+			id: kd::ID::new_sortable(),
+			user: user.to_string(),
+			name,
+			filter,
+			sort,
+			created: now,
+			updated: now,
+		};
+		self.save_search(&search)?;
+		Ok(search)
+	}
+
+	/// Changes a saved search. Returns `None` if the search doesn't exist.
+	pub fn update_saved_search(
+		&self,
+		id: &kd::ID,
+		changes: SavedSearchChanges,
+	) -> Result<Option<SavedSearch>> {
+		let mut search = match self.saved_search(id)? {
+			Some(search) => search,
+			None => return Ok(None),
+		};
+		if let Some(name) = changes.name {
+			search.name = name;
+		}
+		if let Some(filter) = changes.filter {
+			search.filter = filter;
+		}
+		if let Some(sort) = changes.sort {
+			search.sort = sort;
+		}
+		search.updated = unix_millis();
+		self.save_search(&search)?;
+		Ok(Some(search))
+	}
+
+	/// Deletes a saved search. Returns false if the search doesn't exist.
+	pub fn delete_saved_search(&self, id: &kd::ID) -> Result<bool> {
+		let searches = self.database().collection(SEARCHES_COLLECTION)?;
+		Ok(searches.delete(&id.to_string())?)
+	}
+
+	fn save_search(&self, search: &SavedSearch) -> Result<()> {
+		let searches = self.database().collection(SEARCHES_COLLECTION)?;
+		let data = serde_json::to_vec(search)?;
+		searches.put(&search.id.to_string(), &data)?;
+		Ok(())
+	}
+}
+
+fn parse_search(id: &kd::ID, data: &[u8]) -> Result<SavedSearch> {
+	let mut search: SavedSearch = serde_json::from_slice(data)?;
+	search.id = *id;
+	Ok(search)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn note(title: &str, text: &str, created: u64, updated: u64) -> Note {
+		Note {
+			id: kd::ID::new_sortable(),
+			title: title.to_string(),
+			text: text.to_string(),
+			created,
+			updated,
+			metadata: Default::default(),
+			reminder: None,
+			archived: false,
+		}
+	}
+
+	#[test]
+	fn test_search_filter() {
+		let note = note("Shopping", "Buy milk", 1, 10);
+		assert!(SearchFilter::default().matches(&note));
+
+		let filter = |text: Option<&str>, updated_after| SearchFilter {
+			text: text.map(String::from),
+			updated_after,
+			archived: None,
+		};
+		assert!(filter(Some("MILK"), None).matches(&note));
+		assert!(filter(Some("shop"), Some(9)).matches(&note));
+		assert!(!filter(Some("eggs"), None).matches(&note));
+		assert!(!filter(None, Some(10)).matches(&note));
+
+		let archived = |archived| SearchFilter {
+			archived: Some(archived),
+			..Default::default()
+		};
+		assert!(archived(false).matches(&note));
+		assert!(!archived(true).matches(&note));
+	}
+
+	#[test]
+	fn test_search_sort() {
+		let mut notes = vec![
+			note("b", "", 1, 30),
+			note("C", "", 3, 10),
+			note("a", "", 2, 20),
+		];
+		let titles = |notes: &[Note]| {
+			notes
+				.iter()
+				.map(|note| note.title.clone())
+				.collect::<Vec<_>>()
+		};
+
+		SearchSort::Created.sort(&mut notes);
+		assert_eq!(titles(&notes), ["C", "a", "b"]);
+		SearchSort::Updated.sort(&mut notes);
+		assert_eq!(titles(&notes), ["b", "a", "C"]);
+		SearchSort::Title.sort(&mut notes);
+		assert_eq!(titles(&notes), ["a", "b", "C"]);
+	}
+
+	#[test]
+	fn test_saved_search_record() {
+		let data = br#"{"user":"ana","name":"Recent","created":1,"updated":2}"#;
+		let id = kd::ID::new_sortable();
+		let search = parse_search(&id, data).unwrap();
+		assert_eq!(search.id, id);
+		assert_eq!(search.filter, SearchFilter::default());
+		assert_eq!(search.sort, SearchSort::Created);
+	}
+}
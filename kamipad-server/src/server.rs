@@ -1,22 +1,207 @@
-use rocket::State;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rocket::http::{ContentType, Status};
+use rocket::response::{status, Content, NamedFile};
+use rocket::{Response, Route, State};
 use rocket_contrib::json::Json;
 
+use kamipad_data as kd;
+
+use crate::api_version::{self, ApiVersion};
 use crate::app::App;
 use crate::common;
+use crate::compression::Compression;
+use crate::etag::ETagged;
 use crate::graph;
 use crate::logging;
+use crate::openapi;
+use crate::rate_limit::{RateLimiting, RetryAfter};
+use crate::status::{Health, Readiness};
+use crate::timeout::RequestTimeout;
+
+/// Path that requests are routed to while shutting down, see `App::shutdown`.
+pub const UNAVAILABLE_URI: &str = "/api/unavailable";
+
+/// Path that requests over the rate limit are routed to, see `rate_limit`.
+pub const RATE_LIMITED_URI: &str = "/api/rate-limited";
+
+/// Path that requests for an unknown API version are routed to, see
+/// `api_version`.
+pub const UNSUPPORTED_VERSION_URI: &str = "/api/unsupported-version";
+
+/// Default for `graphql_max_streams`, as a fraction of the Rocket workers,
+/// since each stream holds one.
+const DEFAULT_MAX_STREAMS_DIVISOR: usize = 2;
+
+/// Launch the Rocket server, blocking the thread, with `runtime` for the async
+/// work. This only returns if the server fails to start.
+pub fn launch(app: &'static App, runtime: tokio::runtime::Handle) {
+	// Rocket 0.4 only reads `Rocket.toml` with `ignite`, so the address, port
+	// and TLS from the configuration go through its environment overrides.
+	if let Some(address) = &app.config.address {
+		std::env::set_var("ROCKET_ADDRESS", address);
+	}
+	if let Some(port) = app.config.port {
+		std::env::set_var("ROCKET_PORT", port.to_string());
+	}
+	if let Some(tls) = &app.config.tls {
+		// Rocket resolves relative paths from its own configuration file, and
+		// doesn't unescape the values, so paths with quotes can't be used.
+		let cwd = std::env::current_dir().unwrap_or_default();
+		let (certs, key) = (cwd.join(&tls.certs), cwd.join(&tls.key));
+		let (certs, key) = (certs.to_string_lossy(), key.to_string_lossy());
+		if certs.contains('"') || key.contains('"') {
+			error!(
+				app.log,
+				"TLS certificate and key paths can't contain quotes"
+			);
+			std::process::exit(1);
+		}
+		let value = format!("{{certs=\"{}\",key=\"{}\"}}", certs, key);
+		std::env::set_var("ROCKET_TLS", value);
+		info!(app.log, "serving HTTPS with the certificate at {}", certs);
+	}
+
+	let rocket = rocket::ignite();
+
+	// Introspection is disabled by default in production, so that the schema
+	// is not publicly explorable.
+	let config = rocket.config();
+	let introspection = config
+		.get_bool("graphql_introspection")
+		.unwrap_or(!config.environment.is_prod());
+	if !introspection {
+		info!(
+			app.log,
+			"GraphQL introspection, GraphiQL and the schema are disabled"
+		);
+	}
+
+	let cache_ttl = graph::cache::read_cache_ttl(config, &app.log);
+	let rate_limit = graph::rate_limit::read_rate_limit(config, &app.log);
+	if let Some(limit) = rate_limit {
+		info!(
+			app.log,
+			"GraphQL requests are limited to {} per second, with bursts of {}",
+			limit.per_second,
+			limit.burst
+		);
+	}
+
+	// Reminders can't be advanced in a read-only database.
+	let webhook = config.get_string("reminder_webhook").ok();
+	if app.config.read_only {
+		info!(app.log, "database is read-only, reminders are disabled");
+	} else {
+		if let Some(webhook) = &webhook {
+			info!(app.log, "posting reminders to {}", webhook);
+		}
+		app.start_scheduler(webhook);
+	}
+
+	let timeout = app.config.request_timeout();
+	match timeout {
+		Some(timeout) => info!(app.log, "requests time out after {:?}", timeout),
+		None => info!(app.log, "requests have no timeout"),
+	}
 
-/// Launch the Rocket server.
-pub fn launch(app: &'static App) {
-	rocket::ignite()
+	let max_streams = match config.get_int("graphql_max_streams") {
+		Ok(count) if count >= 0 => count as usize,
+		_ => (config.workers as usize / DEFAULT_MAX_STREAMS_DIVISOR).max(1),
+	};
+	info!(
+		app.log,
+		"GraphQL subscriptions are limited to {} streams", max_streams
+	);
+
+	let tracing = config.get_bool("graphql_tracing").unwrap_or(false);
+	if tracing {
+		info!(app.log, "GraphQL tracing is enabled");
+	}
+
+	let mut rocket = rocket
 		.attach(logging::ServerLogger {})
+		.attach(Compression)
 		.manage(app)
-		.manage(graph::Schema::new(graph::Query, graph::Mutation))
-		.mount(
-			"/api",
-			routes![index, logs, log_by_req, graph::api::ide, graph::api::query],
-		)
-		.launch();
+		.manage(graph::schema())
+		.manage(runtime)
+		.manage(graph::rate_limit::RateLimiter::new(rate_limit))
+		.manage(graph::api::StreamLimit::new(max_streams))
+		.manage(graph::GraphConfig {
+			introspection,
+			cache_ttl: Arc::new(cache_ttl),
+			tracing,
+			timeout,
+		})
+		.attach(ApiVersion);
+
+	// The routes at `/api` are the ones for the default version.
+	let api_v1 = api_routes(introspection);
+	rocket = rocket
+		.mount("/api", api_v1.clone())
+		.mount("/api/v1", api_v1);
+	if let Some(dir) = &app.config.frontend {
+		if !dir.join("index.html").is_file() {
+			warn!(
+				app.log,
+				"no index.html in the frontend at {}",
+				dir.to_string_lossy()
+			);
+		}
+		info!(
+			app.log,
+			"serving the frontend from {}",
+			dir.to_string_lossy()
+		);
+		rocket = rocket.mount("/", routes![frontend_index, frontend_file]);
+	}
+	rocket = rocket.mount("/", routes![metrics]);
+	if let Some(cors) = &app.config.cors {
+		info!(app.log, "CORS is enabled for {}", cors.origins.join(", "));
+		rocket = rocket.attach(cors.clone());
+	}
+	if let Some(limit) = app.config.rate_limit {
+		info!(
+			app.log,
+			"requests are limited to {} per second, with bursts of {}",
+			limit.per_second,
+			limit.burst
+		);
+		rocket = rocket.attach(RateLimiting::new(limit));
+	}
+	let err = rocket.launch();
+	error!(app.log, "failed to launch the server: {}", err);
+}
+
+/// Returns the routes for version 1 of the API. The GraphiQL interface and
+/// the schemas are only included with introspection.
+pub fn api_routes(introspection: bool) -> Vec<Route> {
+	let mut routes = routes![
+		index,
+		health,
+		healthz,
+		readyz,
+		unavailable,
+		rate_limited,
+		unsupported_version,
+		download,
+		download_export,
+		graph::api::query,
+		graph::api::get_query,
+		graph::api::stream,
+		graph::api::upload,
+	];
+	if introspection {
+		routes.extend(routes![
+			openapi_document,
+			graph::api::ide,
+			graph::api::ide_asset,
+			graph::api::schema
+		]);
+	}
+	routes
 }
 
 //============================================================================//
@@ -31,30 +216,204 @@ struct IndexData {
 }
 
 #[get("/")]
-fn index() -> Json<IndexData> {
-	Json(IndexData {
+fn index() -> ETagged<Json<IndexData>> {
+	ETagged(Json(IndexData {
 		name: common::PACKAGE_NAME,
 		version: common::VERSION,
 		description: common::PACKAGE_DESCRIPTION,
-	})
+	}))
+}
+
+/// Describes the REST routes with an OpenAPI 3 document, see `openapi`. Like
+/// the GraphQL schema, it is only available with introspection.
+#[get("/openapi.json")]
+fn openapi_document() -> ETagged<Json<serde_json::Value>> {
+	ETagged(Json(openapi::document()))
 }
 
 //============================================================================//
-// Logging
+// Health
 //============================================================================//
 
-#[get("/logs")]
-fn logs(app: State<&App>) -> Json<Vec<logging::LogEntry>> {
-	Json(app.all_logs())
+/// Checks the health of the server, for load balancers and monitoring. This
+/// responds with `503 Service Unavailable` if any check fails or times out,
+/// and doesn't require authentication.
+#[get("/health")]
+fn health(timeout: RequestTimeout) -> Result<status::Custom<Json<Health>>, Status> {
+	let health = timeout.run(|app| app.health())?;
+	let code = if health.is_ok() {
+		Status::Ok
+	} else {
+		Status::ServiceUnavailable
+	};
+	Ok(status::Custom(code, Json(health)))
 }
 
-#[get("/log/<req>")]
-fn log_by_req(req: logging::RequestId, app: State<&App>) -> Json<Vec<logging::LogEntry>> {
-	let cache = app.cache();
-	if let Some(entries) = cache.get(&req) {
-		let entries: &Vec<logging::LogEntry> = &*entries;
-		Json(entries.clone())
+/// Liveness probe, which only checks that the server responds. Failing
+/// this means the server must be restarted.
+#[get("/healthz")]
+fn healthz() -> &'static str {
+	"ok"
+}
+
+/// Readiness probe, which checks that the database is open and the
+/// background tasks started. Failing this means requests must not be routed
+/// to the server yet, with `503 Service Unavailable`.
+#[get("/readyz")]
+fn readyz(timeout: RequestTimeout) -> Result<status::Custom<Json<Readiness>>, Status> {
+	let readiness = timeout.run(|app| app.readiness())?;
+	let code = if readiness.ready {
+		Status::Ok
 	} else {
-		Json(vec![])
+		Status::ServiceUnavailable
+	};
+	Ok(status::Custom(code, Json(readiness)))
+}
+
+/// Fails the requests received while shutting down, see `UNAVAILABLE_URI`.
+#[get("/unavailable")]
+fn unavailable() -> Status {
+	Status::ServiceUnavailable
+}
+
+/// Fails the requests for an unknown API version, see
+/// `UNSUPPORTED_VERSION_URI`.
+#[get("/unsupported-version")]
+fn unsupported_version() -> status::BadRequest<String> {
+	status::BadRequest(Some(format!(
+		"unsupported API version, use one of: {}",
+		api_version::VERSIONS.join(", ")
+	)))
+}
+
+/// Fails the requests over the rate limit, see `RATE_LIMITED_URI`.
+#[get("/rate-limited")]
+fn rate_limited(retry_after: RetryAfter) -> Response<'static> {
+	Response::build()
+		.status(Status::TooManyRequests)
+		.raw_header("Retry-After", retry_after.0.to_string())
+		.finalize()
+}
+
+//============================================================================//
+// Metrics
+//============================================================================//
+
+/// Exports the metrics in the Prometheus text format, see `metrics`.
+#[get("/metrics")]
+fn metrics(timeout: RequestTimeout, log: logging::RequestLog) -> Result<Content<String>, Status> {
+	match timeout.run(|app| app.metrics_text())? {
+		Ok(text) => Ok(Content(ContentType::Plain, text)),
+		Err(err) => {
+			error!(log, "failed to export metrics: {}", err);
+			Err(Status::InternalServerError)
+		}
 	}
 }
+
+//============================================================================//
+// Frontend
+//============================================================================//
+
+/// Serves the `index.html` of the frontend bundle, from the directory in
+/// the configuration.
+#[get("/")]
+fn frontend_index(app: State<&App>) -> Option<NamedFile> {
+	let dir = app.config.frontend.as_ref()?;
+	NamedFile::open(dir.join("index.html")).ok()
+}
+
+/// Serves a file from the frontend bundle. Other paths are client-side
+/// routes, which get the `index.html` so that the frontend can handle them.
+#[get("/<path..>", rank = 20)]
+fn frontend_file(app: State<&App>, path: PathBuf) -> Option<NamedFile> {
+	let dir = app.config.frontend.as_ref()?;
+	let file = dir.join(&path);
+	if file.is_file() {
+		return NamedFile::open(file).ok();
+	}
+
+	// Missing assets and API paths are not routes.
+	if path.starts_with("api") || path.extension().is_some() {
+		return None;
+	}
+	NamedFile::open(dir.join("index.html")).ok()
+}
+
+//============================================================================//
+// Attachments
+//============================================================================//
+
+/// Downloads the content of an attachment, from the signed URL returned by
+/// the `downloadUrl` field in GraphQL. See `attachments`.
+#[get("/attachments/<id>?<expires>&<signature>")]
+fn download(
+	timeout: RequestTimeout,
+	log: logging::RequestLog,
+	id: String,
+	expires: u64,
+	signature: String,
+) -> Result<ETagged<Response<'static>>, Status> {
+	let id = kd::ID::parse(&id).ok_or(Status::NotFound)?;
+	let download = timeout.run(move |app| app.download(&id, expires, &signature))?;
+	let (attachment, data) = match download {
+		Ok(Some(download)) => download,
+		Ok(None) => return Err(Status::Forbidden),
+		Err(err) => {
+			error!(log, "failed to download attachment {}: {}", id, err);
+			return Err(Status::InternalServerError);
+		}
+	};
+	let content_type =
+		ContentType::parse_flexible(&attachment.content_type).unwrap_or(ContentType::Binary);
+	let filename = attachment
+		.filename
+		.replace(|c: char| c == '"' || c.is_control(), "_");
+	Ok(ETagged(
+		Response::build()
+			.header(content_type)
+			.raw_header(
+				"Content-Disposition",
+				format!("attachment; filename=\"{}\"", filename),
+			)
+			.sized_body(Cursor::new(data))
+			.finalize(),
+	))
+}
+
+//============================================================================//
+// Exports
+//============================================================================//
+
+/// Downloads the archive of an export, from the signed URL returned by the
+/// `downloadUrl` field in GraphQL. See `export`.
+#[get("/exports/<id>?<expires>&<signature>")]
+fn download_export(
+	timeout: RequestTimeout,
+	log: logging::RequestLog,
+	id: String,
+	expires: u64,
+	signature: String,
+) -> Result<ETagged<Response<'static>>, Status> {
+	let id = kd::ID::parse(&id).ok_or(Status::NotFound)?;
+	let download = timeout.run(move |app| app.download_export(&id, expires, &signature))?;
+	let (tag, data) = match download {
+		Ok(Some(download)) => download,
+		Ok(None) => return Err(Status::Forbidden),
+		Err(err) => {
+			error!(log, "failed to download export {}: {}", id, err);
+			return Err(Status::InternalServerError);
+		}
+	};
+	let filename = format!("kamipad-{}.zip", tag.as_deref().unwrap_or("notes"));
+	Ok(ETagged(
+		Response::build()
+			.header(ContentType::new("application", "zip"))
+			.raw_header(
+				"Content-Disposition",
+				format!("attachment; filename=\"{}\"", filename),
+			)
+			.sized_body(Cursor::new(data))
+			.finalize(),
+	))
+}
@@ -0,0 +1,211 @@
+//! Status of the running server, for monitoring.
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::util::Result;
+
+/// Time to wait for the cache map in a health check.
+const CACHE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Snapshot of the server status.
+#[derive(Clone, Debug)]
+pub struct ServerStatus {
+	/// Start time in milliseconds since the UNIX epoch.
+	pub started: u64,
+	/// Time since the server started, in milliseconds.
+	pub uptime: u64,
+	/// Resident memory of the process in bytes, if available.
+	pub memory: Option<u64>,
+	/// Number of requests being handled.
+	pub active_requests: usize,
+	pub database: kd::DatabaseStats,
+}
+
+/// Result of the health checks, see `App::health`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Health {
+	/// Failed if any of the checks failed.
+	pub status: HealthStatus,
+	/// The database still holds its lock file.
+	pub database_lock: HealthStatus,
+	/// The journal can be written, skipped for a read-only database.
+	pub journal: HealthStatus,
+	/// The cache map can be locked.
+	pub cache: HealthStatus,
+}
+
+/// Result of the readiness checks, see `App::readiness`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Readiness {
+	/// True if all the checks passed.
+	pub ready: bool,
+	/// The database is open and holds its lock.
+	pub database: bool,
+	/// The background tasks are running.
+	pub scheduler: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+	Ok,
+	Skipped,
+	Failed,
+}
+
+impl Health {
+	pub fn is_ok(&self) -> bool {
+		self.status != HealthStatus::Failed
+	}
+}
+
+impl App {
+	/// Checks the health of the database and caches. Failures are logged,
+	/// since their details are not part of the result.
+	pub fn health(&self) -> Health {
+		let database = self.database();
+		let database_lock = match database.check_lock() {
+			Ok(()) => HealthStatus::Ok,
+			Err(err) => {
+				warn!(
+					self.log,
+					"health check failed for the database lock: {}", err
+				);
+				HealthStatus::Failed
+			}
+		};
+		let journal = match database.check_journal() {
+			Ok(()) => HealthStatus::Ok,
+			Err(kd::Error::ReadOnly) => HealthStatus::Skipped,
+			Err(err) => {
+				warn!(self.log, "health check failed for the journal: {}", err);
+				HealthStatus::Failed
+			}
+		};
+		let cache = if self.cache_map.is_responsive(CACHE_TIMEOUT) {
+			HealthStatus::Ok
+		} else {
+			warn!(self.log, "health check failed for the cache map");
+			HealthStatus::Failed
+		};
+
+		let checks = [database_lock, journal, cache];
+		let status = if checks.contains(&HealthStatus::Failed) {
+			HealthStatus::Failed
+		} else {
+			HealthStatus::Ok
+		};
+		Health {
+			status,
+			database_lock,
+			journal,
+			cache,
+		}
+	}
+
+	/// Checks if the server is ready to handle requests.
+	///
+	/// The database is open once the instance exists. Opening it for writing
+	/// migrates it to the current format, and opening it read-only fails if
+	/// it needs migrating, so it is always migrated. The scheduler is not
+	/// started for a read-only database.
+	pub fn readiness(&self) -> Readiness {
+		let database = self.database().check_lock().is_ok();
+		let scheduler = self.config.read_only || self.scheduler_started.load(Ordering::Relaxed);
+		Readiness {
+			ready: database && scheduler,
+			database,
+			scheduler,
+		}
+	}
+
+	/// Returns the current status of the server.
+	pub fn status(&self) -> Result<ServerStatus> {
+		Ok(ServerStatus {
+			started: self.started,
+			uptime: self.start_time.elapsed().as_millis() as u64,
+			memory: resident_memory(),
+			active_requests: self.active_requests.load(Ordering::Relaxed),
+			database: self.database().stats()?,
+		})
+	}
+
+	/// Counts a request as active, until `request_finished` is called.
+	pub fn request_started(&self) {
+		self.active_requests.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn request_finished(&self) {
+		self.active_requests.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	/// Counts a request as active for as long as the returned guard lives.
+	///
+	/// `ServerLogger` counts requests until the response starts, so this is
+	/// for response bodies that keep going after it, like the subscription
+	/// streams, so that `shutdown` waits for them too.
+	pub fn active_request(&'static self) -> ActiveRequest {
+		self.request_started();
+		ActiveRequest(self)
+	}
+
+	/// Returns true once `shutdown` has been called. New requests are then
+	/// rejected, see `ServerLogger`.
+	pub fn is_shutting_down(&self) -> bool {
+		self.shutting_down.load(Ordering::Relaxed)
+	}
+
+	/// Shuts down the application: new requests are rejected, the log
+	/// subscriptions are closed so that their streams end, the requests
+	/// being handled have up to `timeout` to finish, and then the database
+	/// is flushed. Returns false if requests were still running when it was
+	/// flushed.
+	///
+	/// Rocket 0.4 can't close its listener, so new connections are still
+	/// accepted until the process exits, and answered with 503 from
+	/// `/unavailable`. The process must exit after this.
+	pub fn shutdown(&self, timeout: Duration) -> Result<bool> {
+		self.shutting_down.store(true, Ordering::Relaxed);
+		self.close_log_subscriptions();
+		let deadline = Instant::now() + timeout;
+		let drained = loop {
+			let active = self.active_requests.load(Ordering::Relaxed);
+			if active == 0 {
+				break true;
+			}
+			if Instant::now() >= deadline {
+				warn!(
+					self.log,
+					"shutting down with {} requests still running", active
+				);
+				break false;
+			}
+			std::thread::sleep(Duration::from_millis(50));
+		};
+		self.database().flush()?;
+		info!(self.log, "database flushed");
+		Ok(drained)
+	}
+}
+
+/// Guard for a request counted as active, see `App::active_request`.
+pub struct ActiveRequest(&'static App);
+
+impl Drop for ActiveRequest {
+	fn drop(&mut self) {
+		self.0.request_finished();
+	}
+}
+
+/// Returns the resident memory of the process in bytes. This is only
+/// available on Linux.
+fn resident_memory() -> Option<u64> {
+	let status = std::fs::read_to_string("/proc/self/status").ok()?;
+	let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+	let kb = line["VmRSS:".len()..].trim().trim_end_matches("kB").trim();
+	kb.parse::<u64>().ok().map(|kb| kb * 1024)
+}
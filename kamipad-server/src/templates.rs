@@ -0,0 +1,202 @@
+//! Templates for new notes.
+//!
+//! Each template is a JSON record in the `templates` collection, keyed by its
+//! ID. The title and text of a template can have placeholders, which are
+//! replaced when creating a note from it:
+//!
+//! ```text
+//! {{name}}
+//! ```
+//!
+//! The names `date`, `time` and `datetime` are replaced with the current UTC
+//! time, other names with the values given when creating the note.
+//! Placeholders without a value are kept as is.
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+use kamipad_data as kd;
+
+use crate::app::App;
+use crate::notes::Note;
+use crate::util::time::unix_millis;
+use crate::util::Result;
+
+/// Collection for the templates.
+pub const TEMPLATES_COLLECTION: &'static str = "templates";
+
+/// A template for new notes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Template {
+	#[serde(skip, default = "kd::ID::nil")]
+	pub id: kd::ID,
+	pub name: String,
+	/// Title for new notes, with placeholders.
+	pub title: String,
+	/// Text for new notes, with placeholders.
+	pub text: String,
+	/// Creation time in milliseconds since the UNIX epoch.
+	pub created: u64,
+	/// Last update time in milliseconds since the UNIX epoch.
+	pub updated: u64,
+}
+
+/// Changes to apply to a template. Fields that are `None` are not changed.
+#[derive(Clone, Debug, Default)]
+pub struct TemplateChanges {
+	pub name: Option<String>,
+	pub title: Option<String>,
+	pub text: Option<String>,
+}
+
+impl App {
+	/// Returns a template by its ID.
+	pub fn template(&self, id: &kd::ID) -> Result<Option<Template>> {
+		let templates = self.database().collection(TEMPLATES_COLLECTION)?;
+		match templates.get(&id.to_string())? {
+			Some(data) => Ok(Some(parse_template(id, &data)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Returns all templates, sorted by name.
+	pub fn templates(&self) -> Result<Vec<Template>> {
+		let templates = self.database().collection(TEMPLATES_COLLECTION)?;
+		let mut result = Vec::new();
+		for key in templates.keys()? {
+			let id = match kd::ID::parse(&key) {
+				Some(id) => id,
+				None => continue,
+			};
+			// The template may have been deleted since listing the keys.
+			if let Some(data) = templates.get(&key)? {
+				result.push(parse_template(&id, &data)?);
+			}
+		}
+		result.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+		Ok(result)
+	}
+
+	/// Creates a new template.
+	pub fn create_template(&self, name: String, title: String, text: String) -> Result<Template> {
+		let now = unix_millis();
+		let template = Template {
+			id: kd::ID::new_sortable(),
+			name,
+			title,
+			text,
+			created: now,
+			updated: now,
+		};
+		self.save_template(&template)?;
+		Ok(template)
+	}
+
+	/// Changes a template. Returns `None` if the template doesn't exist.
+	pub fn update_template(
+		&self,
+		id: &kd::ID,
+		changes: TemplateChanges,
+	) -> Result<Option<Template>> {
+		let mut template = match self.template(id)? {
+			Some(template) => template,
+			None => return Ok(None),
+		};
+		if let Some(name) = changes.name {
+			template.name = name;
+		}
+		if let Some(title) = changes.title {
+			template.title = title;
+		}
+		if let Some(text) = changes.text {
+			template.text = text;
+		}
+		template.updated = unix_millis();
+		self.save_template(&template)?;
+		Ok(Some(template))
+	}
+
+	/// Deletes a template. Returns false if the template doesn't exist.
+	pub fn delete_template(&self, id: &kd::ID) -> Result<bool> {
+		let templates = self.database().collection(TEMPLATES_COLLECTION)?;
+		Ok(templates.delete(&id.to_string())?)
+	}
+
+	/// Creates a note from a template, replacing the placeholders with the
+	/// given values and the current time. Returns `None` if the template
+	/// doesn't exist.
+	pub fn create_note_from_template(
+		&self,
+		id: &kd::ID,
+		mut values: HashMap<String, String>,
+	) -> Result<Option<Note>> {
+		let template = match self.template(id)? {
+			Some(template) => template,
+			None => return Ok(None),
+		};
+		let now = Utc::now();
+		values.insert("date".into(), now.format("%Y-%m-%d").to_string());
+		values.insert("time".into(), now.format("%H:%M").to_string());
+		values.insert("datetime".into(), now.format("%Y-%m-%d %H:%M").to_string());
+		let title = fill_placeholders(&template.title, &values);
+		let text = fill_placeholders(&template.text, &values);
+		let note = self.create_note(title, text, Default::default())?;
+		Ok(Some(note))
+	}
+
+	fn save_template(&self, template: &Template) -> Result<()> {
+		let templates = self.database().collection(TEMPLATES_COLLECTION)?;
+		let data = serde_json::to_vec(template)?;
+		templates.put(&template.id.to_string(), &data)?;
+		Ok(())
+	}
+}
+
+/// Returns the text with the `{{name}}` placeholders replaced by their
+/// values. Placeholders without a value are kept.
+pub fn fill_placeholders(text: &str, values: &HashMap<String, String>) -> String {
+	let mut result = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(start) = rest.find("{{") {
+		let after = &rest[start + 2..];
+		let end = match after.find("}}") {
+			Some(end) => end,
+			None => break,
+		};
+		result.push_str(&rest[..start]);
+		match values.get(after[..end].trim()) {
+			Some(value) => result.push_str(value),
+			None => result.push_str(&rest[start..start + end + 4]),
+		}
+		rest = &after[end + 2..];
+	}
+	result.push_str(rest);
+	result
+}
+
+fn parse_template(id: &kd::ID, data: &[u8]) -> Result<Template> {
+	let mut template: Template = serde_json::from_slice(data)?;
+	template.id = *id;
+	Ok(template)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_fill_placeholders() {
+		let mut values = HashMap::new();
+		values.insert("name".to_string(), "Ana".to_string());
+		values.insert("date".to_string(), "2020-08-01".to_string());
+
+		let fill = |text| fill_placeholders(text, &values);
+		assert_eq!(fill(""), "");
+		assert_eq!(fill("no placeholders"), "no placeholders");
+		assert_eq!(fill("Hi {{name}}, {{ date }}"), "Hi Ana, 2020-08-01");
+		assert_eq!(fill("{{name}}{{name}}"), "AnaAna");
+		assert_eq!(fill("{{other}} {{name}}"), "{{other}} Ana");
+		assert_eq!(fill("open {{name"), "open {{name");
+		assert_eq!(fill("{{}}"), "{{}}");
+	}
+}
@@ -0,0 +1,97 @@
+//! Timeout for the requests, so that a stuck resolver or database scan can't
+//! hold a Rocket worker forever.
+//!
+//! The timeout is `request_timeout` in `kamipad.toml` (see `config`). Rocket
+//! 0.4 handlers are synchronous and fairings can't respond, so the routes run
+//! their blocking work with the `RequestTimeout` guard. The work runs on the
+//! database threads with `App::run`, and the worker only waits for it up to
+//! the timeout. Requests that time out fail with `503 Service Unavailable`
+//! and are logged to their `RequestLog`.
+//!
+//! GraphQL requests use the same timeout, failing with a `TIMEOUT` error
+//! instead (see `graph::api`). Subscription streams are not limited, since
+//! they are meant to stay open.
+
+use std::future::Future;
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, State};
+use tokio::runtime::Handle;
+
+use crate::app::App;
+use crate::logging::RequestLog;
+
+/// Request guard that runs the blocking work for a route with the timeout.
+pub struct RequestTimeout {
+	app: &'static App,
+	runtime: Handle,
+	log: RequestLog,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestTimeout {
+	type Error = ();
+
+	fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+		let app = request.guard::<State<&App>>()?;
+		let runtime = request.guard::<State<Handle>>()?;
+		let log = request.guard::<RequestLog>()?;
+		Outcome::Success(RequestTimeout {
+			app: *app.inner(),
+			runtime: runtime.inner().clone(),
+			log,
+		})
+	}
+}
+
+impl RequestTimeout {
+	/// Runs the work on the database threads, failing with `503 Service
+	/// Unavailable` if it doesn't finish in time. The work still runs to the
+	/// end, but the worker is free to handle other requests.
+	pub fn run<T, F>(&self, callback: F) -> Result<T, Status>
+	where
+		T: Send + 'static,
+		F: FnOnce(&'static App) -> T + Send + 'static,
+	{
+		let timeout = self.app.config.request_timeout();
+		wait(&self.runtime, timeout, self.app.run(callback)).ok_or_else(|| {
+			let timeout = timeout.unwrap_or_default();
+			warn!(self.log, "request timed out after {:?}", timeout);
+			Status::ServiceUnavailable
+		})
+	}
+}
+
+/// Blocks on the future with the runtime, up to the timeout if any. Returns
+/// `None` if it timed out.
+pub fn wait<F: Future>(
+	runtime: &Handle,
+	timeout: Option<Duration>,
+	future: F,
+) -> Option<F::Output> {
+	runtime.enter(|| {
+		futures::executor::block_on(async {
+			match timeout {
+				Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+				None => Some(future.await),
+			}
+		})
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_wait() {
+		let runtime = tokio::runtime::Runtime::new().unwrap();
+		let handle = runtime.handle().clone();
+		let timeout = Some(Duration::from_millis(50));
+		assert_eq!(wait(&handle, timeout, async { 1 }), Some(1));
+		assert_eq!(wait(&handle, None, async { 2 }), Some(2));
+		let pending = futures::future::pending::<()>();
+		assert_eq!(wait(&handle, timeout, pending), None);
+	}
+}
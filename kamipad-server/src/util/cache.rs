@@ -8,10 +8,14 @@
 //!
 //! Additionally, the traits `CacheKey` and `CacheVal` must be implemented for
 //! the cache keys and values.
+//!
+//! Each cache instance in a `CacheMap` is named after its key and value types,
+//! such as `QueryHash/QueryText`, which is used to inspect and clear it with
+//! `CacheMap::stats` and `CacheMap::clear`.
 
 use std::collections::{BinaryHeap, HashMap};
 use std::hash::Hash;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
 use std::time::{Duration, Instant};
 
 use std::any::{Any, TypeId};
@@ -31,6 +35,7 @@ pub struct CacheMap {
 struct CacheMapInner {
 	init: bool,
 	data: UnsafeCell<*mut HashMap<TypeId, *mut dyn Any>>,
+	named: Vec<(String, Box<dyn AnyCache>)>,
 }
 
 unsafe impl Send for CacheMapInner {}
@@ -41,6 +46,7 @@ impl Default for CacheMap {
 			inner: Arc::new(Mutex::new(CacheMapInner {
 				init: false,
 				data: UnsafeCell::new(0 as *mut _),
+				named: Vec::new(),
 			})),
 		}
 	}
@@ -73,6 +79,8 @@ impl CacheMap {
 			*entry
 		} else {
 			let entry: Box<Cache<K, V>> = Box::new(Cache::default());
+			let name = format!("{}/{}", short_type_name::<K>(), short_type_name::<V>());
+			inner.named.push((name, Box::new((*entry).clone())));
 			unsafe {
 				let entry = entry as Box<dyn Any>;
 				let entry = Box::into_raw(entry);
@@ -86,6 +94,94 @@ impl CacheMap {
 			(*cache).clone()
 		}
 	}
+
+	/// Returns the name and statistics for each cache instance, sorted by
+	/// name.
+	pub fn stats(&self) -> Vec<(String, CacheStats)> {
+		let inner = self.inner.lock().unwrap();
+		let mut stats = inner
+			.named
+			.iter()
+			.map(|(name, cache)| (name.clone(), cache.stats()))
+			.collect::<Vec<_>>();
+		stats.sort_by(|a, b| a.0.cmp(&b.0));
+		stats
+	}
+
+	/// Clears the cache instance with the given name. Returns false if there
+	/// is no cache with the name.
+	pub fn clear(&self, name: &str) -> bool {
+		let inner = self.inner.lock().unwrap();
+		match inner.named.iter().find(|(it, _)| it == name) {
+			Some((_, cache)) => {
+				cache.clear();
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Returns true if the cache map can be locked within the timeout. This
+	/// fails if it is deadlocked, or poisoned by a panic while locked.
+	pub fn is_responsive(&self, timeout: Duration) -> bool {
+		let deadline = Instant::now() + timeout;
+		loop {
+			match self.inner.try_lock() {
+				Ok(_) => return true,
+				Err(TryLockError::Poisoned(_)) => return false,
+				Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+					std::thread::sleep(Duration::from_millis(1));
+				}
+				Err(TryLockError::WouldBlock) => return false,
+			}
+		}
+	}
+}
+
+/// Returns the name of a type without the module paths, such as
+/// `Vec<LogEntry>` for `alloc::vec::Vec<kamipad::logging::LogEntry>`.
+fn short_type_name<T: ?Sized>() -> String {
+	let name = std::any::type_name::<T>();
+	let mut output = String::new();
+	let mut path = String::new();
+	for c in name.chars() {
+		if c.is_alphanumeric() || c == '_' || c == ':' {
+			path.push(c);
+		} else {
+			output.push_str(path.rsplit("::").next().unwrap());
+			output.push(c);
+			path.clear();
+		}
+	}
+	output.push_str(path.rsplit("::").next().unwrap());
+	output
+}
+
+/// Statistics for a cache instance.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+	/// Number of entries currently in the cache.
+	pub entries: usize,
+	/// Number of lookups that found an entry.
+	pub hits: u64,
+	/// Number of lookups that did not find an entry.
+	pub misses: u64,
+}
+
+/// Type-erased access to a cache instance in the `CacheMap`.
+trait AnyCache {
+	fn stats(&self) -> CacheStats;
+	fn clear(&self);
+}
+
+impl<K: CacheKey, V: CacheVal> AnyCache for Cache<K, V> {
+	fn stats(&self) -> CacheStats {
+		Cache::stats(self)
+	}
+
+	fn clear(&self) {
+		Cache::clear(self)
+	}
 }
 
 impl Drop for CacheMapInner {
@@ -124,6 +220,8 @@ struct CacheStore<K: CacheKey, V: CacheVal> {
 	real_ttl: HashMap<K, Instant>,
 	next_ttl: BinaryHeap<CacheKeyEntry<K>>,
 	map: HashMap<K, Arc<V>>,
+	hits: u64,
+	misses: u64,
 }
 
 #[allow(dead_code)]
@@ -161,10 +259,12 @@ impl<K: CacheKey, V: CacheVal> Cache<K, V> {
 	}
 
 	pub fn get(&self, key: &K) -> Option<Arc<V>> {
-		let store = self.store.lock().unwrap();
-		if let Some(val) = store.map.get(key) {
-			Some(val.clone())
+		let mut store = self.store.lock().unwrap();
+		if let Some(val) = store.map.get(key).cloned() {
+			store.hits += 1;
+			Some(val)
 		} else {
+			store.misses += 1;
 			None
 		}
 	}
@@ -175,12 +275,35 @@ impl<K: CacheKey, V: CacheVal> Cache<K, V> {
 		let mut store = self.store.lock().unwrap();
 		if let Some(val) = store.map.get(key).cloned() {
 			store.real_ttl.insert(key.clone(), ttl); // Update the expiration
+			store.hits += 1;
 			Some(val)
 		} else {
+			store.misses += 1;
 			None
 		}
 	}
 
+	/// Returns the number of entries, not counting expired ones, and the
+	/// hits and misses since the cache was created or cleared.
+	pub fn stats(&self) -> CacheStats {
+		let store = Self::do_purge(self.store.lock().unwrap());
+		CacheStats {
+			entries: store.map.len(),
+			hits: store.hits,
+			misses: store.misses,
+		}
+	}
+
+	/// Removes all entries from the cache and resets its statistics.
+	pub fn clear(&self) {
+		let mut store = self.store.lock().unwrap();
+		store.real_ttl.clear();
+		store.next_ttl.clear();
+		store.map.clear();
+		store.hits = 0;
+		store.misses = 0;
+	}
+
 	/// Purge all expired entries from the cache.
 	#[allow(dead_code)]
 	pub fn purge(&self) {
@@ -222,6 +345,8 @@ impl<K: CacheKey, V: CacheVal> Default for Cache<K, V> {
 				real_ttl: Default::default(),
 				next_ttl: Default::default(),
 				map: Default::default(),
+				hits: 0,
+				misses: 0,
 			})),
 		}
 	}
@@ -351,6 +476,62 @@ mod tests {
 		assert_eq!(*c3_b.get(&307_u32).unwrap(), "307");
 	}
 
+	#[test]
+	fn test_cache_map_stats_and_clear() {
+		let cache_map = CacheMap::new();
+		let duration = Duration::from_secs(99999);
+
+		let c1 = cache_map.get::<&'static str, Vec<String>>();
+		let c2 = cache_map.get::<u32, u32>();
+		c1.save("a", vec![], duration);
+		c1.save("b", vec![], duration);
+		c2.save(1, 1, duration);
+		assert!(c1.get(&"a").is_some());
+		assert!(c1.get(&"c").is_none());
+		assert!(c2.get_and_renew(&1, duration).is_some());
+
+		let stats = cache_map.stats();
+		let names = stats
+			.iter()
+			.map(|(name, _)| name.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(names, ["&str/Vec<String>", "u32/u32"]);
+		assert_eq!(
+			stats[0].1,
+			CacheStats {
+				entries: 2,
+				hits: 1,
+				misses: 1
+			}
+		);
+		assert_eq!(
+			stats[1].1,
+			CacheStats {
+				entries: 1,
+				hits: 1,
+				misses: 0
+			}
+		);
+
+		assert!(cache_map.clear("&str/Vec<String>"));
+		assert!(!cache_map.clear("unknown"));
+		assert!(c1.get(&"a").is_none());
+		assert!(c2.get(&1).is_some());
+		assert_eq!(c1.stats().entries, 0);
+	}
+
+	#[test]
+	fn test_cache_map_is_responsive() {
+		let cache_map = CacheMap::new();
+		let timeout = Duration::from_millis(10);
+		assert!(cache_map.is_responsive(timeout));
+
+		let guard = cache_map.inner.lock().unwrap();
+		assert!(!cache_map.is_responsive(timeout));
+		drop(guard);
+		assert!(cache_map.is_responsive(timeout));
+	}
+
 	#[test]
 	fn test_cache_map_drops() {
 		struct DropCheck<T: Fn()> {
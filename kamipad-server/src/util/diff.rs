@@ -0,0 +1,122 @@
+//! Line-based diff between two texts.
+//!
+//! The diff is computed from the longest common subsequence of lines, after
+//! trimming the lines common to the start and end of both texts. Texts too
+//! large for that, beyond `MAX_DIFF_CELLS`, are diffed as a full replacement.
+
+/// Maximum size of the table for the longest common subsequence, as the
+/// product of the number of lines that differ in each text.
+pub const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Change to a single line in a diff.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LineChange {
+	Same,
+	Added,
+	Removed,
+}
+
+/// Returns the diff from the lines of `old` to the lines of `new`, in order.
+/// Removed lines come before added lines where they replace each other.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<(LineChange, &'a str)> {
+	let old = old.lines().collect::<Vec<_>>();
+	let new = new.lines().collect::<Vec<_>>();
+
+	let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+	let suffix = old[prefix..]
+		.iter()
+		.rev()
+		.zip(new[prefix..].iter().rev())
+		.take_while(|(a, b)| a == b)
+		.count();
+	let (a, b) = (
+		&old[prefix..old.len() - suffix],
+		&new[prefix..new.len() - suffix],
+	);
+
+	let mut result = old[..prefix]
+		.iter()
+		.map(|line| (LineChange::Same, *line))
+		.collect::<Vec<_>>();
+	if a.len().saturating_mul(b.len()) > MAX_DIFF_CELLS {
+		result.extend(a.iter().map(|line| (LineChange::Removed, *line)));
+		result.extend(b.iter().map(|line| (LineChange::Added, *line)));
+	} else {
+		diff_lcs(a, b, &mut result);
+	}
+	result.extend(
+		old[old.len() - suffix..]
+			.iter()
+			.map(|line| (LineChange::Same, *line)),
+	);
+	result
+}
+
+fn diff_lcs<'a>(a: &[&'a str], b: &[&'a str], result: &mut Vec<(LineChange, &'a str)>) {
+	// lcs[i][j] is the length of the longest common subsequence of a[i..]
+	// and b[j..].
+	let width = b.len() + 1;
+	let mut lcs = vec![0u32; (a.len() + 1) * width];
+	for i in (0..a.len()).rev() {
+		for j in (0..b.len()).rev() {
+			lcs[i * width + j] = if a[i] == b[j] {
+				lcs[(i + 1) * width + j + 1] + 1
+			} else {
+				lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+			};
+		}
+	}
+
+	let (mut i, mut j) = (0, 0);
+	while i < a.len() && j < b.len() {
+		if a[i] == b[j] {
+			result.push((LineChange::Same, a[i]));
+			i += 1;
+			j += 1;
+		} else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+			result.push((LineChange::Removed, a[i]));
+			i += 1;
+		} else {
+			result.push((LineChange::Added, b[j]));
+			j += 1;
+		}
+	}
+	result.extend(a[i..].iter().map(|line| (LineChange::Removed, *line)));
+	result.extend(b[j..].iter().map(|line| (LineChange::Added, *line)));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use LineChange::*;
+
+	#[test]
+	fn test_diff_lines() {
+		assert_eq!(diff_lines("", ""), []);
+		assert_eq!(diff_lines("a\nb", "a\nb"), [(Same, "a"), (Same, "b")]);
+		assert_eq!(diff_lines("", "a"), [(Added, "a")]);
+		assert_eq!(diff_lines("a", ""), [(Removed, "a")]);
+		assert_eq!(
+			diff_lines("a\nb\nc\nd", "a\nx\nc\nd\ne"),
+			[
+				(Same, "a"),
+				(Removed, "b"),
+				(Added, "x"),
+				(Same, "c"),
+				(Same, "d"),
+				(Added, "e"),
+			]
+		);
+		assert_eq!(
+			diff_lines("x\na\nb\nc", "a\nc\ny"),
+			[
+				(Removed, "x"),
+				(Same, "a"),
+				(Removed, "b"),
+				(Same, "c"),
+				(Added, "y"),
+			]
+		);
+	}
+}
@@ -8,4 +8,7 @@ pub use self::result::Error;
 pub use self::result::Result;
 
 mod cache;
-pub use self::cache::{Cache, CacheKey, CacheMap, CacheVal};
+pub use self::cache::{Cache, CacheKey, CacheMap, CacheStats, CacheVal};
+
+mod diff;
+pub use self::diff::{diff_lines, LineChange};
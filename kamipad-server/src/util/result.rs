@@ -62,6 +62,7 @@ error_from!(std::io::Error);
 error_from!(uuid::Error);
 error_from!(serde_json::Error);
 error_from!(std::fmt::Error);
+error_from!(kamipad_data::Error);
 
 // error_from!(reqwest::header::ToStrError);
 // error_from!(reqwest::Error);
@@ -35,6 +35,14 @@ impl slog::KV for PerfTimer {
 	}
 }
 
+/// Returns the current UNIX time in milliseconds.
+pub fn unix_millis() -> u64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0)
+}
+
 /// Simple macro to instantiate a [PerfTimer].
 macro_rules! time {
 	($id:ident) => {
@@ -0,0 +1,203 @@
+//! Asynchronous facade for the database.
+//!
+//! All database operations are blocking file system calls, which must not run
+//! on the threads of an async runtime. `AsyncDatabase` wraps a `Database` and
+//! runs operations on its own pool of worker threads, returning futures that
+//! complete with the result.
+//!
+//! The facade does not depend on any particular runtime.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures_channel::oneshot;
+
+use crate::database::Database;
+use crate::Result;
+
+type Job = Box<dyn FnOnce(&Database) + Send>;
+
+/// Wrapper for a `Database` exposing asynchronous operations, which run on a
+/// pool of worker threads.
+pub struct AsyncDatabase {
+	db: Arc<Database>,
+	jobs: Mutex<Option<Sender<Job>>>,
+	workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl AsyncDatabase {
+	/// Wraps a database, running operations on the given number of worker
+	/// threads.
+	pub fn new(db: Database, threads: usize) -> AsyncDatabase {
+		let db = Arc::new(db);
+		let (sender, receiver) = mpsc::channel::<Job>();
+		let receiver = Arc::new(Mutex::new(receiver));
+		let workers = (0..threads.max(1))
+			.map(|_| {
+				let db = db.clone();
+				let receiver = receiver.clone();
+				thread::spawn(move || run_worker(&db, &receiver))
+			})
+			.collect();
+		AsyncDatabase {
+			db,
+			jobs: Mutex::new(Some(sender)),
+			workers,
+		}
+	}
+
+	/// Returns the wrapped database, for operations that are cheap enough to
+	/// run synchronously.
+	pub fn database(&self) -> &Database {
+		&self.db
+	}
+
+	/// Runs an operation on a worker thread, returning its result.
+	///
+	/// Panics in the operation are caught on the worker, which keeps running,
+	/// and resumed with the original payload when the future is polled.
+	pub fn run<T, F>(&self, callback: F) -> impl Future<Output = T>
+	where
+		T: Send + 'static,
+		F: FnOnce(&Database) -> T + Send + 'static,
+	{
+		let (sender, receiver) = oneshot::channel();
+		let job: Job = Box::new(move |db| {
+			let result = panic::catch_unwind(AssertUnwindSafe(|| callback(db)));
+			let _ = sender.send(result);
+		});
+		if let Some(jobs) = &*self.jobs.lock().unwrap_or_else(|err| err.into_inner()) {
+			let _ = jobs.send(job);
+		}
+		async move {
+			match receiver.await {
+				Ok(Ok(result)) => result,
+				Ok(Err(payload)) => panic::resume_unwind(payload),
+				Err(_) => panic!("database operation was dropped by the worker thread"),
+			}
+		}
+	}
+
+	/// Reads a record. See `Collection::get`.
+	pub async fn get(&self, collection: &str, key: &str) -> Result<Option<Vec<u8>>> {
+		let (collection, key) = (collection.to_string(), key.to_string());
+		self.run(move |db| db.collection(collection)?.get(&key))
+			.await
+	}
+
+	/// Writes a record. See `Collection::put`.
+	pub async fn put(&self, collection: &str, key: &str, value: Vec<u8>) -> Result<()> {
+		let (collection, key) = (collection.to_string(), key.to_string());
+		self.run(move |db| db.collection(collection)?.put(&key, &value))
+			.await
+	}
+
+	/// Deletes a record. See `Collection::delete`.
+	pub async fn delete(&self, collection: &str, key: &str) -> Result<bool> {
+		let (collection, key) = (collection.to_string(), key.to_string());
+		self.run(move |db| db.collection(collection)?.delete(&key))
+			.await
+	}
+
+	/// Reads all records in a collection, as key and value pairs sorted by
+	/// key.
+	pub async fn iter(&self, collection: &str) -> Result<Vec<(String, Vec<u8>)>> {
+		let collection = collection.to_string();
+		self.run(move |db| {
+			let collection = db.collection(collection)?;
+			let mut records = Vec::new();
+			for key in collection.keys()? {
+				// Records may be deleted by another thread while iterating.
+				if let Some(value) = collection.get(&key)? {
+					records.push((key, value));
+				}
+			}
+			Ok(records)
+		})
+		.await
+	}
+}
+
+impl Drop for AsyncDatabase {
+	fn drop(&mut self) {
+		// Closing the channel stops the workers once pending jobs are done.
+		self.jobs
+			.lock()
+			.unwrap_or_else(|err| err.into_inner())
+			.take();
+		for worker in self.workers.drain(..) {
+			let _ = worker.join();
+		}
+	}
+}
+
+fn run_worker(db: &Database, receiver: &Mutex<Receiver<Job>>) {
+	loop {
+		let job = receiver
+			.lock()
+			.unwrap_or_else(|err| err.into_inner())
+			.recv();
+		match job {
+			Ok(job) => job(db),
+			Err(_) => return,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use futures_executor::block_on;
+
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_run_operations_on_workers() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let db = AsyncDatabase::new(db, 2);
+
+		block_on(async {
+			db.put("notes", "a", b"A".to_vec()).await.unwrap();
+			db.put("notes", "b", b"B".to_vec()).await.unwrap();
+			assert_eq!(db.get("notes", "a").await.unwrap().unwrap(), b"A");
+			assert!(db.delete("notes", "b").await.unwrap());
+			assert!(db.get("notes", "b").await.unwrap().is_none());
+			assert!(db.get("notes", "../x").await.is_err());
+
+			let records = db.iter("notes").await.unwrap();
+			assert_eq!(records, vec![("a".to_string(), b"A".to_vec())]);
+			assert_eq!(db.run(|db| db.sequence()).await, 3);
+		});
+		assert_eq!(db.database().sequence(), 3);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_survive_panics_in_operations() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let db = AsyncDatabase::new(db, 1);
+
+		for _ in 0..3 {
+			let result = panic::catch_unwind(AssertUnwindSafe(|| {
+				block_on(db.run(|_| -> () { panic!("operation failed") }))
+			}));
+			let payload = result.unwrap_err();
+			assert_eq!(payload.downcast_ref::<&str>(), Some(&"operation failed"));
+		}
+
+		// The only worker is still running.
+		block_on(async {
+			db.put("notes", "a", b"A".to_vec()).await.unwrap();
+			assert_eq!(db.get("notes", "a").await.unwrap().unwrap(), b"A");
+		});
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
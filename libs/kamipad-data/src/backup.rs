@@ -0,0 +1,443 @@
+//! Hot backups for the database.
+//!
+//! A backup is a plain copy of the database directory, which can be opened
+//! as a database on its own. Backups can be taken while the database is open
+//! for writing: writes are blocked while the copy is in progress, so the
+//! backup is always consistent.
+//!
+//! Temporary files and quarantined entries are never copied. The backup gets
+//! a fresh lock file.
+//!
+//! Incremental backups only contain the files changed after a given journal
+//! sequence number, together with the journal entries for those changes and
+//! a `backup` file describing the range of changes included:
+//!
+//! ```text
+//! base=<seq>
+//! seq=<seq>
+//! ```
+//!
+//! An incremental backup can only be restored on top of a database at its
+//! `base` sequence number, which is either a restored full backup or the
+//! result of restoring the previous incremental backup.
+//!
+//! `Database::clone_to` copies the database the same way as a full backup,
+//! but hard-links the record and blob files instead of copying them when
+//! possible. This is safe because these files are never changed in place:
+//! writes always replace them with a new file, so a change to either
+//! database never shows up in the other.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::blob::BLOBS_DIR;
+use crate::check::QUARANTINE_DIR;
+use crate::collection::DATA_DIR;
+use crate::database::Database;
+use crate::error::{Error, IOError};
+use crate::journal;
+use crate::mvcc::{COMMIT_LOCK_FILENAME, READERS_DIR};
+use crate::open::DB_LOCK_FILENAME;
+use crate::transaction::TRANSACTION_DIR;
+use crate::util::{self, read_error, write_error};
+use crate::Result;
+
+const BACKUP_INFO_FILENAME: &str = "backup";
+
+/// Summary of a backup.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct BackupStats {
+	/// Number of files copied.
+	pub files: usize,
+	/// Total size in bytes of the copied files.
+	pub bytes: u64,
+	/// Sequence number of the last change included in the backup.
+	pub seq: u64,
+}
+
+impl Database {
+	/// Creates a backup of the database at the given directory.
+	///
+	/// The target directory must not exist or be empty.
+	pub fn backup<P: AsRef<Path>>(&self, path: P) -> Result<BackupStats> {
+		let target = path.as_ref();
+		prepare_target(target)?;
+
+		// Holding the write lock guarantees a consistent snapshot.
+		let _guard = self.write_guard();
+
+		let mut stats = BackupStats {
+			seq: last_committed(self)?,
+			..Default::default()
+		};
+		copy_dir(&self.path, target, true, false, &mut stats)?;
+
+		let lock_path = target.join(DB_LOCK_FILENAME);
+		fs::write(&lock_path, b"").map_err(|err| util::write_error(err, &lock_path))?;
+		Ok(stats)
+	}
+
+	/// Creates an independent copy of the database at the given directory,
+	/// which can be opened and changed without affecting this database.
+	///
+	/// This is the same as `backup`, but faster and using less space, since
+	/// records and blobs are shared with the copy until changed. The target
+	/// directory must not exist or be empty.
+	pub fn clone_to<P: AsRef<Path>>(&self, path: P) -> Result<BackupStats> {
+		let target = path.as_ref();
+		prepare_target(target)?;
+
+		let _guard = self.write_guard();
+		let mut stats = BackupStats {
+			seq: last_committed(self)?,
+			..Default::default()
+		};
+		copy_dir(&self.path, target, true, true, &mut stats)?;
+
+		let lock_path = target.join(DB_LOCK_FILENAME);
+		fs::write(&lock_path, b"").map_err(|err| util::write_error(err, &lock_path))?;
+		Ok(stats)
+	}
+
+	/// Creates an incremental backup at the given directory, containing only
+	/// the changes made after the sequence number `since`.
+	///
+	/// Use the `seq` from the previous backup as `since` to create a chain
+	/// of backups. The target directory must not exist or be empty. Fails
+	/// with `Error::JournalTruncated` if the changes were removed from the
+	/// journal, in which case a full backup is needed.
+	pub fn backup_incremental<P: AsRef<Path>>(&self, path: P, since: u64) -> Result<BackupStats> {
+		let target = path.as_ref();
+		prepare_target(target)?;
+
+		let _guard = self.write_guard();
+		let seq = last_committed(self)?;
+		if since > seq {
+			return Err(Error::InvalidBackup(format!(
+				"sequence {} is past the last change ({})",
+				since, seq
+			)));
+		}
+
+		let entries = self.changes_since(since)?;
+		let mut stats = BackupStats {
+			seq,
+			..Default::default()
+		};
+
+		// Files that were deleted since are simply missing from the backup.
+		for relative in changed_paths(&entries) {
+			let source_path = self.path.join(&relative);
+			if !source_path.is_file() {
+				continue;
+			}
+			let target_path = target.join(&relative);
+			let bytes = fs::create_dir_all(target_path.parent().unwrap())
+				.and_then(|_| fs::copy(&source_path, &target_path))
+				.map_err(|err| write_error(err, &target_path))?;
+			stats.files += 1;
+			stats.bytes += bytes;
+		}
+
+		journal::append_journal(target, &entries)?;
+		let info_path = target.join(BACKUP_INFO_FILENAME);
+		let info = format!("base={}\nseq={}\n", since, seq);
+		fs::write(&info_path, info).map_err(|err| write_error(err, &info_path))?;
+		Ok(stats)
+	}
+
+	/// Applies an incremental backup created by `backup_incremental` to the
+	/// database.
+	///
+	/// The database must be at the exact sequence number the backup was
+	/// based on.
+	pub fn restore_incremental<P: AsRef<Path>>(&self, path: P) -> Result<BackupStats> {
+		let source = path.as_ref();
+		self.check_writable()?;
+		let _guard = self.write_guard();
+
+		let (base, seq) = read_backup_info(source)?;
+		if self.sequence() != base {
+			return Err(Error::InvalidBackup(format!(
+				"backup is based on sequence {}, but the database is at {}",
+				base,
+				self.sequence()
+			)));
+		}
+
+		let entries = journal::read_journal(source)?;
+		if entries.last().map(|entry| entry.seq).unwrap_or(base) != seq {
+			return Err(Error::InvalidBackup("journal does not match backup".into()));
+		}
+
+		let mut stats = BackupStats {
+			seq,
+			..Default::default()
+		};
+		for relative in changed_paths(&entries) {
+			let source_path = source.join(&relative);
+			let target_path = self.path.join(&relative);
+			if source_path.is_file() {
+				// Copy through a temporary file, so that readers never see a
+				// partially written file.
+				let data = fs::read(&source_path).map_err(|err| read_error(err, &source_path))?;
+				fs::create_dir_all(target_path.parent().unwrap())
+					.and_then(|_| util::write_file(&target_path, &data))
+					.map_err(|err| write_error(err, &target_path))?;
+				stats.files += 1;
+				stats.bytes += data.len() as u64;
+			} else {
+				match fs::remove_file(&target_path) {
+					Ok(_) => {}
+					Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+					Err(err) => return Err(write_error(err, &target_path)),
+				}
+			}
+		}
+
+		journal::append_journal(&self.path, &entries)?;
+		self.reset_usage();
+		*self.last_seq() = seq;
+		for entry in entries {
+			self.queue_notification(entry);
+		}
+		Ok(stats)
+	}
+}
+
+/// Returns the set of files affected by the given journal entries.
+pub(crate) fn changed_paths(entries: &[journal::JournalEntry]) -> BTreeSet<PathBuf> {
+	entries
+		.iter()
+		.flat_map(|entry| entry.change.paths())
+		.collect()
+}
+
+/// Reads the `base` and `seq` from an incremental backup.
+fn read_backup_info(source: &Path) -> Result<(u64, u64)> {
+	let path = source.join(BACKUP_INFO_FILENAME);
+	let text = match fs::read_to_string(&path) {
+		Ok(text) => text,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {
+			return Err(Error::InvalidBackup(format!(
+				"`{}` is not an incremental backup",
+				source.to_string_lossy()
+			)));
+		}
+		Err(err) => return Err(read_error(err, &path)),
+	};
+
+	let params = text
+		.lines()
+		.filter_map(|line| {
+			let mut parts = line.splitn(2, '=');
+			Some((parts.next()?.trim(), parts.next()?.trim()))
+		})
+		.collect::<HashMap<_, _>>();
+	let base = params.get("base").and_then(|s| s.parse().ok());
+	let seq = params.get("seq").and_then(|s| s.parse().ok());
+	match (base, seq) {
+		(Some(base), Some(seq)) if base <= seq => Ok((base, seq)),
+		_ => Err(Error::InvalidBackup(format!(
+			"invalid backup file `{}`",
+			path.to_string_lossy()
+		))),
+	}
+}
+
+/// Makes sure the backup target exists and is empty.
+pub(crate) fn prepare_target(target: &Path) -> Result<()> {
+	let backup_error = |err| {
+		Error::Write(IOError::new(
+			err,
+			format!("creating backup at `{}`", target.to_string_lossy()),
+		))
+	};
+
+	fs::create_dir_all(target).map_err(backup_error)?;
+	let is_empty = fs::read_dir(target)
+		.map_err(|err| read_error(err, target))?
+		.next()
+		.is_none();
+	if !is_empty {
+		let err = io::Error::new(io::ErrorKind::AlreadyExists, "directory is not empty");
+		return Err(backup_error(err));
+	}
+	Ok(())
+}
+
+/// Returns the sequence number of the last change in the journal. Backups
+/// copy the current files, which for a reader may be ahead of its own view
+/// of the database, so this is used instead of `Database::sequence`.
+fn last_committed(db: &Database) -> Result<u64> {
+	let entries = journal::read_journal(&db.path)?;
+	Ok(entries.last().map(|entry| entry.seq).unwrap_or(0))
+}
+
+/// Recursively copies the database files from `source` to `target`. With
+/// `link`, record and blob files are hard-linked if possible.
+fn copy_dir(
+	source: &Path,
+	target: &Path,
+	root: bool,
+	link: bool,
+	stats: &mut BackupStats,
+) -> Result<()> {
+	let entries = fs::read_dir(source).map_err(|err| read_error(err, source))?;
+	for entry in entries {
+		let entry = entry.map_err(|err| read_error(err, source))?;
+		let name = entry.file_name();
+		let source_path = entry.path();
+		let target_path: PathBuf = target.join(&name);
+
+		let skipped = [
+			DB_LOCK_FILENAME,
+			COMMIT_LOCK_FILENAME,
+			QUARANTINE_DIR,
+			READERS_DIR,
+			TRANSACTION_DIR,
+		];
+		if root && skipped.iter().any(|skipped| name == *skipped) {
+			continue;
+		}
+
+		let file_type = entry
+			.file_type()
+			.map_err(|err| read_error(err, &source_path))?;
+		if file_type.is_dir() {
+			fs::create_dir(&target_path).map_err(|err| util::write_error(err, &target_path))?;
+			// Only records and blobs are never changed in place.
+			let link = link && (!root || name == DATA_DIR || name == BLOBS_DIR);
+			copy_dir(&source_path, &target_path, false, link, stats)?;
+		} else if file_type.is_file() && !util::is_temp_file(&source_path) {
+			let linked = !root && link && fs::hard_link(&source_path, &target_path).is_ok();
+			let bytes = if linked {
+				entry.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+			} else {
+				fs::copy(&source_path, &target_path)
+					.map_err(|err| util::write_error(err, &target_path))?
+			};
+			stats.files += 1;
+			stats.bytes += bytes;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use crate::testing::create_db;
+	use crate::{open, Error, OpenFlags};
+
+	#[test]
+	fn should_backup_open_database() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		let backup_path = temp.path().join("backup");
+		let stats = db.backup(&backup_path).unwrap();
+		assert_eq!(stats.files, 6);
+		assert_eq!(stats.seq, 3);
+
+		// Changes after the backup are not visible in it.
+		notes.put("c", b"C").unwrap();
+
+		let backup = open(&backup_path, OpenFlags::read_only()).unwrap();
+		let backup_notes = backup.collection("notes").unwrap();
+		assert_eq!(backup_notes.keys().unwrap(), vec!["a", "b"]);
+		assert_eq!(backup.get_blob(&blob).unwrap().unwrap(), b"blob");
+		assert!(backup.check().unwrap().is_ok());
+
+		// Backups never overwrite existing data.
+		assert!(db.backup(&backup_path).is_err());
+
+		drop(backup);
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_clone_database() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		let clone_path = temp.path().join("clone");
+		let stats = db.clone_to(&clone_path).unwrap();
+		assert_eq!(stats.files, 6);
+		assert_eq!(stats.seq, 3);
+
+		let clone = open(&clone_path, OpenFlags::default()).unwrap();
+		let clone_notes = clone.collection("notes").unwrap();
+		assert_eq!(clone.sequence(), 3);
+
+		// Changes to either database don't affect the other.
+		notes.put("a", b"A2").unwrap();
+		clone_notes.put("b", b"B2").unwrap();
+		clone_notes.delete("a").unwrap();
+		clone.release_blob(&blob).unwrap();
+		clone.gc_blobs().unwrap();
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A2");
+		assert_eq!(notes.get("b").unwrap().unwrap(), b"B");
+		assert_eq!(db.get_blob(&blob).unwrap().unwrap(), b"blob");
+		assert_eq!(clone_notes.keys().unwrap(), vec!["b"]);
+		assert_eq!(db.sequence(), 4);
+		assert!(db.check().unwrap().is_ok());
+		assert!(clone.check().unwrap().is_ok());
+
+		drop(clone);
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_backup_incrementally() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+
+		let full_path = temp.path().join("full");
+		let full = db.backup(&full_path).unwrap();
+
+		notes.put("b", b"B2").unwrap();
+		notes.put("c", b"C").unwrap();
+		notes.delete("a").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		let incr_path = temp.path().join("incr");
+		let incr = db.backup_incremental(&incr_path, full.seq).unwrap();
+		assert_eq!(incr.files, 4);
+		assert_eq!(incr.seq, db.sequence());
+		assert!(!incr_path.join("data").join("notes").join("a").exists());
+
+		// Restore the full backup and apply the incremental on top.
+		let restored = open(&full_path, OpenFlags::default()).unwrap();
+		assert_eq!(restored.sequence(), full.seq);
+		restored.restore_incremental(&incr_path).unwrap();
+		assert_eq!(restored.sequence(), incr.seq);
+
+		let restored_notes = restored.collection("notes").unwrap();
+		assert_eq!(restored_notes.keys().unwrap(), vec!["b", "c"]);
+		assert_eq!(restored_notes.get("b").unwrap().unwrap(), b"B2");
+		assert_eq!(restored.get_blob(&blob).unwrap().unwrap(), b"blob");
+		assert!(restored.check().unwrap().is_ok());
+
+		// The same backup cannot be applied twice.
+		match restored.restore_incremental(&incr_path) {
+			Err(Error::InvalidBackup(_)) => (),
+			other => panic!("expected Error::InvalidBackup, got {:?}", other),
+		}
+
+		drop(restored);
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,513 @@
+//! Content-addressed storage for external data blobs.
+//!
+//! Notes can only contain text, so any non-textual content is stored in the
+//! database as an opaque blob. Blobs live under the `blobs` directory and are
+//! named after the SHA-256 hash of their content, which means identical data
+//! is only ever stored once.
+//!
+//! Each blob has a reference count stored alongside it in a `.refs` file.
+//! Saving the same content again just increments the count, while releasing
+//! a blob decrements it. Blobs are never removed when their count reaches
+//! zero; instead `Database::gc_blobs` sweeps the directory and removes any
+//! blob no longer referenced.
+//!
+//! Records are opaque to the database, so the count is the only record of
+//! the references to a blob: every `put_blob` and `retain_blob` must be
+//! matched by a `release_blob` once the reference is dropped. To keep the
+//! count trustworthy:
+//!
+//! - A blob and its count are changed together, atomically through the
+//!   journal the same as for transactions (see the `transaction` module), so
+//!   an interrupted change is rolled back when the database is next opened.
+//! - Both files are synced to disk before the change completes.
+//! - A count that can't be read is never taken as zero. The blob is kept by
+//!   `gc_blobs` until `Database::repair` resets the count.
+//!
+//! Collecting a blob only removes files of a blob without references, so it
+//! is not journaled: an interrupted collection leaves the blob or its count
+//! for the next one.
+//!
+//! Blob files use the same format as records (see the `record` module), so
+//! they are encrypted along with records when the database is encrypted. The
+//! content address is always computed from the unencrypted data.
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::database::Database;
+use crate::error::{Error, IOError};
+use crate::journal::Change;
+use crate::record::Codec;
+use crate::util;
+use crate::Result;
+
+pub(crate) const BLOBS_DIR: &str = "blobs";
+
+pub(crate) const REFS_EXTENSION: &str = "refs";
+
+lazy_static! {
+	static ref RE_BLOB_ID: Regex = Regex::new(r"^[0-9a-f]{64}$").unwrap();
+}
+
+/// Content address for a blob, which is the hex-encoded SHA-256 hash of the
+/// blob's data.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BlobId(String);
+
+impl BlobId {
+	/// Computes the ID for the given blob content.
+	pub fn of(data: &[u8]) -> BlobId {
+		BlobId(format!("{:x}", Sha256::digest(data)))
+	}
+
+	/// Parses a string into a BlobId.
+	pub fn parse<S: AsRef<str>>(input: S) -> Option<BlobId> {
+		let input = input.as_ref();
+		if RE_BLOB_ID.is_match(input) {
+			Some(BlobId(input.to_string()))
+		} else {
+			None
+		}
+	}
+
+	/// Returns the ID as a string.
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Display for BlobId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.0.fmt(f)
+	}
+}
+
+impl fmt::Debug for BlobId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		<Self as fmt::Display>::fmt(self, f)
+	}
+}
+
+/// Summary of a `Database::gc_blobs` run.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct BlobGcStats {
+	/// Number of blobs removed.
+	pub removed: usize,
+	/// Total size in bytes of the removed blobs.
+	pub removed_bytes: u64,
+	/// Number of blobs still referenced after the collection.
+	pub retained: usize,
+}
+
+impl Database {
+	/// Stores a blob in the database, returning its content address.
+	///
+	/// If a blob with the same content already exists, it is not written
+	/// again and just has its reference count incremented.
+	pub fn put_blob(&self, data: &[u8]) -> Result<BlobId> {
+		self.check_writable()?;
+
+		let id = BlobId::of(data);
+		let _guard = self.write_guard();
+		let exists = self.blob_path(&id).is_file();
+		if !exists {
+			self.check_quota(data.len() as i64, 0)?;
+		}
+
+		let refs = self.read_blob_refs(&id)?;
+		self.apply_atomically(&[Change::PutBlob(id.clone())], || {
+			self.log_change(Change::PutBlob(id.clone()))?;
+			if refs == 0 || !exists {
+				self.write_blob(&id, data)?;
+			}
+			self.write_blob_refs(&id, refs + 1)
+		})?;
+		Ok(id)
+	}
+
+	/// Stores a blob with the given reference count, replacing the count of
+	/// an existing blob. Used when importing data.
+	pub(crate) fn put_blob_with_refs(&self, data: &[u8], refs: usize) -> Result<BlobId> {
+		self.check_writable()?;
+
+		let id = BlobId::of(data);
+		let _guard = self.write_guard();
+		let exists = self.blob_path(&id).is_file();
+		if !exists {
+			self.check_quota(data.len() as i64, 0)?;
+		}
+		self.apply_atomically(&[Change::PutBlob(id.clone())], || {
+			self.log_change(Change::PutBlob(id.clone()))?;
+			if !exists {
+				self.write_blob(&id, data)?;
+			}
+			self.write_blob_refs(&id, refs)
+		})?;
+		Ok(id)
+	}
+
+	/// Reads a blob from the database. Returns `None` if the blob does not
+	/// exist.
+	///
+	/// Note that this will still return blobs that have no references but
+	/// haven't been collected yet.
+	pub fn get_blob(&self, id: &BlobId) -> Result<Option<Vec<u8>>> {
+		let blob_path = self.blob_path(id);
+		let read_error = |err| {
+			Error::Read(IOError::new(
+				err,
+				format!("reading blob `{}`", blob_path.to_string_lossy()),
+			))
+		};
+		let data = match self.read_file(&blob_path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(read_error(err)),
+		};
+
+		// Since blobs are content addressed, we can also verify the decoded
+		// content against the address.
+		let data = self
+			.blob_codec()
+			.decode(&data)
+			.map_err(|err| util::corrupt_error(err, &blob_path))?;
+		if &BlobId::of(&data) != id {
+			let err = util::invalid_data("content does not match blob id");
+			return Err(util::corrupt_error(err, &blob_path));
+		}
+		Ok(Some(data))
+	}
+
+	/// Returns the current reference count for a blob. A blob that does not
+	/// exist has zero references.
+	///
+	/// Fails with `Error::Corrupt` if the count can't be read, which
+	/// `Database::repair` fixes.
+	pub fn blob_refs(&self, id: &BlobId) -> Result<usize> {
+		self.read_blob_refs(id)
+	}
+
+	/// Adds a reference to an existing blob, returning the new reference
+	/// count.
+	pub fn retain_blob(&self, id: &BlobId) -> Result<usize> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+		if !self.blob_path(id).is_file() {
+			return Err(Error::NotFound(format!("blob {}", id)));
+		}
+		let refs = self.read_blob_refs(id)? + 1;
+		self.update_blob_refs(id, refs)?;
+		Ok(refs)
+	}
+
+	/// Drops a reference to a blob, returning the remaining reference count.
+	///
+	/// Blobs without references are only removed by `gc_blobs`.
+	pub fn release_blob(&self, id: &BlobId) -> Result<usize> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+		let refs = self.read_blob_refs(id)?;
+		if refs == 0 {
+			return Err(Error::NotFound(format!("blob {}", id)));
+		}
+		self.update_blob_refs(id, refs - 1)?;
+		Ok(refs - 1)
+	}
+
+	/// Removes all blobs that are no longer referenced.
+	pub fn gc_blobs(&self) -> Result<BlobGcStats> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+
+		let mut stats = BlobGcStats::default();
+		let blobs_dir = self.path.join(BLOBS_DIR);
+		let entries = match fs::read_dir(&blobs_dir) {
+			Ok(entries) => entries,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(stats),
+			Err(err) => {
+				return Err(Error::Read(IOError::new(
+					err,
+					format!("listing blobs at `{}`", blobs_dir.to_string_lossy()),
+				)))
+			}
+		};
+
+		// Files may already be gone, since we remove a blob and its
+		// reference file together while iterating.
+		let remove = |path: &PathBuf| match fs::remove_file(path) {
+			Ok(_) => Ok(()),
+			Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+			Err(err) => Err(Error::Write(IOError::new(
+				err,
+				format!("removing `{}`", path.to_string_lossy()),
+			))),
+		};
+
+		for entry in entries {
+			let entry = entry.map_err(|err| {
+				Error::Read(IOError::new(
+					err,
+					format!("listing blobs at `{}`", blobs_dir.to_string_lossy()),
+				))
+			})?;
+			let path = entry.path();
+			let name = entry.file_name();
+			let name = name.to_string_lossy();
+
+			// Reference files are handled with their blobs, except for those
+			// that lost their blob.
+			if let Some(name) = name.strip_suffix(&format!(".{}", REFS_EXTENSION)) {
+				if !blobs_dir.join(name).is_file() {
+					remove(&path)?;
+				}
+				continue;
+			}
+
+			let id = match BlobId::parse(&*name) {
+				Some(id) => id,
+				// Leftover temporary files from an interrupted write.
+				None if util::is_temp_file(&path) => {
+					remove(&path)?;
+					continue;
+				}
+				None => continue,
+			};
+
+			let referenced = match self.read_blob_refs(&id) {
+				Ok(refs) => refs > 0,
+				Err(Error::Corrupt(_)) => true,
+				Err(err) => return Err(err),
+			};
+			if referenced {
+				stats.retained += 1;
+			} else {
+				let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+				self.log_change(Change::DeleteBlob(id.clone()))?;
+				remove(&path)?;
+				remove(&self.blob_refs_path(&id))?;
+				self.update_usage(-(size as i64), 0);
+				stats.removed += 1;
+				stats.removed_bytes += size;
+			}
+		}
+
+		Ok(stats)
+	}
+
+	// Blobs are usually binary data that doesn't compress well, so we don't
+	// bother compressing them.
+	fn blob_codec(&self) -> Codec<'_> {
+		Codec {
+			compress: false,
+			..self.codec()
+		}
+	}
+
+	fn write_blob(&self, id: &BlobId, data: &[u8]) -> Result<()> {
+		let blob_path = self.blob_path(id);
+		let previous = fs::metadata(&blob_path).map(|m| m.len()).unwrap_or(0);
+		let data = fs::create_dir_all(self.path.join(BLOBS_DIR))
+			.and_then(|_| self.blob_codec().encode(data))
+			.and_then(|data| crate::fs::atomic_write(&blob_path, &data).map(|_| data))
+			.map_err(|err| {
+				Error::Write(IOError::new(
+					err,
+					format!("writing blob `{}`", blob_path.to_string_lossy()),
+				))
+			})?;
+		self.update_usage(data.len() as i64 - previous as i64, 0);
+		Ok(())
+	}
+
+	fn blob_path(&self, id: &BlobId) -> PathBuf {
+		self.path.join(BLOBS_DIR).join(id.as_str())
+	}
+
+	fn blob_refs_path(&self, id: &BlobId) -> PathBuf {
+		self.blob_path(id).with_extension(REFS_EXTENSION)
+	}
+
+	fn read_blob_refs(&self, id: &BlobId) -> Result<usize> {
+		let refs_path = self.blob_refs_path(id);
+		let text = match self.read_file(&refs_path) {
+			Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+			Err(err) => {
+				return Err(Error::Read(IOError::new(
+					err,
+					format!("reading `{}`", refs_path.to_string_lossy()),
+				)))
+			}
+		};
+
+		text.trim().parse().map_err(|_| {
+			let err = util::invalid_data("invalid reference count");
+			util::corrupt_error(err, &refs_path)
+		})
+	}
+
+	/// Changes the reference count of an existing blob. Must be called with
+	/// the write lock held.
+	fn update_blob_refs(&self, id: &BlobId, refs: usize) -> Result<()> {
+		self.apply_atomically(&[Change::PutBlob(id.clone())], || {
+			self.log_change(Change::PutBlob(id.clone()))?;
+			self.write_blob_refs(id, refs)
+		})
+	}
+
+	fn write_blob_refs(&self, id: &BlobId, refs: usize) -> Result<()> {
+		let refs_path = self.blob_refs_path(id);
+		let data = format!("{}\n", refs);
+		crate::fs::atomic_write(&refs_path, data.as_bytes()).map_err(|err| {
+			Error::Write(IOError::new(
+				err,
+				format!("writing `{}`", refs_path.to_string_lossy()),
+			))
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_deduplicate_blobs() {
+		let (db, temp) = create_db(OpenFlags::default());
+
+		let a1 = db.put_blob(b"some data").unwrap();
+		let a2 = db.put_blob(b"some data").unwrap();
+		let b = db.put_blob(b"other data").unwrap();
+		assert_eq!(a1, a2);
+		assert_ne!(a1, b);
+
+		assert_eq!(db.blob_refs(&a1).unwrap(), 2);
+		assert_eq!(db.blob_refs(&b).unwrap(), 1);
+		assert_eq!(db.get_blob(&a1).unwrap().unwrap(), b"some data");
+
+		let files = fs::read_dir(db.path.join(BLOBS_DIR)).unwrap().count();
+		assert_eq!(files, 4);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_collect_unreferenced_blobs() {
+		let (db, temp) = create_db(OpenFlags::default());
+
+		let a = db.put_blob(b"A").unwrap();
+		let b = db.put_blob(b"B").unwrap();
+		db.retain_blob(&a).unwrap();
+
+		assert_eq!(db.release_blob(&a).unwrap(), 1);
+		assert_eq!(db.release_blob(&b).unwrap(), 0);
+
+		// Released blobs are kept until collected.
+		assert!(db.get_blob(&b).unwrap().is_some());
+
+		let stats = db.gc_blobs().unwrap();
+		assert_eq!(stats.removed, 1);
+		let expected_size = db.blob_codec().encode(b"B").unwrap().len();
+		assert_eq!(stats.removed_bytes, expected_size as u64);
+		assert_eq!(stats.retained, 1);
+
+		assert!(db.get_blob(&a).unwrap().is_some());
+		assert!(db.get_blob(&b).unwrap().is_none());
+		assert!(db.release_blob(&b).is_err());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_keep_blobs_with_damaged_refs() {
+		let (db, temp) = create_db(OpenFlags::default());
+
+		let a = db.put_blob(b"A").unwrap();
+		fs::write(db.blob_refs_path(&a), b"").unwrap();
+		match db.blob_refs(&a) {
+			Err(Error::Corrupt(_)) => (),
+			other => panic!("expected Error::Corrupt, got {:?}", other),
+		}
+		assert!(db.release_blob(&a).is_err());
+
+		let stats = db.gc_blobs().unwrap();
+		assert_eq!(stats.removed, 0);
+		assert_eq!(stats.retained, 1);
+		assert!(db.get_blob(&a).unwrap().is_some());
+
+		db.repair().unwrap();
+		assert_eq!(db.blob_refs(&a).unwrap(), 1);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_rollback_failed_blob_changes() {
+		let (db, temp) = create_db(OpenFlags::default());
+
+		let a = db.put_blob(b"A").unwrap();
+		let b = BlobId::of(b"B");
+		let result = {
+			let _guard = db.write_guard();
+			let changes = [Change::PutBlob(a.clone()), Change::PutBlob(b.clone())];
+			db.apply_atomically(&changes, || {
+				db.log_change(Change::PutBlob(a.clone()))?;
+				db.write_blob_refs(&a, 5)?;
+				db.log_change(Change::PutBlob(b.clone()))?;
+				db.write_blob(&b, b"B")?;
+				Err::<(), _>(Error::NotFound("test".into()))
+			})
+		};
+		assert!(result.is_err());
+		assert_eq!(db.blob_refs(&a).unwrap(), 1);
+		assert!(db.get_blob(&b).unwrap().is_none());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_detect_corrupted_blobs() {
+		let (db, temp) = create_db(OpenFlags::default());
+
+		let a = db.put_blob(b"some data").unwrap();
+		let b = db.put_blob(b"other data").unwrap();
+
+		// Replace the content of `b` with a valid blob file for `a`.
+		fs::copy(db.blob_path(&a), db.blob_path(&b)).unwrap();
+		match db.get_blob(&b) {
+			Err(Error::Corrupt(_)) => (),
+			other => panic!("expected Error::Corrupt, got {:?}", other),
+		}
+
+		fs::write(db.blob_path(&a), b"garbage").unwrap();
+		match db.get_blob(&a) {
+			Err(Error::Corrupt(_)) => (),
+			other => panic!("expected Error::Corrupt, got {:?}", other),
+		}
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_parse_blob_id() {
+		let id = BlobId::of(b"");
+		assert_eq!(
+			id.as_str(),
+			"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+		);
+		assert_eq!(BlobId::parse(id.as_str()), Some(id.clone()));
+		assert!(BlobId::parse(&id.as_str()[1..]).is_none());
+		assert!(BlobId::parse(id.as_str().to_uppercase()).is_none());
+	}
+}
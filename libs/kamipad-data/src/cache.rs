@@ -0,0 +1,208 @@
+//! In-memory cache of decoded records.
+//!
+//! When `OpenFlags::cache_size` is set, records read with `Collection::get`
+//! are kept in memory, up to the given number of bytes, so that reading a
+//! hot record doesn't require reading and decoding its file again. Once the
+//! cache is full, the least recently used records are evicted.
+//!
+//! Every change to a record goes through the write guard, so the cache
+//! drops the records changed by a write when the guard is released, and
+//! readers do the same for the changes seen by `poll_changes`.
+//!
+//! A read may race with a write to the same record, and end up with the
+//! value from before the write after the guard already dropped it from the
+//! cache. To avoid caching that value, the cache has a generation number
+//! that is incremented whenever the write guard is acquired, and a value is
+//! only cached if no write started since it was read.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::record::Header;
+
+/// Bytes accounted for each entry, in addition to the key and payload.
+const ENTRY_OVERHEAD: usize = 64;
+
+type CacheKey = (String, String);
+
+struct CacheEntry {
+	header: Header,
+	payload: Vec<u8>,
+	size: usize,
+	tick: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct RecordCache {
+	budget: usize,
+	used: usize,
+	tick: u64,
+	generation: u64,
+	entries: HashMap<CacheKey, CacheEntry>,
+	// Keys by last access, for eviction.
+	lru: BTreeMap<u64, CacheKey>,
+}
+
+impl RecordCache {
+	/// Creates a cache holding up to `budget` bytes. A zero budget disables
+	/// the cache.
+	pub fn new(budget: usize) -> RecordCache {
+		RecordCache {
+			budget,
+			..Default::default()
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.budget > 0
+	}
+
+	/// Returns the current generation, to be passed to `insert` for a value
+	/// read after this call.
+	pub fn generation(&self) -> u64 {
+		self.generation
+	}
+
+	/// Starts a write, discarding any value read before it.
+	pub fn begin_write(&mut self) {
+		self.generation += 1;
+	}
+
+	/// Returns a cached record, marking it as recently used.
+	pub fn get(&mut self, collection: &str, key: &str) -> Option<(Header, Vec<u8>)> {
+		let cache_key = (collection.to_string(), key.to_string());
+		self.tick += 1;
+		let entry = self.entries.get_mut(&cache_key)?;
+		self.lru.remove(&entry.tick);
+		entry.tick = self.tick;
+		self.lru.insert(entry.tick, cache_key);
+		Some((entry.header.clone(), entry.payload.clone()))
+	}
+
+	/// Caches a record read at the given generation, unless a write started
+	/// since then.
+	pub fn insert(
+		&mut self,
+		generation: u64,
+		collection: &str,
+		key: &str,
+		header: &Header,
+		payload: &[u8],
+	) {
+		let size = ENTRY_OVERHEAD + collection.len() + key.len() + payload.len();
+		if generation != self.generation || size > self.budget {
+			return;
+		}
+
+		self.remove(collection, key);
+		while self.used + size > self.budget {
+			let oldest = match self.lru.keys().next() {
+				Some(tick) => *tick,
+				None => break,
+			};
+			let (collection, key) = self.lru[&oldest].clone();
+			self.remove(&collection, &key);
+		}
+
+		self.tick += 1;
+		let cache_key = (collection.to_string(), key.to_string());
+		self.lru.insert(self.tick, cache_key.clone());
+		self.entries.insert(
+			cache_key,
+			CacheEntry {
+				header: header.clone(),
+				payload: payload.to_vec(),
+				size,
+				tick: self.tick,
+			},
+		);
+		self.used += size;
+	}
+
+	/// Removes a record from the cache.
+	pub fn remove(&mut self, collection: &str, key: &str) {
+		let cache_key = (collection.to_string(), key.to_string());
+		if let Some(entry) = self.entries.remove(&cache_key) {
+			self.lru.remove(&entry.tick);
+			self.used -= entry.size;
+		}
+	}
+
+	/// Removes all records from the cache.
+	pub fn clear(&mut self) {
+		self.entries.clear();
+		self.lru.clear();
+		self.used = 0;
+		self.generation += 1;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_evict_least_recently_used() {
+		let mut cache = RecordCache::new(3 * (ENTRY_OVERHEAD + 2 + 10));
+		let header = Header::default();
+		for key in &["a", "b", "c"] {
+			cache.insert(0, "n", key, &header, &[0; 10]);
+		}
+		assert!(cache.get("n", "a").is_some());
+		cache.insert(0, "n", "d", &header, &[0; 10]);
+		assert!(cache.get("n", "b").is_none());
+		assert!(cache.get("n", "a").is_some());
+		assert!(cache.get("n", "c").is_some());
+		assert!(cache.get("n", "d").is_some());
+
+		// Values read before a write are not cached.
+		let generation = cache.generation();
+		cache.begin_write();
+		cache.insert(generation, "n", "e", &header, &[0; 10]);
+		assert!(cache.get("n", "e").is_none());
+
+		// Records larger than the budget are never cached.
+		cache.insert(cache.generation(), "n", "f", &header, &[0; 1000]);
+		assert!(cache.get("n", "f").is_none());
+		assert!(cache.get("n", "a").is_some());
+	}
+
+	#[test]
+	fn should_cache_records() {
+		let flags = OpenFlags::config(|f| f.cache_size = 1024);
+		let (db, temp) = create_db(flags);
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A1").unwrap();
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A1");
+		assert!(db.cache().get("notes", "a").is_some());
+
+		// Writes drop the cached record.
+		notes.put("a", b"A2").unwrap();
+		assert!(db.cache().get("notes", "a").is_none());
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A2");
+		notes.delete("a").unwrap();
+		assert!(notes.get("a").unwrap().is_none());
+
+		// Readers drop records changed by polled changes.
+		notes.put("b", b"B1").unwrap();
+		let reader = crate::open(
+			&db.path,
+			OpenFlags::config(|f| {
+				f.read_only = true;
+				f.cache_size = 1024;
+			}),
+		)
+		.unwrap();
+		let reader_notes = reader.collection("notes").unwrap();
+		assert_eq!(reader_notes.get("b").unwrap().unwrap(), b"B1");
+		notes.put("b", b"B2").unwrap();
+		assert_eq!(reader_notes.get("b").unwrap().unwrap(), b"B1");
+		reader.poll_changes().unwrap();
+		assert_eq!(reader_notes.get("b").unwrap().unwrap(), b"B2");
+
+		drop(reader);
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,162 @@
+//! Cascading deletes for linked records.
+//!
+//! Cascade rules are registered with `Database::add_cascade` for a pair of
+//! collections, and apply to records in the second collection that are
+//! linked from a deleted record in the first (see the `links` module):
+//!
+//! - `Cascade::Delete` deletes the linked records, failing the delete if they
+//!   are also linked from other records;
+//! - `Cascade::DeleteOrphans` deletes the linked records only if no other
+//!   record links to them.
+//!
+//! Rules apply recursively to the records deleted by them. All records are
+//! deleted in a single `Transaction`, so either all or none are deleted.
+//!
+//! Rules are kept in memory only, so they must be registered every time the
+//! database is opened.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::database::Database;
+use crate::links::{RecordId, BACKLINKS_DIR, LINKS_DIR};
+use crate::Result;
+
+/// What to do with linked records when deleting a record.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Cascade {
+	/// Delete the linked records.
+	Delete,
+	/// Delete the linked records that are not linked from any other record.
+	DeleteOrphans,
+}
+
+/// Cascade rules registered on a database, by collection pair.
+#[derive(Default)]
+pub(crate) struct Cascades {
+	rules: HashMap<(String, String), Cascade>,
+}
+
+impl Database {
+	/// Registers a rule for records in `linked_collection` that are linked
+	/// from a deleted record in `collection`, replacing any existing rule for
+	/// the same pair.
+	pub fn add_cascade(&self, collection: &str, linked_collection: &str, cascade: Cascade) {
+		let pair = (collection.to_string(), linked_collection.to_string());
+		self.cascades().rules.insert(pair, cascade);
+	}
+
+	/// Returns true if deleting records in the collection can cascade.
+	pub(crate) fn has_cascades(&self, collection: &str) -> bool {
+		let cascades = self.cascades();
+		cascades.rules.keys().any(|(from, _)| from == collection)
+	}
+
+	/// Returns the records to delete along with a record, in the order they
+	/// must be deleted. Records in `deleted` are already being deleted.
+	pub(crate) fn cascaded_deletes(
+		&self,
+		id: &RecordId,
+		deleted: &[RecordId],
+	) -> Result<Vec<RecordId>> {
+		let mut planned = deleted.to_vec();
+		planned.push(id.clone());
+		let mut cascaded = Vec::new();
+		let mut queue = VecDeque::new();
+		queue.push_back(id.clone());
+		while let Some(current) = queue.pop_front() {
+			for linked in self.read_links(LINKS_DIR, &current)? {
+				if planned.contains(&linked) {
+					continue;
+				}
+				let pair = (current.collection.clone(), linked.collection.clone());
+				let cascade = self.cascades().rules.get(&pair).cloned();
+				let delete = match cascade {
+					Some(Cascade::Delete) => true,
+					Some(Cascade::DeleteOrphans) => self
+						.read_links(BACKLINKS_DIR, &linked)?
+						.iter()
+						.all(|from| planned.contains(from)),
+					None => false,
+				};
+				if delete {
+					planned.push(linked.clone());
+					cascaded.push(linked.clone());
+					queue.push_back(linked);
+				}
+			}
+		}
+		Ok(cascaded)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::error::Error;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_cascade_deletes() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.add_cascade("notes", "files", Cascade::Delete);
+		db.add_cascade("notes", "tags", Cascade::DeleteOrphans);
+		db.add_cascade("files", "thumbs", Cascade::Delete);
+
+		let notes = db.collection("notes").unwrap();
+		let files = db.collection("files").unwrap();
+		let tags = db.collection("tags").unwrap();
+		let thumbs = db.collection("thumbs").unwrap();
+		for key in &["a", "b"] {
+			notes.put(key, b"").unwrap();
+		}
+		files.put("f", b"").unwrap();
+		thumbs.put("t", b"").unwrap();
+		tags.put("shared", b"").unwrap();
+		tags.put("own", b"").unwrap();
+
+		notes.link("a", &RecordId::new("files", "f")).unwrap();
+		files.link("f", &RecordId::new("thumbs", "t")).unwrap();
+		notes.link("a", &RecordId::new("tags", "own")).unwrap();
+		notes.link("a", &RecordId::new("tags", "shared")).unwrap();
+		notes.link("b", &RecordId::new("tags", "shared")).unwrap();
+
+		assert!(notes.delete("a").unwrap());
+		assert_eq!(notes.keys().unwrap(), vec!["b"]);
+		assert!(files.keys().unwrap().is_empty());
+		assert!(thumbs.keys().unwrap().is_empty());
+		assert_eq!(tags.keys().unwrap(), vec!["shared"]);
+		assert_eq!(
+			tags.backlinks("shared").unwrap(),
+			vec![RecordId::new("notes", "b")]
+		);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_not_cascade_to_linked_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.add_cascade("notes", "files", Cascade::Delete);
+		let notes = db.collection("notes").unwrap();
+		let files = db.collection("files").unwrap();
+		notes.put("a", b"").unwrap();
+		notes.put("b", b"").unwrap();
+		files.put("f", b"").unwrap();
+		let file = RecordId::new("files", "f");
+		notes.link("a", &file).unwrap();
+		notes.link("b", &file).unwrap();
+
+		// Nothing is deleted, since the file is still linked from `b`.
+		match notes.delete("a") {
+			Err(Error::Linked(_)) => (),
+			other => panic!("expected Error::Linked, got {:?}", other),
+		}
+		assert_eq!(notes.keys().unwrap(), vec!["a", "b"]);
+		assert!(files.contains("f").unwrap());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,569 @@
+//! Consistency checking and repair for the database.
+//!
+//! `Database::check` walks the database directory structure and validates
+//! the manifest, every record and blob file, and the tag and link indexes,
+//! reporting any problems found. It never modifies the database, so it is
+//! also available in read-only mode.
+//!
+//! Only files that fail to decode are reported as corrupt. Any other error
+//! reading the database, such as a permission error, fails the check.
+//!
+//! `Database::repair` runs the same checks and fixes the problems found:
+//!
+//! - Damaged records and blobs are moved to the `quarantine` directory,
+//!   preserving their relative path, so they can be inspected and recovered
+//!   manually.
+//! - Leftover temporary files and reference counts without a blob are
+//!   removed.
+//! - Unreadable reference counts are reset to one, so that the blob is kept
+//!   until explicitly released.
+//! - An invalid manifest is rewritten with the current format version.
+//! - Tags and links to missing or corrupt records are removed. The `links`
+//!   index is the source of truth for links, so `backlinks` is updated to
+//!   match it.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::blob::{BlobId, BLOBS_DIR, REFS_EXTENSION};
+use crate::collection::{is_valid_name, DATA_DIR};
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::Change;
+use crate::links::{RecordId, BACKLINKS_DIR, LINKS_DIR};
+use crate::manifest::{self, FORMAT_VERSION, MANIFEST_FILENAME};
+use crate::tags::TAGS_DIR;
+use crate::util::{self, read_error, write_error};
+use crate::Result;
+
+pub(crate) const QUARANTINE_DIR: &str = "quarantine";
+
+/// A problem found by `Database::check`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Problem {
+	/// Path of the affected file, relative to the database root.
+	pub path: PathBuf,
+	pub kind: ProblemKind,
+}
+
+/// Types of problems found by `Database::check`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ProblemKind {
+	/// A record file could not be decoded or failed its checksum.
+	CorruptRecord(String),
+	/// A blob file could not be decoded or does not match its content
+	/// address.
+	CorruptBlob(String),
+	/// A blob reference count file could not be parsed.
+	InvalidRefCount,
+	/// A blob reference count file without the corresponding blob.
+	OrphanRefCount,
+	/// A leftover temporary file from an interrupted write.
+	TempFile,
+	/// A file or directory that does not belong to the database.
+	UnknownEntry,
+	/// The manifest could not be parsed or has the wrong version.
+	InvalidManifest(String),
+	/// A tag lists a record, by key, that is missing or corrupt.
+	DanglingTag(String),
+	/// A link to a record, from a record that is missing or corrupt, or to
+	/// one.
+	DanglingLink(RecordId),
+	/// A backlink from a record that does not have the link.
+	StaleBacklink(RecordId),
+	/// A link to a record that does not have the backlink.
+	MissingBacklink(RecordId),
+}
+
+impl Problem {
+	/// Returns the change to the database from repairing this problem.
+	fn change(&self) -> Option<Change> {
+		match &self.kind {
+			ProblemKind::CorruptRecord(_) => {
+				let collection = name(self.path.parent().unwrap());
+				let key = name(&self.path);
+				Some(Change::Delete { collection, key })
+			}
+			ProblemKind::CorruptBlob(_) => BlobId::parse(name(&self.path)).map(Change::DeleteBlob),
+			ProblemKind::InvalidRefCount => refs_blob_id(&name(&self.path)).map(Change::PutBlob),
+			ProblemKind::DanglingTag(key) => {
+				let collection = name(self.path.parent().unwrap());
+				let tag = name(&self.path);
+				Some(Change::Untag {
+					collection,
+					key: key.clone(),
+					tag,
+				})
+			}
+			ProblemKind::DanglingLink(to) => Some(Change::Unlink {
+				from: index_record(&self.path),
+				to: to.clone(),
+			}),
+			ProblemKind::StaleBacklink(from) => Some(Change::Unlink {
+				from: from.clone(),
+				to: index_record(&self.path),
+			}),
+			ProblemKind::MissingBacklink(to) => Some(Change::Link {
+				from: index_record(&self.path),
+				to: to.clone(),
+			}),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for Problem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let path = self.path.to_string_lossy();
+		match &self.kind {
+			ProblemKind::CorruptRecord(reason) => {
+				write!(f, "corrupt record `{}`: {}", path, reason)
+			}
+			ProblemKind::CorruptBlob(reason) => write!(f, "corrupt blob `{}`: {}", path, reason),
+			ProblemKind::InvalidRefCount => write!(f, "invalid reference count `{}`", path),
+			ProblemKind::OrphanRefCount => write!(f, "reference count without blob `{}`", path),
+			ProblemKind::TempFile => write!(f, "leftover temporary file `{}`", path),
+			ProblemKind::UnknownEntry => write!(f, "unknown entry `{}`", path),
+			ProblemKind::InvalidManifest(reason) => {
+				write!(f, "invalid manifest `{}`: {}", path, reason)
+			}
+			ProblemKind::DanglingTag(key) => {
+				write!(f, "tag `{}` lists missing record `{}`", path, key)
+			}
+			ProblemKind::DanglingLink(to) => write!(f, "dangling link `{}` to `{}`", path, to),
+			ProblemKind::StaleBacklink(from) => {
+				write!(f, "backlink `{}` from `{}` without a link", path, from)
+			}
+			ProblemKind::MissingBacklink(to) => {
+				write!(f, "link `{}` to `{}` without a backlink", path, to)
+			}
+		}
+	}
+}
+
+/// Result of a `Database::check` or `Database::repair`.
+#[derive(Debug, Default, Clone)]
+pub struct CheckReport {
+	/// Number of records checked.
+	pub records: usize,
+	/// Number of blobs checked.
+	pub blobs: usize,
+	/// Problems found. For `repair`, these are the problems that were fixed.
+	pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+	/// Returns true if no problems were found.
+	pub fn is_ok(&self) -> bool {
+		self.problems.is_empty()
+	}
+
+	fn push(&mut self, path: PathBuf, kind: ProblemKind) {
+		self.problems.push(Problem { path, kind });
+	}
+}
+
+impl Database {
+	/// Checks the database for problems, without changing it.
+	pub fn check(&self) -> Result<CheckReport> {
+		let mut report = CheckReport::default();
+		self.check_manifest(&mut report)?;
+		let records = self.check_records(&mut report)?;
+		self.check_blobs(&mut report)?;
+		self.check_tags(&records, &mut report)?;
+		self.check_links(&records, &mut report)?;
+		Ok(report)
+	}
+
+	/// Checks the database and fixes any problems found. Returns the list of
+	/// problems that were fixed.
+	pub fn repair(&self) -> Result<CheckReport> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+
+		let report = self.check()?;
+		self.reset_usage();
+		for problem in report.problems.iter() {
+			let path = self.path.join(&problem.path);
+			if let Some(change) = problem.change() {
+				self.log_change(change)?;
+			}
+			match &problem.kind {
+				ProblemKind::CorruptRecord(_) | ProblemKind::CorruptBlob(_) => {
+					let target = self.path.join(QUARANTINE_DIR).join(&problem.path);
+					let parent = target.parent().unwrap();
+					fs::create_dir_all(parent)
+						.and_then(|_| fs::rename(&path, &target))
+						.map_err(|err| write_error(err, &target))?;
+				}
+				ProblemKind::InvalidRefCount => {
+					crate::fs::atomic_write(&path, b"1\n")
+						.map_err(|err| write_error(err, &path))?;
+				}
+				ProblemKind::OrphanRefCount | ProblemKind::TempFile => {
+					fs::remove_file(&path).map_err(|err| write_error(err, &path))?;
+				}
+				// We don't know what these are, so it's safer to leave them.
+				ProblemKind::UnknownEntry => {}
+				ProblemKind::InvalidManifest(_) => {
+					manifest::write_version(&self.path, FORMAT_VERSION)?;
+				}
+				ProblemKind::DanglingTag(key) => {
+					let collection = self.collection(name(problem.path.parent().unwrap()))?;
+					let tag = name(&problem.path);
+					let mut keys = collection.read_tag(&tag)?;
+					keys.remove(key);
+					collection.write_tag(&tag, keys)?;
+				}
+				ProblemKind::DanglingLink(to) => {
+					let from = index_record(&problem.path);
+					self.edit_links(LINKS_DIR, &from, |links| links.retain(|id| id != to))?;
+					self.edit_links(BACKLINKS_DIR, to, |links| links.retain(|id| *id != from))?;
+				}
+				ProblemKind::StaleBacklink(from) => {
+					let to = index_record(&problem.path);
+					self.edit_links(BACKLINKS_DIR, &to, |links| links.retain(|id| id != from))?;
+				}
+				ProblemKind::MissingBacklink(to) => {
+					let from = index_record(&problem.path);
+					self.edit_links(BACKLINKS_DIR, to, |links| links.push(from))?;
+				}
+			}
+		}
+		Ok(report)
+	}
+
+	fn check_manifest(&self, report: &mut CheckReport) -> Result<()> {
+		let reason = match manifest::read_version(&self.path) {
+			// A database is only new if it has nothing besides the lock file.
+			Ok(None) => return Ok(()),
+			Ok(Some(version)) if version == FORMAT_VERSION => return Ok(()),
+			Ok(Some(0)) => "missing manifest".to_string(),
+			Ok(Some(version)) => format!("unexpected version {}", version),
+			Err(Error::Open(err)) if err.kind() == io::ErrorKind::InvalidData => err.to_string(),
+			Err(err) => return Err(err),
+		};
+		report.push(
+			PathBuf::from(MANIFEST_FILENAME),
+			ProblemKind::InvalidManifest(reason),
+		);
+		Ok(())
+	}
+
+	/// Checks the record files, returning the records that can be read.
+	fn check_records(&self, report: &mut CheckReport) -> Result<BTreeSet<RecordId>> {
+		let mut records = BTreeSet::new();
+		for_each_file(DATA_DIR, &self.path, report, |report, relative, id| {
+			let path = self.path.join(&relative);
+			report.records += 1;
+			let data = fs::read(&path).map_err(|err| read_error(err, &path))?;
+			match self.codec().decode(&data) {
+				Ok(_) => {
+					records.insert(id);
+				}
+				Err(err) => report.push(relative, ProblemKind::CorruptRecord(err.to_string())),
+			}
+			Ok(())
+		})?;
+		Ok(records)
+	}
+
+	fn check_blobs(&self, report: &mut CheckReport) -> Result<()> {
+		let blobs_dir = self.path.join(BLOBS_DIR);
+		for (name, is_dir) in read_dir(&blobs_dir)? {
+			let relative = Path::new(BLOBS_DIR).join(&name);
+			let path = blobs_dir.join(&name);
+			if is_dir {
+				report.push(relative, ProblemKind::UnknownEntry);
+			} else if util::is_temp_file(&path) {
+				report.push(relative, ProblemKind::TempFile);
+			} else if let Some(id) = BlobId::parse(&name) {
+				report.blobs += 1;
+				match self.get_blob(&id) {
+					Ok(_) => {}
+					Err(Error::Corrupt(err)) => {
+						report.push(relative, ProblemKind::CorruptBlob(err.to_string()))
+					}
+					Err(err) => return Err(err),
+				}
+			} else if let Some(id) = refs_blob_id(&name) {
+				let text = fs::read_to_string(&path).map_err(|err| read_error(err, &path))?;
+				if !blobs_dir.join(id.as_str()).is_file() {
+					report.push(relative, ProblemKind::OrphanRefCount);
+				} else if text.trim().parse::<usize>().is_err() {
+					report.push(relative, ProblemKind::InvalidRefCount);
+				}
+			} else {
+				report.push(relative, ProblemKind::UnknownEntry);
+			}
+		}
+		Ok(())
+	}
+
+	fn check_tags(&self, records: &BTreeSet<RecordId>, report: &mut CheckReport) -> Result<()> {
+		for_each_file(TAGS_DIR, &self.path, report, |report, relative, id| {
+			let (collection, tag) = (id.collection, id.key);
+			for key in self.collection(collection.as_str())?.read_tag(&tag)? {
+				if !records.contains(&RecordId::new(collection.as_str(), key.as_str())) {
+					report.push(relative.clone(), ProblemKind::DanglingTag(key));
+				}
+			}
+			Ok(())
+		})
+	}
+
+	fn check_links(&self, records: &BTreeSet<RecordId>, report: &mut CheckReport) -> Result<()> {
+		// Links between existing records, which must have a backlink.
+		let mut links = BTreeSet::new();
+		let mut dangling = BTreeSet::new();
+		for_each_file(LINKS_DIR, &self.path, report, |report, relative, from| {
+			for to in self.read_links(LINKS_DIR, &from)? {
+				if records.contains(&from) && records.contains(&to) {
+					links.insert((from.clone(), to));
+				} else {
+					dangling.insert((from.clone(), to.clone()));
+					report.push(relative.clone(), ProblemKind::DanglingLink(to));
+				}
+			}
+			Ok(())
+		})?;
+
+		let mut backlinks = BTreeSet::new();
+		for_each_file(BACKLINKS_DIR, &self.path, report, |report, relative, to| {
+			for from in self.read_links(BACKLINKS_DIR, &to)? {
+				let link = (from, to.clone());
+				if !links.contains(&link) && !dangling.contains(&link) {
+					report.push(relative.clone(), ProblemKind::StaleBacklink(link.0));
+				} else {
+					backlinks.insert(link);
+				}
+			}
+			Ok(())
+		})?;
+
+		for (from, to) in links.difference(&backlinks) {
+			let relative = Path::new(LINKS_DIR).join(&from.collection).join(&from.key);
+			report.push(relative, ProblemKind::MissingBacklink(to.clone()));
+		}
+		Ok(())
+	}
+
+	/// Changes the list of links in a `links` or `backlinks` file.
+	fn edit_links<F: FnOnce(&mut Vec<RecordId>)>(
+		&self,
+		dir: &str,
+		id: &RecordId,
+		f: F,
+	) -> Result<()> {
+		let mut links = self.read_links(dir, id)?;
+		f(&mut links);
+		self.write_links(dir, id, links)
+	}
+}
+
+/// Calls `f` for each file in a directory with a subdirectory for each
+/// collection, such as `data` or `tags`, with the relative path of the file
+/// and its collection and name. Invalid entries are added to the report.
+fn for_each_file<F>(dir: &str, root: &Path, report: &mut CheckReport, mut f: F) -> Result<()>
+where
+	F: FnMut(&mut CheckReport, PathBuf, RecordId) -> Result<()>,
+{
+	for (collection, is_dir) in read_dir(&root.join(dir))? {
+		let relative = Path::new(dir).join(&collection);
+		if !is_dir || !is_valid_name(&collection) {
+			report.push(relative, ProblemKind::UnknownEntry);
+			continue;
+		}
+
+		for (name, is_dir) in read_dir(&root.join(&relative))? {
+			let relative = relative.join(&name);
+			if util::is_temp_file(&relative) {
+				report.push(relative, ProblemKind::TempFile);
+			} else if is_dir || !is_valid_name(&name) {
+				report.push(relative, ProblemKind::UnknownEntry);
+			} else {
+				f(report, relative, RecordId::new(collection.as_str(), name))?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Returns the record for a file in the `links` or `backlinks` index.
+fn index_record(path: &Path) -> RecordId {
+	RecordId::new(name(path.parent().unwrap()), name(path))
+}
+
+/// Returns the file name of a path, which must have one.
+fn name(path: &Path) -> String {
+	path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+/// Returns the blob ID for a reference count file name.
+fn refs_blob_id(name: &str) -> Option<BlobId> {
+	let id = name.strip_suffix(REFS_EXTENSION)?.strip_suffix('.')?;
+	BlobId::parse(id)
+}
+
+/// Returns the sorted list of entries in a directory, which may not exist,
+/// together with a flag indicating if the entry is a directory.
+pub(crate) fn read_dir(dir: &Path) -> Result<Vec<(String, bool)>> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(read_error(err, dir)),
+	};
+
+	let mut out = Vec::new();
+	for entry in entries {
+		let entry = entry.map_err(|err| read_error(err, dir))?;
+		let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+		out.push((entry.file_name().to_string_lossy().into_owned(), is_dir));
+	}
+	out.sort();
+	Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_check_healthy_database() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		db.put_blob(b"blob").unwrap();
+
+		let report = db.check().unwrap();
+		assert!(report.is_ok(), "{:?}", report.problems);
+		assert_eq!(report.records, 2);
+		assert_eq!(report.blobs, 1);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_report_and_repair_problems() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		let notes_dir = db.path.join(DATA_DIR).join("notes");
+		fs::write(notes_dir.join("b"), b"#record crc32=00000000\nB").unwrap();
+		fs::write(notes_dir.join("c.tmp"), b"").unwrap();
+		fs::write(db.path.join(BLOBS_DIR).join(format!("{}.refs", blob)), b"x").unwrap();
+		let orphan = BlobId::of(b"orphan");
+		fs::write(
+			db.path.join(BLOBS_DIR).join(format!("{}.refs", orphan)),
+			b"1",
+		)
+		.unwrap();
+
+		let report = db.check().unwrap();
+		let kinds = report.problems.iter().map(|p| &p.kind).collect::<Vec<_>>();
+		assert_eq!(kinds.len(), 4, "{:?}", report.problems);
+		assert!(matches!(kinds[0], ProblemKind::CorruptRecord(_)));
+		assert_eq!(kinds[1], &ProblemKind::TempFile);
+		assert!(kinds.contains(&&ProblemKind::InvalidRefCount));
+		assert!(kinds.contains(&&ProblemKind::OrphanRefCount));
+
+		let report = db.repair().unwrap();
+		assert_eq!(report.problems.len(), 4);
+		assert!(db.check().unwrap().is_ok());
+
+		// The corrupt record is moved to quarantine.
+		assert!(db
+			.path
+			.join(QUARANTINE_DIR)
+			.join(DATA_DIR)
+			.join("notes")
+			.join("b")
+			.is_file());
+		assert_eq!(notes.keys().unwrap(), vec!["a"]);
+		assert_eq!(db.blob_refs(&blob).unwrap(), 1);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_repair_manifest_and_indexes() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		for key in &["a", "b", "c", "d"] {
+			notes.put(key, key.as_bytes()).unwrap();
+		}
+		let id = |key: &str| RecordId::new("notes", key);
+		notes.tag("a", "todo").unwrap();
+		notes.tag("b", "todo").unwrap();
+		notes.link("a", &id("b")).unwrap();
+		notes.link("a", &id("c")).unwrap();
+		notes.link("c", &id("d")).unwrap();
+
+		fs::write(db.path.join(MANIFEST_FILENAME), b"garbage").unwrap();
+		let notes_dir = db.path.join(DATA_DIR).join("notes");
+		fs::write(notes_dir.join("b"), b"#record crc32=00000000\nB").unwrap();
+		let backlinks_dir = db.path.join(BACKLINKS_DIR).join("notes");
+		fs::remove_file(backlinks_dir.join("d")).unwrap();
+		fs::write(backlinks_dir.join("a"), b"notes/d\n").unwrap();
+
+		let report = db.check().unwrap();
+		let problems = report
+			.problems
+			.iter()
+			.map(|p| (p.path.to_string_lossy().replace('\\', "/"), p.kind.clone()))
+			.collect::<Vec<_>>();
+		assert_eq!(problems.len(), 6, "{:?}", problems);
+		assert_eq!(problems[0].0, MANIFEST_FILENAME);
+		assert!(matches!(problems[0].1, ProblemKind::InvalidManifest(_)));
+		assert!(matches!(problems[1].1, ProblemKind::CorruptRecord(_)));
+		assert_eq!(
+			problems[2..],
+			[
+				(
+					"tags/notes/todo".to_string(),
+					ProblemKind::DanglingTag("b".to_string())
+				),
+				(
+					"links/notes/a".to_string(),
+					ProblemKind::DanglingLink(id("b"))
+				),
+				(
+					"backlinks/notes/a".to_string(),
+					ProblemKind::StaleBacklink(id("d"))
+				),
+				(
+					"links/notes/c".to_string(),
+					ProblemKind::MissingBacklink(id("d"))
+				),
+			]
+		);
+
+		let report = db.repair().unwrap();
+		assert_eq!(report.problems.len(), 6);
+		assert!(db.check().unwrap().is_ok());
+
+		assert_eq!(
+			manifest::read_version(&db.path).unwrap(),
+			Some(FORMAT_VERSION)
+		);
+		assert_eq!(notes.find_by_tag("todo").unwrap(), vec!["a"]);
+		assert_eq!(notes.links("a").unwrap(), vec![id("c")]);
+		assert!(notes.backlinks("a").unwrap().is_empty());
+		assert!(notes.backlinks("b").unwrap().is_empty());
+		assert_eq!(notes.backlinks("d").unwrap(), vec![id("c")]);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
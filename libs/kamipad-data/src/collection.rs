@@ -0,0 +1,536 @@
+//! Named collections of records.
+//!
+//! A collection is just a directory under `data` in the database, with each
+//! record stored in a file named after its key (see the `record` module for
+//! the file format).
+//!
+//! Collection names and record keys are restricted to ASCII letters, digits,
+//! `_` and `-`, so that they can be safely used as file names.
+//!
+//! Records can be written with an expiration time. Expired records behave as
+//! if they had been deleted, and their files are removed by
+//! `Database::compact`.
+//!
+//! Every record has a version, incremented each time it is written, which
+//! allows clients to detect concurrent changes with
+//! `Collection::put_if_version`. A missing record is at version zero.
+
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::{now, Change};
+use crate::record::{self, Header};
+use crate::util::{self, corrupt_error, read_error, write_error};
+use crate::Result;
+
+pub(crate) const DATA_DIR: &str = "data";
+
+lazy_static! {
+	static ref RE_NAME: Regex = Regex::new(r"^[0-9A-Za-z_\-]{1,128}$").unwrap();
+}
+
+/// Returns true if the name is valid for a collection, record key or tag.
+pub fn is_valid_name(name: &str) -> bool {
+	RE_NAME.is_match(name)
+}
+
+/// Handle to a collection of records in a Database.
+///
+/// Collections are created implicitly when the first record is written.
+pub struct Collection<'a> {
+	pub(crate) db: &'a Database,
+	pub(crate) name: String,
+	pub(crate) path: PathBuf,
+}
+
+impl Database {
+	/// Returns a handle to the named collection.
+	pub fn collection<S: Into<String>>(&self, name: S) -> Result<Collection<'_>> {
+		let name = name.into();
+		if !is_valid_name(&name) {
+			return Err(Error::InvalidName(name));
+		}
+		let path = self.path.join(DATA_DIR).join(&name);
+		Ok(Collection {
+			db: self,
+			name,
+			path,
+		})
+	}
+
+	/// Returns the names of all collections in the database, sorted.
+	pub fn collections(&self) -> Result<Vec<String>> {
+		list_names(&self.path.join(DATA_DIR), true)
+	}
+}
+
+impl<'a> Collection<'a> {
+	/// Name of the collection.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Reads a record. Returns `None` if the record does not exist or has
+	/// expired.
+	pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+		let record = self.get_versioned(key)?;
+		Ok(record.map(|(payload, _)| payload))
+	}
+
+	/// Reads a record together with its version. Returns `None` if the
+	/// record does not exist or has expired.
+	pub fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>> {
+		let path = self.record_path(key)?;
+		let generation = {
+			let mut cache = self.db.cache();
+			if let Some((header, payload)) = cache.get(&self.name, key) {
+				return Ok(live_payload(header, payload));
+			}
+			cache.generation()
+		};
+
+		let data = match self.db.read_file(&path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(read_error(err, &path)),
+		};
+		let (header, payload) = self
+			.db
+			.codec()
+			.decode_with_header(&data)
+			.map_err(|err| corrupt_error(err, &path))?;
+		let mut cache = self.db.cache();
+		if cache.is_enabled() {
+			cache.insert(generation, &self.name, key, &header, &payload);
+		}
+		Ok(live_payload(header, payload))
+	}
+
+	/// Returns true if the record exists and has not expired.
+	pub fn contains(&self, key: &str) -> Result<bool> {
+		is_live(self.db, &self.record_path(key)?)
+	}
+
+	/// Returns the version of a record, or zero if the record does not exist
+	/// or has expired.
+	pub fn version(&self, key: &str) -> Result<u64> {
+		let header = read_live_header(self.db, &self.record_path(key)?)?;
+		Ok(header.map(|header| header.version()).unwrap_or(0))
+	}
+
+	/// Writes a record, replacing any existing value.
+	pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+		self.put_with_header(key, value, Header::default(), None)
+			.map(|_| ())
+	}
+
+	/// Writes a record only if its current version matches `expected`,
+	/// returning the new version. Use zero as the expected version to create
+	/// a record that must not exist.
+	///
+	/// Fails with `Error::Conflict` if the record was changed since the
+	/// expected version was read.
+	pub fn put_if_version(&self, key: &str, value: &[u8], expected: u64) -> Result<u64> {
+		self.put_with_header(key, value, Header::default(), Some(expected))
+	}
+
+	/// Writes a record that expires at the given time, replacing any existing
+	/// value.
+	pub fn put_expiring(&self, key: &str, value: &[u8], expires: SystemTime) -> Result<()> {
+		let expires = expires
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_millis() as u64)
+			.unwrap_or(0);
+		let header = Header {
+			expires: Some(expires),
+			..Default::default()
+		};
+		self.put_with_header(key, value, header, None).map(|_| ())
+	}
+
+	pub(crate) fn put_with_header(
+		&self,
+		key: &str,
+		value: &[u8],
+		header: Header,
+		expected: Option<u64>,
+	) -> Result<u64> {
+		self.db.check_writable()?;
+		let _guard = self.db.write_guard();
+		let (bytes, records) = self.put_usage(key, value.len())?;
+		self.db.check_quota(bytes, records)?;
+		self.put_locked(key, value, header, expected)
+	}
+
+	/// Writes a record, returning the new version. Must be called with the
+	/// write lock held. The quota is not checked.
+	pub(crate) fn put_locked(
+		&self,
+		key: &str,
+		value: &[u8],
+		mut header: Header,
+		expected: Option<u64>,
+	) -> Result<u64> {
+		let path = self.record_path(key)?;
+		self.db.validate(&self.name, key, value)?;
+		let current = read_header(self.db, &path)?;
+		let live = current.as_ref().map(|header| !header.is_expired(now()));
+		if let Some(expected) = expected {
+			let version = match (&current, live) {
+				(Some(header), Some(true)) => header.version(),
+				_ => 0,
+			};
+			if version != expected {
+				return Err(Error::Conflict(format!(
+					"record `{}/{}` is at version {}, expected {}",
+					self.name, key, version, expected
+				)));
+			}
+		}
+
+		// Versions keep increasing over expired records, so that a version
+		// is never reused for a record. Imported records may ask for a higher
+		// version, to keep the one they were exported with.
+		let version = current.map(|header| header.version()).unwrap_or(0) + 1;
+		let version = version.max(header.version.unwrap_or(0));
+		header.version = Some(version);
+		let data = self
+			.db
+			.codec()
+			.encode_with(value, header)
+			.map_err(|err| write_error(err, &path))?;
+
+		let (size, records) = self.file_usage(key)?;
+		self.db.log_change(self.change(key, true))?;
+		if self.db.max_revisions() > 0 && path.is_file() {
+			self.save_revision(key)?;
+		}
+		fs::create_dir_all(&self.path)
+			.and_then(|_| util::write_file(&path, &data))
+			.map_err(|err| write_error(err, &path))?;
+		self.db.update_usage(data.len() as i64 - size, 1 - records);
+		Ok(version)
+	}
+
+	/// Deletes a record, along with its links to other records. Returns false
+	/// if the record did not exist or had expired.
+	///
+	/// Fails with `Error::Linked` if other records link to it. Records linked
+	/// from it may also be deleted, according to the rules registered with
+	/// `Database::add_cascade`.
+	pub fn delete(&self, key: &str) -> Result<bool> {
+		if self.db.has_cascades(&self.name) {
+			return self.delete_cascading(key);
+		}
+		self.db.check_writable()?;
+		let _guard = self.db.write_guard();
+		self.delete_locked(key)
+	}
+
+	/// Deletes a record along with the records from cascade rules, in a
+	/// transaction.
+	fn delete_cascading(&self, key: &str) -> Result<bool> {
+		let live = self.contains(key)?;
+		let mut tx = self.db.transaction();
+		tx.delete(&self.name, key)?;
+		tx.commit()?;
+		Ok(live)
+	}
+
+	/// Deletes a record. Must be called with the write lock held.
+	pub(crate) fn delete_locked(&self, key: &str) -> Result<bool> {
+		let path = self.record_path(key)?;
+		if !path.is_file() {
+			return Ok(false);
+		}
+
+		self.db.unlink_for_delete(&self.record_id(key)?)?;
+		self.untag_for_delete(key)?;
+		let live = is_live(self.db, &path)?;
+		let (bytes, records) = self.delete_usage(key)?;
+		self.db.log_change(self.change(key, false))?;
+		let result = if live && self.db.soft_delete() {
+			self.move_to_trash(key).map(|_| true)
+		} else {
+			match fs::remove_file(&path) {
+				Ok(_) => Ok(live),
+				Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+				Err(err) => Err(write_error(err, &path)),
+			}
+		};
+		if result.is_ok() {
+			self.db.update_usage(bytes, records);
+		}
+		result
+	}
+
+	/// Returns the keys for all records in the collection, sorted. Expired
+	/// records are not included.
+	pub fn keys(&self) -> Result<Vec<String>> {
+		// Readers may see records that were since deleted by the writer.
+		let mut candidates = list_names(&self.path, false)?
+			.into_iter()
+			.collect::<BTreeSet<_>>();
+		candidates.extend(self.db.keys_changed_after_snapshot(&self.name)?);
+
+		let mut keys = Vec::new();
+		for key in candidates {
+			if is_live(self.db, &self.path.join(&key))? {
+				keys.push(key);
+			}
+		}
+		Ok(keys)
+	}
+
+	pub(crate) fn change(&self, key: &str, put: bool) -> Change {
+		let (collection, key) = (self.name.clone(), key.to_string());
+		if put {
+			Change::Put { collection, key }
+		} else {
+			Change::Delete { collection, key }
+		}
+	}
+
+	pub(crate) fn record_path(&self, key: &str) -> Result<PathBuf> {
+		if is_valid_name(key) {
+			Ok(self.path.join(key))
+		} else {
+			Err(Error::InvalidName(key.to_string()))
+		}
+	}
+}
+
+/// Returns true if the record file exists and has not expired.
+///
+/// Only the header is read, so damaged records are considered live and
+/// reported when read.
+fn is_live(db: &Database, path: &Path) -> Result<bool> {
+	read_live_header(db, path).map(|header| header.is_some())
+}
+
+/// Reads the header of a record file, if it exists and has not expired.
+fn read_live_header(db: &Database, path: &Path) -> Result<Option<Header>> {
+	let header = read_header(db, path)?;
+	Ok(header.filter(|header| !header.is_expired(now())))
+}
+
+/// Returns the payload and version of a decoded record, unless expired.
+fn live_payload(header: Header, payload: Vec<u8>) -> Option<(Vec<u8>, u64)> {
+	if header.is_expired(now()) {
+		None
+	} else {
+		Some((payload, header.version()))
+	}
+}
+
+/// Reads the header of a record file, if it exists. A damaged header is
+/// returned as the default header.
+fn read_header(db: &Database, path: &Path) -> Result<Option<Header>> {
+	let data = match db.read_file(path) {
+		Ok(data) => data,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(err) => return Err(read_error(err, path)),
+	};
+	Ok(Some(record::read_header(&data).unwrap_or_default()))
+}
+
+/// Lists the valid entry names in a directory, which may not exist.
+pub(crate) fn list_names(dir: &Path, dirs: bool) -> Result<Vec<String>> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(read_error(err, dir)),
+	};
+
+	let mut names = Vec::new();
+	for entry in entries {
+		let entry = entry.map_err(|err| read_error(err, dir))?;
+		let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+		if is_dir != dirs {
+			continue;
+		}
+		if let Some(name) = entry.file_name().to_str() {
+			if is_valid_name(name) {
+				names.push(name.to_string());
+			}
+		}
+	}
+	names.sort();
+	Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_store_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+
+		let notes = db.collection("notes").unwrap();
+		assert!(notes.get("a").unwrap().is_none());
+
+		notes.put("a", b"note A").unwrap();
+		notes.put("b", b"note B").unwrap();
+		notes.put("a", b"note A2").unwrap();
+		db.collection("tags").unwrap().put("x", b"").unwrap();
+
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"note A2");
+		assert!(notes.contains("b").unwrap());
+		assert_eq!(notes.keys().unwrap(), vec!["a", "b"]);
+		assert_eq!(db.collections().unwrap(), vec!["notes", "tags"]);
+
+		assert!(notes.delete("b").unwrap());
+		assert!(!notes.delete("b").unwrap());
+		assert_eq!(notes.keys().unwrap(), vec!["a"]);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_check_record_versions() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		assert_eq!(notes.version("a").unwrap(), 0);
+
+		assert_eq!(notes.put_if_version("a", b"A1", 0).unwrap(), 1);
+		notes.put("a", b"A2").unwrap();
+		assert_eq!(
+			notes.get_versioned("a").unwrap().unwrap(),
+			(b"A2".to_vec(), 2)
+		);
+
+		match notes.put_if_version("a", b"A3", 1) {
+			Err(Error::Conflict(_)) => (),
+			other => panic!("expected Error::Conflict, got {:?}", other),
+		}
+		match notes.put_if_version("a", b"A3", 0) {
+			Err(Error::Conflict(_)) => (),
+			other => panic!("expected Error::Conflict, got {:?}", other),
+		}
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A2");
+		assert_eq!(notes.put_if_version("a", b"A3", 2).unwrap(), 3);
+
+		// Versions are not reused for expired records.
+		let past = SystemTime::now() - std::time::Duration::from_secs(1);
+		notes.put_expiring("a", b"A4", past).unwrap();
+		assert_eq!(notes.version("a").unwrap(), 0);
+		assert_eq!(notes.put_if_version("a", b"A5", 0).unwrap(), 5);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_read_memory_mapped_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"note A").unwrap();
+		notes.put("empty", b"").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+		let path = db.path.clone();
+		drop(db);
+
+		let db = open(
+			&path,
+			OpenFlags::config(|f| {
+				f.read_only = true;
+				f.mmap = true;
+			}),
+		)
+		.unwrap();
+		let notes = db.collection("notes").unwrap();
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"note A");
+		assert_eq!(notes.get("empty").unwrap().unwrap(), b"");
+		assert!(notes.get("missing").unwrap().is_none());
+		assert_eq!(db.get_blob(&blob).unwrap().unwrap(), b"blob");
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_compress_records() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.compress = true));
+		let notes = db.collection("notes").unwrap();
+
+		let text = "some very repetitive note text\n".repeat(50);
+		notes.put("a", text.as_bytes()).unwrap();
+
+		let size = fs::metadata(db.path.join(DATA_DIR).join("notes").join("a"))
+			.unwrap()
+			.len();
+		assert!((size as usize) < text.len());
+		assert_eq!(notes.get("a").unwrap().unwrap(), text.as_bytes());
+
+		// Compressed records can be read regardless of the flag.
+		let path = db.path.clone();
+		drop(db);
+		let db = crate::open(path, OpenFlags::read_only()).unwrap();
+		let notes = db.collection("notes").unwrap();
+		assert_eq!(notes.get("a").unwrap().unwrap(), text.as_bytes());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_detect_corrupted_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"some note").unwrap();
+
+		let path = db.path.join(DATA_DIR).join("notes").join("a");
+		let mut data = fs::read(&path).unwrap();
+		let last = data.len() - 1;
+		data[last] ^= 0x20;
+		fs::write(&path, data).unwrap();
+
+		match notes.get("a") {
+			Err(Error::Corrupt(_)) => (),
+			other => panic!("expected Error::Corrupt, got {:?}", other),
+		}
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_validate_names() {
+		let (db, temp) = create_db(OpenFlags::default());
+		assert!(db.collection("").is_err());
+		assert!(db.collection("../x").is_err());
+
+		let notes = db.collection("notes").unwrap();
+		assert!(notes.put("a.b", b"").is_err());
+		assert!(notes.get("a/b").is_err());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_not_write_in_read_only_mode() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let path = db.path.clone();
+		drop(db);
+
+		let db = crate::open(path, OpenFlags::read_only()).unwrap();
+		match db.collection("notes").unwrap().put("a", b"") {
+			Err(Error::ReadOnly) => (),
+			other => panic!("expected Error::ReadOnly, got {:?}", other),
+		}
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,121 @@
+//! Compaction of the database.
+//!
+//! Compaction removes data that is no longer visible but still takes space
+//! on disk: expired records, unreferenced blobs and old undo data.
+
+use std::fs;
+use std::io;
+
+use crate::blob::BlobGcStats;
+use crate::collection::{list_names, DATA_DIR};
+use crate::database::Database;
+use crate::journal::{now, Change};
+use crate::record;
+use crate::util::{read_error, write_error};
+use crate::Result;
+
+/// Summary of a `Database::compact` run.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct CompactStats {
+	/// Number of expired records removed.
+	pub expired: usize,
+	/// Result of collecting unreferenced blobs.
+	pub blobs: BlobGcStats,
+	/// Number of changes for which undo data was removed.
+	pub undo: usize,
+}
+
+impl Database {
+	/// Compacts the database, removing expired records, unreferenced blobs
+	/// and undo data older than `OpenFlags::snapshots`.
+	pub fn compact(&self) -> Result<CompactStats> {
+		self.check_writable()?;
+
+		let mut stats = CompactStats::default();
+		{
+			let _guard = self.write_guard();
+			let now = now();
+			let data_dir = self.path.join(DATA_DIR);
+			for collection in list_names(&data_dir, true)? {
+				let collection_dir = data_dir.join(&collection);
+				for key in list_names(&collection_dir, false)? {
+					let path = collection_dir.join(&key);
+					let data = fs::read(&path).map_err(|err| read_error(err, &path))?;
+					let expired = record::read_header(&data)
+						.map(|header| header.is_expired(now))
+						.unwrap_or(false);
+					if !expired {
+						continue;
+					}
+
+					self.collection(collection.as_str())?
+						.untag_for_delete(&key)?;
+					let collection = collection.clone();
+					self.log_change(Change::Delete { collection, key })?;
+					match fs::remove_file(&path) {
+						Ok(_) => {
+							self.update_usage(-(data.len() as i64), -1);
+							stats.expired += 1;
+						}
+						Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+						Err(err) => return Err(write_error(err, &path)),
+					}
+				}
+			}
+		}
+
+		stats.blobs = self.gc_blobs()?;
+		stats.undo = {
+			let _guard = self.write_guard();
+			self.prune_undo()?
+		};
+		Ok(stats)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::{Duration, SystemTime};
+
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_expire_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let sessions = db.collection("sessions").unwrap();
+		let past = SystemTime::now() - Duration::from_secs(1);
+		let future = SystemTime::now() + Duration::from_secs(3600);
+		sessions.put("permanent", b"P").unwrap();
+		sessions.put_expiring("expired", b"E", past).unwrap();
+		sessions.put_expiring("valid", b"V", future).unwrap();
+
+		assert!(sessions.get("expired").unwrap().is_none());
+		assert!(!sessions.contains("expired").unwrap());
+		assert_eq!(sessions.get("valid").unwrap().unwrap(), b"V");
+		assert_eq!(sessions.keys().unwrap(), vec!["permanent", "valid"]);
+
+		let blob = db.put_blob(b"blob").unwrap();
+		db.release_blob(&blob).unwrap();
+
+		let stats = db.compact().unwrap();
+		assert_eq!(stats.expired, 1);
+		assert_eq!(stats.blobs.removed, 1);
+		assert!(!db
+			.path
+			.join("data")
+			.join("sessions")
+			.join("expired")
+			.exists());
+		assert_eq!(db.compact().unwrap().expired, 0);
+
+		// Writing again makes the record permanent.
+		sessions.put_expiring("valid", b"V", past).unwrap();
+		assert!(sessions.get("valid").unwrap().is_none());
+		sessions.put("valid", b"V2").unwrap();
+		assert_eq!(sessions.get("valid").unwrap().unwrap(), b"V2");
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,274 @@
+//! Records that merge concurrent changes.
+//!
+//! A `LwwMap` is a map of fields to JSON values, where each field keeps the
+//! time it was last written. Merging two maps takes the most recent value of
+//! each field, so that concurrent changes to different fields are all kept,
+//! and concurrent changes to the same field resolve to the last one written.
+//! Fields written at the same time resolve to the greater value, so that
+//! every replica merges to the same result. Removed fields are kept as
+//! tombstones to merge with changes made before the removal.
+//!
+//! Collections are marked with `Database::add_crdt` to hold maps, after
+//! which `Database::sync` merges records changed on both sides instead of
+//! reporting them as conflicts. A record deleted on one side and changed on
+//! the other merges as an empty map, which keeps the changes.
+//!
+//! Like validators, the collections must be marked every time the database
+//! is opened.
+//!
+//! The map is stored as JSON:
+//!
+//! ```text
+//! {"title": {"value": "Notes", "time": 1589000000000}, "old": {"time": 1589000000001}}
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::collection::Collection;
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::now;
+use crate::Result;
+
+/// Collections marked with `add_crdt`.
+#[derive(Default)]
+pub(crate) struct CrdtCollections {
+	names: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct Field {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	value: Option<Value>,
+	time: u64,
+}
+
+impl Field {
+	/// Returns true if the field takes precedence over another field when
+	/// merging.
+	fn wins_over(&self, other: &Field) -> bool {
+		if self.time != other.time {
+			return self.time > other.time;
+		}
+		let key = |field: &Field| field.value.as_ref().map(|value| value.to_string());
+		key(self) > key(other)
+	}
+}
+
+/// Map of last-writer-wins fields. See the `crdt` module.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LwwMap {
+	fields: BTreeMap<String, Field>,
+}
+
+impl LwwMap {
+	pub fn new() -> LwwMap {
+		LwwMap::default()
+	}
+
+	/// Parses a map stored in a record.
+	pub fn from_bytes(data: &[u8]) -> Result<LwwMap> {
+		serde_json::from_slice(data).map_err(|err| Error::InvalidCrdt(err.to_string()))
+	}
+
+	/// Returns the map as stored in a record.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		serde_json::to_vec(self).unwrap()
+	}
+
+	/// Returns the value of a field, or `None` if the field was never set or
+	/// was removed.
+	pub fn get(&self, name: &str) -> Option<&Value> {
+		self.fields.get(name).and_then(|field| field.value.as_ref())
+	}
+
+	/// Returns the fields that have a value, in name order.
+	pub fn fields(&self) -> impl Iterator<Item = (&str, &Value)> {
+		self.fields
+			.iter()
+			.filter_map(|(name, field)| Some((name.as_str(), field.value.as_ref()?)))
+	}
+
+	/// Sets the value of a field.
+	pub fn set<S: Into<String>>(&mut self, name: S, value: Value) {
+		self.write(name.into(), Some(value));
+	}
+
+	/// Removes a field.
+	pub fn remove(&mut self, name: &str) {
+		if self.fields.contains_key(name) {
+			self.write(name.to_string(), None);
+		}
+	}
+
+	/// Merges the changes from another map. Returns true if the map changed.
+	pub fn merge(&mut self, other: &LwwMap) -> bool {
+		let mut changed = false;
+		for (name, field) in other.fields.iter() {
+			let wins = match self.fields.get(name) {
+				Some(current) => field.wins_over(current),
+				None => true,
+			};
+			if wins {
+				self.fields.insert(name.clone(), field.clone());
+				changed = true;
+			}
+		}
+		changed
+	}
+
+	// The time of a write is always after the current value of the field,
+	// even if the clock went back, so that a write is never lost to an older
+	// value from the same replica.
+	fn write(&mut self, name: String, value: Option<Value>) {
+		let time = match self.fields.get(&name) {
+			Some(field) => now().max(field.time + 1),
+			None => now(),
+		};
+		self.fields.insert(name, Field { value, time });
+	}
+}
+
+impl Database {
+	/// Marks a collection as holding `LwwMap` records, which are merged by
+	/// `sync` instead of conflicting.
+	///
+	/// This also adds a validator that rejects records that are not maps.
+	pub fn add_crdt(&self, collection: &str) {
+		if self.crdt_collections().names.insert(collection.to_string()) {
+			self.add_validator(collection, |_, value| {
+				LwwMap::from_bytes(value)
+					.map(|_| ())
+					.map_err(|err| err.to_string())
+			});
+		}
+	}
+
+	/// Returns true if the collection was marked with `add_crdt`.
+	pub fn is_crdt(&self, collection: &str) -> bool {
+		self.crdt_collections().names.contains(collection)
+	}
+
+	/// Merges the value of a record changed on both sides of a sync. Returns
+	/// the merged value, or `None` if the collection doesn't hold maps.
+	pub(crate) fn merge_crdt(
+		&self,
+		collection: &str,
+		local: Option<&[u8]>,
+		remote: Option<&[u8]>,
+	) -> Result<Option<Vec<u8>>> {
+		if !self.is_crdt(collection) {
+			return Ok(None);
+		}
+		let parse = |value: Option<&[u8]>| {
+			value
+				.map(LwwMap::from_bytes)
+				.unwrap_or_else(|| Ok(LwwMap::new()))
+		};
+		let mut map = parse(local)?;
+		map.merge(&parse(remote)?);
+		Ok(Some(map.to_bytes()))
+	}
+}
+
+impl<'a> Collection<'a> {
+	/// Changes the `LwwMap` stored in a record, creating it if it doesn't
+	/// exist. Returns the new version of the record.
+	///
+	/// The callback may be called more than once, if the record is changed
+	/// concurrently.
+	pub fn update_map<F: FnMut(&mut LwwMap)>(&self, key: &str, mut callback: F) -> Result<u64> {
+		loop {
+			let (mut map, version) = match self.get_versioned(key)? {
+				Some((value, version)) => (LwwMap::from_bytes(&value)?, version),
+				None => (LwwMap::new(), 0),
+			};
+			callback(&mut map);
+			match self.put_if_version(key, &map.to_bytes(), version) {
+				Err(Error::Conflict(_)) => continue,
+				result => return result,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, LocalTransport, OpenFlags};
+	use serde_json::json;
+
+	#[test]
+	fn should_merge_maps() {
+		let mut a = LwwMap::new();
+		a.set("title", json!("A"));
+		a.set("tags", json!(["x"]));
+		let mut b = a.clone();
+
+		b.set("title", json!("B"));
+		a.set("body", json!("text"));
+		a.remove("tags");
+		b.set("color", json!("red"));
+
+		let mut ab = a.clone();
+		let mut ba = b.clone();
+		assert!(ab.merge(&b));
+		assert!(ba.merge(&a));
+		assert_eq!(ab, ba);
+		assert_eq!(ab.get("title"), Some(&json!("B")));
+		assert_eq!(ab.get("body"), Some(&json!("text")));
+		assert_eq!(ab.get("tags"), None);
+		let names = ab.fields().map(|(name, _)| name).collect::<Vec<_>>();
+		assert_eq!(names, vec!["body", "color", "title"]);
+
+		// Merging again changes nothing.
+		assert!(!ab.merge(&a));
+		assert_eq!(LwwMap::from_bytes(&ab.to_bytes()).unwrap(), ab);
+		assert!(LwwMap::from_bytes(b"[]").is_err());
+	}
+
+	#[test]
+	fn should_merge_maps_on_sync() {
+		let (a, temp) = create_db(OpenFlags::default());
+		let b = open(temp.path().join("b"), OpenFlags::default()).unwrap();
+		a.add_crdt("notes");
+		b.add_crdt("notes");
+		let (a_notes, b_notes) = (
+			a.collection("notes").unwrap(),
+			b.collection("notes").unwrap(),
+		);
+		assert!(a_notes.put("invalid", b"[]").is_err());
+
+		a_notes
+			.update_map("1", |map| map.set("title", json!("T")))
+			.unwrap();
+		a.sync("b", &mut LocalTransport(&b)).unwrap();
+
+		a_notes
+			.update_map("1", |map| map.set("title", json!("T2")))
+			.unwrap();
+		b_notes
+			.update_map("1", |map| map.set("body", json!("B")))
+			.unwrap();
+		let summary = a.sync("b", &mut LocalTransport(&b)).unwrap();
+		assert!(summary.conflicts.is_empty());
+		assert_eq!((summary.pulled, summary.pushed), (1, 1));
+		for notes in &[&a_notes, &b_notes] {
+			let map = LwwMap::from_bytes(&notes.get("1").unwrap().unwrap()).unwrap();
+			assert_eq!(map.get("title"), Some(&json!("T2")));
+			assert_eq!(map.get("body"), Some(&json!("B")));
+		}
+
+		// Both sides are the same, so nothing is exchanged again.
+		let summary = b.sync("a", &mut LocalTransport(&a)).unwrap();
+		assert_eq!((summary.pulled, summary.pushed), (0, 0));
+
+		drop((a, b));
+		temp.close().unwrap();
+	}
+}
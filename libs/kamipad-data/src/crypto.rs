@@ -0,0 +1,236 @@
+//! Encryption at rest for records and blobs.
+//!
+//! Encryption is enabled by providing a passphrase when opening the database.
+//! The encryption key is derived from the passphrase using PBKDF2-SHA256 with
+//! a random salt, and data is encrypted with ChaCha20-Poly1305 using a random
+//! nonce for every write.
+//!
+//! The key derivation parameters are stored in the `encryption` file at the
+//! database root, together with an encrypted check value that is used to
+//! validate the passphrase on open:
+//!
+//! ```text
+//! kdf=pbkdf2-sha256
+//! iterations=100000
+//! salt=<hex>
+//! check=<hex>
+//! ```
+//!
+//! Once a database has an `encryption` file, it can only be opened with the
+//! passphrase. Enabling encryption on an existing database only affects data
+//! written afterwards.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::{Error, IOError};
+use crate::util;
+use crate::Result;
+
+pub(crate) const ENCRYPTION_FILENAME: &str = "encryption";
+
+/// Name of the cipher used in record headers.
+pub(crate) const CIPHER_NAME: &str = "chacha20poly1305";
+
+const KDF_NAME: &str = "pbkdf2-sha256";
+const KDF_ITERATIONS: u32 = 100_000;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Known plain text encrypted in the `check` value.
+const CHECK_TEXT: &[u8] = b"kamipad";
+
+/// Symmetric cipher used to encrypt data in the database.
+pub(crate) struct Cipher {
+	inner: ChaCha20Poly1305,
+}
+
+impl Cipher {
+	fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Cipher {
+		let mut key = [0u8; KEY_LEN];
+		pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+		Cipher {
+			inner: ChaCha20Poly1305::new(Key::from_slice(&key)),
+		}
+	}
+
+	/// Encrypts the data, returning the nonce followed by the cipher text.
+	pub fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+		let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+		let encrypted = self
+			.inner
+			.encrypt(&nonce, data)
+			.map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+
+		let mut out = Vec::with_capacity(NONCE_LEN + encrypted.len());
+		out.extend_from_slice(&nonce);
+		out.extend_from_slice(&encrypted);
+		Ok(out)
+	}
+
+	/// Decrypts data generated by `encrypt`. Fails if the data has been
+	/// tampered with or was encrypted with a different key.
+	pub fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+		let failed = || io::Error::new(io::ErrorKind::InvalidData, "decryption failed");
+		if data.len() < NONCE_LEN {
+			return Err(failed());
+		}
+		let (nonce, encrypted) = data.split_at(NONCE_LEN);
+		self.inner
+			.decrypt(Nonce::from_slice(nonce), encrypted)
+			.map_err(|_| failed())
+	}
+}
+
+/// Loads the database cipher, validating the passphrase.
+///
+/// If the database is not encrypted and a passphrase is given, encryption
+/// is set up when `writable` is true.
+pub(crate) fn load(
+	root: &Path,
+	passphrase: Option<&str>,
+	writable: bool,
+) -> Result<Option<Cipher>> {
+	let path = root.join(ENCRYPTION_FILENAME);
+	let text = match fs::read_to_string(&path) {
+		Ok(text) => Some(text),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+		Err(err) => return Err(open_error(err, "reading", &path)),
+	};
+
+	match (text, passphrase) {
+		(None, None) => Ok(None),
+		(None, Some(_)) if !writable => Ok(None),
+		(None, Some(passphrase)) => create(&path, passphrase).map(Some),
+		(Some(_), None) => Err(Error::WrongPassphrase),
+		(Some(text), Some(passphrase)) => {
+			let invalid = || open_error(util::invalid_data("invalid format"), "reading", &path);
+			let params = text
+				.lines()
+				.filter_map(|line| {
+					let mut parts = line.splitn(2, '=');
+					Some((parts.next()?.trim(), parts.next()?.trim()))
+				})
+				.collect::<HashMap<_, _>>();
+
+			if params.get("kdf") != Some(&KDF_NAME) {
+				return Err(invalid());
+			}
+			let iterations = params.get("iterations").and_then(|s| s.parse().ok());
+			let salt = params.get("salt").and_then(|s| util::from_hex(s));
+			let check = params.get("check").and_then(|s| util::from_hex(s));
+			let (iterations, salt, check) = match (iterations, salt, check) {
+				(Some(iterations), Some(salt), Some(check)) => (iterations, salt, check),
+				_ => return Err(invalid()),
+			};
+
+			let cipher = Cipher::derive(passphrase, &salt, iterations);
+			match cipher.decrypt(&check) {
+				Ok(ref text) if text == CHECK_TEXT => Ok(Some(cipher)),
+				_ => Err(Error::WrongPassphrase),
+			}
+		}
+	}
+}
+
+fn create(path: &Path, passphrase: &str) -> Result<Cipher> {
+	let mut salt = [0u8; SALT_LEN];
+	OsRng.fill_bytes(&mut salt);
+
+	let cipher = Cipher::derive(passphrase, &salt, KDF_ITERATIONS);
+	let write = |err| open_error(err, "writing", path);
+	let check = cipher.encrypt(CHECK_TEXT).map_err(write)?;
+	let text = format!(
+		"kdf={}\niterations={}\nsalt={}\ncheck={}\n",
+		KDF_NAME,
+		KDF_ITERATIONS,
+		util::to_hex(&salt),
+		util::to_hex(&check),
+	);
+	crate::fs::atomic_write(path, text.as_bytes()).map_err(write)?;
+	Ok(cipher)
+}
+
+fn open_error(err: io::Error, action: &str, path: &Path) -> Error {
+	Error::Open(IOError::new(
+		err,
+		format!("{} encryption file `{}`", action, path.to_string_lossy()),
+	))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	fn with_passphrase(passphrase: &str) -> OpenFlags {
+		let passphrase = passphrase.to_string();
+		OpenFlags::config(|f| f.passphrase = Some(passphrase))
+	}
+
+	#[test]
+	fn should_encrypt_records_and_blobs() {
+		let (db, temp) = create_db(with_passphrase("secret"));
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"secret note").unwrap();
+		let blob = db.put_blob(b"secret blob").unwrap();
+
+		let raw = fs::read(db.path.join("data").join("notes").join("a")).unwrap();
+		assert!(raw.starts_with(b"#record cipher=chacha20poly1305 version=1 crc32="));
+		assert!(!raw.windows(6).any(|w| w == b"secret"));
+
+		let path = db.path.clone();
+		drop(db);
+
+		let db = open(&path, with_passphrase("secret")).unwrap();
+		let notes = db.collection("notes").unwrap();
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"secret note");
+		assert_eq!(db.get_blob(&blob).unwrap().unwrap(), b"secret blob");
+		drop(db);
+
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_reject_wrong_passphrase() {
+		let (db, temp) = create_db(with_passphrase("secret"));
+		let path = db.path.clone();
+		drop(db);
+
+		match open(&path, with_passphrase("wrong")) {
+			Err(Error::WrongPassphrase) => (),
+			other => panic!("expected Error::WrongPassphrase, got {:?}", other),
+		}
+
+		match open(&path, OpenFlags::default()) {
+			Err(Error::WrongPassphrase) => (),
+			other => panic!("expected Error::WrongPassphrase, got {:?}", other),
+		}
+
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_detect_tampering() {
+		let salt = [1u8; SALT_LEN];
+		let cipher = Cipher::derive("secret", &salt, 1);
+		let mut data = cipher.encrypt(b"data").unwrap();
+		assert_eq!(cipher.decrypt(&data).unwrap(), b"data");
+
+		let last = data.len() - 1;
+		data[last] ^= 1;
+		assert!(cipher.decrypt(&data).is_err());
+
+		let other = Cipher::derive("other", &salt, 1);
+		assert!(other.decrypt(&cipher.encrypt(b"data").unwrap()).is_err());
+	}
+}
@@ -1,6 +1,40 @@
 use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::cache::RecordCache;
+use crate::cascade::Cascades;
+use crate::crdt::CrdtCollections;
+use crate::crypto::Cipher;
+use crate::error::{Error, IOError};
+use crate::journal::{Change, JournalEntry, JOURNAL_FILENAME};
+use crate::lock;
+use crate::mvcc::{self, Registration};
+use crate::open::{self, DB_LOCK_FILENAME};
+use crate::quota::Usage;
+use crate::record::Codec;
+use crate::search::SearchIndexes;
+use crate::transaction;
+use crate::util::{self, FileData};
+use crate::validate::Validators;
+use crate::watch::Hooks;
+use crate::Result;
+
+/// Overview of a database, as returned by `Database::stats`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DatabaseStats {
+	pub path: PathBuf,
+	pub read_only: bool,
+	/// Number of collections.
+	pub collections: usize,
+	/// Space used by the records and blobs.
+	pub usage: Usage,
+	/// Sequence number of the last change.
+	pub sequence: u64,
+}
 
 /// Root type for a Database.
 pub struct Database {
@@ -8,34 +42,407 @@ pub struct Database {
 	pub path: PathBuf,
 
 	read_only: bool,
+	compress: bool,
+	mmap: bool,
+	soft_delete: bool,
+	max_revisions: usize,
+	snapshots: u64,
+	max_size: Option<u64>,
+	max_records: Option<u64>,
+	cipher: Option<Cipher>,
 
 	// We keep this tied to the Database instance, so that the database file
 	// lock is released when the instance is dropped.
-	_lock_file: fs::File,
+	lock_file: fs::File,
+
+	// Kept open by the writer to lock while making a change, so that readers
+	// never see a partial change. See the `mvcc` module.
+	commit_lock: Option<fs::File>,
+
+	// Registration for a reader, which the writer keeps undo data for.
+	registration: Option<Registration>,
+
+	// Serializes write operations within the process. The lock file only
+	// protects against other processes.
+	write_lock: Mutex<()>,
+
+	// Sequence number of the last change in the journal.
+	last_seq: Mutex<u64>,
+
+	// Offset in the journal up to which changes were seen by `poll_changes`.
+	journal_offset: Mutex<u64>,
+
+	// Subscribers from `watch` and changes waiting to be sent to them.
+	watchers: Mutex<Vec<Sender<JournalEntry>>>,
+	pending_notifications: Mutex<Vec<JournalEntry>>,
+
+	// Callbacks from `on_write` and `on_commit`.
+	hooks: Mutex<Hooks>,
+
+	// Validators from `add_validator`.
+	validators: Mutex<Validators>,
+
+	// Rules from `add_cascade`.
+	cascades: Mutex<Cascades>,
+
+	// Collections from `add_crdt`.
+	crdt_collections: Mutex<CrdtCollections>,
+
+	// Full-text indexes added with `add_search_index`.
+	search_indexes: Mutex<SearchIndexes>,
+
+	// Records cached by `Collection::get`.
+	cache: Mutex<RecordCache>,
+
+	// Usage for the quota, once measured.
+	usage: Mutex<Option<Usage>>,
+}
+
+/// Guard for the in-process write lock. Sends the change notifications for
+/// the write when released.
+pub(crate) struct WriteGuard<'a> {
+	db: &'a Database,
+	_guard: MutexGuard<'a, ()>,
+	commit_lock: Option<fs::File>,
+}
+
+impl<'a> Drop for WriteGuard<'a> {
+	fn drop(&mut self) {
+		self.db.uncache_pending();
+		self.db.send_notifications();
+		if let Some(file) = &self.commit_lock {
+			let _ = fs2::FileExt::unlock(file);
+		}
+	}
 }
 
 pub(crate) struct InitConfig {
 	pub path: PathBuf,
 	pub read_only: bool,
+	pub compress: bool,
+	pub mmap: bool,
+	pub soft_delete: bool,
+	pub max_revisions: usize,
+	pub snapshots: u64,
+	pub cache_size: usize,
+	pub max_size: Option<u64>,
+	pub max_records: Option<u64>,
+	pub cipher: Option<Cipher>,
 	pub lock_file: fs::File,
+	pub commit_lock: Option<fs::File>,
+	pub registration: Option<Registration>,
+	pub last_seq: u64,
+	pub journal_offset: u64,
 }
 
 impl Database {
 	/// Returns a new instance of the database. This is used internally by the
 	/// library to construct a new instance.
 	pub(crate) fn new(config: InitConfig) -> Database {
-		let db = Database {
+		Database {
 			path: config.path,
 			read_only: config.read_only,
-			_lock_file: config.lock_file,
-		};
-		db
+			compress: config.compress,
+			mmap: config.mmap,
+			soft_delete: config.soft_delete,
+			max_revisions: config.max_revisions,
+			snapshots: config.snapshots,
+			max_size: config.max_size,
+			max_records: config.max_records,
+			cipher: config.cipher,
+			lock_file: config.lock_file,
+			commit_lock: config.commit_lock,
+			registration: config.registration,
+			write_lock: Mutex::new(()),
+			last_seq: Mutex::new(config.last_seq),
+			journal_offset: Mutex::new(config.journal_offset),
+			watchers: Mutex::new(Vec::new()),
+			pending_notifications: Mutex::new(Vec::new()),
+			hooks: Mutex::new(Hooks::default()),
+			validators: Mutex::new(Validators::default()),
+			cascades: Mutex::new(Cascades::default()),
+			crdt_collections: Mutex::new(CrdtCollections::default()),
+			search_indexes: Mutex::new(SearchIndexes::default()),
+			cache: Mutex::new(RecordCache::new(config.cache_size)),
+			usage: Mutex::new(None),
+		}
 	}
 
 	/// Returns true if the database has been opened in read-only mode.
 	pub fn is_read_only(&self) -> bool {
 		self.read_only
 	}
+
+	/// Returns an overview of the database, for monitoring.
+	pub fn stats(&self) -> Result<DatabaseStats> {
+		Ok(DatabaseStats {
+			path: self.path.clone(),
+			read_only: self.read_only,
+			collections: self.collections()?.len(),
+			usage: self.usage()?,
+			sequence: self.sequence(),
+		})
+	}
+
+	/// Checks that the database still holds its lock, for monitoring. Fails
+	/// with `Error::WriteLock` if the lock file was removed, such as by
+	/// `break_lock`, or if a writer no longer owns it, since either allows
+	/// another writer to open the database.
+	pub fn check_lock(&self) -> Result<()> {
+		let path = self.path.join(DB_LOCK_FILENAME);
+		let lost = |reason: &str| {
+			Error::WriteLock(IOError::new(
+				io::Error::new(io::ErrorKind::Other, reason),
+				format!("checking lock on `{}`", path.to_string_lossy()),
+			))
+		};
+		if !path.is_file() {
+			return Err(lost("the lock file was removed"));
+		}
+		if !self.read_only {
+			let owner = lock::lock_info(&self.path)?.map(|info| info.pid);
+			if owner != Some(std::process::id()) {
+				return Err(lost("the lock is owned by another process"));
+			}
+		}
+		Ok(())
+	}
+
+	/// Checks that the journal can be opened for appending, for monitoring.
+	/// This fails if the disk became read-only or full of errors, before a
+	/// write does. Fails with `Error::ReadOnly` in read-only mode.
+	pub fn check_journal(&self) -> Result<()> {
+		self.check_writable()?;
+		let path = self.path.join(JOURNAL_FILENAME);
+		fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)
+			.map(|_| ())
+			.map_err(|err| util::write_error(err, &path))
+	}
+
+	/// Upgrades a database opened in read-only mode to writable, by
+	/// acquiring the exclusive write lock.
+	///
+	/// This fails with `Error::WriteLock` if there is already a writer. In
+	/// that case the database stays in read-only mode.
+	///
+	/// Memory-mapped reads are disabled by the upgrade. Encryption is not set
+	/// up for an unencrypted database, even if opened with a passphrase.
+	pub fn upgrade_to_writable(&mut self) -> Result<()> {
+		if !self.read_only {
+			return Ok(());
+		}
+
+		let lock_error = |err, action| {
+			let path = self.path.join(DB_LOCK_FILENAME);
+			IOError::new(
+				err,
+				format!("{} lock on `{}`", action, path.to_string_lossy()),
+			)
+		};
+
+		open::acquire_lock(&self.lock_file, true, None)
+			.map_err(|err| Error::WriteLock(lock_error(err, "acquiring exclusive")))?;
+
+		// Pick up any change made by a writer since we opened, so that our
+		// sequence numbers continue from the journal.
+		self.poll_changes()?;
+		lock::write_lock_info(&self.path)?;
+		transaction::recover(&self.path)?;
+		self.cache().clear();
+
+		self.commit_lock = mvcc::open_commit_lock(&self.path, true)?;
+		self.registration = None;
+		self.read_only = false;
+		self.mmap = false;
+		Ok(())
+	}
+
+	/// Forces the changes made so far to disk, waiting for any write in
+	/// progress.
+	///
+	/// Files are written without syncing, so changes made right before a
+	/// system crash may be lost. After flushing, every change in the journal
+	/// is guaranteed to survive a crash.
+	pub fn flush(&self) -> Result<()> {
+		if self.read_only {
+			return Ok(());
+		}
+		let _guard = self.write_guard();
+		let path = self.path.join(JOURNAL_FILENAME);
+		let result = match fs::File::open(&path) {
+			Ok(file) => file.sync_all(),
+			Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+			Err(err) => Err(err),
+		};
+		result.map_err(|err| util::write_error(err, &path))
+	}
+
+	/// Flushes and closes the database, releasing its locks.
+	///
+	/// This is the same as dropping the database, except that errors are
+	/// returned instead of ignored.
+	pub fn close(mut self) -> Result<()> {
+		self.flush()?;
+		if let Some(registration) = self.registration.take() {
+			registration.remove()?;
+		}
+		if !self.read_only {
+			lock::clear_lock_info(&self.path)?;
+			// Nothing is left for `drop` to clean up.
+			self.read_only = true;
+		}
+		Ok(())
+	}
+
+	/// Returns `Error::ReadOnly` if the database cannot be written to.
+	pub(crate) fn check_writable(&self) -> Result<()> {
+		if self.read_only {
+			Err(Error::ReadOnly)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Returns true if the database is encrypted.
+	pub fn is_encrypted(&self) -> bool {
+		self.cipher.is_some()
+	}
+
+	/// Returns true if deleted records are moved to the trash.
+	pub(crate) fn soft_delete(&self) -> bool {
+		self.soft_delete
+	}
+
+	/// Returns the maximum size and number of records for the quota.
+	pub(crate) fn quota(&self) -> (Option<u64>, Option<u64>) {
+		(self.max_size, self.max_records)
+	}
+
+	/// Returns the number of prior versions kept for each record.
+	pub(crate) fn max_revisions(&self) -> usize {
+		self.max_revisions
+	}
+
+	/// Returns the number of past changes that can be read with `read_at`.
+	pub(crate) fn snapshots(&self) -> u64 {
+		self.snapshots
+	}
+
+	/// Codec used to encode and decode records in the database.
+	pub(crate) fn codec(&self) -> Codec<'_> {
+		Codec {
+			compress: self.compress,
+			cipher: self.cipher.as_ref(),
+		}
+	}
+
+	/// Reads the current version of a database file, memory-mapping it if
+	/// enabled.
+	pub(crate) fn read_current_file(&self, path: &Path) -> io::Result<FileData> {
+		util::read_file(path, self.mmap)
+	}
+
+	/// Acquires the in-process write lock for the database, together with
+	/// the commit lock: exclusive for the writer, shared for readers.
+	pub(crate) fn write_guard(&self) -> WriteGuard<'_> {
+		let guard = lock(&self.write_lock);
+		self.cache().begin_write();
+		WriteGuard {
+			db: self,
+			_guard: guard,
+			commit_lock: self.lock_commit(),
+		}
+	}
+
+	/// Returns the open commit lock file, for the writer.
+	pub(crate) fn commit_lock(&self) -> Option<&fs::File> {
+		self.commit_lock.as_ref()
+	}
+
+	/// Returns the registration for a reader.
+	pub(crate) fn registration(&self) -> Option<&Registration> {
+		self.registration.as_ref()
+	}
+
+	/// Locks and returns the sequence number of the last change.
+	pub(crate) fn last_seq(&self) -> MutexGuard<'_, u64> {
+		lock(&self.last_seq)
+	}
+
+	pub(crate) fn journal_offset(&self) -> MutexGuard<'_, u64> {
+		lock(&self.journal_offset)
+	}
+
+	pub(crate) fn watchers(&self) -> MutexGuard<'_, Vec<Sender<JournalEntry>>> {
+		lock(&self.watchers)
+	}
+
+	pub(crate) fn pending_notifications(&self) -> MutexGuard<'_, Vec<JournalEntry>> {
+		lock(&self.pending_notifications)
+	}
+
+	pub(crate) fn hooks(&self) -> MutexGuard<'_, Hooks> {
+		lock(&self.hooks)
+	}
+
+	pub(crate) fn validators(&self) -> MutexGuard<'_, Validators> {
+		lock(&self.validators)
+	}
+
+	pub(crate) fn cascades(&self) -> MutexGuard<'_, Cascades> {
+		lock(&self.cascades)
+	}
+
+	pub(crate) fn crdt_collections(&self) -> MutexGuard<'_, CrdtCollections> {
+		lock(&self.crdt_collections)
+	}
+
+	pub(crate) fn search_indexes(&self) -> MutexGuard<'_, SearchIndexes> {
+		lock(&self.search_indexes)
+	}
+
+	pub(crate) fn cache(&self) -> MutexGuard<'_, RecordCache> {
+		lock(&self.cache)
+	}
+
+	pub(crate) fn cached_usage(&self) -> MutexGuard<'_, Option<Usage>> {
+		lock(&self.usage)
+	}
+
+	/// Drops the records changed by the pending notifications from the
+	/// cache. See the `cache` module.
+	fn uncache_pending(&self) {
+		let mut cache = self.cache();
+		if !cache.is_enabled() {
+			return;
+		}
+		for entry in self.pending_notifications().iter() {
+			match &entry.change {
+				Change::Put { collection, key } | Change::Delete { collection, key } => {
+					cache.remove(collection, key);
+				}
+				_ => {}
+			}
+		}
+	}
+}
+
+/// Locks a mutex, ignoring poisoning. Our locks only protect file system
+/// state, which is always left consistent.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl Drop for Database {
+	fn drop(&mut self) {
+		// The lock itself is released when the lock file is closed.
+		if !self.read_only {
+			let _ = lock::clear_lock_info(&self.path);
+		}
+	}
 }
 
 impl fmt::Display for Database {
@@ -54,3 +461,111 @@ impl fmt::Debug for Database {
 		<Self as fmt::Display>::fmt(self, f)
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use crate::mvcc::active_readers;
+	use crate::testing::create_db;
+	use crate::{break_lock, lock_info, open, Error, OpenFlags};
+
+	#[test]
+	fn should_close_database() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.collection("notes").unwrap().put("a", b"A").unwrap();
+		db.flush().unwrap();
+		let path = db.path.clone();
+
+		let reader = open(&path, OpenFlags::read_only()).unwrap();
+		assert_eq!(active_readers(&path).unwrap().len(), 1);
+		reader.close().unwrap();
+		assert!(active_readers(&path).unwrap().is_empty());
+
+		assert!(lock_info(&path).unwrap().is_some());
+		db.close().unwrap();
+		assert_eq!(lock_info(&path).unwrap(), None);
+
+		let db = open(&path, OpenFlags::default()).unwrap();
+		assert_eq!(db.sequence(), 1);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_return_stats() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.collection("notes").unwrap().put("a", b"A").unwrap();
+		db.collection("notes").unwrap().put("b", b"BB").unwrap();
+		db.collection("tags").unwrap().put("a", b"").unwrap();
+
+		let stats = db.stats().unwrap();
+		assert_eq!(stats.path, db.path);
+		assert!(!stats.read_only);
+		assert_eq!(stats.collections, 2);
+		assert_eq!(stats.usage.records, 3);
+		assert_eq!(stats.sequence, 3);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_check_health() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let path = db.path.clone();
+		db.check_lock().unwrap();
+		db.check_journal().unwrap();
+
+		let reader = open(&path, OpenFlags::read_only()).unwrap();
+		reader.check_lock().unwrap();
+		match reader.check_journal() {
+			Err(Error::ReadOnly) => (),
+			other => panic!("expected Error::ReadOnly, got {:?}", other),
+		}
+
+		// Forcing the lock open loses it for both.
+		assert!(break_lock(&path, true).unwrap());
+		match db.check_lock() {
+			Err(Error::WriteLock(_)) => (),
+			other => panic!("expected Error::WriteLock, got {:?}", other),
+		}
+		assert!(reader.check_lock().is_err());
+
+		drop(reader);
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_upgrade_to_writable() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.collection("notes").unwrap().put("a", b"A").unwrap();
+		let path = db.path.clone();
+		drop(db);
+
+		let mut db = open(&path, OpenFlags::read_only()).unwrap();
+		let other = open(&path, OpenFlags::read_only()).unwrap();
+
+		// Fails while there is a writer.
+		let writer = open(&path, OpenFlags::default()).unwrap();
+		match db.upgrade_to_writable() {
+			Err(Error::WriteLock(_)) => (),
+			other => panic!("expected Error::WriteLock, got {:?}", other),
+		}
+		assert!(db.is_read_only());
+		drop(writer);
+
+		// Other readers don't prevent the upgrade.
+		db.upgrade_to_writable().unwrap();
+		assert!(!db.is_read_only());
+		assert!(open(&path, OpenFlags::default()).is_err());
+		drop(other);
+
+		let notes = db.collection("notes").unwrap();
+		notes.put("b", b"B").unwrap();
+		assert_eq!(db.sequence(), 2);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -1,11 +1,34 @@
 use std::fmt;
 use std::io;
 
+use crate::verify::VerifyError;
+
 /// The error type associated with Database operations.
 pub enum Error {
 	Open(IOError),
 	ReadLock(IOError),
 	WriteLock(IOError),
+	Read(IOError),
+	Write(IOError),
+	ReadOnly,
+	NotFound(String),
+	InvalidName(String),
+	WrongPassphrase,
+	Corrupt(IOError),
+	InvalidBackup(String),
+	InvalidImport(String),
+	UnsupportedVersion(u32),
+	MigrationRequired(u32),
+	SnapshotUnavailable(u64),
+	Conflict(String),
+	Validation(ValidationError),
+	Linked(String),
+	InvalidCursor(String),
+	QuotaExceeded(String),
+	Verify(VerifyError),
+	InvalidJournal(String),
+	InvalidCrdt(String),
+	JournalTruncated(u64),
 }
 
 impl Error {
@@ -14,6 +37,44 @@ impl Error {
 			Error::Open(error) => write!(f, "opening the database: {}", error),
 			Error::ReadLock(error) => write!(f, "locking the database for reading: {}", error),
 			Error::WriteLock(error) => write!(f, "locking the database for writing: {}", error),
+			Error::Read(error) => write!(f, "reading from the database: {}", error),
+			Error::Write(error) => write!(f, "writing to the database: {}", error),
+			Error::ReadOnly => write!(f, "the database is open in read-only mode"),
+			Error::NotFound(what) => write!(f, "{} not found", what),
+			Error::InvalidName(name) => write!(f, "invalid collection or key name `{}`", name),
+			Error::WrongPassphrase => {
+				write!(f, "wrong or missing passphrase for encrypted database")
+			}
+			Error::Corrupt(error) => write!(f, "corrupted data in the database: {}", error),
+			Error::InvalidBackup(reason) => write!(f, "invalid backup: {}", reason),
+			Error::InvalidImport(reason) => write!(f, "invalid import data: {}", reason),
+			Error::UnsupportedVersion(version) => write!(
+				f,
+				"database format version {} is newer than supported ({})",
+				version,
+				crate::FORMAT_VERSION
+			),
+			Error::SnapshotUnavailable(seq) => {
+				write!(f, "snapshot at sequence {} is not available", seq)
+			}
+			Error::Conflict(reason) => write!(f, "conflicting change: {}", reason),
+			Error::Validation(error) => write!(f, "invalid record: {}", error),
+			Error::Linked(reason) => write!(f, "record is linked: {}", reason),
+			Error::InvalidCursor(cursor) => write!(f, "invalid query cursor `{}`", cursor),
+			Error::QuotaExceeded(reason) => write!(f, "quota exceeded: {}", reason),
+			Error::Verify(error) => write!(f, "database failed verification: {}", error),
+			Error::InvalidJournal(reason) => write!(f, "invalid journal batch: {}", reason),
+			Error::InvalidCrdt(reason) => write!(f, "invalid CRDT record: {}", reason),
+			Error::JournalTruncated(seq) => write!(
+				f,
+				"changes after sequence {} were removed from the journal",
+				seq
+			),
+			Error::MigrationRequired(version) => write!(
+				f,
+				"database format version {} must be migrated by opening it for writing",
+				version
+			),
 		}
 	}
 }
@@ -45,6 +106,11 @@ impl IOError {
 			inner,
 		}
 	}
+
+	/// Returns the kind of the underlying IO error.
+	pub fn kind(&self) -> io::ErrorKind {
+		self.inner.kind()
+	}
 }
 
 impl fmt::Display for IOError {
@@ -52,3 +118,18 @@ impl fmt::Display for IOError {
 		write!(f, "{} -- {}", self.message, self.inner)
 	}
 }
+
+/// Details for a record rejected by a validator, used in `Error::Validation`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ValidationError {
+	pub collection: String,
+	pub key: String,
+	/// Problem reported by the validator.
+	pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{} -- {}", self.collection, self.key, self.message)
+	}
+}
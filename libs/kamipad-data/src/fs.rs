@@ -0,0 +1,105 @@
+//! File system helpers, also available to applications using the database.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::id::ID;
+
+/// Extension for the temporary files used when writing a file.
+pub(crate) const TEMP_EXTENSION: &str = "tmp";
+
+/// Writes a file atomically and durably.
+///
+/// The data is written to a temporary file alongside the target, which is
+/// synced to disk and then renamed into place. The directory is then synced
+/// as well, so that the rename itself survives a crash. Readers see either
+/// the previous or the new contents of the file, never a partial write, and
+/// after a crash the file is also in one of these states.
+///
+/// # Examples
+///
+/// ```
+/// # let dir = tempdir::TempDir::new("kamipad-data").unwrap();
+/// # let path = dir.path().join("config");
+/// kamipad_data::fs::atomic_write(&path, b"name=value").unwrap();
+/// assert_eq!(std::fs::read(&path).unwrap(), b"name=value");
+/// ```
+pub fn atomic_write<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+	write_replacing(path.as_ref(), data, true)
+}
+
+/// Writes a file by writing to a temporary file and renaming it into place,
+/// syncing both the file and its directory if `sync` is true.
+pub(crate) fn write_replacing(path: &Path, data: &[u8], sync: bool) -> io::Result<()> {
+	let temp_path = temp_path(path);
+	let result = write_temp(&temp_path, data, sync).and_then(|_| fs::rename(&temp_path, path));
+	if result.is_err() {
+		let _ = fs::remove_file(&temp_path);
+	}
+	result?;
+	if sync {
+		sync_dir(path)?;
+	}
+	Ok(())
+}
+
+fn write_temp(temp_path: &Path, data: &[u8], sync: bool) -> io::Result<()> {
+	use std::io::Write;
+	let mut file = fs::File::create(temp_path)?;
+	file.write_all(data)?;
+	if sync {
+		file.sync_all()?;
+	}
+	Ok(())
+}
+
+/// Syncs the directory containing the path. Directories can't be opened on
+/// Windows, where renames are durable without this.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+	let dir = match path.parent() {
+		Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+		Some(dir) => dir,
+		None => return Ok(()),
+	};
+	fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+	Ok(())
+}
+
+/// Returns a unique temporary path alongside the given path, so that
+/// concurrent writes to the same file don't clash.
+fn temp_path(path: &Path) -> PathBuf {
+	let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+	name.push(format!(".{}.{}", ID::new(), TEMP_EXTENSION));
+	path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::util::is_temp_file;
+	use tempdir::TempDir;
+
+	#[test]
+	fn should_write_atomically() {
+		let temp = TempDir::new("kamipad-data").unwrap();
+		let path = temp.path().join("file");
+		atomic_write(&path, b"one").unwrap();
+		atomic_write(&path, b"two").unwrap();
+		assert_eq!(fs::read(&path).unwrap(), b"two");
+		assert!(is_temp_file(temp_path(&path)));
+
+		// No temporary file is left behind, even on failure.
+		assert!(atomic_write(temp.path().join("missing").join("file"), b"").is_err());
+		let entries = fs::read_dir(temp.path()).unwrap().count();
+		assert_eq!(entries, 1);
+
+		temp.close().unwrap();
+	}
+}
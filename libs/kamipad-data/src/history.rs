@@ -0,0 +1,126 @@
+//! Revision history for records.
+//!
+//! With `OpenFlags::max_revisions`, the current version of a record is
+//! saved before it is overwritten. Prior versions are stored as is under the
+//! `history` directory, in `history/<collection>/<key>/<revision>`, where
+//! revisions are numbered sequentially from one for each record.
+//!
+//! Only the latest `max_revisions` versions are kept. Revisions are kept
+//! when a record is deleted, so a deleted record can still be recovered
+//! from its history.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::collection::{list_names, Collection};
+use crate::util::{self, corrupt_error, read_error, write_error};
+use crate::Result;
+
+pub(crate) const HISTORY_DIR: &str = "history";
+
+impl<'a> Collection<'a> {
+	/// Returns the revision numbers for the prior versions of a record, from
+	/// oldest to newest.
+	pub fn revisions(&self, key: &str) -> Result<Vec<u64>> {
+		self.record_path(key)?;
+		let mut revisions = list_names(&self.history_path(key), false)?
+			.into_iter()
+			.filter_map(|name| name.parse().ok())
+			.collect::<Vec<u64>>();
+		revisions.sort_unstable();
+		Ok(revisions)
+	}
+
+	/// Reads a prior version of a record. Returns `None` if the revision
+	/// does not exist.
+	pub fn get_revision(&self, key: &str, revision: u64) -> Result<Option<Vec<u8>>> {
+		self.record_path(key)?;
+		let path = self.history_path(key).join(revision.to_string());
+		let data = match self.db.read_file(&path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(read_error(err, &path)),
+		};
+		self.db
+			.codec()
+			.decode(&data)
+			.map(Some)
+			.map_err(|err| corrupt_error(err, &path))
+	}
+
+	/// Saves the current version of a record as a new revision, removing
+	/// the oldest revisions beyond the limit. Must be called with the write
+	/// lock held.
+	pub(crate) fn save_revision(&self, key: &str) -> Result<()> {
+		let path = self.record_path(key)?;
+		let history_path = self.history_path(key);
+		let mut revisions = self.revisions(key)?;
+		let next = revisions.last().map(|n| n + 1).unwrap_or(1);
+
+		let revision_path = history_path.join(next.to_string());
+		fs::create_dir_all(&history_path)
+			.and_then(|_| fs::read(&path))
+			.and_then(|data| util::write_file(&revision_path, &data))
+			.map_err(|err| write_error(err, &revision_path))?;
+		revisions.push(next);
+
+		let excess = revisions.len().saturating_sub(self.db.max_revisions());
+		for revision in &revisions[..excess] {
+			let path = history_path.join(revision.to_string());
+			fs::remove_file(&path).map_err(|err| write_error(err, &path))?;
+		}
+		Ok(())
+	}
+
+	fn history_path(&self, key: &str) -> PathBuf {
+		self.db.path.join(HISTORY_DIR).join(&self.name).join(key)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_keep_revisions() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.max_revisions = 2));
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"v1").unwrap();
+		assert!(notes.revisions("a").unwrap().is_empty());
+
+		notes.put("a", b"v2").unwrap();
+		notes.put("a", b"v3").unwrap();
+		assert_eq!(notes.revisions("a").unwrap(), vec![1, 2]);
+		assert_eq!(notes.get_revision("a", 1).unwrap().unwrap(), b"v1");
+		assert_eq!(notes.get_revision("a", 2).unwrap().unwrap(), b"v2");
+
+		// Older revisions are dropped beyond the limit.
+		notes.put("a", b"v4").unwrap();
+		assert_eq!(notes.revisions("a").unwrap(), vec![2, 3]);
+		assert!(notes.get_revision("a", 1).unwrap().is_none());
+		assert_eq!(notes.get_revision("a", 3).unwrap().unwrap(), b"v3");
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"v4");
+
+		// History survives deletion.
+		notes.delete("a").unwrap();
+		assert_eq!(notes.revisions("a").unwrap(), vec![2, 3]);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_not_keep_revisions_by_default() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"v1").unwrap();
+		notes.put("a", b"v2").unwrap();
+		assert!(notes.revisions("a").unwrap().is_empty());
+		assert!(notes.revisions("../a").is_err());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -1,19 +1,31 @@
 use regex::Regex;
+use std::cmp::Ordering;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
+/// Digits for the short form, in ASCII order so that short IDs sort the same
+/// as the full form.
+const BASE62_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Length of the short form, which is enough for any 128-bit value.
+const SHORT_LEN: usize = 22;
+
 lazy_static! {
 	static ref RE_ID_FORMAT: Regex =
 		Regex::new(r"^[0-9a-f]{8}(-[0-9a-f]{4}){3}-[0-9a-f]{12}$").unwrap();
 }
 
 /// Universally unique ID for the database.
-#[derive(Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ID {
 	// Out ID is just a thin wrapper around an uuid::Uuid.
 	inner: Uuid,
 }
 
+#[allow(clippy::new_without_default)]
 impl ID {
 	/// Creates a new unique ID.
 	pub fn new() -> ID {
@@ -22,6 +34,40 @@ impl ID {
 		}
 	}
 
+	/// Creates a new unique ID that sorts by creation time.
+	///
+	/// The ID starts with the creation time in milliseconds, followed by
+	/// random bits, in the layout of a version 7 UUID. IDs created within
+	/// the same millisecond sort in random order.
+	///
+	/// Both the ID and its string form sort by creation time, so using them
+	/// as keys keeps recently created records together.
+	pub fn new_sortable() -> ID {
+		let millis = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_millis() as u64)
+			.unwrap_or(0);
+		let mut bytes = *Uuid::new_v4().as_bytes();
+		bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+		bytes[6] = (bytes[6] & 0x0F) | 0x70;
+		bytes[8] = (bytes[8] & 0x3F) | 0x80;
+		ID {
+			inner: Uuid::from_bytes(bytes),
+		}
+	}
+
+	/// Returns the creation time of an ID from `new_sortable`, or `None` for
+	/// other IDs.
+	pub fn timestamp(&self) -> Option<SystemTime> {
+		let bytes = self.inner.as_bytes();
+		if bytes[6] >> 4 != 7 {
+			return None;
+		}
+		let mut millis = [0; 8];
+		millis[2..].copy_from_slice(&bytes[..6]);
+		Some(UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis)))
+	}
+
 	/// Returns a nil ID.
 	pub fn nil() -> ID {
 		ID { inner: Uuid::nil() }
@@ -41,6 +87,33 @@ impl ID {
 		}
 	}
 
+	/// Returns the ID as 22 base62 digits, for use in URLs.
+	pub fn to_short(&self) -> String {
+		let mut value = self.inner.as_u128();
+		let mut digits = [b'0'; SHORT_LEN];
+		for digit in digits.iter_mut().rev() {
+			*digit = BASE62_DIGITS[(value % 62) as usize];
+			value /= 62;
+		}
+		digits.iter().map(|&digit| digit as char).collect()
+	}
+
+	/// Parses an ID in the form returned by `to_short`.
+	pub fn parse_short<S: AsRef<str>>(input: S) -> Option<ID> {
+		let input = input.as_ref();
+		if input.len() != SHORT_LEN {
+			return None;
+		}
+		let mut value: u128 = 0;
+		for byte in input.bytes() {
+			let digit = BASE62_DIGITS.iter().position(|&digit| digit == byte)?;
+			value = value.checked_mul(62)?.checked_add(digit as u128)?;
+		}
+		Some(ID {
+			inner: Uuid::from_u128(value),
+		})
+	}
+
 	/// Returns true if the ID is nil.
 	pub fn is_nil(&self) -> bool {
 		self.inner.is_nil()
@@ -59,6 +132,164 @@ impl fmt::Debug for ID {
 	}
 }
 
+/// ID for a specific type of entity, so that IDs for different entities
+/// can't be mixed up:
+///
+/// ```
+/// use kamipad_data::{Id, ID};
+///
+/// struct Note;
+/// type NoteId = Id<Note>;
+///
+/// let id = NoteId::new();
+/// let untyped: ID = id.into();
+/// assert_eq!(NoteId::from(untyped), id);
+/// ```
+///
+/// The type parameter is only a marker, so `Id<T>` is `Copy`, `Send` and
+/// `Sync` for any `T`.
+pub struct Id<T> {
+	inner: ID,
+	_type: PhantomData<fn() -> T>,
+}
+
+#[allow(clippy::new_without_default)]
+impl<T> Id<T> {
+	/// Creates a new unique ID.
+	pub fn new() -> Id<T> {
+		ID::new().into()
+	}
+
+	/// Creates a new unique ID that sorts by creation time. See
+	/// `ID::new_sortable`.
+	pub fn new_sortable() -> Id<T> {
+		ID::new_sortable().into()
+	}
+
+	/// Parses a string into an ID.
+	pub fn parse<S: AsRef<str>>(input: S) -> Option<Id<T>> {
+		ID::parse(input).map(Id::from)
+	}
+
+	/// Returns the untyped ID.
+	pub fn untyped(&self) -> ID {
+		self.inner
+	}
+}
+
+impl<T> From<ID> for Id<T> {
+	fn from(inner: ID) -> Id<T> {
+		Id {
+			inner,
+			_type: PhantomData,
+		}
+	}
+}
+
+impl<T> From<Id<T>> for ID {
+	fn from(id: Id<T>) -> ID {
+		id.inner
+	}
+}
+
+impl<T> AsRef<ID> for Id<T> {
+	fn as_ref(&self) -> &ID {
+		&self.inner
+	}
+}
+
+// These are implemented by hand, as deriving them would require `T` to
+// implement them too.
+
+impl<T> Clone for Id<T> {
+	fn clone(&self) -> Id<T> {
+		*self
+	}
+}
+
+impl<T> Copy for Id<T> {}
+
+impl<T> PartialEq for Id<T> {
+	fn eq(&self, other: &Id<T>) -> bool {
+		self.inner == other.inner
+	}
+}
+
+impl<T> Eq for Id<T> {}
+
+impl<T> PartialOrd for Id<T> {
+	fn partial_cmp(&self, other: &Id<T>) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl<T> Ord for Id<T> {
+	fn cmp(&self, other: &Id<T>) -> Ordering {
+		self.inner.cmp(&other.inner)
+	}
+}
+
+impl<T> Hash for Id<T> {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.inner.hash(state)
+	}
+}
+
+impl<T> fmt::Display for Id<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.inner.fmt(f)
+	}
+}
+
+impl<T> fmt::Debug for Id<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		<Self as fmt::Display>::fmt(self, f)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<T> serde::Serialize for Id<T> {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		self.inner.serialize(serializer)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T> serde::Deserialize<'de> for Id<T> {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Id<T>, D::Error> {
+		ID::deserialize(deserializer).map(Id::from)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ID {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ID {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<ID, D::Error> {
+		struct IDVisitor;
+
+		impl<'de> serde::de::Visitor<'de> for IDVisitor {
+			type Value = ID;
+
+			fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				write!(f, "a hyphenated lowercase UUID string")
+			}
+
+			fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<ID, E> {
+				ID::parse(value)
+					.ok_or_else(|| E::invalid_value(serde::de::Unexpected::Str(value), &self))
+			}
+		}
+
+		deserializer.deserialize_str(IDVisitor)
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -80,6 +311,49 @@ mod test {
 		assert_ne!(id1, id2);
 	}
 
+	#[test]
+	fn test_sortable_id() {
+		let start = SystemTime::now() - Duration::from_millis(1);
+		let id1 = ID::new_sortable();
+		std::thread::sleep(Duration::from_millis(2));
+		let id2 = ID::new_sortable();
+		assert!(id1 < id2);
+		assert!(id1.to_string() < id2.to_string());
+		assert_eq!(ID::parse(id1.to_string()).unwrap(), id1);
+
+		let time = id1.timestamp().unwrap();
+		assert!(time >= start && time <= SystemTime::now());
+		assert!(ID::new().timestamp().is_none());
+	}
+
+	#[test]
+	fn test_short_id() {
+		let id = ID::parse("645a9c23-9590-49d0-879e-250bff5b621a").unwrap();
+		let short = id.to_short();
+		assert_eq!(short.len(), 22);
+		assert_eq!(ID::parse_short(&short).unwrap(), id);
+		assert_eq!(ID::nil().to_short(), "0000000000000000000000");
+		let max = ID::parse("ffffffff-ffff-ffff-ffff-ffffffffffff").unwrap();
+		assert_eq!(max.to_short(), "7n42DGM5Tflk9n8mt7Fhc7");
+		assert_eq!(ID::parse_short(max.to_short()).unwrap(), max);
+
+		assert!(ID::parse_short("7n42DGM5Tflk9n8mt7Fhc8").is_none());
+		assert!(ID::parse_short("000000000000000000000").is_none());
+		assert!(ID::parse_short("000000000000000000000-").is_none());
+	}
+
+	#[test]
+	fn test_typed_id() {
+		struct Note;
+		let id = Id::<Note>::parse("645a9c23-9590-49d0-879e-250bff5b621a").unwrap();
+		let copy = id;
+		assert_eq!(copy, id);
+		assert_eq!(id.to_string(), "645a9c23-9590-49d0-879e-250bff5b621a");
+		assert_eq!(ID::from(id), id.untyped());
+		assert_eq!(Id::<Note>::from(id.untyped()), id);
+		assert_ne!(Id::<Note>::new(), id);
+	}
+
 	#[test]
 	fn test_nil_id() {
 		let id = ID::nil();
@@ -98,4 +372,17 @@ mod test {
 		assert!(ID::parse("645a9c23-9590-49d0-879e-250bff5b621").is_none());
 		assert!(ID::parse("645a9c23959049d0879e250bff5b621a").is_none());
 	}
+
+	#[test]
+	#[cfg(feature = "serde")]
+	fn test_id_serde() {
+		let id = ID::parse("645a9c23-9590-49d0-879e-250bff5b621a").unwrap();
+		let json = serde_json::to_string(&id).unwrap();
+		assert_eq!(json, r#""645a9c23-9590-49d0-879e-250bff5b621a""#);
+		assert_eq!(serde_json::from_str::<ID>(&json).unwrap(), id);
+
+		assert!(serde_json::from_str::<ID>(r#""645A9C23-9590-49D0-879E-250BFF5B621A""#).is_err());
+		assert!(serde_json::from_str::<ID>(r#""645a9c23959049d0879e250bff5b621a""#).is_err());
+		assert!(serde_json::from_str::<ID>("1").is_err());
+	}
 }
@@ -0,0 +1,606 @@
+//! Change journal for the database.
+//!
+//! Every change to the database is assigned a sequence number and recorded
+//! in the `journal` file at the database root. The journal is append-only,
+//! with one line per change:
+//!
+//! ```text
+//! <seq> <time> put <collection> <key>
+//! <seq> <time> delete <collection> <key>
+//! <seq> <time> put-blob <blob-id>
+//! <seq> <time> delete-blob <blob-id>
+//! <seq> <time> link <collection> <key> <target-collection> <target-key>
+//! <seq> <time> unlink <collection> <key> <target-collection> <target-key>
+//! <seq> <time> tag <collection> <key> <tag>
+//! <seq> <time> untag <collection> <key> <tag>
+//! ```
+//!
+//! Where `time` is the UNIX timestamp of the change, in milliseconds.
+//!
+//! Entries are appended before the change is applied. If a write is
+//! interrupted, the journal may list a change that never happened, but it
+//! never misses one.
+//!
+//! Old entries are removed with `Database::truncate_journal`, which starts
+//! the journal with a line recording what was removed:
+//!
+//! ```text
+//! #journal offset=<offset> seq=<seq>
+//! ```
+//!
+//! Where `seq` is the sequence number of the last removed entry and `offset`
+//! is the position the first remaining entry had before any truncation.
+//! Offsets in the journal, such as the ones kept by readers, are always
+//! positions in the untruncated journal, so they remain valid.
+//!
+//! Entries are only removed once nothing needs them: readers and snapshots
+//! need the entries after their sequence number, and syncing with a peer the
+//! entries after the last sync. Replicas and incremental backups are not
+//! known to the database, so the oldest sequence number they need is given
+//! when truncating. The last entry is always kept, so that the sequence
+//! number can be read from the journal.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::blob::{BlobId, BLOBS_DIR, REFS_EXTENSION};
+use crate::collection::{is_valid_name, DATA_DIR};
+use crate::database::Database;
+use crate::error::Error;
+use crate::links::{RecordId, BACKLINKS_DIR, LINKS_DIR};
+use crate::mvcc::active_readers;
+use crate::tags::TAGS_DIR;
+use crate::transaction;
+use crate::util::{corrupt_error, invalid_data, read_error, write_error};
+use crate::Result;
+
+pub(crate) const JOURNAL_FILENAME: &str = "journal";
+
+const TRUNCATED_PREFIX: &str = "#journal ";
+
+/// A change to the database.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Change {
+	/// A record was created or updated.
+	Put { collection: String, key: String },
+	/// A record was deleted.
+	Delete { collection: String, key: String },
+	/// A blob was stored or had its reference count changed.
+	PutBlob(BlobId),
+	/// A blob was removed.
+	DeleteBlob(BlobId),
+	/// A link was added between two records.
+	Link { from: RecordId, to: RecordId },
+	/// A link between two records was removed.
+	Unlink { from: RecordId, to: RecordId },
+	/// A tag was added to a record.
+	Tag {
+		collection: String,
+		key: String,
+		tag: String,
+	},
+	/// A tag was removed from a record.
+	Untag {
+		collection: String,
+		key: String,
+		tag: String,
+	},
+}
+
+impl Change {
+	/// Returns the files affected by the change, relative to the database
+	/// root.
+	pub(crate) fn paths(&self) -> Vec<PathBuf> {
+		match self {
+			Change::Put { collection, key } | Change::Delete { collection, key } => {
+				vec![Path::new(DATA_DIR).join(collection).join(key)]
+			}
+			Change::PutBlob(id) | Change::DeleteBlob(id) => {
+				let blobs_dir = Path::new(BLOBS_DIR);
+				vec![
+					blobs_dir.join(id.as_str()),
+					blobs_dir.join(format!("{}.{}", id, REFS_EXTENSION)),
+				]
+			}
+			Change::Link { from, to } | Change::Unlink { from, to } => vec![
+				Path::new(LINKS_DIR).join(&from.collection).join(&from.key),
+				Path::new(BACKLINKS_DIR).join(&to.collection).join(&to.key),
+			],
+			Change::Tag {
+				collection, tag, ..
+			}
+			| Change::Untag {
+				collection, tag, ..
+			} => vec![Path::new(TAGS_DIR).join(collection).join(tag)],
+		}
+	}
+}
+
+/// An entry in the change journal.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct JournalEntry {
+	/// Sequence number for the change. Sequence numbers start at one and
+	/// increase with every change.
+	pub seq: u64,
+	/// UNIX timestamp of the change, in milliseconds.
+	pub time: u64,
+	pub change: Change,
+}
+
+impl fmt::Display for JournalEntry {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {} ", self.seq, self.time)?;
+		match &self.change {
+			Change::Put { collection, key } => write!(f, "put {} {}", collection, key),
+			Change::Delete { collection, key } => write!(f, "delete {} {}", collection, key),
+			Change::PutBlob(id) => write!(f, "put-blob {}", id),
+			Change::DeleteBlob(id) => write!(f, "delete-blob {}", id),
+			Change::Link { from, to } => write!(
+				f,
+				"link {} {} {} {}",
+				from.collection, from.key, to.collection, to.key
+			),
+			Change::Unlink { from, to } => write!(
+				f,
+				"unlink {} {} {} {}",
+				from.collection, from.key, to.collection, to.key
+			),
+			Change::Tag {
+				collection,
+				key,
+				tag,
+			} => write!(f, "tag {} {} {}", collection, key, tag),
+			Change::Untag {
+				collection,
+				key,
+				tag,
+			} => write!(f, "untag {} {} {}", collection, key, tag),
+		}
+	}
+}
+
+impl JournalEntry {
+	/// Parses a journal line.
+	pub(crate) fn parse(line: &str) -> Option<JournalEntry> {
+		let parts = line.split(' ').collect::<Vec<_>>();
+		let seq = parts.first()?.parse().ok()?;
+		let time = parts.get(1)?.parse().ok()?;
+		let change = match &parts[2..] {
+			["put", collection, key] | ["delete", collection, key] => {
+				if !is_valid_name(collection) || !is_valid_name(key) {
+					return None;
+				}
+				let (collection, key) = (collection.to_string(), key.to_string());
+				if parts[2] == "put" {
+					Change::Put { collection, key }
+				} else {
+					Change::Delete { collection, key }
+				}
+			}
+			["put-blob", id] => Change::PutBlob(BlobId::parse(id)?),
+			["delete-blob", id] => Change::DeleteBlob(BlobId::parse(id)?),
+			["link", collection, key, to_collection, to_key]
+			| ["unlink", collection, key, to_collection, to_key] => {
+				let names = [collection, key, to_collection, to_key];
+				if !names.iter().all(|name| is_valid_name(name)) {
+					return None;
+				}
+				let from = RecordId::new(*collection, *key);
+				let to = RecordId::new(*to_collection, *to_key);
+				if parts[2] == "link" {
+					Change::Link { from, to }
+				} else {
+					Change::Unlink { from, to }
+				}
+			}
+			["tag", collection, key, tag] | ["untag", collection, key, tag] => {
+				if ![collection, key, tag]
+					.iter()
+					.all(|name| is_valid_name(name))
+				{
+					return None;
+				}
+				let (collection, key, tag) =
+					(collection.to_string(), key.to_string(), tag.to_string());
+				if parts[2] == "tag" {
+					Change::Tag {
+						collection,
+						key,
+						tag,
+					}
+				} else {
+					Change::Untag {
+						collection,
+						key,
+						tag,
+					}
+				}
+			}
+			_ => return None,
+		};
+		Some(JournalEntry { seq, time, change })
+	}
+}
+
+/// Part of the journal removed by `Database::truncate_journal`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+struct Truncated {
+	/// Offset of the first remaining entry in the untruncated journal.
+	offset: u64,
+	/// Sequence number of the last removed entry.
+	seq: u64,
+}
+
+impl Truncated {
+	/// Parses the line at the start of a truncated journal, returning the
+	/// truncation and the length of the line. A journal that was never
+	/// truncated has no such line.
+	fn parse(data: &[u8], path: &Path) -> Result<(Truncated, usize)> {
+		if !data.starts_with(TRUNCATED_PREFIX.as_bytes()) {
+			return Ok((Truncated::default(), 0));
+		}
+		let invalid = || corrupt_error(invalid_data("invalid journal truncation"), path);
+		let end = data.iter().position(|&c| c == b'\n').ok_or_else(invalid)?;
+		let line =
+			std::str::from_utf8(&data[TRUNCATED_PREFIX.len()..end]).map_err(|_| invalid())?;
+		let mut truncated = Truncated::default();
+		for param in line.split_whitespace() {
+			let mut parts = param.splitn(2, '=');
+			let (name, value) = (parts.next(), parts.next().and_then(|v| v.parse().ok()));
+			match (name, value) {
+				(Some("offset"), Some(value)) => truncated.offset = value,
+				(Some("seq"), Some(value)) => truncated.seq = value,
+				_ => return Err(invalid()),
+			}
+		}
+		Ok((truncated, end + 1))
+	}
+}
+
+/// Reads all entries from the journal at the database root.
+pub(crate) fn read_journal(root: &Path) -> Result<Vec<JournalEntry>> {
+	read_journal_from(root, 0).map(|(entries, _)| entries)
+}
+
+/// Reads the entries from the journal at the database root, starting at the
+/// given byte offset. Returns the entries and the offset to continue from.
+///
+/// Only complete lines are read, since the journal may be in the middle of
+/// being written by another process.
+pub(crate) fn read_journal_from(root: &Path, offset: u64) -> Result<(Vec<JournalEntry>, u64)> {
+	read_journal_file(root, offset).map(|(_, entries, offset)| (entries, offset))
+}
+
+/// Reads the entries from the journal starting at the given offset, which
+/// is a position in the untruncated journal. Returns the truncation of the
+/// journal together with the entries and the offset to continue from.
+///
+/// Offsets before the truncation read every remaining entry. Readers never
+/// have one, since the entries they haven't seen are never removed.
+fn read_journal_file(root: &Path, offset: u64) -> Result<(Truncated, Vec<JournalEntry>, u64)> {
+	let path = root.join(JOURNAL_FILENAME);
+	let mut file = match fs::File::open(&path) {
+		Ok(file) => file,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {
+			return Ok((Truncated::default(), Vec::new(), 0))
+		}
+		Err(err) => return Err(read_error(err, &path)),
+	};
+
+	// The journal may be replaced by a truncation while being read, so the
+	// start is read from the same file as the entries.
+	let mut start = Vec::new();
+	Read::by_ref(&mut file)
+		.take(256)
+		.read_to_end(&mut start)
+		.map_err(|err| read_error(err, &path))?;
+	let (truncated, header) = Truncated::parse(&start, &path)?;
+	let offset = offset.max(truncated.offset);
+
+	let mut data = Vec::new();
+	file.seek(SeekFrom::Start(header as u64 + offset - truncated.offset))
+		.and_then(|_| file.read_to_end(&mut data))
+		.map_err(|err| read_error(err, &path))?;
+	let complete = data
+		.iter()
+		.rposition(|&c| c == b'\n')
+		.map(|n| n + 1)
+		.unwrap_or(0);
+	let text = std::str::from_utf8(&data[..complete])
+		.map_err(|_| corrupt_error(invalid_data("invalid journal text"), &path))?;
+
+	let mut entries = Vec::new();
+	for line in text.lines() {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		match JournalEntry::parse(line) {
+			Some(entry) => entries.push(entry),
+			None => {
+				let err = invalid_data(format!("invalid journal entry `{}`", line));
+				return Err(corrupt_error(err, &path));
+			}
+		}
+	}
+	Ok((truncated, entries, offset + complete as u64))
+}
+
+/// Appends entries to the journal at the database root.
+pub(crate) fn append_journal(root: &Path, entries: &[JournalEntry]) -> Result<()> {
+	let path = root.join(JOURNAL_FILENAME);
+	let mut text = String::new();
+	for entry in entries {
+		text.push_str(&entry.to_string());
+		text.push('\n');
+	}
+
+	fs::OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(&path)
+		.and_then(|mut file| file.write_all(text.as_bytes()))
+		.map_err(|err| write_error(err, &path))
+}
+
+/// Returns the current UNIX time in milliseconds.
+pub(crate) fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0)
+}
+
+impl Database {
+	/// Returns the sequence number of the last change to the database, or
+	/// zero if the database has no changes.
+	pub fn sequence(&self) -> u64 {
+		*self.last_seq()
+	}
+
+	/// Returns all journal entries with a sequence number greater than
+	/// `since`.
+	///
+	/// Fails with `Error::JournalTruncated` if some of them were removed by
+	/// `truncate_journal`.
+	pub fn changes_since(&self, since: u64) -> Result<Vec<JournalEntry>> {
+		let (truncated, mut entries, _) = read_journal_file(&self.path, 0)?;
+		if since < truncated.seq {
+			return Err(Error::JournalTruncated(since));
+		}
+		entries.retain(|entry| entry.seq > since);
+		Ok(entries)
+	}
+
+	/// Removes the journal entries up to the sequence number `keep_after`,
+	/// returning the number of entries removed.
+	///
+	/// Use the oldest sequence number still needed by replicas and
+	/// incremental backups, which fail with `Error::JournalTruncated` for
+	/// older ones. Entries needed by readers, snapshots and syncing are kept
+	/// regardless (see the module documentation).
+	pub fn truncate_journal(&self, keep_after: u64) -> Result<usize> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+
+		// An interrupted transaction needs its entries to be rolled back.
+		transaction::recover(&self.path)?;
+		let seq = self.sequence();
+		let mut keep_after = keep_after
+			.min(seq.saturating_sub(1))
+			.min(seq.saturating_sub(self.snapshots()));
+		if let Some(&oldest) = active_readers(&self.path)?.iter().min() {
+			keep_after = keep_after.min(oldest);
+		}
+		if let Some(oldest) = self.oldest_sync()? {
+			keep_after = keep_after.min(oldest);
+		}
+
+		let path = self.path.join(JOURNAL_FILENAME);
+		let data = match fs::read(&path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+			Err(err) => return Err(read_error(err, &path)),
+		};
+		let (truncated, header) = Truncated::parse(&data, &path)?;
+		let mut end = header;
+		let mut last = truncated;
+		let mut removed = 0;
+		for line in data[header..].split_inclusive(|&c| c == b'\n') {
+			let text = std::str::from_utf8(line).unwrap_or("").trim();
+			if !text.is_empty() {
+				let entry = JournalEntry::parse(text).ok_or_else(|| {
+					let err = invalid_data(format!("invalid journal entry `{}`", text));
+					corrupt_error(err, &path)
+				})?;
+				if entry.seq > keep_after {
+					break;
+				}
+				last.seq = entry.seq;
+				removed += 1;
+			}
+			end += line.len();
+		}
+		if removed == 0 {
+			return Ok(0);
+		}
+
+		last.offset = truncated.offset + (end - header) as u64;
+		let mut journal = format!(
+			"{}offset={} seq={}\n",
+			TRUNCATED_PREFIX, last.offset, last.seq
+		)
+		.into_bytes();
+		journal.extend_from_slice(&data[end..]);
+		crate::fs::atomic_write(&path, &journal).map_err(|err| write_error(err, &path))?;
+		Ok(removed)
+	}
+
+	/// Records a change in the journal, returning its sequence number.
+	///
+	/// This must be called with the write lock held and before applying the
+	/// change.
+	pub(crate) fn log_change(&self, change: Change) -> Result<u64> {
+		let mut last_seq = self.last_seq();
+		let entry = JournalEntry {
+			seq: *last_seq + 1,
+			time: now(),
+			change,
+		};
+		if self.keeps_undo()? {
+			self.save_undo(entry.seq, &entry.change)?;
+		}
+		append_journal(&self.path, std::slice::from_ref(&entry))?;
+		*last_seq += 1;
+		self.queue_notification(entry);
+		Ok(*last_seq)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_journal_changes() {
+		let (db, temp) = create_db(OpenFlags::default());
+		assert_eq!(db.sequence(), 0);
+
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		notes.delete("a").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+		db.release_blob(&blob).unwrap();
+		db.gc_blobs().unwrap();
+		assert_eq!(db.sequence(), 6);
+
+		let changes = db
+			.changes_since(2)
+			.unwrap()
+			.into_iter()
+			.map(|entry| (entry.seq, entry.change))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			changes,
+			vec![
+				(
+					3,
+					Change::Delete {
+						collection: "notes".into(),
+						key: "a".into()
+					}
+				),
+				(4, Change::PutBlob(blob.clone())),
+				(5, Change::PutBlob(blob.clone())),
+				(6, Change::DeleteBlob(blob.clone())),
+			]
+		);
+
+		// Sequence numbers continue after reopening.
+		let path = db.path.clone();
+		drop(db);
+		let db = open(&path, OpenFlags::default()).unwrap();
+		assert_eq!(db.sequence(), 6);
+		db.collection("notes").unwrap().put("c", b"C").unwrap();
+		assert_eq!(db.sequence(), 7);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_truncate_journal() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		for key in &["a", "b", "c", "d"] {
+			notes.put(key, b"1").unwrap();
+		}
+
+		assert_eq!(db.truncate_journal(2).unwrap(), 2);
+		assert_eq!(db.changes_since(2).unwrap().len(), 2);
+		match db.changes_since(1) {
+			Err(Error::JournalTruncated(1)) => (),
+			other => panic!("expected Error::JournalTruncated, got {:?}", other),
+		}
+
+		// Readers keep the entries they haven't seen, and continue reading
+		// after another truncation.
+		let reader = open(&db.path, OpenFlags::read_only()).unwrap();
+		notes.put("a", b"2").unwrap();
+		notes.put("b", b"2").unwrap();
+		assert_eq!(db.truncate_journal(10).unwrap(), 2);
+		assert_eq!(
+			reader
+				.collection("notes")
+				.unwrap()
+				.get("b")
+				.unwrap()
+				.unwrap(),
+			b"1"
+		);
+		assert_eq!(reader.poll_changes().unwrap().len(), 2);
+		assert_eq!(
+			reader
+				.collection("notes")
+				.unwrap()
+				.get("b")
+				.unwrap()
+				.unwrap(),
+			b"2"
+		);
+		drop(reader);
+
+		// The last entry is always kept.
+		assert_eq!(db.truncate_journal(10).unwrap(), 1);
+		assert_eq!(db.truncate_journal(10).unwrap(), 0);
+		let path = db.path.clone();
+		drop(db);
+		let db = open(&path, OpenFlags::default()).unwrap();
+		assert_eq!(db.sequence(), 6);
+		db.collection("notes").unwrap().put("c", b"2").unwrap();
+		assert_eq!(db.changes_since(5).unwrap().len(), 2);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_parse_entries() {
+		let entry = JournalEntry {
+			seq: 12,
+			time: 1_600_000_000_000,
+			change: Change::Put {
+				collection: "notes".into(),
+				key: "a".into(),
+			},
+		};
+		let line = entry.to_string();
+		assert_eq!(line, "12 1600000000000 put notes a");
+		assert_eq!(JournalEntry::parse(&line), Some(entry));
+
+		assert!(JournalEntry::parse("12 0 put notes").is_none());
+		assert!(JournalEntry::parse("x 0 put notes a").is_none());
+		assert!(JournalEntry::parse("1 0 put-blob xyz").is_none());
+		assert!(JournalEntry::parse("1 0 put ../x a").is_none());
+
+		let entry = JournalEntry {
+			seq: 1,
+			time: 0,
+			change: Change::Link {
+				from: RecordId::new("notes", "a"),
+				to: RecordId::new("files", "b"),
+			},
+		};
+		assert_eq!(entry.to_string(), "1 0 link notes a files b");
+		assert_eq!(JournalEntry::parse(&entry.to_string()), Some(entry));
+		assert!(JournalEntry::parse("1 0 unlink notes a files").is_none());
+		assert!(JournalEntry::parse("1 0 tag notes a todo").is_some());
+		assert!(JournalEntry::parse("1 0 untag notes a ../x").is_none());
+	}
+}
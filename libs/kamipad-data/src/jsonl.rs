@@ -0,0 +1,528 @@
+//! Export and import of the database in the JSON Lines format.
+//!
+//! The export has one JSON object per line, for every record, link and blob
+//! in the database. Records are exported with their collection and key, and
+//! their value as a string if it is valid UTF-8 or base64-encoded in `data`
+//! otherwise. They also have their version, their expiration in milliseconds
+//! since the epoch, if any, and their tags. Links come after all records, so
+//! that both ends exist when importing them:
+//!
+//! ```text
+//! {"collection":"notes","key":"a","value":"some text","version":2,"tags":["todo"]}
+//! {"collection":"files","key":"b","data":"AAECAw==","version":1,"expires":1600000000000}
+//! {"from":"notes/a","to":"files/b"}
+//! {"blob":"<blob-id>","refs":1,"data":"AAECAw=="}
+//! ```
+//!
+//! Values are exported decoded, so an export of a compressed or encrypted
+//! database is plain text. Expired records are not exported, and neither are
+//! the revision history and the trash.
+//!
+//! Imports accept the same format, with only the collection, key and value
+//! required for records. Imported records keep their version, unless the
+//! record existed with a higher one. Blank lines are ignored, which makes it
+//! easy to write fixtures by hand.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use crate::blob::{BlobId, BLOBS_DIR, REFS_EXTENSION};
+use crate::collection::{is_valid_name, DATA_DIR};
+use crate::database::Database;
+use crate::error::{Error, IOError};
+use crate::links::{parse_links, RecordId, LINKS_DIR};
+use crate::mvcc::Registration;
+use crate::record::Header;
+use crate::snapshot::Snapshot;
+use crate::tags::{check_tag, parse_tag, TAGS_DIR};
+use crate::Result;
+
+/// A line in a JSON Lines export.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Line {
+	Record {
+		collection: String,
+		key: String,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		value: Option<String>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		data: Option<String>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		version: Option<u64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		expires: Option<u64>,
+		#[serde(default, skip_serializing_if = "Vec::is_empty")]
+		tags: Vec<String>,
+	},
+	Link {
+		from: String,
+		to: String,
+	},
+	Blob {
+		blob: String,
+		refs: usize,
+		data: String,
+	},
+}
+
+impl Line {
+	fn record(id: &RecordId, header: &Header, payload: Vec<u8>, tags: Vec<String>) -> Line {
+		let (value, data) = match String::from_utf8(payload) {
+			Ok(value) => (Some(value), None),
+			Err(err) => (None, Some(base64::encode(err.as_bytes()))),
+		};
+		Line::Record {
+			collection: id.collection.clone(),
+			key: id.key.clone(),
+			value,
+			data,
+			version: Some(header.version()),
+			expires: header.expires,
+			tags,
+		}
+	}
+}
+
+/// What to do when an imported record or blob already exists.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OnConflict {
+	/// Keep the existing data.
+	Skip,
+	/// Replace the existing data with the imported one.
+	Overwrite,
+}
+
+/// Summary of a JSON Lines import.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ImportStats {
+	/// Number of records imported.
+	pub records: usize,
+	/// Number of blobs imported.
+	pub blobs: usize,
+	/// Number of links imported.
+	pub links: usize,
+	/// Number of records, links and blobs skipped because they already
+	/// existed.
+	pub skipped: usize,
+}
+
+/// Summary of a JSON Lines export.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ExportStats {
+	/// Number of records exported.
+	pub records: usize,
+	/// Number of blobs exported.
+	pub blobs: usize,
+	/// Number of links exported.
+	pub links: usize,
+}
+
+impl Database {
+	/// Exports all records, links and blobs in the database to the writer,
+	/// as JSON Lines.
+	///
+	/// The export reads the database as it was when it started, the same as
+	/// a reader (see the `mvcc` module), so it is consistent without blocking
+	/// writes while in progress.
+	pub fn export_jsonl<W: Write>(&self, mut writer: W) -> Result<ExportStats> {
+		// The registration makes the writer keep the undo data for changes
+		// made during the export. It is created with the write lock held, so
+		// that no change is in progress.
+		let (snapshot, _registration) = {
+			let _guard = self.write_guard();
+			let seq = self.sequence();
+			let registration = Registration::new(&self.path, seq)?;
+			(self.read_at(seq)?, registration)
+		};
+
+		let mut stats = ExportStats::default();
+		let mut records = BTreeSet::new();
+		for name in snapshot.names(Path::new(DATA_DIR))? {
+			if !is_valid_name(&name) {
+				continue;
+			}
+			let mut tags = read_tags(&snapshot, &name)?;
+			for key in snapshot.keys(&name)? {
+				let (header, payload) = match snapshot.get_with_header(&name, &key)? {
+					Some(record) => record,
+					None => continue,
+				};
+				let id = RecordId::new(name.as_str(), key.as_str());
+				let tags = tags.remove(&key).unwrap_or_default();
+				write_line(&mut writer, &Line::record(&id, &header, payload, tags))?;
+				records.insert(id);
+				stats.records += 1;
+			}
+		}
+
+		// Links to records that have expired are left out, since they can't
+		// be imported.
+		for from in records.iter() {
+			let path = Path::new(LINKS_DIR).join(&from.collection).join(&from.key);
+			let links = snapshot.read(&path)?.unwrap_or_default();
+			for to in parse_links(&links) {
+				if records.contains(&to) {
+					let line = Line::Link {
+						from: from.to_string(),
+						to: to.to_string(),
+					};
+					write_line(&mut writer, &line)?;
+					stats.links += 1;
+				}
+			}
+		}
+
+		for name in snapshot.names(Path::new(BLOBS_DIR))? {
+			let id = match BlobId::parse(&name) {
+				Some(id) => id,
+				None => continue,
+			};
+			if let Some(data) = snapshot.get_blob(&id)? {
+				let refs_path = Path::new(BLOBS_DIR).join(format!("{}.{}", id, REFS_EXTENSION));
+				let refs = snapshot.read(&refs_path)?.unwrap_or_default();
+				let line = Line::Blob {
+					blob: id.to_string(),
+					refs: String::from_utf8_lossy(&refs).trim().parse().unwrap_or(0),
+					data: base64::encode(&data),
+				};
+				write_line(&mut writer, &line)?;
+				stats.blobs += 1;
+			}
+		}
+
+		writer.flush().map_err(export_error)?;
+		Ok(stats)
+	}
+}
+
+impl Database {
+	/// Imports records and blobs from JSON Lines, as generated by
+	/// `export_jsonl`.
+	///
+	/// The import is not atomic: if it fails, lines before the failing one
+	/// will have been imported.
+	pub fn import_jsonl<R: BufRead>(
+		&self,
+		reader: R,
+		on_conflict: OnConflict,
+	) -> Result<ImportStats> {
+		self.check_writable()?;
+
+		let mut stats = ImportStats::default();
+		for (index, line) in reader.lines().enumerate() {
+			let invalid =
+				|reason: String| Error::InvalidImport(format!("line {}: {}", index + 1, reason));
+			let line = line.map_err(|err| Error::Read(IOError::new(err, "reading import")))?;
+			if line.trim().is_empty() {
+				continue;
+			}
+
+			let line: Line = serde_json::from_str(&line).map_err(|err| invalid(err.to_string()))?;
+			match line {
+				Line::Record {
+					collection,
+					key,
+					value,
+					data,
+					version,
+					expires,
+					tags,
+				} => {
+					let payload = match (value, data) {
+						(Some(value), None) => value.into_bytes(),
+						(None, Some(data)) => {
+							base64::decode(&data).map_err(|err| invalid(err.to_string()))?
+						}
+						_ => {
+							return Err(invalid("record must have either `value` or `data`".into()))
+						}
+					};
+					let collection = self.collection(collection)?;
+					if on_conflict == OnConflict::Skip && collection.contains(&key)? {
+						stats.skipped += 1;
+						continue;
+					}
+					for tag in tags.iter() {
+						check_tag(tag)?;
+					}
+					let header = Header {
+						version,
+						expires,
+						..Default::default()
+					};
+					collection.put_with_header(&key, &payload, header, None)?;
+
+					// Tags are added even if the record has already expired,
+					// same as for the records that expire after being tagged.
+					let _guard = self.write_guard();
+					for tag in tags.iter() {
+						collection.tag_locked(&key, tag)?;
+					}
+					stats.records += 1;
+				}
+				Line::Link { from, to } => {
+					let parse = |id: &str| {
+						RecordId::parse(id)
+							.ok_or_else(|| invalid(format!("invalid record `{}`", id)))
+					};
+					let (from, to) = (parse(&from)?, parse(&to)?);
+					let collection = self.collection(from.collection.as_str())?;
+					if collection.link(&from.key, &to)? {
+						stats.links += 1;
+					} else {
+						stats.skipped += 1;
+					}
+				}
+				Line::Blob { blob, refs, data } => {
+					let id = BlobId::parse(&blob)
+						.ok_or_else(|| invalid(format!("invalid blob id `{}`", blob)))?;
+					let data = base64::decode(&data).map_err(|err| invalid(err.to_string()))?;
+					if BlobId::of(&data) != id {
+						return Err(invalid(format!("content does not match blob id `{}`", id)));
+					}
+					if on_conflict == OnConflict::Skip && self.blob_refs(&id)? > 0 {
+						stats.skipped += 1;
+					} else {
+						self.put_blob_with_refs(&data, refs)?;
+						stats.blobs += 1;
+					}
+				}
+			}
+		}
+		Ok(stats)
+	}
+}
+
+/// Reads the tags of a collection at the snapshot, by key.
+fn read_tags(snapshot: &Snapshot<'_>, collection: &str) -> Result<BTreeMap<String, Vec<String>>> {
+	let dir = Path::new(TAGS_DIR).join(collection);
+	let mut tags = BTreeMap::new();
+	for tag in snapshot.names(&dir)? {
+		if !is_valid_name(&tag) {
+			continue;
+		}
+		let keys = snapshot.read(&dir.join(&tag))?.unwrap_or_default();
+		for key in parse_tag(&keys) {
+			tags.entry(key).or_insert_with(Vec::new).push(tag.clone());
+		}
+	}
+	Ok(tags)
+}
+
+fn write_line<W: Write>(writer: &mut W, line: &Line) -> Result<()> {
+	serde_json::to_writer(&mut *writer, line)
+		.map_err(|err| export_error(err.into()))
+		.and_then(|_| writer.write_all(b"\n").map_err(export_error))
+}
+
+fn export_error(err: std::io::Error) -> Error {
+	Error::Write(IOError::new(err, "writing export"))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+	use std::time::{Duration, UNIX_EPOCH};
+
+	const FIXTURE: &str = r#"
+{"collection":"notes","key":"a","value":"imported"}
+{"collection":"notes","key":"b","data":"AP8B","version":5,"tags":["todo"]}
+{"from":"notes/b","to":"notes/a"}
+
+{"blob":"fa2c8cc4f28176bbeed4b736df569a34c79cd3723e9ec42f9674b4d46ac6b8b8","refs":2,"data":"YmxvYg=="}
+"#;
+
+	#[test]
+	fn should_export_jsonl() {
+		let flags = OpenFlags::config(|f| f.compress = true);
+		let (db, temp) = create_db(flags);
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"first note").unwrap();
+		notes.put("a", b"first note").unwrap();
+		notes.put("b", "second \"note\"\n".as_bytes()).unwrap();
+		let expires = UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+		notes.put_expiring("c", b"later", expires).unwrap();
+		notes.put("d", b"expired").unwrap();
+		notes.tag("a", "todo").unwrap();
+		notes.tag("a", "work").unwrap();
+		notes.tag("d", "todo").unwrap();
+		notes.put_expiring("d", b"expired", UNIX_EPOCH).unwrap();
+		let files = db.collection("files").unwrap();
+		files.put("x", &[0, 0xff, 1]).unwrap();
+		notes.link("a", &RecordId::new("files", "x")).unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		let mut out = Vec::new();
+		let stats = db.export_jsonl(&mut out).unwrap();
+		assert_eq!(
+			stats,
+			ExportStats {
+				records: 4,
+				blobs: 1,
+				links: 1,
+			}
+		);
+
+		let text = String::from_utf8(out).unwrap();
+		let lines = text.lines().collect::<Vec<_>>();
+		assert_eq!(
+			lines,
+			vec![
+				r#"{"collection":"files","key":"x","data":"AP8B","version":1}"#.to_string(),
+				r#"{"collection":"notes","key":"a","value":"first note","version":2,"tags":["todo","work"]}"#.to_string(),
+				r#"{"collection":"notes","key":"b","value":"second \"note\"\n","version":1}"#.to_string(),
+				r#"{"collection":"notes","key":"c","value":"later","version":1,"expires":4000000000000}"#.to_string(),
+				r#"{"from":"notes/a","to":"files/x"}"#.to_string(),
+				format!(r#"{{"blob":"{}","refs":1,"data":"YmxvYg=="}}"#, blob),
+			]
+		);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_import_jsonl() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"existing").unwrap();
+
+		let stats = db
+			.import_jsonl(FIXTURE.as_bytes(), OnConflict::Skip)
+			.unwrap();
+		assert_eq!(
+			stats,
+			ImportStats {
+				records: 1,
+				blobs: 1,
+				links: 1,
+				skipped: 1
+			}
+		);
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"existing");
+		assert_eq!(notes.get("b").unwrap().unwrap(), &[0, 0xff, 1]);
+		assert_eq!(notes.version("b").unwrap(), 5);
+		assert_eq!(notes.tags("b").unwrap(), vec!["todo"]);
+		assert_eq!(notes.links("b").unwrap(), vec![RecordId::new("notes", "a")]);
+		let blob = BlobId::of(b"blob");
+		assert_eq!(db.blob_refs(&blob).unwrap(), 2);
+
+		let stats = db
+			.import_jsonl(FIXTURE.as_bytes(), OnConflict::Overwrite)
+			.unwrap();
+		assert_eq!(stats.skipped, 1);
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"imported");
+		assert_eq!(notes.version("b").unwrap(), 6);
+		assert_eq!(db.blob_refs(&blob).unwrap(), 2);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_round_trip_export() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		notes.put("b", b"B").unwrap();
+		notes.tag("a", "todo").unwrap();
+		notes.link("b", &RecordId::new("notes", "a")).unwrap();
+		db.put_blob(&[1, 2, 3]).unwrap();
+		let mut out = Vec::new();
+		db.export_jsonl(&mut out).unwrap();
+
+		let (other, other_temp) = create_db(OpenFlags::default());
+		other.import_jsonl(&out[..], OnConflict::Skip).unwrap();
+		let mut other_out = Vec::new();
+		other.export_jsonl(&mut other_out).unwrap();
+		assert_eq!(out, other_out);
+
+		drop(other);
+		other_temp.close().unwrap();
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	/// Writer that changes the database in the middle of an export.
+	struct Interleaved<'a> {
+		db: &'a Database,
+		out: Vec<u8>,
+	}
+
+	impl<'a> Write for Interleaved<'a> {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			if self.out.is_empty() {
+				let notes = self.db.collection("notes").unwrap();
+				notes.put("a", b"changed").unwrap();
+				notes.untag("b", "todo").unwrap();
+				notes.put("c", b"C").unwrap();
+				notes.delete("b").unwrap();
+			}
+			self.out.write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn should_export_snapshot_while_writing() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		notes.tag("b", "todo").unwrap();
+
+		let mut writer = Interleaved {
+			db: &db,
+			out: Vec::new(),
+		};
+		db.export_jsonl(&mut writer).unwrap();
+		let text = String::from_utf8(writer.out).unwrap();
+		assert_eq!(
+			text.lines().collect::<Vec<_>>(),
+			vec![
+				r#"{"collection":"notes","key":"a","value":"A","version":1}"#,
+				r#"{"collection":"notes","key":"b","value":"B","version":1,"tags":["todo"]}"#,
+			]
+		);
+
+		// The changes are still made, and the export is no longer a reader.
+		assert_eq!(notes.keys().unwrap(), vec!["a", "c"]);
+		assert!(!db.has_readers().unwrap());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_reject_invalid_imports() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let invalid = [
+			"not json",
+			r#"{"collection":"notes","key":"a"}"#,
+			r#"{"collection":"../x","key":"a","value":""}"#,
+			r#"{"blob":"xyz","refs":1,"data":""}"#,
+			r#"{"from":"notes","to":"notes/a"}"#,
+			r#"{"collection":"notes","key":"a","value":"","tags":["bad tag"]}"#,
+			r#"{"blob":"fa2c8cc4f28176bbeed4b736df569a34c79cd3723e9ec42f9674b4d46ac6b8b8","refs":1,"data":"AA=="}"#,
+		];
+		for input in invalid.iter() {
+			assert!(
+				db.import_jsonl(input.as_bytes(), OnConflict::Skip).is_err(),
+				"{}",
+				input
+			);
+		}
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -1,11 +1,17 @@
+// The server builds this with the older nightly Rocket 0.4 needs, so the
+// suggestions for newer standard library APIs don't apply.
+#![allow(clippy::manual_is_multiple_of, clippy::io_other_error)]
+
 #[macro_use]
 extern crate lazy_static;
 
 mod error;
-pub use error::Error;
+pub use error::{Error, ValidationError};
+
+pub mod fs;
 
 mod database;
-pub use database::Database;
+pub use database::{Database, DatabaseStats};
 
 /// Result type for the data library.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -13,5 +19,102 @@ pub type Result<T> = std::result::Result<T, Error>;
 mod open;
 pub use open::{open, OpenFlags};
 
+mod history;
+
+mod async_db;
+pub use async_db::AsyncDatabase;
+
 mod id;
-pub use id::ID;
+pub use id::{Id, ID};
+
+mod blob;
+pub use blob::{BlobGcStats, BlobId};
+
+mod cache;
+
+mod backup;
+pub use backup::BackupStats;
+
+mod cascade;
+pub use cascade::Cascade;
+
+mod check;
+pub use check::{CheckReport, Problem, ProblemKind};
+
+mod collection;
+pub use collection::{is_valid_name, Collection};
+
+mod compact;
+pub use compact::CompactStats;
+
+mod crdt;
+pub use crdt::LwwMap;
+
+mod crypto;
+
+mod journal;
+pub use journal::{Change, JournalEntry};
+
+mod jsonl;
+pub use jsonl::{ExportStats, ImportStats, OnConflict};
+
+mod links;
+pub use links::RecordId;
+
+mod lock;
+pub use lock::{break_lock, lock_info, LockInfo};
+
+mod manager;
+pub use manager::DatabaseManager;
+
+mod manifest;
+pub use manifest::FORMAT_VERSION;
+
+mod mvcc;
+
+mod query;
+pub use query::{
+	field_eq, field_exists, field_gt, field_lt, field_ne, has_tag, not, Document, Filter, Query,
+};
+
+mod quota;
+pub use quota::Usage;
+
+mod record;
+
+mod replica;
+pub use replica::JournalBatch;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+mod search;
+pub use search::{SearchHit, Snippet, SNIPPET_LENGTH};
+
+mod snapshot;
+pub use snapshot::Snapshot;
+
+mod sync;
+pub use sync::{
+	LocalTransport, SyncApplied, SyncBatch, SyncConflict, SyncRecord, SyncSummary, SyncTransport,
+};
+
+mod trash;
+
+mod tags;
+
+mod transaction;
+pub use transaction::{Savepoint, Transaction};
+
+mod util;
+
+mod validate;
+pub use validate::validate_json;
+
+mod verify;
+pub use verify::VerifyError;
+
+mod watch;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,310 @@
+//! Links between records.
+//!
+//! A record can link to any number of other records, in any collection.
+//! Links are stored on both ends, so that they can be listed in either
+//! direction without scanning the database:
+//!
+//! ```text
+//! links/<collection>/<key>       -- records linked from the record
+//! backlinks/<collection>/<key>   -- records linking to the record
+//! ```
+//!
+//! Each file lists one record per line, as `<collection>/<key>`, sorted.
+//! Files for records without links are removed.
+//!
+//! Links are only created between existing records, and a record cannot be
+//! deleted while other records link to it, so that links never dangle.
+//! Deleting a record removes the links from it. Records that expire are not
+//! checked, so links to them remain until removed.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::collection::{is_valid_name, Collection};
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::Change;
+use crate::util::{self, read_error, write_error};
+use crate::Result;
+
+pub(crate) const LINKS_DIR: &str = "links";
+
+pub(crate) const BACKLINKS_DIR: &str = "backlinks";
+
+/// Identifies a record in the database.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct RecordId {
+	pub collection: String,
+	pub key: String,
+}
+
+impl RecordId {
+	pub fn new<C: Into<String>, K: Into<String>>(collection: C, key: K) -> RecordId {
+		RecordId {
+			collection: collection.into(),
+			key: key.into(),
+		}
+	}
+
+	/// Parses a record ID in the `<collection>/<key>` format.
+	pub fn parse(text: &str) -> Option<RecordId> {
+		let mut parts = text.splitn(2, '/');
+		let (collection, key) = (parts.next()?, parts.next()?);
+		if is_valid_name(collection) && is_valid_name(key) {
+			Some(RecordId::new(collection, key))
+		} else {
+			None
+		}
+	}
+}
+
+impl fmt::Display for RecordId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.collection, self.key)
+	}
+}
+
+impl<'a> Collection<'a> {
+	/// Adds a link from a record to another record. Returns false if the link
+	/// already existed.
+	///
+	/// Fails with `Error::NotFound` if either record does not exist.
+	pub fn link(&self, key: &str, to: &RecordId) -> Result<bool> {
+		self.db.check_writable()?;
+		let from = self.record_id(key)?;
+		let target = self.db.collection(to.collection.as_str())?;
+		target.record_path(&to.key)?;
+
+		let _guard = self.db.write_guard();
+		for (collection, id) in [(self, &from), (&target, to)].iter() {
+			if !collection.contains(&id.key)? {
+				return Err(Error::NotFound(format!("record `{}`", id)));
+			}
+		}
+		self.db.add_link_locked(&from, to)
+	}
+
+	/// Removes a link from a record to another record. Returns false if the
+	/// link did not exist.
+	pub fn unlink(&self, key: &str, to: &RecordId) -> Result<bool> {
+		self.db.check_writable()?;
+		let from = self.record_id(key)?;
+		let _guard = self.db.write_guard();
+		self.db.remove_link_locked(&from, to)
+	}
+
+	/// Returns the records linked from a record, sorted.
+	pub fn links(&self, key: &str) -> Result<Vec<RecordId>> {
+		let id = self.record_id(key)?;
+		self.db.read_links(LINKS_DIR, &id)
+	}
+
+	/// Returns the records linking to a record, sorted.
+	pub fn backlinks(&self, key: &str) -> Result<Vec<RecordId>> {
+		let id = self.record_id(key)?;
+		self.db.read_links(BACKLINKS_DIR, &id)
+	}
+
+	pub(crate) fn record_id(&self, key: &str) -> Result<RecordId> {
+		self.record_path(key)?;
+		Ok(RecordId::new(self.name.as_str(), key))
+	}
+}
+
+impl Database {
+	/// Adds a link between two records. Must be called with the write lock
+	/// held.
+	pub(crate) fn add_link_locked(&self, from: &RecordId, to: &RecordId) -> Result<bool> {
+		let mut links = self.read_links(LINKS_DIR, from)?;
+		if links.contains(to) {
+			return Ok(false);
+		}
+		let mut backlinks = self.read_links(BACKLINKS_DIR, to)?;
+		self.log_change(Change::Link {
+			from: from.clone(),
+			to: to.clone(),
+		})?;
+		links.push(to.clone());
+		backlinks.push(from.clone());
+		self.write_links(LINKS_DIR, from, links)?;
+		self.write_links(BACKLINKS_DIR, to, backlinks)?;
+		Ok(true)
+	}
+
+	/// Removes a link between two records. Must be called with the write
+	/// lock held.
+	pub(crate) fn remove_link_locked(&self, from: &RecordId, to: &RecordId) -> Result<bool> {
+		let mut links = self.read_links(LINKS_DIR, from)?;
+		if !links.contains(to) {
+			return Ok(false);
+		}
+		let mut backlinks = self.read_links(BACKLINKS_DIR, to)?;
+		self.log_change(Change::Unlink {
+			from: from.clone(),
+			to: to.clone(),
+		})?;
+		links.retain(|id| id != to);
+		backlinks.retain(|id| id != from);
+		self.write_links(LINKS_DIR, from, links)?;
+		self.write_links(BACKLINKS_DIR, to, backlinks)?;
+		Ok(true)
+	}
+
+	/// Returns the changes that remove the links from a record, which are
+	/// made when it is deleted.
+	pub(crate) fn unlink_changes(&self, id: &RecordId) -> Result<Vec<Change>> {
+		let links = self.read_links(LINKS_DIR, id)?;
+		Ok(links
+			.into_iter()
+			.map(|to| Change::Unlink {
+				from: id.clone(),
+				to,
+			})
+			.collect())
+	}
+
+	/// Fails with `Error::Linked` if any record other than the ones given
+	/// links to the record.
+	pub(crate) fn check_unlinked(&self, id: &RecordId, except: &[RecordId]) -> Result<()> {
+		let backlinks = self.read_links(BACKLINKS_DIR, id)?;
+		match backlinks
+			.iter()
+			.find(|from| *from != id && !except.contains(from))
+		{
+			Some(from) => Err(Error::Linked(format!(
+				"record `{}` is linked from `{}`",
+				id, from
+			))),
+			None => Ok(()),
+		}
+	}
+
+	/// Removes the links from a record that is being deleted, failing if
+	/// other records link to it. Must be called with the write lock held.
+	pub(crate) fn unlink_for_delete(&self, id: &RecordId) -> Result<()> {
+		self.check_unlinked(id, &[])?;
+		for change in self.unlink_changes(id)? {
+			if let Change::Unlink { from, to } = change {
+				self.remove_link_locked(&from, &to)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn links_path(&self, dir: &str, id: &RecordId) -> PathBuf {
+		self.path.join(dir).join(&id.collection).join(&id.key)
+	}
+
+	pub(crate) fn read_links(&self, dir: &str, id: &RecordId) -> Result<Vec<RecordId>> {
+		let path = self.links_path(dir, id);
+		let data = match self.read_file(&path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(err) => return Err(read_error(err, &path)),
+		};
+		Ok(parse_links(&data))
+	}
+
+	pub(crate) fn write_links(&self, dir: &str, id: &RecordId, links: Vec<RecordId>) -> Result<()> {
+		let path = self.links_path(dir, id);
+		if links.is_empty() {
+			return match fs::remove_file(&path) {
+				Err(err) if err.kind() != io::ErrorKind::NotFound => Err(write_error(err, &path)),
+				_ => Ok(()),
+			};
+		}
+		let links = links.into_iter().collect::<BTreeSet<_>>();
+		let mut text = String::new();
+		for link in links {
+			text.push_str(&link.to_string());
+			text.push('\n');
+		}
+		fs::create_dir_all(path.parent().unwrap())
+			.and_then(|_| util::write_file(&path, text.as_bytes()))
+			.map_err(|err| write_error(err, &path))
+	}
+}
+
+/// Parses the records listed in a `links` or `backlinks` file.
+pub(crate) fn parse_links(data: &[u8]) -> Vec<RecordId> {
+	// Invalid lines can only come from manual edits, and are ignored.
+	let text = String::from_utf8_lossy(data);
+	text.lines().filter_map(RecordId::parse).collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_link_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		let files = db.collection("files").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		files.put("f", b"F").unwrap();
+
+		let file = RecordId::new("files", "f");
+		let b = RecordId::new("notes", "b");
+		assert!(notes.link("a", &file).unwrap());
+		assert!(!notes.link("a", &file).unwrap());
+		assert!(notes.link("a", &b).unwrap());
+		assert!(notes.link("b", &file).unwrap());
+		match notes.link("a", &RecordId::new("files", "missing")) {
+			Err(Error::NotFound(_)) => (),
+			other => panic!("expected Error::NotFound, got {:?}", other),
+		}
+
+		assert_eq!(notes.links("a").unwrap(), vec![file.clone(), b.clone()]);
+		assert_eq!(
+			files.backlinks("f").unwrap(),
+			vec![RecordId::new("notes", "a"), b.clone()]
+		);
+
+		assert!(notes.unlink("b", &file).unwrap());
+		assert!(!notes.unlink("b", &file).unwrap());
+		assert!(notes.links("b").unwrap().is_empty());
+		assert!(!db.path.join(LINKS_DIR).join("notes").join("b").exists());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_not_delete_linked_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		let b = RecordId::new("notes", "b");
+		notes.link("a", &b).unwrap();
+		notes.link("b", &b).unwrap();
+
+		match notes.delete("b") {
+			Err(Error::Linked(_)) => (),
+			other => panic!("expected Error::Linked, got {:?}", other),
+		}
+		let mut tx = db.transaction();
+		tx.delete("notes", "b").unwrap();
+		assert!(tx.commit().is_err());
+		assert!(notes.contains("b").unwrap());
+
+		// Deleting the source removes its links, in a transaction as well.
+		let mut tx = db.transaction();
+		tx.delete("notes", "a").unwrap();
+		tx.delete("notes", "b").unwrap();
+		tx.commit().unwrap();
+		assert!(notes.keys().unwrap().is_empty());
+		assert!(notes.links("a").unwrap().is_empty());
+		assert!(notes.backlinks("b").unwrap().is_empty());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
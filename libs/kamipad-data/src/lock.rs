@@ -0,0 +1,209 @@
+//! Owner information for the database lock and recovery of stale locks.
+//!
+//! When a database is opened for writing, information about the owner of
+//! the lock is written to the lock file, and cleared when the database is
+//! closed:
+//!
+//! ```text
+//! pid=1234
+//! host=hostname
+//! time=1600000000000
+//! ```
+//!
+//! File locks are released by the operating system when a process dies, so
+//! a leftover lock is usually harmless. The exception is a lock held through
+//! a network file system from a machine that crashed, which can remain in
+//! place indefinitely. `break_lock` recovers from that by removing the lock
+//! file, once it is known that the owner is gone.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::{Error, IOError};
+use crate::journal::now;
+use crate::open::DB_LOCK_FILENAME;
+use crate::Result;
+
+/// Information about the process holding the database write lock.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LockInfo {
+	/// Process ID of the owner.
+	pub pid: u32,
+	/// Host name of the machine running the owner.
+	pub host: String,
+	/// UNIX timestamp in milliseconds of when the lock was acquired.
+	pub time: u64,
+}
+
+impl LockInfo {
+	/// Returns the information for the current process.
+	pub(crate) fn current() -> LockInfo {
+		LockInfo {
+			pid: std::process::id(),
+			host: hostname(),
+			time: now(),
+		}
+	}
+
+	/// Returns true if the owner is known to be gone, which is only possible
+	/// to determine for processes on the current host.
+	pub fn is_owner_gone(&self) -> bool {
+		self.host == hostname() && process_exists(self.pid) == Some(false)
+	}
+
+	fn to_text(&self) -> String {
+		format!("pid={}\nhost={}\ntime={}\n", self.pid, self.host, self.time)
+	}
+
+	pub(crate) fn parse(text: &str) -> Option<LockInfo> {
+		let params = text
+			.lines()
+			.filter_map(|line| {
+				let mut parts = line.splitn(2, '=');
+				Some((parts.next()?.trim(), parts.next()?.trim()))
+			})
+			.collect::<HashMap<_, _>>();
+		Some(LockInfo {
+			pid: params.get("pid")?.parse().ok()?,
+			host: params.get("host")?.to_string(),
+			time: params.get("time")?.parse().ok()?,
+		})
+	}
+}
+
+/// Returns the owner information from the lock file of the database at the
+/// given path, if any.
+///
+/// The information may be left behind by a process that crashed, so it
+/// does not mean that the database is currently locked.
+pub fn lock_info<P: AsRef<Path>>(path: P) -> Result<Option<LockInfo>> {
+	let lock_path = path.as_ref().join(DB_LOCK_FILENAME);
+	match fs::read_to_string(&lock_path) {
+		Ok(text) => Ok(LockInfo::parse(&text)),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(lock_error(err, "reading", &lock_path)),
+	}
+}
+
+/// Breaks a stale write lock on the database at the given path, by removing
+/// the lock file. Returns true if the lock was broken.
+///
+/// The lock is only considered stale if its owner is a process on this host
+/// that no longer exists, unless `force` is true. Forcing should only be
+/// used when the owner is known to be gone, since breaking a live lock
+/// allows two writers on the same database.
+pub fn break_lock<P: AsRef<Path>>(path: P, force: bool) -> Result<bool> {
+	let info = match lock_info(&path)? {
+		Some(info) => info,
+		None => return Ok(false),
+	};
+	if !force && !info.is_owner_gone() {
+		return Ok(false);
+	}
+
+	let lock_path = path.as_ref().join(DB_LOCK_FILENAME);
+	match fs::remove_file(&lock_path) {
+		Ok(_) => Ok(true),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+		Err(err) => Err(lock_error(err, "removing", &lock_path)),
+	}
+}
+
+/// Writes the owner information for the current process to the lock file.
+pub(crate) fn write_lock_info(root: &Path) -> Result<()> {
+	let lock_path = root.join(DB_LOCK_FILENAME);
+	// The lock file is written in place, since replacing it would detach it
+	// from the lock we hold.
+	fs::write(&lock_path, LockInfo::current().to_text())
+		.map_err(|err| lock_error(err, "writing", &lock_path))
+}
+
+/// Clears the owner information from the lock file.
+pub(crate) fn clear_lock_info(root: &Path) -> Result<()> {
+	let lock_path = root.join(DB_LOCK_FILENAME);
+	fs::write(&lock_path, b"").map_err(|err| lock_error(err, "writing", &lock_path))
+}
+
+fn lock_error(err: io::Error, action: &str, path: &Path) -> Error {
+	Error::Open(IOError::new(
+		err,
+		format!("{} lock file `{}`", action, path.to_string_lossy()),
+	))
+}
+
+fn hostname() -> String {
+	fs::read_to_string("/proc/sys/kernel/hostname")
+		.or_else(|_| fs::read_to_string("/etc/hostname"))
+		.ok()
+		.or_else(|| env::var("HOSTNAME").ok())
+		.or_else(|| env::var("COMPUTERNAME").ok())
+		.map(|name| name.trim().to_string())
+		.filter(|name| !name.is_empty())
+		.unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Returns whether a process exists, if that can be determined.
+#[cfg(target_os = "linux")]
+fn process_exists(pid: u32) -> Option<bool> {
+	Some(Path::new("/proc").join(pid.to_string()).exists())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_exists(_pid: u32) -> Option<bool> {
+	None
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_write_lock_info() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let path = db.path.clone();
+
+		let info = lock_info(&path).unwrap().unwrap();
+		assert_eq!(info.pid, std::process::id());
+		assert_eq!(info.host, hostname());
+		assert!(!info.is_owner_gone());
+		assert!(!break_lock(&path, false).unwrap());
+
+		// Readers don't touch the lock information.
+		drop(db);
+		assert_eq!(lock_info(&path).unwrap(), None);
+		let db = open(&path, OpenFlags::read_only()).unwrap();
+		assert_eq!(lock_info(&path).unwrap(), None);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn should_break_stale_lock() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let path = db.path.clone();
+		drop(db);
+
+		// PIDs are limited to 2^22 on Linux, so this one cannot exist.
+		let stale = LockInfo {
+			pid: u32::MAX,
+			host: hostname(),
+			time: 0,
+		};
+		fs::write(path.join(DB_LOCK_FILENAME), stale.to_text()).unwrap();
+		assert_eq!(lock_info(&path).unwrap(), Some(stale));
+
+		assert!(break_lock(&path, false).unwrap());
+		assert!(!path.join(DB_LOCK_FILENAME).exists());
+
+		let db = open(&path, OpenFlags::default()).unwrap();
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,188 @@
+//! Management of multiple databases.
+//!
+//! A `DatabaseManager` hosts several databases by name, as directories under
+//! a common root:
+//!
+//! ```text
+//! <root>/
+//!   <name>/    -- database, see `open`
+//!   ...
+//! ```
+//!
+//! Databases are opened on first use and shared between callers, since only
+//! one instance in the process can hold the write lock for a database. Each
+//! database is opened under its own lock, so that a slow open (e.g. running
+//! a migration) doesn't hold back access to the others.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::collection::{is_valid_name, list_names};
+use crate::database::{lock, Database};
+use crate::error::Error;
+use crate::open::{open, OpenFlags, DB_LOCK_FILENAME};
+use crate::Result;
+
+type Slot = Arc<Mutex<Option<Arc<Database>>>>;
+
+/// Opens and tracks multiple named databases under a root directory.
+pub struct DatabaseManager {
+	root: PathBuf,
+	flags: OpenFlags,
+	databases: Mutex<HashMap<String, Slot>>,
+}
+
+impl DatabaseManager {
+	/// Creates a manager for the databases under the given root, which are
+	/// opened with the given flags.
+	pub fn new<P: Into<PathBuf>>(root: P, flags: OpenFlags) -> DatabaseManager {
+		DatabaseManager {
+			root: root.into(),
+			flags,
+			databases: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Returns the root directory for the databases.
+	pub fn root(&self) -> &Path {
+		&self.root
+	}
+
+	/// Returns a handle to the named database, opening it if necessary.
+	///
+	/// With `OpenFlags::create`, the database is created if it does not
+	/// exist. Otherwise opening a missing database fails.
+	pub fn get(&self, name: &str) -> Result<Arc<Database>> {
+		if !is_valid_name(name) {
+			return Err(Error::InvalidName(name.to_string()));
+		}
+		let slot = lock(&self.databases)
+			.entry(name.to_string())
+			.or_default()
+			.clone();
+
+		let mut slot = lock(&slot);
+		if let Some(db) = &*slot {
+			return Ok(db.clone());
+		}
+		let db = Arc::new(open(self.root.join(name), self.flags.clone())?);
+		*slot = Some(db.clone());
+		Ok(db)
+	}
+
+	/// Returns the names of all databases under the root, sorted.
+	pub fn names(&self) -> Result<Vec<String>> {
+		let names = list_names(&self.root, true)?;
+		Ok(names
+			.into_iter()
+			.filter(|name| self.root.join(name).join(DB_LOCK_FILENAME).is_file())
+			.collect())
+	}
+
+	/// Returns the names of the databases currently open, sorted.
+	pub fn open_names(&self) -> Vec<String> {
+		let databases = lock(&self.databases);
+		let mut names = databases
+			.iter()
+			.filter(|(_, slot)| lock(slot).is_some())
+			.map(|(name, _)| name.clone())
+			.collect::<Vec<_>>();
+		names.sort();
+		names
+	}
+
+	/// Stops tracking the named database, closing it. Returns false if the
+	/// database was not open.
+	///
+	/// If there are other handles to the database, it is only closed once
+	/// they are all dropped, and errors from closing are not reported.
+	pub fn close(&self, name: &str) -> Result<bool> {
+		let slot = match lock(&self.databases).remove(name) {
+			Some(slot) => slot,
+			None => return Ok(false),
+		};
+		let db = lock(&slot).take();
+		match db.map(Arc::try_unwrap) {
+			Some(Ok(db)) => db.close().map(|_| true),
+			Some(Err(_)) => Ok(true),
+			None => Ok(false),
+		}
+	}
+
+	/// Closes all open databases, as with `close`. Returns the first error,
+	/// after trying to close every database.
+	pub fn close_all(&self) -> Result<()> {
+		let names = lock(&self.databases).keys().cloned().collect::<Vec<_>>();
+		let mut result = Ok(());
+		for name in names {
+			if let Err(err) = self.close(&name) {
+				if result.is_ok() {
+					result = Err(err);
+				}
+			}
+		}
+		result
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::lock_info;
+	use std::thread;
+	use tempdir::TempDir;
+
+	#[test]
+	fn should_manage_databases() {
+		let temp = TempDir::new("kamipad-data").unwrap();
+		let manager = Arc::new(DatabaseManager::new(temp.path(), OpenFlags::default()));
+		assert!(manager.names().unwrap().is_empty());
+
+		let a = manager.get("a").unwrap();
+		a.collection("notes").unwrap().put("x", b"X").unwrap();
+		let b = manager.get("b").unwrap();
+		assert!(b.collection("notes").unwrap().get("x").unwrap().is_none());
+		assert!(manager.get("bad/name").is_err());
+
+		// Handles are shared, including between threads.
+		let handles = (0..4)
+			.map(|_| {
+				let manager = manager.clone();
+				thread::spawn(move || manager.get("a").unwrap())
+			})
+			.collect::<Vec<_>>();
+		for handle in handles {
+			assert!(Arc::ptr_eq(&handle.join().unwrap(), &a));
+		}
+		assert_eq!(manager.names().unwrap(), vec!["a", "b"]);
+		assert_eq!(manager.open_names(), vec!["a", "b"]);
+
+		// Closing releases the database once all handles are dropped.
+		drop(a);
+		assert!(manager.close("a").unwrap());
+		assert!(!manager.close("a").unwrap());
+		assert!(lock_info(temp.path().join("a")).unwrap().is_none());
+		assert_eq!(manager.open_names(), vec!["b"]);
+		let a = manager.get("a").unwrap();
+		assert_eq!(
+			a.collection("notes").unwrap().get("x").unwrap().unwrap(),
+			b"X"
+		);
+
+		drop((a, b));
+		manager.close_all().unwrap();
+		assert!(manager.open_names().is_empty());
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_not_create_missing_databases() {
+		let temp = TempDir::new("kamipad-data").unwrap();
+		let flags = OpenFlags::config(|f| f.create = false);
+		let manager = DatabaseManager::new(temp.path(), flags);
+		assert!(manager.get("missing").is_err());
+		assert!(manager.open_names().is_empty());
+		temp.close().unwrap();
+	}
+}
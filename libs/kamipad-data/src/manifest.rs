@@ -0,0 +1,238 @@
+//! Format manifest and on-disk format migrations.
+//!
+//! The `manifest` file at the database root records the version of the
+//! on-disk format:
+//!
+//! ```text
+//! format=kamipad
+//! version=1
+//! ```
+//!
+//! The manifest is written when the database is created. Databases created
+//! before the manifest was introduced have no manifest and are considered to
+//! be at version zero.
+//!
+//! When opening a database for writing, any registered migration for a
+//! version newer than the database's is run in order, updating the manifest
+//! after each step so that an interrupted upgrade resumes where it stopped.
+//! Databases in a format newer than the library are never opened.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::{Error, IOError};
+use crate::open::DB_LOCK_FILENAME;
+use crate::util;
+use crate::Result;
+
+pub(crate) const MANIFEST_FILENAME: &str = "manifest";
+
+const FORMAT_NAME: &str = "kamipad";
+
+/// Version of the on-disk format written by this library.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// An on-disk format migration.
+pub(crate) struct Migration {
+	/// Format version after the migration is run.
+	pub version: u32,
+	/// Short description of the migration.
+	pub description: &'static str,
+	/// Migrates the database at the given root from the previous version.
+	pub run: fn(&Path) -> io::Result<()>,
+}
+
+/// Registered migrations, in order of version.
+pub(crate) const MIGRATIONS: &[Migration] = &[Migration {
+	version: 1,
+	description: "add format manifest",
+	// Version one is the same layout, with the manifest added.
+	run: |_| Ok(()),
+}];
+
+/// Reads the format version from the manifest at the database root.
+///
+/// Returns `None` for a new database, which has nothing besides the lock
+/// file.
+pub(crate) fn read_version(root: &Path) -> Result<Option<u32>> {
+	let path = root.join(MANIFEST_FILENAME);
+	let text = match fs::read_to_string(&path) {
+		Ok(text) => text,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {
+			let is_new = fs::read_dir(root)
+				.map_err(|err| open_error(err, "reading", root))?
+				.filter_map(|entry| entry.ok())
+				.all(|entry| entry.file_name() == DB_LOCK_FILENAME);
+			return Ok(if is_new { None } else { Some(0) });
+		}
+		Err(err) => return Err(open_error(err, "reading", &path)),
+	};
+
+	let params = text
+		.lines()
+		.filter_map(|line| {
+			let mut parts = line.splitn(2, '=');
+			Some((parts.next()?.trim(), parts.next()?.trim()))
+		})
+		.collect::<HashMap<_, _>>();
+	match (params.get("format"), params.get("version")) {
+		(Some(&FORMAT_NAME), Some(version)) => match version.parse() {
+			Ok(version) => Ok(Some(version)),
+			Err(_) => Err(open_error(
+				util::invalid_data("invalid version"),
+				"reading",
+				&path,
+			)),
+		},
+		_ => Err(open_error(
+			util::invalid_data("invalid format"),
+			"reading",
+			&path,
+		)),
+	}
+}
+
+/// Writes the manifest at the database root with the given version.
+pub(crate) fn write_version(root: &Path, version: u32) -> Result<()> {
+	let path = root.join(MANIFEST_FILENAME);
+	let text = format!("format={}\nversion={}\n", FORMAT_NAME, version);
+	crate::fs::atomic_write(&path, text.as_bytes()).map_err(|err| open_error(err, "writing", &path))
+}
+
+/// Checks the database format at open, creating the manifest for a new
+/// database and running any pending migration.
+pub(crate) fn prepare(root: &Path, writable: bool, migrations: &[Migration]) -> Result<()> {
+	let latest = migrations.last().map(|m| m.version).unwrap_or(0);
+	let version = match read_version(root)? {
+		Some(version) => version,
+		None if writable => return write_version(root, latest),
+		None => return Ok(()),
+	};
+
+	if version > latest {
+		return Err(Error::UnsupportedVersion(version));
+	} else if version == latest {
+		return Ok(());
+	} else if !writable {
+		return Err(Error::MigrationRequired(version));
+	}
+
+	for migration in migrations.iter().filter(|m| m.version > version) {
+		(migration.run)(root).map_err(|err| {
+			Error::Open(IOError::new(
+				err,
+				format!(
+					"migrating database to version {} ({})",
+					migration.version, migration.description
+				),
+			))
+		})?;
+		write_version(root, migration.version)?;
+	}
+	Ok(())
+}
+
+fn open_error(err: io::Error, action: &str, path: &Path) -> Error {
+	Error::Open(IOError::new(
+		err,
+		format!("{} manifest `{}`", action, path.to_string_lossy()),
+	))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_write_manifest_on_create() {
+		let (db, temp) = create_db(OpenFlags::default());
+		assert_eq!(read_version(&db.path).unwrap(), Some(FORMAT_VERSION));
+		let text = fs::read_to_string(db.path.join(MANIFEST_FILENAME)).unwrap();
+		assert_eq!(text, "format=kamipad\nversion=1\n");
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_reject_newer_format() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let path = db.path.clone();
+		drop(db);
+
+		write_version(&path, FORMAT_VERSION + 1).unwrap();
+		match open(&path, OpenFlags::default()) {
+			Err(Error::UnsupportedVersion(version)) => assert_eq!(version, FORMAT_VERSION + 1),
+			other => panic!("expected Error::UnsupportedVersion, got {:?}", other),
+		}
+
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_migrate_old_databases() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.collection("notes").unwrap().put("a", b"A").unwrap();
+		let path = db.path.clone();
+		drop(db);
+
+		// Databases without a manifest predate it.
+		fs::remove_file(path.join(MANIFEST_FILENAME)).unwrap();
+		assert_eq!(read_version(&path).unwrap(), Some(0));
+
+		match open(&path, OpenFlags::read_only()) {
+			Err(Error::MigrationRequired(0)) => (),
+			other => panic!("expected Error::MigrationRequired, got {:?}", other),
+		}
+
+		let db = open(&path, OpenFlags::default()).unwrap();
+		assert_eq!(read_version(&path).unwrap(), Some(FORMAT_VERSION));
+		let notes = db.collection("notes").unwrap();
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A");
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_run_migrations_in_order() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let path = db.path.clone();
+		drop(db);
+
+		let migrations = [
+			Migration {
+				version: 1,
+				description: "first",
+				run: |root| fs::write(root.join("log"), "1"),
+			},
+			Migration {
+				version: 2,
+				description: "second",
+				run: |root| {
+					let log = fs::read_to_string(root.join("log"))?;
+					fs::write(root.join("log"), log + "2")
+				},
+			},
+			Migration {
+				version: 3,
+				description: "broken",
+				run: |_| Err(io::Error::new(io::ErrorKind::Other, "failed")),
+			},
+		];
+
+		write_version(&path, 0).unwrap();
+		assert!(prepare(&path, true, &migrations).is_err());
+		assert_eq!(fs::read_to_string(path.join("log")).unwrap(), "12");
+		assert_eq!(read_version(&path).unwrap(), Some(2));
+
+		prepare(&path, true, &migrations[..2]).unwrap();
+		assert_eq!(fs::read_to_string(path.join("log")).unwrap(), "12");
+
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,332 @@
+//! Snapshot isolation for readers.
+//!
+//! Read-only instances don't lock the database, so they can be open while a
+//! writer commits changes. Instead, each reader sees the database as it was
+//! at a fixed sequence number in the journal, which only moves forward when
+//! calling `Database::poll_changes`.
+//!
+//! Readers register themselves in the `readers` directory, with a file
+//! containing their current sequence number. The file is kept locked by the
+//! reader, so that registrations left by crashed readers can be detected.
+//!
+//! While there are readers, the writer saves undo data for every change
+//! (see the `snapshot` module) and keeps it for as long as a reader might
+//! need it. Reading a file then works the same as for a `Snapshot`: the file
+//! is read, and if the journal has a change for the file after the reader's
+//! sequence number the undo copy for that change is used instead.
+//!
+//! The writer holds an exclusive lock on the `commit.lock` file while making
+//! a change, and readers take a shared lock on it while establishing their
+//! sequence number. This guarantees that a reader never picks a sequence
+//! number for a change that is not fully applied, and that the writer sees
+//! every reader before making a change the reader would need undo data for.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+use crate::id::ID;
+use crate::journal::{self, Change};
+use crate::open;
+use crate::snapshot::UNDO_DIR;
+use crate::util::{read_error, write_error, FileData};
+use crate::Result;
+
+pub(crate) const READERS_DIR: &str = "readers";
+
+pub(crate) const COMMIT_LOCK_FILENAME: &str = "commit.lock";
+
+/// Registration of a reader in the `readers` directory. The registration is
+/// removed when dropped.
+pub(crate) struct Registration {
+	path: PathBuf,
+	// Kept open and locked for as long as the reader is alive.
+	_file: fs::File,
+}
+
+impl Registration {
+	/// Registers a new reader at the given sequence number.
+	pub fn new(root: &Path, seq: u64) -> Result<Registration> {
+		let dir = root.join(READERS_DIR);
+		let path = dir.join(format!("{}-{}", std::process::id(), ID::new()));
+		let file = fs::create_dir_all(&dir)
+			.and_then(|_| fs::File::create(&path))
+			.and_then(|file| open::acquire_lock(&file, true, None).map(|_| file))
+			.map_err(|err| write_error(err, &path))?;
+		let registration = Registration { path, _file: file };
+		registration.update(seq)?;
+		Ok(registration)
+	}
+
+	/// Removes the registration, returning any error.
+	pub fn remove(self) -> Result<()> {
+		fs::remove_file(&self.path).map_err(|err| write_error(err, &self.path))
+	}
+
+	/// Updates the sequence number for the reader.
+	pub fn update(&self, seq: u64) -> Result<()> {
+		fs::write(&self.path, seq.to_string()).map_err(|err| write_error(err, &self.path))
+	}
+}
+
+impl Drop for Registration {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+/// Opens the commit lock file. Returns `None` if it doesn't exist, which is
+/// the case for a database that has never been opened for writing.
+pub(crate) fn open_commit_lock(root: &Path, create: bool) -> Result<Option<fs::File>> {
+	let path = root.join(COMMIT_LOCK_FILENAME);
+	let result = fs::OpenOptions::new()
+		.read(true)
+		.write(create)
+		.create(create)
+		.open(&path);
+	match result {
+		Ok(file) => Ok(Some(file)),
+		Err(err) if err.kind() == io::ErrorKind::NotFound && !create => Ok(None),
+		Err(err) => Err(read_error(err, &path)),
+	}
+}
+
+/// Runs the callback with a shared lock on the commit lock file, if it
+/// exists, so that no change is in progress while it runs.
+pub(crate) fn with_commit_lock<T, F: FnOnce() -> Result<T>>(root: &Path, callback: F) -> Result<T> {
+	use fs2::FileExt;
+	let file = open_commit_lock(root, false)?;
+	if let Some(file) = &file {
+		let path = root.join(COMMIT_LOCK_FILENAME);
+		FileExt::lock_shared(file).map_err(|err| read_error(err, &path))?;
+	}
+	// The lock is released when the file is closed.
+	callback()
+}
+
+/// Returns the sequence numbers of all active readers, removing the
+/// registration of readers that are gone.
+pub(crate) fn active_readers(root: &Path) -> Result<Vec<u64>> {
+	let dir = root.join(READERS_DIR);
+	let entries = match fs::read_dir(&dir) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(read_error(err, &dir)),
+	};
+
+	let mut readers = Vec::new();
+	for entry in entries {
+		let path = entry.map_err(|err| read_error(err, &dir))?.path();
+		let file = match fs::File::open(&path) {
+			Ok(file) => file,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+			Err(err) => return Err(read_error(err, &path)),
+		};
+
+		// A registration we can lock belongs to a reader that is gone.
+		if open::acquire_lock(&file, true, None).is_ok() {
+			let _ = fs::remove_file(&path);
+			continue;
+		}
+
+		// A reader that is still writing its registration is at least at
+		// the current sequence number, so it doesn't hold anything back.
+		let seq = fs::read_to_string(&path)
+			.ok()
+			.and_then(|s| s.trim().parse().ok());
+		if let Some(seq) = seq {
+			readers.push(seq);
+		}
+	}
+	Ok(readers)
+}
+
+impl Database {
+	/// Locks the commit lock file: exclusively for the writer and shared for
+	/// readers. Returns the locked file, which must be unlocked when done.
+	///
+	/// Failing to lock only affects the isolation of readers, so errors are
+	/// ignored, same as for file systems that don't support locking.
+	pub(crate) fn lock_commit(&self) -> Option<fs::File> {
+		use fs2::FileExt;
+		if self.is_read_only() {
+			let file = open_commit_lock(&self.path, false).ok()??;
+			FileExt::lock_shared(&file).ok()?;
+			Some(file)
+		} else {
+			let file = self.commit_lock()?.try_clone().ok()?;
+			FileExt::lock_exclusive(&file).ok()?;
+			Some(file)
+		}
+	}
+
+	/// Reads a database file as seen by this instance.
+	///
+	/// For readers, this returns the file as it was at the reader's sequence
+	/// number. The writer always reads the current file.
+	pub(crate) fn read_file(&self, path: &Path) -> io::Result<FileData> {
+		let current = self.read_current_file(path);
+		if self.reader_seq().is_none() {
+			return current;
+		}
+
+		// The current file must be read before checking the journal, see
+		// `Snapshot::read`.
+		let relative = match path.strip_prefix(&self.path) {
+			Ok(relative) => relative,
+			Err(_) => return current,
+		};
+		match self.change_after_snapshot(relative) {
+			Ok(Some(seq)) => {
+				let undo_path = self.path.join(UNDO_DIR).join(seq.to_string());
+				if !undo_path.is_dir() {
+					return Err(io::Error::new(
+						io::ErrorKind::Other,
+						format!("missing undo data for change {}", seq),
+					));
+				}
+				self.read_current_file(&undo_path.join(relative))
+			}
+			Ok(None) => current,
+			Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+		}
+	}
+
+	/// Returns the keys in a collection that were changed after the
+	/// reader's sequence number. These may not exist in the current data but
+	/// be visible to the reader.
+	pub(crate) fn keys_changed_after_snapshot(&self, collection: &str) -> Result<Vec<String>> {
+		if self.reader_seq().is_none() {
+			return Ok(Vec::new());
+		}
+		let mut keys = Vec::new();
+		for entry in self.changes_after_snapshot()? {
+			match entry.change {
+				Change::Put { collection: c, key } | Change::Delete { collection: c, key }
+					if c == collection =>
+				{
+					keys.push(key);
+				}
+				_ => {}
+			}
+		}
+		Ok(keys)
+	}
+
+	/// Returns true if readers are registered, which requires the writer to
+	/// save undo data.
+	pub(crate) fn has_readers(&self) -> Result<bool> {
+		active_readers(&self.path).map(|readers| !readers.is_empty())
+	}
+
+	/// Returns the sequence number for the reader, or `None` if this is not
+	/// a registered reader.
+	fn reader_seq(&self) -> Option<u64> {
+		if self.registration().is_some() {
+			Some(self.sequence())
+		} else {
+			None
+		}
+	}
+
+	fn changes_after_snapshot(&self) -> Result<Vec<journal::JournalEntry>> {
+		let offset = *self.journal_offset();
+		journal::read_journal_from(&self.path, offset).map(|(entries, _)| entries)
+	}
+
+	/// Returns the sequence number of the first change after the reader's
+	/// sequence number that affected the file.
+	fn change_after_snapshot(&self, relative: &Path) -> Result<Option<u64>> {
+		let entries = self.changes_after_snapshot()?;
+		Ok(entries
+			.iter()
+			.find(|entry| entry.change.paths().iter().any(|path| path == relative))
+			.map(|entry| entry.seq))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::collection::DATA_DIR;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_isolate_readers_from_writer() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A1").unwrap();
+		notes.put("b", b"B1").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		// Readers can open while the writer is active.
+		let reader = open(&db.path, OpenFlags::read_only()).unwrap();
+		let reader_notes = reader.collection("notes").unwrap();
+		assert_eq!(reader.sequence(), 3);
+		assert_eq!(active_readers(&db.path).unwrap(), vec![3]);
+
+		notes.put("a", b"A2").unwrap();
+		notes.delete("b").unwrap();
+		notes.put("c", b"C").unwrap();
+		db.release_blob(&blob).unwrap();
+		db.gc_blobs().unwrap();
+
+		// The reader still sees the data at its sequence number.
+		assert_eq!(reader_notes.get("a").unwrap().unwrap(), b"A1");
+		assert_eq!(reader_notes.get("b").unwrap().unwrap(), b"B1");
+		assert!(reader_notes.get("c").unwrap().is_none());
+		assert_eq!(reader_notes.keys().unwrap(), vec!["a", "b"]);
+		assert_eq!(reader.get_blob(&blob).unwrap().unwrap(), b"blob");
+		assert_eq!(reader.blob_refs(&blob).unwrap(), 1);
+
+		// Polling moves the reader forward.
+		assert_eq!(reader.poll_changes().unwrap().len(), 5);
+		assert_eq!(reader_notes.get("a").unwrap().unwrap(), b"A2");
+		assert_eq!(reader_notes.keys().unwrap(), vec!["a", "c"]);
+		assert!(reader.get_blob(&blob).unwrap().is_none());
+		assert_eq!(active_readers(&db.path).unwrap(), vec![8]);
+
+		// Undo data is kept while needed by readers.
+		db.compact().unwrap();
+		assert_eq!(reader_notes.get("a").unwrap().unwrap(), b"A2");
+		drop(reader);
+		assert!(active_readers(&db.path).unwrap().is_empty());
+		notes.put("a", b"A3").unwrap();
+		db.compact().unwrap();
+		assert!(fs::read_dir(db.path.join(UNDO_DIR))
+			.unwrap()
+			.next()
+			.is_none());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_remove_stale_readers() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let dir = db.path.join(READERS_DIR);
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("stale"), "1").unwrap();
+
+		assert!(active_readers(&db.path).unwrap().is_empty());
+		assert!(!dir.join("stale").exists());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_read_data_dir_as_writer() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.collection("notes").unwrap().put("a", b"A").unwrap();
+		let path = db.path.join(DATA_DIR).join("notes").join("a");
+		assert!(db.read_file(&path).is_ok());
+		assert!(!db.has_readers().unwrap());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -8,26 +8,41 @@
 //! which is kept open. The lock is an empty file that is used only to control
 //! shared access to the database.
 //!
-//! Given the above, opening the database amounts to opening the lock file
-//! and acquiring an exclusive lock (if writing is enabled) or a shared lock
-//! (if mode is read-only) on it.
+//! Given the above, opening the database for writing amounts to opening the
+//! lock file and acquiring an exclusive lock on it. Readers don't lock the
+//! database, and instead register themselves to get a consistent view of it
+//! while the writer makes changes (see the `mvcc` module).
 //!
 //! If the create flag is set, then the open function will also create
 //! the database directory structure and lock file.
 //!
-//! The open function may also perform sanity checks on the database file system
-//! to make sure structure is valid and fail early if it's not.
+//! The open function checks the format version in the manifest and runs any
+//! pending format migrations (see the `manifest` module). With the verify
+//! flag, it also performs sanity checks on the database file system to make
+//! sure the structure is valid and fail early if it's not (see the `verify`
+//! module).
 //!
 //! Additionally, when opening the database in writing mode, the open function
-//! will also flush the transaction log to commit any pending transactions.
+//! will also roll back any transaction that was interrupted while being
+//! committed (see the `transaction` module).
 
 use std::fs;
+use std::io;
 use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
 
-const DB_LOCK_FILENAME: &'static str = "db.lock";
+pub(crate) const DB_LOCK_FILENAME: &str = "db.lock";
 
+use crate::crypto;
 use crate::database::{Database, InitConfig};
 use crate::error::{Error, IOError};
+use crate::journal;
+use crate::lock;
+use crate::manifest;
+use crate::mvcc::{self, Registration};
+use crate::transaction;
+use crate::verify;
 use crate::Result;
 
 /// Opens a database, optionally creating it if it does not exist.
@@ -74,19 +89,10 @@ pub fn open<P: Into<PathBuf>>(path: P, flags: OpenFlags) -> Result<Database> {
 			))
 		})?;
 
-	// Acquire a lock on the database lock file. If the database is being
-	// opened for writing, we acquire an exclusive lock, otherwise we acquire
-	// a shared lock.
-	use fs2::FileExt;
-	if flags.read_only {
-		lock_file.try_lock_shared().map_err(|err| {
-			Error::ReadLock(IOError::new(
-				err,
-				format!("acquiring shared lock on `{}`", lock_path.to_string_lossy()),
-			))
-		})?;
-	} else {
-		lock_file.try_lock_exclusive().map_err(|err| {
+	// Acquire an exclusive lock on the database lock file if the database is
+	// being opened for writing.
+	if !flags.read_only {
+		acquire_lock(&lock_file, true, flags.lock_timeout).map_err(|err| {
 			Error::WriteLock(IOError::new(
 				err,
 				format!(
@@ -97,19 +103,100 @@ pub fn open<P: Into<PathBuf>>(path: P, flags: OpenFlags) -> Result<Database> {
 		})?;
 	}
 
+	let commit_lock = if flags.read_only {
+		None
+	} else {
+		lock::write_lock_info(&main_path)?;
+		mvcc::open_commit_lock(&main_path, true)?
+	};
+
+	if flags.verify {
+		verify::verify(&main_path)?;
+	}
+
+	// Check the on-disk format. Migrations require the exclusive lock.
+	manifest::prepare(&main_path, !flags.read_only, manifest::MIGRATIONS)?;
+
+	// Load the encryption key, if the database is encrypted. This must be
+	// done after acquiring the lock, since it may set up encryption.
+	let cipher = crypto::load(&main_path, flags.passphrase.as_deref(), !flags.read_only)?;
+
+	if !flags.read_only {
+		transaction::recover(&main_path)?;
+	}
+
+	// Find the last sequence number from the journal. Readers register at
+	// that sequence number, while no change is in progress. Registering is
+	// not possible on a read-only file system, in which case there can be no
+	// writer either.
+	let (last_seq, journal_offset, registration) = mvcc::with_commit_lock(&main_path, || {
+		let (entries, journal_offset) = journal::read_journal_from(&main_path, 0)?;
+		let last_seq = entries.last().map(|entry| entry.seq).unwrap_or(0);
+		let registration = if flags.read_only {
+			Registration::new(&main_path, last_seq).ok()
+		} else {
+			None
+		};
+		Ok((last_seq, journal_offset, registration))
+	})?;
+
 	let db = Database::new(InitConfig {
 		path: main_path,
 		read_only: flags.read_only,
+		compress: flags.compress,
+		mmap: flags.mmap && flags.read_only,
+		soft_delete: flags.soft_delete,
+		max_revisions: flags.max_revisions,
+		snapshots: flags.snapshots,
+		cache_size: flags.cache_size,
+		max_size: flags.max_size,
+		max_records: flags.max_records,
+		cipher,
 		lock_file,
+		commit_lock,
+		registration,
+		last_seq,
+		journal_offset,
 	});
 
 	Result::Ok(db)
 }
 
+/// Interval between attempts to acquire the lock when waiting for it.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Acquires a lock on the lock file, waiting up to `timeout` for it to be
+/// available.
+pub(crate) fn acquire_lock(
+	lock_file: &fs::File,
+	exclusive: bool,
+	timeout: Option<Duration>,
+) -> io::Result<()> {
+	use fs2::FileExt;
+	let deadline = timeout.map(|timeout| Instant::now() + timeout);
+	loop {
+		let result = if exclusive {
+			FileExt::try_lock_exclusive(lock_file)
+		} else {
+			FileExt::try_lock_shared(lock_file)
+		};
+		match (result, deadline) {
+			(Err(err), Some(deadline))
+				if err.kind() == fs2::lock_contended_error().kind()
+					&& Instant::now() < deadline =>
+			{
+				thread::sleep(LOCK_POLL_INTERVAL);
+			}
+			(result, _) => return result,
+		}
+	}
+}
+
 /// Opening flags for a Database.
 ///
 /// The default for this is to create the database if it does not exist and
 /// to open in write mode.
+#[derive(Clone)]
 pub struct OpenFlags {
 	/// If true, will attempt to create the database if it does not exist.
 	///
@@ -117,10 +204,93 @@ pub struct OpenFlags {
 	pub create: bool,
 
 	/// Opens the database in read-only mode. This allows multiple consumers
-	/// for the database, as long as there in no writer.
+	/// for the database, alongside a writer. Readers see the database as it
+	/// was when opened, until calling `Database::poll_changes`.
 	///
 	/// Default: false
 	pub read_only: bool,
+
+	/// Compresses record payloads with zstd when writing. Records are always
+	/// readable regardless of this flag, so it can be changed freely between
+	/// openings.
+	///
+	/// Default: false
+	pub compress: bool,
+
+	/// Passphrase used to encrypt the database records and blobs.
+	///
+	/// If the database is encrypted, opening it with a missing or wrong
+	/// passphrase fails with `Error::WrongPassphrase`. Providing a passphrase
+	/// to an unencrypted database opened for writing enables encryption for
+	/// any data written from then on.
+	///
+	/// Default: None
+	pub passphrase: Option<String>,
+
+	/// Memory-maps record and blob files when reading, instead of copying
+	/// them into memory. This avoids a copy and reduces system calls for
+	/// large read-mostly databases.
+	///
+	/// Only used in read-only mode, since the files may otherwise be
+	/// replaced while mapped.
+	///
+	/// Default: false
+	pub mmap: bool,
+
+	/// How long to wait for the database write lock if it is held by
+	/// another process. If `None`, opening for writing fails immediately when
+	/// the database is locked. Readers never wait.
+	///
+	/// Default: None
+	pub lock_timeout: Option<Duration>,
+
+	/// Moves deleted records to the trash instead of removing them, so that
+	/// they can be restored with `Collection::restore`.
+	///
+	/// Default: false
+	pub soft_delete: bool,
+
+	/// Number of prior versions to keep for each record when it is
+	/// overwritten, which can be read with `Collection::revisions`. Older
+	/// versions beyond this limit are removed. Zero disables the history.
+	///
+	/// Default: 0
+	pub max_revisions: usize,
+
+	/// Number of past changes for which the database can be read back with
+	/// `Database::read_at`. Keeping snapshots requires saving a copy of the
+	/// data affected by each change, which is removed by `Database::compact`
+	/// once it is older than this. Zero disables snapshots.
+	///
+	/// Default: 0
+	pub snapshots: u64,
+
+	/// Maximum size in bytes of the in-memory cache of records read with
+	/// `Collection::get`, so that hot records don't need to be read and
+	/// decoded again. Zero disables the cache.
+	///
+	/// Default: 0
+	pub cache_size: usize,
+
+	/// Maximum size in bytes for the records and blobs in the database.
+	/// Writes that would grow the database past this fail with
+	/// `Error::QuotaExceeded`.
+	///
+	/// Default: None
+	pub max_size: Option<u64>,
+
+	/// Maximum number of records in the database. Writes that would add a
+	/// record past this fail with `Error::QuotaExceeded`.
+	///
+	/// Default: None
+	pub max_records: Option<u64>,
+
+	/// Checks the directory layout, manifest and lock file when opening,
+	/// failing with `Error::Verify` if the database looks damaged. See
+	/// `VerifyError` for the problems detected.
+	///
+	/// Default: false
+	pub verify: bool,
 }
 
 impl OpenFlags {
@@ -134,6 +304,7 @@ impl OpenFlags {
 	/// assert!(cfg.create);
 	/// assert!(!cfg.read_only);
 	/// ```
+	#[allow(clippy::should_implement_trait)]
 	pub fn default() -> Self {
 		Default::default()
 	}
@@ -181,6 +352,17 @@ impl Default for OpenFlags {
 		OpenFlags {
 			create: true,
 			read_only: false,
+			compress: false,
+			passphrase: None,
+			mmap: false,
+			lock_timeout: None,
+			soft_delete: false,
+			max_revisions: 0,
+			snapshots: 0,
+			cache_size: 0,
+			max_size: None,
+			max_records: None,
+			verify: false,
 		}
 	}
 }
@@ -189,8 +371,8 @@ impl Default for OpenFlags {
 mod test {
 	use super::*;
 
+	use crate::testing::create_db;
 	use std::fs;
-	use tempdir::TempDir;
 
 	#[test]
 	fn should_open_new_database() {
@@ -239,11 +421,9 @@ mod test {
 		let (db, temp) = create_db(OpenFlags::default());
 		let path = db.path.clone();
 
-		let err = open(&path, OpenFlags::read_only()).unwrap_err();
-		match err {
-			Error::ReadLock(_) => (),
-			_ => panic!("open error should be Error::ReadLock, but it was {}", err),
-		}
+		// Readers are allowed alongside the writer.
+		let reader = open(&path, OpenFlags::read_only()).unwrap();
+		drop(reader);
 
 		let err = open(&path, OpenFlags::default()).unwrap_err();
 		match err {
@@ -257,10 +437,30 @@ mod test {
 		temp.close().unwrap();
 	}
 
-	fn create_db(flags: OpenFlags) -> (Database, TempDir) {
-		let temp = tempdir::TempDir::new("kamipad-data").unwrap();
-		let path = temp.path().join("db");
-		let db = open(&path, flags).unwrap();
-		(db, temp)
+	#[test]
+	fn should_wait_for_lock() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let path = db.path.clone();
+		let timeout = |ms| OpenFlags::config(|f| f.lock_timeout = Some(Duration::from_millis(ms)));
+
+		// Times out while the lock is held.
+		let start = Instant::now();
+		let err = open(&path, timeout(50)).unwrap_err();
+		assert!(start.elapsed() >= Duration::from_millis(50));
+		match err {
+			Error::WriteLock(_) => (),
+			_ => panic!("open error should be Error::WriteLock, but it was {}", err),
+		}
+
+		// Succeeds once the lock is released.
+		let releaser = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			drop(db);
+		});
+		let db = open(&path, timeout(5000)).unwrap();
+		releaser.join().unwrap();
+		drop(db);
+
+		temp.close().unwrap();
 	}
 }
@@ -0,0 +1,487 @@
+//! Queries over JSON records.
+//!
+//! A `Query` selects the records in a collection matching a filter, built
+//! from the functions in this module:
+//!
+//! ```
+//! use kamipad_data::{field_eq, has_tag, Query};
+//! let query = Query::new()
+//!     .filter(field_eq("status", "open"))
+//!     .and(has_tag("work"));
+//! ```
+//!
+//! Records are parsed as JSON documents, and fields are referenced by name,
+//! with dots for nested fields (e.g. `author.name`). Records that are not
+//! valid JSON never match.
+//!
+//! Queries filtering on a tag use the tag index to find candidate records.
+//! Otherwise, all the records in the collection are scanned.
+//!
+//! Results are sorted by key, or by a field with `Query::order_by`, and can
+//! be paged with `limit` and either `offset` or a cursor. The cursor for a
+//! result is returned by `Query::cursor` and resumes the query right after
+//! that result, even if records were added or removed since:
+//!
+//! ```text
+//! let query = Query::new().order_by("created", true).limit(20);
+//! let page = notes.query(&query)?;
+//! let next = query.after(&query.cursor(page.last().unwrap()));
+//! ```
+//!
+//! Only the records up to `offset + limit` are kept in memory while the
+//! query runs. Without `order_by`, the scan also stops as soon as the page
+//! is complete.
+
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+use crate::collection::Collection;
+use crate::{Error, Result};
+
+/// A condition on a record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+	/// The field is equal to the value.
+	Eq(String, Value),
+	/// The field is missing or not equal to the value.
+	Ne(String, Value),
+	/// The field is less than the value. Only numbers and strings compare.
+	Lt(String, Value),
+	/// The field is greater than the value. Only numbers and strings compare.
+	Gt(String, Value),
+	/// The field exists.
+	Exists(String),
+	/// The record has the tag.
+	Tag(String),
+	And(Vec<Filter>),
+	Or(Vec<Filter>),
+	Not(Box<Filter>),
+}
+
+pub fn field_eq<V: Into<Value>>(field: &str, value: V) -> Filter {
+	Filter::Eq(field.to_string(), value.into())
+}
+
+pub fn field_ne<V: Into<Value>>(field: &str, value: V) -> Filter {
+	Filter::Ne(field.to_string(), value.into())
+}
+
+pub fn field_lt<V: Into<Value>>(field: &str, value: V) -> Filter {
+	Filter::Lt(field.to_string(), value.into())
+}
+
+pub fn field_gt<V: Into<Value>>(field: &str, value: V) -> Filter {
+	Filter::Gt(field.to_string(), value.into())
+}
+
+pub fn field_exists(field: &str) -> Filter {
+	Filter::Exists(field.to_string())
+}
+
+pub fn has_tag(tag: &str) -> Filter {
+	Filter::Tag(tag.to_string())
+}
+
+pub fn not(filter: Filter) -> Filter {
+	Filter::Not(Box::new(filter))
+}
+
+impl Filter {
+	/// Returns true if the document matches the filter. Tags are checked
+	/// against the given list.
+	fn matches(&self, doc: &Value, tags: &[String]) -> bool {
+		match self {
+			Filter::Eq(field, value) => get_field(doc, field) == Some(value),
+			Filter::Ne(field, value) => get_field(doc, field) != Some(value),
+			Filter::Lt(field, value) => compare(get_field(doc, field), value)
+				.map(|ord| ord == Ordering::Less)
+				.unwrap_or(false),
+			Filter::Gt(field, value) => compare(get_field(doc, field), value)
+				.map(|ord| ord == Ordering::Greater)
+				.unwrap_or(false),
+			Filter::Exists(field) => get_field(doc, field).is_some(),
+			Filter::Tag(tag) => tags.contains(tag),
+			Filter::And(filters) => filters.iter().all(|f| f.matches(doc, tags)),
+			Filter::Or(filters) => filters.iter().any(|f| f.matches(doc, tags)),
+			Filter::Not(filter) => !filter.matches(doc, tags),
+		}
+	}
+
+	/// Returns a tag that every matching record must have, if any, which
+	/// allows using the tag index.
+	fn required_tag(&self) -> Option<&str> {
+		match self {
+			Filter::Tag(tag) => Some(tag),
+			Filter::And(filters) => filters.iter().find_map(|f| f.required_tag()),
+			_ => None,
+		}
+	}
+
+	fn uses_tags(&self) -> bool {
+		match self {
+			Filter::Tag(_) => true,
+			Filter::And(filters) | Filter::Or(filters) => filters.iter().any(|f| f.uses_tags()),
+			Filter::Not(filter) => filter.uses_tags(),
+			_ => false,
+		}
+	}
+}
+
+/// A record returned by a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+	pub key: String,
+	pub value: Value,
+}
+
+/// A query for records in a collection, run with `Collection::query`.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+	filter: Option<Filter>,
+	order: Option<(String, bool)>,
+	limit: Option<usize>,
+	offset: usize,
+	after: Option<String>,
+}
+
+impl Query {
+	/// Returns a query matching all records.
+	pub fn new() -> Query {
+		Query::default()
+	}
+
+	/// Adds a filter that records must match.
+	pub fn filter(self, filter: Filter) -> Query {
+		self.and(filter)
+	}
+
+	/// Adds a filter that records must match, in addition to the existing
+	/// ones.
+	pub fn and(self, filter: Filter) -> Query {
+		let filter = match self.filter {
+			Some(Filter::And(mut filters)) => {
+				filters.push(filter);
+				Filter::And(filters)
+			}
+			Some(existing) => Filter::And(vec![existing, filter]),
+			None => filter,
+		};
+		Query {
+			filter: Some(filter),
+			..self
+		}
+	}
+
+	/// Adds a filter that records may match instead of the existing ones.
+	pub fn or(self, filter: Filter) -> Query {
+		let filter = match self.filter {
+			Some(existing) => Filter::Or(vec![existing, filter]),
+			None => filter,
+		};
+		Query {
+			filter: Some(filter),
+			..self
+		}
+	}
+
+	/// Sorts the results by a field, in descending order if `desc` is true.
+	/// Records missing the field come first, and records with the same value
+	/// are sorted by key.
+	pub fn order_by(self, field: &str, desc: bool) -> Query {
+		Query {
+			order: Some((field.to_string(), desc)),
+			..self
+		}
+	}
+
+	/// Returns at most `limit` results.
+	pub fn limit(self, limit: usize) -> Query {
+		Query {
+			limit: Some(limit),
+			..self
+		}
+	}
+
+	/// Skips the first `offset` results.
+	pub fn offset(self, offset: usize) -> Query {
+		Query { offset, ..self }
+	}
+
+	/// Returns only the results after the one for the cursor, which must
+	/// come from `Query::cursor` for a query with the same order.
+	pub fn after(self, cursor: &str) -> Query {
+		Query {
+			after: Some(cursor.to_string()),
+			..self
+		}
+	}
+
+	/// Returns the cursor for a result of the query, used with `after` to
+	/// get the following results.
+	pub fn cursor(&self, doc: &Document) -> String {
+		let value = self.sort_value(&doc.value);
+		let cursor = Value::Array(vec![value, Value::String(doc.key.clone())]);
+		base64::encode_config(cursor.to_string(), base64::URL_SAFE_NO_PAD)
+	}
+
+	fn parse_cursor(&self) -> Result<Option<(Value, String)>> {
+		let cursor = match &self.after {
+			Some(cursor) => cursor,
+			None => return Ok(None),
+		};
+		let invalid = || Error::InvalidCursor(cursor.clone());
+		let data = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+		match serde_json::from_slice(&data).map_err(|_| invalid())? {
+			Value::Array(mut items) if items.len() == 2 => match items.pop() {
+				Some(Value::String(key)) => Ok(Some((items.pop().unwrap(), key))),
+				_ => Err(invalid()),
+			},
+			_ => Err(invalid()),
+		}
+	}
+
+	fn sort_value(&self, doc: &Value) -> Value {
+		match &self.order {
+			Some((field, _)) => get_field(doc, field).cloned().unwrap_or(Value::Null),
+			None => Value::Null,
+		}
+	}
+
+	/// Compares two results by their sort value and key, in the query order.
+	fn compare(&self, a: (&Value, &str), b: (&Value, &str)) -> Ordering {
+		let ord = sort_order(a.0, b.0).then_with(|| a.1.cmp(b.1));
+		match self.order {
+			Some((_, true)) => ord.reverse(),
+			_ => ord,
+		}
+	}
+}
+
+impl<'a> Collection<'a> {
+	/// Returns the records matching a query, in the query order.
+	pub fn query(&self, query: &Query) -> Result<Vec<Document>> {
+		let filter = query.filter.as_ref();
+		let mut keys = match filter.and_then(|f| f.required_tag()) {
+			Some(tag) => self.find_by_tag(tag)?,
+			None => self.keys()?,
+		};
+		keys.sort();
+		let after = query.parse_cursor()?;
+
+		// Results past this are never returned, so they are dropped as the
+		// query runs. Without `order_by`, keys are already in order and the
+		// scan can stop there.
+		let bound = query.limit.map(|limit| query.offset + limit);
+		let mut results: Vec<(Value, Document)> = Vec::new();
+		for key in keys {
+			if query.order.is_none() && bound.map(|n| results.len() >= n).unwrap_or(false) {
+				break;
+			}
+			if query.order.is_none() {
+				if let Some((_, after_key)) = &after {
+					if key <= *after_key {
+						continue;
+					}
+				}
+			}
+			let value = match self.get(&key)? {
+				Some(data) => data,
+				None => continue,
+			};
+			let value = match serde_json::from_slice::<Value>(&value) {
+				Ok(value) => value,
+				Err(_) => continue,
+			};
+			if let Some(filter) = filter {
+				let tags = if filter.uses_tags() {
+					self.tags(&key)?
+				} else {
+					Vec::new()
+				};
+				if !filter.matches(&value, &tags) {
+					continue;
+				}
+			}
+			let sort_value = query.sort_value(&value);
+			if let Some((after_value, after_key)) = &after {
+				let ord = query.compare((&sort_value, &key), (after_value, after_key));
+				if ord != Ordering::Greater {
+					continue;
+				}
+			}
+			results.push((sort_value, Document { key, value }));
+
+			if let Some(bound) = bound {
+				if query.order.is_some() && results.len() >= 2 * bound.max(1) {
+					sort_results(query, &mut results);
+					results.truncate(bound);
+				}
+			}
+		}
+
+		sort_results(query, &mut results);
+		let results = results.into_iter().map(|(_, doc)| doc).skip(query.offset);
+		Ok(match query.limit {
+			Some(limit) => results.take(limit).collect(),
+			None => results.collect(),
+		})
+	}
+}
+
+/// Returns a field from a document, by its dotted path.
+pub(crate) fn get_field<'v>(doc: &'v Value, field: &str) -> Option<&'v Value> {
+	field
+		.split('.')
+		.try_fold(doc, |value, name| value.as_object()?.get(name))
+}
+
+fn sort_results(query: &Query, results: &mut [(Value, Document)]) {
+	if query.order.is_some() {
+		results.sort_by(|a, b| query.compare((&a.0, &a.1.key), (&b.0, &b.1.key)));
+	}
+}
+
+/// Total order for sort values: null, booleans, numbers, strings, then
+/// arrays and objects by their JSON text.
+fn sort_order(a: &Value, b: &Value) -> Ordering {
+	fn rank(value: &Value) -> u8 {
+		match value {
+			Value::Null => 0,
+			Value::Bool(_) => 1,
+			Value::Number(_) => 2,
+			Value::String(_) => 3,
+			Value::Array(_) => 4,
+			Value::Object(_) => 5,
+		}
+	}
+	match (a, b) {
+		(Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+		(Value::Number(_), Value::Number(_)) | (Value::String(_), Value::String(_)) => {
+			compare(Some(a), b).unwrap_or(Ordering::Equal)
+		}
+		(Value::Array(_), Value::Array(_)) | (Value::Object(_), Value::Object(_)) => {
+			a.to_string().cmp(&b.to_string())
+		}
+		_ => rank(a).cmp(&rank(b)),
+	}
+}
+
+fn compare(a: Option<&Value>, b: &Value) -> Option<Ordering> {
+	match (a?, b) {
+		(Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+		(Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_query_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes
+			.put("a", br#"{"status":"open","size":3,"author":{"name":"x"}}"#)
+			.unwrap();
+		notes.put("b", br#"{"status":"closed","size":10}"#).unwrap();
+		notes.put("c", br#"{"status":"open","size":7}"#).unwrap();
+		notes.put("d", b"not json").unwrap();
+		notes.tag("c", "work").unwrap();
+
+		let keys = |query: Query| {
+			let docs = notes.query(&query).unwrap();
+			docs.into_iter().map(|doc| doc.key).collect::<Vec<_>>()
+		};
+		assert_eq!(keys(Query::new()), vec!["a", "b", "c"]);
+		assert_eq!(
+			keys(Query::new().filter(field_eq("status", "open"))),
+			vec!["a", "c"]
+		);
+		assert_eq!(
+			keys(
+				Query::new()
+					.filter(field_eq("status", "open"))
+					.and(field_gt("size", 5))
+			),
+			vec!["c"]
+		);
+		assert_eq!(
+			keys(
+				Query::new()
+					.filter(field_lt("size", 5))
+					.or(field_eq("status", "closed"))
+			),
+			vec!["a", "b"]
+		);
+		assert_eq!(
+			keys(Query::new().filter(field_eq("author.name", "x"))),
+			vec!["a"]
+		);
+		assert_eq!(keys(Query::new().filter(has_tag("work"))), vec!["c"]);
+		assert_eq!(
+			keys(Query::new().filter(not(has_tag("work")))),
+			vec!["a", "b"]
+		);
+		assert_eq!(
+			keys(Query::new().filter(not(field_exists("author")))),
+			vec!["b", "c"]
+		);
+
+		let docs = notes.query(&Query::new().filter(has_tag("work"))).unwrap();
+		assert_eq!(docs[0].value["size"], 7);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_sort_and_page_results() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		for (key, size) in &[("a", 5), ("b", 1), ("c", 9), ("d", 5), ("e", 3)] {
+			let value = format!(r#"{{"size":{}}}"#, size);
+			notes.put(key, value.as_bytes()).unwrap();
+		}
+		notes.put("f", b"{}").unwrap();
+
+		let keys = |query: &Query| {
+			let docs = notes.query(query).unwrap();
+			docs.into_iter().map(|doc| doc.key).collect::<Vec<_>>()
+		};
+		let query = Query::new().order_by("size", false);
+		assert_eq!(keys(&query), vec!["f", "b", "e", "a", "d", "c"]);
+		let query = Query::new().order_by("size", true);
+		assert_eq!(keys(&query), vec!["c", "d", "a", "e", "b", "f"]);
+		assert_eq!(keys(&query.clone().offset(1).limit(2)), vec!["d", "a"]);
+		assert_eq!(keys(&Query::new().offset(4)), vec!["e", "f"]);
+		assert_eq!(keys(&Query::new().limit(2)), vec!["a", "b"]);
+
+		// Cursors resume after the last result, even after changes.
+		let query = Query::new().order_by("size", true).limit(2);
+		let page = notes.query(&query).unwrap();
+		let cursor = query.cursor(&page[1]);
+		notes.delete("d").unwrap();
+		notes.put("g", br#"{"size":6}"#).unwrap();
+		assert_eq!(keys(&query.clone().after(&cursor)), vec!["a", "e"]);
+
+		let query = Query::new().filter(field_gt("size", 1)).limit(2);
+		let page = notes.query(&query).unwrap();
+		assert_eq!(
+			keys(&query.clone().after(&query.cursor(&page[1]))),
+			vec!["e", "g"]
+		);
+
+		match notes.query(&Query::new().after("bad")) {
+			Err(Error::InvalidCursor(_)) => {}
+			other => panic!("unexpected result: {:?}", other),
+		}
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,215 @@
+//! Limits on the size of the database.
+//!
+//! With `OpenFlags::max_size` or `OpenFlags::max_records`, writes that would
+//! grow the database past a limit fail with `Error::QuotaExceeded`. Writes
+//! that don't grow the database are always allowed, so that a database over
+//! its quota can still be cleaned up.
+//!
+//! The size only counts the record and blob files. The journal and the data
+//! kept for the history, snapshots and the trash are bounded by their own
+//! settings. Expired records count until removed by `Database::compact`.
+//!
+//! The usage is measured from the files the first time it is needed, and
+//! then updated by each write. Writes are checked against the size of the
+//! payload being written, before encoding, and the actual size of the files
+//! is accounted for once written. Restoring incremental backups and
+//! repairing the database are not limited, and just measure the usage again
+//! when next needed.
+
+use std::fs;
+use std::path::Path;
+
+use crate::blob::{BlobId, BLOBS_DIR};
+use crate::collection::{list_names, Collection, DATA_DIR};
+use crate::database::Database;
+use crate::error::Error;
+use crate::util::read_error;
+use crate::Result;
+
+/// Space used by the database, as returned by `Database::usage`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Usage {
+	/// Total size in bytes of the record and blob files.
+	pub bytes: u64,
+	/// Number of records, including expired records not yet compacted.
+	pub records: u64,
+}
+
+impl Usage {
+	fn add(&mut self, bytes: i64, records: i64) {
+		self.bytes = (self.bytes as i64 + bytes).max(0) as u64;
+		self.records = (self.records as i64 + records).max(0) as u64;
+	}
+}
+
+impl Database {
+	/// Returns the space used by the records and blobs in the database.
+	pub fn usage(&self) -> Result<Usage> {
+		// Readers don't see the changes as they are made, so they always
+		// measure the usage.
+		if self.is_read_only() {
+			return measure_usage(&self.path);
+		}
+		let mut cached = self.cached_usage();
+		if let Some(usage) = *cached {
+			return Ok(usage);
+		}
+		let usage = measure_usage(&self.path)?;
+		*cached = Some(usage);
+		Ok(usage)
+	}
+
+	/// Fails with `Error::QuotaExceeded` if adding the given number of bytes
+	/// and records would exceed the quota. Must be called with the write lock
+	/// held.
+	pub(crate) fn check_quota(&self, bytes: i64, records: i64) -> Result<()> {
+		let (max_size, max_records) = self.quota();
+		if (max_size.is_none() && max_records.is_none()) || (bytes <= 0 && records <= 0) {
+			return Ok(());
+		}
+
+		let usage = self.usage()?;
+		if let Some(max) = max_size {
+			if bytes > 0 && usage.bytes + bytes as u64 > max {
+				return Err(Error::QuotaExceeded(format!(
+					"database size would exceed {} bytes",
+					max
+				)));
+			}
+		}
+		if let Some(max) = max_records {
+			if records > 0 && usage.records + records as u64 > max {
+				return Err(Error::QuotaExceeded(format!(
+					"database would exceed {} records",
+					max
+				)));
+			}
+		}
+		Ok(())
+	}
+
+	/// Accounts for a change in the files, if the usage was measured.
+	pub(crate) fn update_usage(&self, bytes: i64, records: i64) {
+		if let Some(usage) = self.cached_usage().as_mut() {
+			usage.add(bytes, records);
+		}
+	}
+
+	/// Discards the measured usage after changes that were not accounted
+	/// for.
+	pub(crate) fn reset_usage(&self) {
+		*self.cached_usage() = None;
+	}
+}
+
+impl<'a> Collection<'a> {
+	/// Returns the change in usage from writing a record with a payload of
+	/// the given size.
+	pub(crate) fn put_usage(&self, key: &str, len: usize) -> Result<(i64, i64)> {
+		let (size, records) = self.file_usage(key)?;
+		Ok((len as i64 - size, 1 - records))
+	}
+
+	/// Returns the change in usage from deleting a record.
+	pub(crate) fn delete_usage(&self, key: &str) -> Result<(i64, i64)> {
+		let (size, records) = self.file_usage(key)?;
+		Ok((-size, -records))
+	}
+
+	/// Returns the size of a record file and one, or zeros if the record does
+	/// not exist.
+	pub(crate) fn file_usage(&self, key: &str) -> Result<(i64, i64)> {
+		let path = self.record_path(key)?;
+		Ok(match fs::metadata(&path) {
+			Ok(metadata) if metadata.is_file() => (metadata.len() as i64, 1),
+			_ => (0, 0),
+		})
+	}
+}
+
+fn measure_usage(root: &Path) -> Result<Usage> {
+	let mut usage = Usage::default();
+	let file_size = |path: &Path| {
+		fs::metadata(path)
+			.map(|metadata| metadata.len())
+			.map_err(|err| read_error(err, path))
+	};
+
+	let data_dir = root.join(DATA_DIR);
+	for collection in list_names(&data_dir, true)? {
+		let collection_dir = data_dir.join(&collection);
+		for key in list_names(&collection_dir, false)? {
+			usage.bytes += file_size(&collection_dir.join(&key))?;
+			usage.records += 1;
+		}
+	}
+
+	let blobs_dir = root.join(BLOBS_DIR);
+	for name in list_names(&blobs_dir, false)? {
+		if BlobId::parse(&name).is_some() {
+			usage.bytes += file_size(&blobs_dir.join(&name))?;
+		}
+	}
+	Ok(usage)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_enforce_record_quota() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.max_records = Some(2)));
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+		match notes.put("c", b"C") {
+			Err(Error::QuotaExceeded(_)) => {}
+			other => panic!("unexpected result: {:?}", other),
+		}
+		assert!(notes.get("c").unwrap().is_none());
+
+		// Overwriting and deleting are still allowed.
+		notes.put("a", b"A2").unwrap();
+		notes.delete("b").unwrap();
+		notes.put("c", b"C").unwrap();
+		assert_eq!(db.usage().unwrap().records, 2);
+
+		let mut tx = db.transaction();
+		tx.put("notes", "d", b"D").unwrap();
+		assert!(tx.commit().is_err());
+		assert!(notes.get("d").unwrap().is_none());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_enforce_size_quota() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.max_size = Some(1000)));
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", &[1; 400]).unwrap();
+		let usage = db.usage().unwrap();
+		assert!(usage.bytes > 400 && usage.bytes < 1000);
+		assert_eq!(usage.records, 1);
+
+		assert!(notes.put("b", &[2; 600]).is_err());
+		assert!(db.put_blob(&[3; 600]).is_err());
+		let blob = db.put_blob(&[3; 100]).unwrap();
+		let with_blob = db.usage().unwrap();
+		assert!(with_blob.bytes >= usage.bytes + 100);
+
+		// Usage is kept in sync with the files.
+		notes.delete("a").unwrap();
+		db.release_blob(&blob).unwrap();
+		db.gc_blobs().unwrap();
+		assert_eq!(db.usage().unwrap(), Usage::default());
+		assert_eq!(measure_usage(&db.path).unwrap(), Usage::default());
+		notes.put("b", &[2; 600]).unwrap();
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,344 @@
+//! On-disk format for records.
+//!
+//! Each record file starts with a single header line, followed by the record
+//! body:
+//!
+//! ```text
+//! #record encoding=zstd cipher=chacha20poly1305 crc32=1a2b3c4d
+//! <body>
+//! ```
+//!
+//! The header is the `#record` marker followed by a list of space separated
+//! `name=value` attributes describing how the body is stored. Without the
+//! `encoding` and `cipher` attributes the payload is stored as is, which
+//! keeps plain records human-readable.
+//!
+//! When both compression and encryption are used, the payload is compressed
+//! before being encrypted.
+//!
+//! The `crc32` attribute is the checksum of the body as stored in the file,
+//! and is verified when reading to detect damaged files. Records without a
+//! checksum are accepted as is.
+//!
+//! Records written with an expiration have an `expires` attribute with the
+//! UNIX timestamp in milliseconds after which the record is considered
+//! deleted. Records in the trash have a `deleted` attribute with the UNIX
+//! timestamp in milliseconds of the deletion.
+//!
+//! The `version` attribute is incremented every time the record is written,
+//! starting from 1. Records without it are considered to be at version 1.
+//!
+//! Unknown attributes are ignored when reading.
+
+use std::io;
+
+use crate::crypto::{Cipher, CIPHER_NAME};
+use crate::util::invalid_data;
+
+const HEADER_MARKER: &str = "#record";
+
+const ENCODING_ZSTD: &str = "zstd";
+
+/// Compression level used for zstd.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Parsed record header.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub(crate) struct Header {
+	/// True if the body is compressed with zstd.
+	pub compressed: bool,
+	/// True if the body is encrypted.
+	pub encrypted: bool,
+	/// CRC-32 checksum of the stored body.
+	pub checksum: Option<u32>,
+	/// Expiration time as a UNIX timestamp in milliseconds.
+	pub expires: Option<u64>,
+	/// Deletion time for records in the trash, as a UNIX timestamp in
+	/// milliseconds.
+	pub deleted: Option<u64>,
+	/// Version of the record, incremented on every write.
+	pub version: Option<u64>,
+}
+
+impl Header {
+	fn to_line(&self) -> String {
+		let mut line = String::from(HEADER_MARKER);
+		if self.compressed {
+			line.push_str(" encoding=");
+			line.push_str(ENCODING_ZSTD);
+		}
+		if self.encrypted {
+			line.push_str(" cipher=");
+			line.push_str(CIPHER_NAME);
+		}
+		if let Some(expires) = self.expires {
+			line.push_str(&format!(" expires={}", expires));
+		}
+		if let Some(deleted) = self.deleted {
+			line.push_str(&format!(" deleted={}", deleted));
+		}
+		if let Some(version) = self.version {
+			line.push_str(&format!(" version={}", version));
+		}
+		if let Some(checksum) = self.checksum {
+			line.push_str(&format!(" crc32={:08x}", checksum));
+		}
+		line.push('\n');
+		line
+	}
+
+	fn parse(line: &str) -> io::Result<Header> {
+		let mut parts = line.split(' ').filter(|s| !s.is_empty());
+		if parts.next() != Some(HEADER_MARKER) {
+			return Err(invalid_data("missing record header"));
+		}
+
+		let mut header = Header::default();
+		for attr in parts {
+			let mut attr = attr.splitn(2, '=');
+			let (name, value) = (attr.next().unwrap(), attr.next().unwrap_or(""));
+			match name {
+				"encoding" if value == ENCODING_ZSTD => header.compressed = true,
+				"encoding" => {
+					return Err(invalid_data(format!("unknown record encoding `{}`", value)))
+				}
+				"cipher" if value == CIPHER_NAME => header.encrypted = true,
+				"cipher" => return Err(invalid_data(format!("unknown record cipher `{}`", value))),
+				"crc32" => match u32::from_str_radix(value, 16) {
+					Ok(checksum) => header.checksum = Some(checksum),
+					Err(_) => return Err(invalid_data(format!("invalid checksum `{}`", value))),
+				},
+				"expires" => match value.parse() {
+					Ok(expires) => header.expires = Some(expires),
+					Err(_) => return Err(invalid_data(format!("invalid expiration `{}`", value))),
+				},
+				"deleted" => match value.parse() {
+					Ok(deleted) => header.deleted = Some(deleted),
+					Err(_) => {
+						return Err(invalid_data(format!("invalid deletion time `{}`", value)))
+					}
+				},
+				"version" => match value.parse() {
+					Ok(version) => header.version = Some(version),
+					Err(_) => return Err(invalid_data(format!("invalid version `{}`", value))),
+				},
+				_ => {}
+			}
+		}
+		Ok(header)
+	}
+
+	/// Returns the version of the record.
+	pub fn version(&self) -> u64 {
+		self.version.unwrap_or(1)
+	}
+
+	/// Returns true if the record has expired at the given time.
+	pub fn is_expired(&self, now: u64) -> bool {
+		self.expires.map(|expires| expires <= now).unwrap_or(false)
+	}
+}
+
+/// Encodes and decodes records according to the database settings.
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Codec<'a> {
+	/// Compress payloads on write.
+	pub compress: bool,
+	/// Cipher used to encrypt payloads, if any.
+	pub cipher: Option<&'a Cipher>,
+}
+
+impl<'a> Codec<'a> {
+	/// Encodes a record payload into its on-disk representation.
+	pub fn encode(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+		self.encode_with(payload, Header::default())
+	}
+
+	/// Encodes a record payload with the given header attributes. The
+	/// encoding attributes of the header are set by the codec.
+	pub fn encode_with(&self, payload: &[u8], mut header: Header) -> io::Result<Vec<u8>> {
+		let mut body = None;
+		if self.compress {
+			// Only keep the compressed data if it actually saves space, which
+			// is not the case for very small records.
+			let data = zstd::encode_all(payload, ZSTD_LEVEL)?;
+			if data.len() < payload.len() {
+				header.compressed = true;
+				body = Some(data);
+			}
+		}
+
+		if let Some(cipher) = self.cipher {
+			header.encrypted = true;
+			body = Some(cipher.encrypt(body.as_deref().unwrap_or(payload))?);
+		}
+
+		let body = body.as_deref().unwrap_or(payload);
+		header.checksum = Some(crc32fast::hash(body));
+
+		let line = header.to_line();
+		let mut out = Vec::with_capacity(line.len() + body.len());
+		out.extend_from_slice(line.as_bytes());
+		out.extend_from_slice(body);
+		Ok(out)
+	}
+
+	/// Decodes a record from its on-disk representation, returning the
+	/// payload.
+	pub fn decode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+		self.decode_with_header(data).map(|(_, payload)| payload)
+	}
+
+	/// Decodes a record from its on-disk representation, returning the
+	/// header and the payload.
+	pub fn decode_with_header(&self, data: &[u8]) -> io::Result<(Header, Vec<u8>)> {
+		let (header, body) = split(data)?;
+		let decrypted;
+		let body = if header.encrypted {
+			let cipher = self
+				.cipher
+				.ok_or_else(|| invalid_data("record is encrypted"))?;
+			decrypted = cipher.decrypt(body)?;
+			&decrypted[..]
+		} else {
+			body
+		};
+
+		let payload = if header.compressed {
+			zstd::decode_all(body)?
+		} else {
+			body.to_vec()
+		};
+		Ok((header, payload))
+	}
+}
+
+/// Parses only the header of a record, without verifying the body.
+pub(crate) fn read_header(data: &[u8]) -> io::Result<Header> {
+	let eol = data
+		.iter()
+		.position(|&c| c == b'\n')
+		.ok_or_else(|| invalid_data("missing record header"))?;
+	let line =
+		std::str::from_utf8(&data[..eol]).map_err(|_| invalid_data("invalid record header"))?;
+	Header::parse(line.trim_end_matches('\r'))
+}
+
+/// Rewrites the header of an encoded record, keeping the body as is.
+///
+/// Since the checksum only covers the body, this is safe to use for any
+/// attribute that does not change how the body is encoded.
+pub(crate) fn rewrite_header<F: FnOnce(&mut Header)>(
+	data: &[u8],
+	update: F,
+) -> io::Result<Vec<u8>> {
+	let (mut header, body) = split(data)?;
+	update(&mut header);
+	let line = header.to_line();
+	let mut out = Vec::with_capacity(line.len() + body.len());
+	out.extend_from_slice(line.as_bytes());
+	out.extend_from_slice(body);
+	Ok(out)
+}
+
+/// Splits a record into its parsed header and raw body.
+pub(crate) fn split(data: &[u8]) -> io::Result<(Header, &[u8])> {
+	let header = read_header(data)?;
+	let eol = data.iter().position(|&c| c == b'\n').unwrap();
+	let body = &data[eol + 1..];
+	if let Some(checksum) = header.checksum {
+		if crc32fast::hash(body) != checksum {
+			return Err(invalid_data("checksum mismatch"));
+		}
+	}
+	Ok((header, body))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn should_encode_plain_records() {
+		let codec = Codec::default();
+		let data = codec.encode(b"hello\nworld").unwrap();
+		assert_eq!(data, b"#record crc32=ab7d37d5\nhello\nworld");
+		assert_eq!(codec.decode(&data).unwrap(), b"hello\nworld");
+	}
+
+	#[test]
+	fn should_compress_records() {
+		let payload = "compressible text ".repeat(100);
+		let codec = Codec {
+			compress: true,
+			cipher: None,
+		};
+		let data = codec.encode(payload.as_bytes()).unwrap();
+		assert!(data.starts_with(b"#record encoding=zstd crc32="));
+		assert!(data.len() < payload.len());
+		assert_eq!(codec.decode(&data).unwrap(), payload.as_bytes());
+
+		// Small records are not worth compressing.
+		let data = codec.encode(b"x").unwrap();
+		assert!(data.starts_with(b"#record crc32="));
+		assert!(data.ends_with(b"\nx"));
+	}
+
+	#[test]
+	fn should_encode_expiration() {
+		let codec = Codec::default();
+		let header = Header {
+			expires: Some(1000),
+			..Default::default()
+		};
+		let data = codec.encode_with(b"x", header).unwrap();
+		assert!(data.starts_with(b"#record expires=1000 crc32="));
+
+		let (header, payload) = codec.decode_with_header(&data).unwrap();
+		assert_eq!(payload, b"x");
+		assert!(!header.is_expired(999));
+		assert!(header.is_expired(1000));
+		assert_eq!(read_header(&data).unwrap(), header);
+		assert!(codec.decode(b"#record expires=x\nbody").is_err());
+	}
+
+	#[test]
+	fn should_encode_version() {
+		let codec = Codec::default();
+		let header = Header {
+			version: Some(3),
+			..Default::default()
+		};
+		let data = codec.encode_with(b"x", header).unwrap();
+		assert!(data.starts_with(b"#record version=3 crc32="));
+		assert_eq!(read_header(&data).unwrap().version(), 3);
+
+		let data = codec.encode(b"x").unwrap();
+		assert_eq!(read_header(&data).unwrap().version(), 1);
+		assert!(codec.decode(b"#record version=x\nbody").is_err());
+	}
+
+	#[test]
+	fn should_reject_invalid_headers() {
+		let codec = Codec::default();
+		assert!(codec.decode(b"no header").is_err());
+		assert!(codec.decode(b"#other\nbody").is_err());
+		assert!(codec.decode(b"#record encoding=lzma\nbody").is_err());
+		assert!(codec
+			.decode(b"#record cipher=chacha20poly1305\nbody")
+			.is_err());
+		assert_eq!(codec.decode(b"#record future=1\nbody").unwrap(), b"body");
+	}
+
+	#[test]
+	fn should_detect_damaged_records() {
+		let codec = Codec::default();
+		let mut data = codec.encode(b"some record").unwrap();
+		assert_eq!(codec.decode(&data).unwrap(), b"some record");
+
+		let last = data.len() - 1;
+		data[last] = b'X';
+		assert!(codec.decode(&data).is_err());
+		assert!(codec.decode(b"#record crc32=zz\nbody").is_err());
+	}
+}
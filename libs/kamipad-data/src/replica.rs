@@ -0,0 +1,303 @@
+//! Journal shipping to read replicas.
+//!
+//! A replica starts as a copy of the primary database, from `backup` or
+//! `clone_to`, and is kept up to date by applying the changes made on the
+//! primary since the replica's sequence number:
+//!
+//! ```text
+//! // on the primary
+//! let batch = primary.journal_since(replica_seq)?.to_json();
+//! // on the replica
+//! replica.apply_journal(&JournalBatch::from_json(&batch)?)?;
+//! ```
+//!
+//! A `JournalBatch` has the journal entries together with the current
+//! contents of the files they affected. Files are shipped as stored, so a
+//! replica of an encrypted database uses the same key, which is copied with
+//! the database. A batch is serialized as a JSON object, with the entries in
+//! the journal format and the files base64-encoded:
+//!
+//! ```text
+//! {"base":3,"entries":["4 1600000000000 put notes a"],"files":{"data/notes/a":"..."}}
+//! ```
+//!
+//! Files affected by the entries that are missing from the batch were
+//! deleted. The replica must be open for writing to apply a batch, but must
+//! not be changed otherwise, since its sequence numbers must follow the
+//! primary. Readers of the replica see the changes in a batch all at once.
+//!
+//! Applying a batch that was interrupted can be retried with the same batch.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backup::changed_paths;
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::{append_journal, JournalEntry};
+use crate::util::{self, read_error, write_error};
+use crate::Result;
+
+/// Changes shipped from a primary database to a replica, returned by
+/// `Database::journal_since`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct JournalBatch {
+	/// Sequence number the batch applies on top of.
+	pub base: u64,
+	/// Changes in the batch, in order.
+	pub entries: Vec<JournalEntry>,
+	// Contents of the files affected by the entries that still exist, by
+	// path relative to the database root.
+	files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+/// Serialized form of a `JournalBatch`.
+#[derive(Serialize, Deserialize)]
+struct BatchJson {
+	base: u64,
+	entries: Vec<String>,
+	files: BTreeMap<String, String>,
+}
+
+impl JournalBatch {
+	/// Returns the sequence number of the last change in the batch, which
+	/// the replica is at once the batch is applied.
+	pub fn seq(&self) -> u64 {
+		self.entries
+			.last()
+			.map(|entry| entry.seq)
+			.unwrap_or(self.base)
+	}
+
+	/// Serializes the batch as JSON.
+	pub fn to_json(&self) -> String {
+		let json = BatchJson {
+			base: self.base,
+			entries: self.entries.iter().map(|entry| entry.to_string()).collect(),
+			files: self
+				.files
+				.iter()
+				.map(|(path, data)| (path_to_string(path), base64::encode(data)))
+				.collect(),
+		};
+		serde_json::to_string(&json).unwrap()
+	}
+
+	/// Parses a batch serialized with `to_json`.
+	pub fn from_json(text: &str) -> Result<JournalBatch> {
+		let invalid = |reason: String| Error::InvalidJournal(reason);
+		let json: BatchJson = serde_json::from_str(text).map_err(|err| invalid(err.to_string()))?;
+		let entries = json
+			.entries
+			.iter()
+			.map(|line| {
+				JournalEntry::parse(line)
+					.ok_or_else(|| invalid(format!("invalid entry `{}`", line)))
+			})
+			.collect::<Result<Vec<_>>>()?;
+
+		for (i, entry) in entries.iter().enumerate() {
+			if entry.seq != json.base + 1 + i as u64 {
+				return Err(invalid(format!("unexpected sequence number {}", entry.seq)));
+			}
+		}
+
+		// Only files affected by the entries are accepted, so that a batch
+		// can never write anywhere else.
+		let paths = changed_paths(&entries);
+		let mut files = BTreeMap::new();
+		for (path, data) in json.files {
+			let path = path_from_string(&path);
+			if !paths.contains(&path) {
+				return Err(invalid(format!(
+					"unexpected file `{}`",
+					path.to_string_lossy()
+				)));
+			}
+			let data = base64::decode(&data).map_err(|err| invalid(err.to_string()))?;
+			files.insert(path, data);
+		}
+
+		Ok(JournalBatch {
+			base: json.base,
+			entries,
+			files,
+		})
+	}
+}
+
+impl Database {
+	/// Returns the changes made after the sequence number `since`, to be
+	/// applied to a replica at that sequence number with `apply_journal`.
+	///
+	/// Fails with `Error::JournalTruncated` if the changes were removed from
+	/// the journal, in which case the replica must be restored from a backup.
+	pub fn journal_since(&self, since: u64) -> Result<JournalBatch> {
+		// Holding the write lock keeps the files consistent with the entries.
+		let _guard = self.write_guard();
+		let entries = self.changes_since(since)?;
+		let last = entries.last().map(|entry| entry.seq).unwrap_or(since);
+		if since > last || since > self.sequence() {
+			return Err(Error::InvalidJournal(format!(
+				"sequence {} is past the last change ({})",
+				since,
+				self.sequence()
+			)));
+		}
+
+		let mut files = BTreeMap::new();
+		for relative in changed_paths(&entries) {
+			let path = self.path.join(&relative);
+			match fs::read(&path) {
+				Ok(data) => {
+					files.insert(relative, data);
+				}
+				Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+				Err(err) => return Err(read_error(err, &path)),
+			}
+		}
+		Ok(JournalBatch {
+			base: since,
+			entries,
+			files,
+		})
+	}
+
+	/// Applies changes shipped from a primary database with `journal_since`.
+	///
+	/// The database must be at the exact sequence number the batch is based
+	/// on, failing with `Error::InvalidJournal` otherwise.
+	pub fn apply_journal(&self, batch: &JournalBatch) -> Result<()> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+		if self.sequence() != batch.base {
+			return Err(Error::InvalidJournal(format!(
+				"batch is based on sequence {}, but the database is at {}",
+				batch.base,
+				self.sequence()
+			)));
+		}
+
+		// Readers must keep seeing the files from before the batch.
+		if self.keeps_undo()? {
+			for entry in batch.entries.iter() {
+				self.save_undo(entry.seq, &entry.change)?;
+			}
+		}
+
+		// Files are written before the journal, so that an interrupted batch
+		// can be applied again.
+		for relative in changed_paths(&batch.entries) {
+			let path = self.path.join(&relative);
+			match batch.files.get(&relative) {
+				Some(data) => fs::create_dir_all(path.parent().unwrap())
+					.and_then(|_| util::write_file(&path, data))
+					.map_err(|err| write_error(err, &path))?,
+				None => match fs::remove_file(&path) {
+					Ok(_) => {}
+					Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+					Err(err) => return Err(write_error(err, &path)),
+				},
+			}
+		}
+
+		append_journal(&self.path, &batch.entries)?;
+		self.reset_usage();
+		*self.last_seq() = batch.seq();
+		for entry in batch.entries.iter() {
+			self.queue_notification(entry.clone());
+		}
+		Ok(())
+	}
+}
+
+fn path_to_string(path: &Path) -> String {
+	let parts = path
+		.iter()
+		.map(|part| part.to_string_lossy())
+		.collect::<Vec<_>>();
+	parts.join("/")
+}
+
+fn path_from_string(path: &str) -> PathBuf {
+	path.split('/').collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_ship_journal_to_replica() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+
+		let replica_path = temp.path().join("replica");
+		db.clone_to(&replica_path).unwrap();
+		let replica = open(&replica_path, OpenFlags::default()).unwrap();
+		let reader = open(&replica_path, OpenFlags::read_only()).unwrap();
+
+		notes.put("a", b"A2").unwrap();
+		notes.delete("b").unwrap();
+		notes.tag("a", "pinned").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		let batch = db.journal_since(replica.sequence()).unwrap();
+		assert_eq!(batch.base, 2);
+		assert_eq!(batch.seq(), 6);
+		let batch = JournalBatch::from_json(&batch.to_json()).unwrap();
+		replica.apply_journal(&batch).unwrap();
+
+		let replica_notes = replica.collection("notes").unwrap();
+		assert_eq!(replica.sequence(), 6);
+		assert_eq!(replica_notes.keys().unwrap(), vec!["a"]);
+		assert_eq!(replica_notes.get("a").unwrap().unwrap(), b"A2");
+		assert_eq!(replica_notes.find_by_tag("pinned").unwrap(), vec!["a"]);
+		assert_eq!(replica.get_blob(&blob).unwrap().unwrap(), b"blob");
+		assert_eq!(
+			replica.changes_since(0).unwrap(),
+			db.changes_since(0).unwrap()
+		);
+		assert!(replica.check().unwrap().is_ok());
+
+		// Readers of the replica move forward when polling.
+		let reader_notes = reader.collection("notes").unwrap();
+		assert_eq!(reader_notes.get("b").unwrap().unwrap(), b"B");
+		reader.poll_changes().unwrap();
+		assert!(reader_notes.get("b").unwrap().is_none());
+
+		// Batches must apply on top of the replica's sequence number.
+		match replica.apply_journal(&batch) {
+			Err(Error::InvalidJournal(_)) => {}
+			other => panic!("unexpected result: {:?}", other),
+		}
+		let empty = db.journal_since(6).unwrap();
+		assert!(empty.entries.is_empty());
+		replica.apply_journal(&empty).unwrap();
+		assert!(db.journal_since(7).is_err());
+
+		drop((reader, replica, db));
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_reject_invalid_batches() {
+		let json = r#"{"base":0,"entries":["1 0 put notes a"],"files":{"data/other/b":""}}"#;
+		assert!(JournalBatch::from_json(json).is_err());
+		let json = r#"{"base":0,"entries":["1 0 put notes a"],"files":{"data/notes/../a":""}}"#;
+		assert!(JournalBatch::from_json(json).is_err());
+		let json = r#"{"base":1,"entries":["1 0 put notes a"],"files":{}}"#;
+		assert!(JournalBatch::from_json(json).is_err());
+		let json = r#"{"base":0,"entries":["1 0 put notes a"],"files":{"data/notes/a":"QQ=="}}"#;
+		let batch = JournalBatch::from_json(json).unwrap();
+		assert_eq!(batch.files[Path::new("data/notes/a")], b"A");
+	}
+}
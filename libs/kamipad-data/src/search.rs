@@ -0,0 +1,413 @@
+//! Full-text search over JSON records.
+//!
+//! Collections are indexed with `Database::add_search_index`, naming the
+//! string fields to index. `Collection::search` then returns the records
+//! containing all the words in a query, best matches first:
+//!
+//! ```text
+//! db.add_search_index("notes", &["title", "text"]);
+//! let hits = db.collection("notes")?.search("shopping list", 10)?;
+//! ```
+//!
+//! Words are sequences of letters and digits, compared ignoring case. Hits
+//! are scored with BM25, and come with a snippet of each field that matched,
+//! with the ranges of the matching words.
+//!
+//! The index is kept in memory. It is built on the first search, and brought
+//! up to date on later searches from the changes in the journal. If the
+//! journal doesn't have all the changes since the index was last updated,
+//! the index is built again.
+//!
+//! Like validators, indexes must be added every time the database is opened.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use serde_json::Value;
+
+use crate::collection::Collection;
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::Change;
+use crate::query::get_field;
+use crate::Result;
+
+/// Maximum length of a snippet, in bytes.
+pub const SNIPPET_LENGTH: usize = 160;
+
+// Parameters for the BM25 score.
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+/// Record matching a search, as returned by `Collection::search`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+	pub key: String,
+	/// Relevance of the record for the query. Scores are only comparable
+	/// between hits of the same search.
+	pub score: f64,
+	/// Snippets of the fields that matched, in the order the fields were
+	/// given to `add_search_index`.
+	pub snippets: Vec<Snippet>,
+}
+
+/// Part of a field matching a search.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snippet {
+	pub field: String,
+	/// Text around the first match in the field, of at most
+	/// `SNIPPET_LENGTH` bytes.
+	pub text: String,
+	/// Byte ranges of the matching words in `text`.
+	pub highlights: Vec<Range<usize>>,
+}
+
+/// Indexes added with `add_search_index`, by collection.
+#[derive(Default)]
+pub(crate) struct SearchIndexes {
+	indexes: HashMap<String, SearchIndex>,
+}
+
+struct SearchIndex {
+	fields: Vec<String>,
+	/// Sequence number up to which changes are indexed, or `None` if the
+	/// index was not built yet.
+	seq: Option<u64>,
+	/// Number of words in each record.
+	lengths: HashMap<String, usize>,
+	/// Number of occurrences of each word, by record.
+	words: HashMap<String, HashMap<String, usize>>,
+}
+
+impl SearchIndex {
+	fn new(fields: Vec<String>) -> SearchIndex {
+		SearchIndex {
+			fields,
+			seq: None,
+			lengths: HashMap::new(),
+			words: HashMap::new(),
+		}
+	}
+
+	fn remove(&mut self, key: &str) {
+		if self.lengths.remove(key).is_some() {
+			self.words.retain(|_, keys| {
+				keys.remove(key);
+				!keys.is_empty()
+			});
+		}
+	}
+
+	fn insert(&mut self, key: &str, value: &[u8]) {
+		self.remove(key);
+		let doc = match serde_json::from_slice::<Value>(value) {
+			Ok(doc) => doc,
+			Err(_) => return,
+		};
+		let mut length = 0;
+		for field in self.fields.iter() {
+			if let Some(text) = get_field(&doc, field).and_then(|value| value.as_str()) {
+				for (word, _) in words(text) {
+					length += 1;
+					let keys = self.words.entry(word).or_default();
+					*keys.entry(key.to_string()).or_insert(0) += 1;
+				}
+			}
+		}
+		self.lengths.insert(key.to_string(), length);
+	}
+
+	/// Returns the keys containing all the words, with their score.
+	fn score(&self, query: &[String]) -> Vec<(String, f64)> {
+		let count = self.lengths.len() as f64;
+		let average = self.lengths.values().sum::<usize>() as f64 / count.max(1.0);
+		let mut scores: Option<HashMap<&String, f64>> = None;
+		for word in query {
+			let keys = match self.words.get(word) {
+				Some(keys) => keys,
+				None => return Vec::new(),
+			};
+			let found = keys.len() as f64;
+			let idf = (1.0 + (count - found + 0.5) / (found + 0.5)).ln();
+			let mut next = HashMap::new();
+			for (key, &times) in keys {
+				let previous = match &scores {
+					Some(scores) => match scores.get(key) {
+						Some(score) => *score,
+						None => continue,
+					},
+					None => 0.0,
+				};
+				let times = times as f64;
+				let length = self.lengths[key] as f64;
+				let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * length / average.max(1.0));
+				next.insert(
+					key,
+					previous + idf * times * (BM25_K1 + 1.0) / (times + norm),
+				);
+			}
+			scores = Some(next);
+		}
+		scores
+			.unwrap_or_default()
+			.into_iter()
+			.map(|(key, score)| (key.clone(), score))
+			.collect()
+	}
+}
+
+impl Database {
+	/// Adds a full-text index for the string fields of the records in a
+	/// collection, by their dotted path. See the `search` module.
+	pub fn add_search_index(&self, collection: &str, fields: &[&str]) {
+		let fields = fields.iter().map(|field| field.to_string()).collect();
+		self.search_indexes()
+			.indexes
+			.insert(collection.to_string(), SearchIndex::new(fields));
+	}
+}
+
+impl<'a> Collection<'a> {
+	/// Returns up to `limit` records containing all the words in the query,
+	/// best matches first. See the `search` module.
+	///
+	/// Fails with `Error::NotFound` if the collection has no search index.
+	pub fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>> {
+		let mut indexes = self.db.search_indexes();
+		let index = match indexes.indexes.get_mut(&self.name) {
+			Some(index) => index,
+			None => return Err(Error::NotFound(format!("search index for `{}`", self.name))),
+		};
+		self.update_index(index)?;
+
+		let query = words(query)
+			.map(|(word, _)| word)
+			.collect::<HashSet<_>>()
+			.into_iter()
+			.collect::<Vec<_>>();
+		if query.is_empty() {
+			return Ok(Vec::new());
+		}
+		let mut scores = index.score(&query);
+		scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+
+		let mut hits = Vec::new();
+		for (key, score) in scores {
+			if hits.len() >= limit {
+				break;
+			}
+			// Expired records stay in the index until compacted.
+			let value = match self.get(&key)? {
+				Some(value) => value,
+				None => continue,
+			};
+			let doc = serde_json::from_slice::<Value>(&value).unwrap_or(Value::Null);
+			let snippets = index
+				.fields
+				.iter()
+				.filter_map(|field| {
+					let text = get_field(&doc, field)?.as_str()?;
+					let snippet = snippet(text, &query)?;
+					Some(Snippet {
+						field: field.clone(),
+						..snippet
+					})
+				})
+				.collect();
+			hits.push(SearchHit {
+				key,
+				score,
+				snippets,
+			});
+		}
+		Ok(hits)
+	}
+
+	/// Indexes the changes since the index was last updated, or builds the
+	/// index if needed.
+	fn update_index(&self, index: &mut SearchIndex) -> Result<()> {
+		let seq = self.db.sequence();
+		if let Some(indexed) = index.seq {
+			if indexed == seq {
+				return Ok(());
+			}
+			let changes = match self.db.changes_since(indexed) {
+				Ok(changes) if indexed < seq => changes,
+				Ok(_) | Err(Error::JournalTruncated(_)) => Vec::new(),
+				Err(err) => return Err(err),
+			};
+			// The journal must have every change since the index was updated.
+			if changes.first().map(|entry| entry.seq) == Some(indexed + 1) {
+				let mut keys = HashSet::new();
+				for entry in changes {
+					match entry.change {
+						Change::Put { collection, key } | Change::Delete { collection, key }
+							if collection == self.name =>
+						{
+							keys.insert(key);
+						}
+						_ => {}
+					}
+				}
+				for key in keys {
+					match self.get(&key)? {
+						Some(value) => index.insert(&key, &value),
+						None => index.remove(&key),
+					}
+				}
+				index.seq = Some(seq);
+				return Ok(());
+			}
+		}
+
+		let fields = std::mem::take(&mut index.fields);
+		*index = SearchIndex::new(fields);
+		for key in self.keys()? {
+			if let Some(value) = self.get(&key)? {
+				index.insert(&key, &value);
+			}
+		}
+		index.seq = Some(seq);
+		Ok(())
+	}
+}
+
+/// Returns the lowercase words in a text, with their byte range.
+fn words(text: &str) -> impl Iterator<Item = (String, Range<usize>)> + '_ {
+	let mut chars = text.char_indices().peekable();
+	std::iter::from_fn(move || {
+		while let Some(&(_, c)) = chars.peek() {
+			if c.is_alphanumeric() {
+				break;
+			}
+			chars.next();
+		}
+		let (start, _) = *chars.peek()?;
+		let mut end = start;
+		while let Some(&(index, c)) = chars.peek() {
+			if !c.is_alphanumeric() {
+				break;
+			}
+			end = index + c.len_utf8();
+			chars.next();
+		}
+		Some((text[start..end].to_lowercase(), start..end))
+	})
+}
+
+/// Returns the text around the first word from the query, with the ranges
+/// of the query words in it, or `None` if the text has none of them.
+fn snippet(text: &str, query: &[String]) -> Option<Snippet> {
+	let matches = words(text)
+		.filter(|(word, _)| query.contains(word))
+		.map(|(_, range)| range)
+		.collect::<Vec<_>>();
+	let first = matches.first()?.clone();
+
+	// Start a little before the first match, at a character boundary.
+	let mut start = first.start.saturating_sub(SNIPPET_LENGTH / 4);
+	while !text.is_char_boundary(start) {
+		start -= 1;
+	}
+	let mut end = (start + SNIPPET_LENGTH).min(text.len());
+	while !text.is_char_boundary(end) {
+		end -= 1;
+	}
+	let highlights = matches
+		.into_iter()
+		.filter(|range| range.start >= start && range.end <= end)
+		.map(|range| range.start - start..range.end - start)
+		.collect();
+	Some(Snippet {
+		field: String::new(),
+		text: text[start..end].to_string(),
+		highlights,
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use crate::testing::create_db;
+	use crate::{Error, OpenFlags};
+
+	use super::words;
+
+	#[test]
+	fn should_split_words() {
+		let words = words("Hello, wörld! 42x  _a").collect::<Vec<_>>();
+		assert_eq!(
+			words,
+			vec![
+				("hello".to_string(), 0..5),
+				("wörld".to_string(), 7..13),
+				("42x".to_string(), 15..18),
+				("a".to_string(), 21..22),
+			]
+		);
+	}
+
+	#[test]
+	fn should_search_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		match notes.search("list", 10) {
+			Err(Error::NotFound(_)) => (),
+			other => panic!("expected Error::NotFound, got {:?}", other),
+		}
+
+		db.add_search_index("notes", &["title", "text"]);
+		let put = |key: &str, title: &str, text: &str| {
+			let value = serde_json::json!({ "title": title, "text": text });
+			notes.put(key, value.to_string().as_bytes()).unwrap();
+		};
+		put("a", "Shopping list", "Milk, eggs and bread");
+		put("b", "Todo", "Write the shopping list for the weekend list");
+		put("c", "Ideas", "Nothing to see here");
+		notes.put("d", b"not json").unwrap();
+
+		let keys = |query: &str| {
+			let hits = notes.search(query, 10).unwrap();
+			hits.into_iter().map(|hit| hit.key).collect::<Vec<_>>()
+		};
+		assert_eq!(keys("LIST"), vec!["b", "a"]);
+		assert_eq!(keys("shopping eggs"), vec!["a"]);
+		assert_eq!(keys("list missing"), Vec::<String>::new());
+		assert_eq!(keys("  "), Vec::<String>::new());
+		assert_eq!(notes.search("list", 1).unwrap().len(), 1);
+
+		let hit = &notes.search("eggs", 10).unwrap()[0];
+		assert!(hit.score > 0.0);
+		assert_eq!(hit.snippets.len(), 1);
+		assert_eq!(hit.snippets[0].field, "text");
+		assert_eq!(hit.snippets[0].text, "Milk, eggs and bread");
+		assert_eq!(hit.snippets[0].highlights, vec![6..10]);
+
+		// The index is updated with the changes.
+		put("c", "List", "A list of ideas");
+		notes.delete("a").unwrap();
+		assert_eq!(keys("list"), vec!["c", "b"]);
+		assert_eq!(keys("eggs"), Vec::<String>::new());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_limit_snippets() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.add_search_index("notes", &["text"]);
+		let notes = db.collection("notes").unwrap();
+		let text = format!("{} needle {}", "a ".repeat(200), "b ".repeat(200));
+		let value = serde_json::json!({ "text": text });
+		notes.put("a", value.to_string().as_bytes()).unwrap();
+
+		let hits = notes.search("needle", 10).unwrap();
+		let snippet = &hits[0].snippets[0];
+		assert_eq!(snippet.text.len(), super::SNIPPET_LENGTH);
+		assert_eq!(snippet.highlights.len(), 1);
+		assert_eq!(&snippet.text[snippet.highlights[0].clone()], "needle");
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
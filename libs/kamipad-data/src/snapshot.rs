@@ -0,0 +1,347 @@
+//! Reading the database as it was at a past point in the journal.
+//!
+//! Before a change is applied, the files it affects are copied to the
+//! `undo` directory, in `undo/<seq>/<path>`, where `seq` is the sequence
+//! number of the change and `path` is the path of the file relative to the
+//! database root. A file missing from the undo directory of a change did not
+//! exist before it.
+//!
+//! The state of a file at sequence number `S` is then either:
+//!
+//! - the undo copy for the first change after `S` that affected the file; or
+//! - the current file, if no change after `S` affected it.
+//!
+//! Undo data is saved with `OpenFlags::snapshots`, which also limits how far
+//! back it is kept, and while there are readers open. Older undo data is
+//! removed by `Database::compact`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::blob::{BlobId, BLOBS_DIR};
+use crate::check::read_dir;
+use crate::collection::{is_valid_name, DATA_DIR};
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::{now, Change, JournalEntry};
+use crate::mvcc::active_readers;
+use crate::record::{Codec, Header};
+use crate::util::{corrupt_error, read_error, write_error};
+use crate::Result;
+
+pub(crate) const UNDO_DIR: &str = "undo";
+
+/// A read-only view of the database at a past sequence number, created with
+/// `Database::read_at`.
+pub struct Snapshot<'a> {
+	db: &'a Database,
+	seq: u64,
+}
+
+impl Database {
+	/// Returns a read-only view of the database as it was right after the
+	/// change with the given sequence number.
+	///
+	/// Fails with `Error::SnapshotUnavailable` if the undo data needed to
+	/// reconstruct that point is not available.
+	pub fn read_at(&self, seq: u64) -> Result<Snapshot<'_>> {
+		if seq > self.sequence() {
+			return Err(Error::SnapshotUnavailable(seq));
+		}
+		let snapshot = Snapshot { db: self, seq };
+		let entries = match self.changes_since(seq) {
+			Err(Error::JournalTruncated(_)) => return Err(Error::SnapshotUnavailable(seq)),
+			result => result?,
+		};
+		for entry in entries {
+			snapshot.undo_dir(entry.seq)?;
+		}
+		Ok(snapshot)
+	}
+
+	/// Returns true if undo data must be saved for changes, either for
+	/// snapshots or for readers (see the `mvcc` module).
+	pub(crate) fn keeps_undo(&self) -> Result<bool> {
+		Ok(self.snapshots() > 0 || self.has_readers()?)
+	}
+
+	/// Saves the files affected by a change before it is applied. Must be
+	/// called with the write lock held.
+	pub(crate) fn save_undo(&self, seq: u64, change: &Change) -> Result<()> {
+		let undo_dir = self.path.join(UNDO_DIR).join(seq.to_string());
+		self.save_files(&undo_dir, change)
+	}
+
+	/// Copies the files affected by a change to the given directory, keeping
+	/// their path relative to the database root. Files that don't exist are
+	/// skipped.
+	pub(crate) fn save_files(&self, dir: &Path, change: &Change) -> Result<()> {
+		fs::create_dir_all(dir).map_err(|err| write_error(err, dir))?;
+		for relative in change.paths() {
+			let source = self.path.join(&relative);
+			let target = dir.join(&relative);
+			let result = fs::create_dir_all(target.parent().unwrap())
+				.and_then(|_| fs::copy(&source, &target));
+			match result {
+				Ok(_) => {}
+				Err(err) if err.kind() == io::ErrorKind::NotFound && !source.exists() => {}
+				Err(err) => return Err(write_error(err, &target)),
+			}
+		}
+		Ok(())
+	}
+
+	/// Removes undo data that is no longer needed. Must be called with the
+	/// write lock held.
+	pub(crate) fn prune_undo(&self) -> Result<usize> {
+		let mut keep_after = self.sequence().saturating_sub(self.snapshots());
+		if let Some(&oldest) = active_readers(&self.path)?.iter().min() {
+			keep_after = keep_after.min(oldest);
+		}
+		let undo_dir = self.path.join(UNDO_DIR);
+		let mut count = 0;
+		for (name, _) in read_dir(&undo_dir)? {
+			match name.parse::<u64>() {
+				Ok(seq) if seq <= keep_after => {
+					let path = undo_dir.join(&name);
+					fs::remove_dir_all(&path).map_err(|err| write_error(err, &path))?;
+					count += 1;
+				}
+				_ => {}
+			}
+		}
+		Ok(count)
+	}
+}
+
+impl<'a> Snapshot<'a> {
+	/// Sequence number of the snapshot.
+	pub fn seq(&self) -> u64 {
+		self.seq
+	}
+
+	/// Reads a record as it was at the snapshot. Returns `None` if the record
+	/// did not exist.
+	pub fn get(&self, collection: &str, key: &str) -> Result<Option<Vec<u8>>> {
+		let record = self.get_with_header(collection, key)?;
+		Ok(record.map(|(_, payload)| payload))
+	}
+
+	/// Reads a record and its header as it was at the snapshot. Returns
+	/// `None` if the record did not exist or has expired.
+	pub(crate) fn get_with_header(
+		&self,
+		collection: &str,
+		key: &str,
+	) -> Result<Option<(Header, Vec<u8>)>> {
+		if !is_valid_name(collection) || !is_valid_name(key) {
+			return Err(Error::InvalidName(format!("{}/{}", collection, key)));
+		}
+		let relative = Path::new(DATA_DIR).join(collection).join(key);
+		let data = match self.read(&relative)? {
+			Some(data) => data,
+			None => return Ok(None),
+		};
+		let path = self.db.path.join(&relative);
+		let (header, payload) = self
+			.db
+			.codec()
+			.decode_with_header(&data)
+			.map_err(|err| corrupt_error(err, &path))?;
+		if header.is_expired(now()) {
+			Ok(None)
+		} else {
+			Ok(Some((header, payload)))
+		}
+	}
+
+	/// Returns the keys for all records in a collection at the snapshot,
+	/// sorted.
+	pub fn keys(&self, collection: &str) -> Result<Vec<String>> {
+		let current = self.db.collection(collection)?.keys()?;
+		let mut candidates = current.into_iter().collect::<BTreeSet<_>>();
+		for entry in self.db.changes_since(self.seq)? {
+			match entry.change {
+				Change::Put { collection: c, key } | Change::Delete { collection: c, key }
+					if c == collection =>
+				{
+					candidates.insert(key);
+				}
+				_ => {}
+			}
+		}
+
+		let mut keys = Vec::new();
+		for key in candidates {
+			if self.get(collection, &key)?.is_some() {
+				keys.push(key);
+			}
+		}
+		Ok(keys)
+	}
+
+	/// Reads a blob as it was at the snapshot. Returns `None` if the blob
+	/// did not exist.
+	pub fn get_blob(&self, id: &BlobId) -> Result<Option<Vec<u8>>> {
+		let relative = Path::new(BLOBS_DIR).join(id.as_str());
+		let data = match self.read(&relative)? {
+			Some(data) => data,
+			None => return Ok(None),
+		};
+		let codec = Codec {
+			compress: false,
+			..self.db.codec()
+		};
+		let path = self.db.path.join(&relative);
+		codec
+			.decode(&data)
+			.map(Some)
+			.map_err(|err| corrupt_error(err, &path))
+	}
+
+	/// Returns the names in a directory, relative to the database root, that
+	/// may have existed at the snapshot, sorted. These are the current
+	/// entries and the names of the entries changed since.
+	pub(crate) fn names(&self, dir: &Path) -> Result<Vec<String>> {
+		let mut names = read_dir(&self.db.path.join(dir))?
+			.into_iter()
+			.map(|(name, _)| name)
+			.collect::<BTreeSet<_>>();
+		for entry in self.db.changes_since(self.seq)? {
+			for path in entry.change.paths() {
+				let name = path
+					.strip_prefix(dir)
+					.ok()
+					.and_then(|rest| rest.iter().next());
+				if let Some(name) = name {
+					names.insert(name.to_string_lossy().into_owned());
+				}
+			}
+		}
+		Ok(names.into_iter().collect())
+	}
+
+	/// Reads a file, relative to the database root, as it was at the
+	/// snapshot.
+	pub(crate) fn read(&self, relative: &Path) -> Result<Option<Vec<u8>>> {
+		// The current file must be read before the journal: changes are
+		// logged before being applied, so any change we might have read is
+		// guaranteed to be in the journal.
+		let current = read_optional(&self.db.path.join(relative))?;
+		let entries = self.db.changes_since(self.seq)?;
+		match first_change(&entries, relative) {
+			Some(seq) => read_optional(&self.undo_dir(seq)?.join(relative)),
+			None => Ok(current),
+		}
+	}
+
+	fn undo_dir(&self, seq: u64) -> Result<PathBuf> {
+		let dir = self.db.path.join(UNDO_DIR).join(seq.to_string());
+		if dir.is_dir() {
+			Ok(dir)
+		} else {
+			Err(Error::SnapshotUnavailable(self.seq))
+		}
+	}
+}
+
+/// Returns the sequence number of the first change affecting the file.
+fn first_change(entries: &[JournalEntry], relative: &Path) -> Option<u64> {
+	entries
+		.iter()
+		.find(|entry| entry.change.paths().iter().any(|path| path == relative))
+		.map(|entry| entry.seq)
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+	match fs::read(path) {
+		Ok(data) => Ok(Some(data)),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(read_error(err, path)),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_read_at_past_sequence() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.snapshots = 100));
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A1").unwrap();
+		notes.put("b", b"B1").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+		let seq = db.sequence();
+
+		notes.put("a", b"A2").unwrap();
+		notes.delete("b").unwrap();
+		notes.put("c", b"C").unwrap();
+		db.release_blob(&blob).unwrap();
+		db.gc_blobs().unwrap();
+
+		let snapshot = db.read_at(seq).unwrap();
+		assert_eq!(snapshot.seq(), seq);
+		assert_eq!(snapshot.get("notes", "a").unwrap().unwrap(), b"A1");
+		assert_eq!(snapshot.get("notes", "b").unwrap().unwrap(), b"B1");
+		assert!(snapshot.get("notes", "c").unwrap().is_none());
+		assert_eq!(snapshot.keys("notes").unwrap(), vec!["a", "b"]);
+		assert_eq!(snapshot.get_blob(&blob).unwrap().unwrap(), b"blob");
+
+		let initial = db.read_at(1).unwrap();
+		assert_eq!(initial.keys("notes").unwrap(), vec!["a"]);
+
+		let current = db.read_at(db.sequence()).unwrap();
+		assert_eq!(current.keys("notes").unwrap(), vec!["a", "c"]);
+		assert!(current.get_blob(&blob).unwrap().is_none());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_prune_undo_data() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.snapshots = 2));
+		let notes = db.collection("notes").unwrap();
+		for value in &["1", "2", "3", "4"] {
+			notes.put("a", value.as_bytes()).unwrap();
+		}
+
+		assert_eq!(
+			db.read_at(1).unwrap().get("notes", "a").unwrap().unwrap(),
+			b"1"
+		);
+		db.compact().unwrap();
+		match db.read_at(1) {
+			Err(Error::SnapshotUnavailable(1)) => (),
+			other => panic!("expected Error::SnapshotUnavailable, got {:?}", other.err()),
+		}
+		assert_eq!(
+			db.read_at(2).unwrap().get("notes", "a").unwrap().unwrap(),
+			b"2"
+		);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_require_undo_data() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"1").unwrap();
+		notes.put("a", b"2").unwrap();
+
+		assert!(db.read_at(1).is_err());
+		assert!(db.read_at(3).is_err());
+		let current = db.read_at(2).unwrap();
+		assert_eq!(current.get("notes", "a").unwrap().unwrap(), b"2");
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,130 @@
+//! Export of the database to SQLite.
+//!
+//! Only available with the `sqlite` feature. The export creates a single
+//! SQLite database file with one table per collection, named after the
+//! collection:
+//!
+//! ```sql
+//! CREATE TABLE "notes" (key TEXT PRIMARY KEY NOT NULL, value);
+//! ```
+//!
+//! Values are stored as `TEXT` if they are valid UTF-8 and as `BLOB`
+//! otherwise. Blobs are not exported.
+
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::io;
+use std::path::Path;
+
+use crate::database::Database;
+use crate::error::{Error, IOError};
+use crate::Result;
+
+impl Database {
+	/// Exports all records in the database to a new SQLite database at the
+	/// given path, returning the number of records exported.
+	///
+	/// The target file must not exist.
+	pub fn export_sqlite<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+		let target = path.as_ref();
+		let export_error = |err: io::Error| {
+			Error::Write(IOError::new(
+				err,
+				format!("exporting to `{}`", target.to_string_lossy()),
+			))
+		};
+		let sqlite_error =
+			|err: rusqlite::Error| export_error(io::Error::new(io::ErrorKind::Other, err));
+		if target.exists() {
+			let err = io::Error::new(io::ErrorKind::AlreadyExists, "file already exists");
+			return Err(export_error(err));
+		}
+
+		let _guard = self.write_guard();
+		let mut conn = Connection::open(target).map_err(sqlite_error)?;
+		let tx = conn.transaction().map_err(sqlite_error)?;
+
+		let mut count = 0;
+		for name in self.collections()? {
+			// Collection names are restricted to safe characters, so they can
+			// be used as identifiers.
+			let sql = format!(
+				"CREATE TABLE \"{}\" (key TEXT PRIMARY KEY NOT NULL, value)",
+				name
+			);
+			tx.execute(&sql, params![]).map_err(sqlite_error)?;
+
+			let collection = self.collection(name)?;
+			let sql = format!(
+				"INSERT INTO \"{}\" (key, value) VALUES (?, ?)",
+				collection.name()
+			);
+			let mut insert = tx.prepare(&sql).map_err(sqlite_error)?;
+			for key in collection.keys()? {
+				let payload = collection.get(&key)?.unwrap_or_default();
+				let value = match String::from_utf8(payload) {
+					Ok(text) => Value::Text(text),
+					Err(err) => Value::Blob(err.into_bytes()),
+				};
+				insert.execute(params![key, value]).map_err(sqlite_error)?;
+				count += 1;
+			}
+		}
+
+		tx.commit().map_err(sqlite_error)?;
+		Ok(count)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_export_sqlite() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"first").unwrap();
+		notes.put("b", b"second").unwrap();
+		db.collection("files")
+			.unwrap()
+			.put("x", &[0, 0xff])
+			.unwrap();
+
+		let path = temp.path().join("export.sqlite");
+		assert_eq!(db.export_sqlite(&path).unwrap(), 3);
+		assert!(db.export_sqlite(&path).is_err());
+
+		let conn = Connection::open(&path).unwrap();
+		let mut stmt = conn
+			.prepare("SELECT key, value FROM notes ORDER BY key")
+			.unwrap();
+		let rows = stmt
+			.query_map(params![], |row| {
+				Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+			})
+			.unwrap()
+			.collect::<rusqlite::Result<Vec<_>>>()
+			.unwrap();
+		assert_eq!(
+			rows,
+			vec![("a".into(), "first".into()), ("b".into(), "second".into())]
+		);
+
+		let value: Vec<u8> = conn
+			.query_row(
+				"SELECT value FROM files WHERE key = 'x'",
+				params![],
+				|row| row.get(0),
+			)
+			.unwrap();
+		assert_eq!(value, vec![0, 0xff]);
+
+		drop(stmt);
+		drop(conn);
+		drop(db);
+		temp.close().unwrap();
+	}
+}
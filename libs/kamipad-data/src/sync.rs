@@ -0,0 +1,520 @@
+//! Synchronization of records between databases.
+//!
+//! `Database::sync` exchanges the records changed since the last sync with a
+//! peer database, reached through a `SyncTransport`:
+//!
+//! 1. Pull the records changed on the peer and apply them locally.
+//! 2. Push the records changed locally, except those just pulled.
+//!
+//! A record changed on both sides since the last sync is a conflict. Neither
+//! side is changed for a conflict, which is reported in the `SyncSummary`
+//! instead, with the version of the record on each side. Resolving it is up
+//! to the caller, e.g. by writing the merged value, which is sent with the
+//! next sync. Records changed to the same value on both sides are not
+//! conflicts, and neither are records in collections marked with
+//! `Database::add_crdt`, which are merged (see the `crdt` module).
+//!
+//! The sequence numbers up to which changes were exchanged with each peer
+//! are kept in `sync/<peer>`:
+//!
+//! ```text
+//! local=<seq>
+//! remote=<seq>
+//! ```
+//!
+//! Only records are synced. Blobs, links and tags stay local.
+//!
+//! Peers on another machine are reached by a transport that sends the
+//! `SyncBatch` to them, serialized with `SyncBatch::to_json`, and calls
+//! `Database::sync_changes` and `Database::apply_sync` on their side. Peers
+//! in the same process can use `LocalTransport`.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::collection::{is_valid_name, list_names};
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::Change;
+use crate::links::RecordId;
+use crate::record::Header;
+use crate::util::{self, read_error, write_error};
+use crate::Result;
+
+pub(crate) const SYNC_DIR: &str = "sync";
+
+/// A record sent to a peer.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SyncRecord {
+	pub collection: String,
+	pub key: String,
+	/// Version of the record on the sender, or zero if deleted.
+	pub version: u64,
+	/// Value of the record, or `None` if deleted.
+	pub value: Option<Vec<u8>>,
+}
+
+/// Records changed on a database after a sequence number, from
+/// `Database::sync_changes`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SyncBatch {
+	/// Sequence number of the sender when the batch was created.
+	pub seq: u64,
+	pub records: Vec<SyncRecord>,
+}
+
+/// A record changed on both sides of a sync.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SyncConflict {
+	pub collection: String,
+	pub key: String,
+	/// Version of the record on the receiving side, or zero if deleted.
+	pub local_version: u64,
+	/// Version of the record on the sending side, or zero if deleted.
+	pub remote_version: u64,
+}
+
+/// Result of `Database::apply_sync`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SyncApplied {
+	/// Number of records written.
+	pub applied: usize,
+	/// Records not written because they changed on both sides.
+	pub conflicts: Vec<SyncConflict>,
+	/// Records that changed on both sides and were merged, including those
+	/// where the merge kept the local value.
+	pub merged: Vec<RecordId>,
+	/// Sequence number before and after applying. Changes in between were
+	/// made by applying the batch.
+	pub start_seq: u64,
+	pub seq: u64,
+}
+
+/// Summary of a `Database::sync`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SyncSummary {
+	/// Number of records changed locally from the peer.
+	pub pulled: usize,
+	/// Number of records changed on the peer.
+	pub pushed: usize,
+	/// Records that changed on both sides, which were left as they are.
+	pub conflicts: Vec<SyncConflict>,
+}
+
+/// Connection to the peer database for a sync.
+pub trait SyncTransport {
+	/// Returns the records changed on the peer after the sequence number,
+	/// from `Database::sync_changes`.
+	fn pull(&mut self, since: u64) -> Result<SyncBatch>;
+
+	/// Applies records to the peer with `Database::apply_sync`, where
+	/// `since` is the peer sequence number the records were based on.
+	fn push(&mut self, batch: &SyncBatch, since: u64) -> Result<SyncApplied>;
+}
+
+/// Transport to a database in the same process.
+pub struct LocalTransport<'a>(pub &'a Database);
+
+impl<'a> SyncTransport for LocalTransport<'a> {
+	fn pull(&mut self, since: u64) -> Result<SyncBatch> {
+		self.0.sync_changes(since)
+	}
+
+	fn push(&mut self, batch: &SyncBatch, since: u64) -> Result<SyncApplied> {
+		self.0.apply_sync(batch, since)
+	}
+}
+
+impl Database {
+	/// Exchanges changed records with a peer, identified by a name used to
+	/// track the changes already exchanged. See the `sync` module.
+	pub fn sync<T: SyncTransport>(&self, peer: &str, transport: &mut T) -> Result<SyncSummary> {
+		self.check_writable()?;
+		if !is_valid_name(peer) {
+			return Err(Error::InvalidName(peer.to_string()));
+		}
+		let (local_seq, remote_seq) = self.read_sync_state(peer)?;
+
+		let incoming = transport.pull(remote_seq)?;
+		let pulled = self.apply_sync(&incoming, local_seq)?;
+		let mut summary = SyncSummary {
+			pulled: pulled.applied,
+			conflicts: pulled.conflicts,
+			..Default::default()
+		};
+
+		// Records changed on both sides are left out, as are the changes
+		// from applying the pulled records. Merged records are sent back, as
+		// their local change is still there.
+		let merged = pulled
+			.merged
+			.into_iter()
+			.map(|id| (id.collection, id.key))
+			.collect::<HashSet<_>>();
+		let skip = incoming
+			.records
+			.iter()
+			.map(|record| (record.collection.clone(), record.key.clone()))
+			.filter(|id| !merged.contains(id))
+			.collect::<HashSet<_>>();
+		let applied = (pulled.start_seq + 1)..=pulled.seq;
+		let mut outgoing = {
+			let _guard = self.write_guard();
+			self.changed_records(local_seq, |seq| !applied.contains(&seq))?
+		};
+		outgoing
+			.records
+			.retain(|record| !skip.contains(&(record.collection.clone(), record.key.clone())));
+
+		let pushed = transport.push(&outgoing, incoming.seq)?;
+		summary.pushed = pushed.applied;
+		summary
+			.conflicts
+			.extend(pushed.conflicts.into_iter().map(|conflict| SyncConflict {
+				local_version: conflict.remote_version,
+				remote_version: conflict.local_version,
+				..conflict
+			}));
+
+		// If the peer changed while pushing, those changes are pulled in the
+		// next sync, along with our own changes to the peer, which are then
+		// skipped for having the same value.
+		let remote_seq = if pushed.start_seq == incoming.seq {
+			pushed.seq
+		} else {
+			incoming.seq
+		};
+		self.write_sync_state(peer, outgoing.seq, remote_seq)?;
+		Ok(summary)
+	}
+
+	/// Returns the records changed after the sequence number, with their
+	/// current value.
+	pub fn sync_changes(&self, since: u64) -> Result<SyncBatch> {
+		let _guard = self.write_guard();
+		self.changed_records(since, |_| true)
+	}
+
+	/// Applies records from a peer, skipping those that changed after the
+	/// sequence number `since`, which are returned as conflicts.
+	///
+	/// Every record is checked before applying any, so that the batch is
+	/// not partially applied if a record is invalid or can't be deleted.
+	pub fn apply_sync(&self, batch: &SyncBatch, since: u64) -> Result<SyncApplied> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+		let start_seq = self.sequence();
+		let changed = self
+			.changes_since(since)?
+			.into_iter()
+			.filter_map(|entry| match entry.change {
+				Change::Put { collection, key } | Change::Delete { collection, key } => {
+					Some((collection, key))
+				}
+				_ => None,
+			})
+			.collect::<HashSet<_>>();
+
+		let mut result = SyncApplied {
+			start_seq,
+			..Default::default()
+		};
+		let mut writes = Vec::new();
+		let (mut bytes, mut records) = (0, 0);
+		for record in batch.records.iter() {
+			let collection = self.collection(record.collection.as_str())?;
+			let current = collection.get_versioned(&record.key)?;
+			if current.as_ref().map(|(value, _)| value) == record.value.as_ref() {
+				continue;
+			}
+			let mut value = record.value.clone();
+			if changed.contains(&(record.collection.clone(), record.key.clone())) {
+				let local = current.as_ref().map(|(value, _)| value.as_slice());
+				match self.merge_crdt(&record.collection, local, record.value.as_deref())? {
+					Some(merged) => {
+						let id = RecordId::new(record.collection.as_str(), record.key.as_str());
+						result.merged.push(id);
+						if local == Some(merged.as_slice()) {
+							continue;
+						}
+						value = Some(merged);
+					}
+					None => {
+						result.conflicts.push(SyncConflict {
+							collection: record.collection.clone(),
+							key: record.key.clone(),
+							local_version: current.map(|(_, version)| version).unwrap_or(0),
+							remote_version: record.version,
+						});
+						continue;
+					}
+				}
+			}
+
+			let (b, r) = match &value {
+				Some(value) => {
+					self.validate(&record.collection, &record.key, value)?;
+					collection.put_usage(&record.key, value.len())?
+				}
+				None => {
+					let id = RecordId::new(record.collection.as_str(), record.key.as_str());
+					self.check_unlinked(&id, &[])?;
+					collection.delete_usage(&record.key)?
+				}
+			};
+			bytes += b;
+			records += r;
+			writes.push((record, value));
+		}
+		self.check_quota(bytes, records)?;
+
+		for (record, value) in writes {
+			let collection = self.collection(record.collection.as_str())?;
+			match value {
+				Some(value) => {
+					collection.put_locked(&record.key, &value, Header::default(), None)?;
+				}
+				None => {
+					collection.delete_locked(&record.key)?;
+				}
+			}
+			result.applied += 1;
+		}
+		result.seq = self.sequence();
+		Ok(result)
+	}
+
+	/// Returns the records changed after the sequence number, for the
+	/// changes accepted by the filter. Must be called with the write lock
+	/// held.
+	fn changed_records<F: Fn(u64) -> bool>(&self, since: u64, filter: F) -> Result<SyncBatch> {
+		let keys = self
+			.changes_since(since)?
+			.into_iter()
+			.filter(|entry| filter(entry.seq))
+			.filter_map(|entry| match entry.change {
+				Change::Put { collection, key } | Change::Delete { collection, key } => {
+					Some((collection, key))
+				}
+				_ => None,
+			})
+			.collect::<BTreeSet<_>>();
+
+		let mut batch = SyncBatch {
+			seq: self.sequence(),
+			records: Vec::new(),
+		};
+		for (collection, key) in keys {
+			let current = self.collection(collection.as_str())?.get_versioned(&key)?;
+			let (value, version) = match current {
+				Some((value, version)) => (Some(value), version),
+				None => (None, 0),
+			};
+			batch.records.push(SyncRecord {
+				collection,
+				key,
+				version,
+				value,
+			});
+		}
+		Ok(batch)
+	}
+
+	/// Returns the oldest local sequence number synced with any peer, which
+	/// the changes after are needed for the next sync.
+	pub(crate) fn oldest_sync(&self) -> Result<Option<u64>> {
+		let mut oldest = None;
+		for peer in list_names(&self.path.join(SYNC_DIR), false)? {
+			let (local, _) = self.read_sync_state(&peer)?;
+			oldest = Some(oldest.map_or(local, |oldest: u64| oldest.min(local)));
+		}
+		Ok(oldest)
+	}
+
+	fn read_sync_state(&self, peer: &str) -> Result<(u64, u64)> {
+		let path = self.path.join(SYNC_DIR).join(peer);
+		let text = match fs::read_to_string(&path) {
+			Ok(text) => text,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+			Err(err) => return Err(read_error(err, &path)),
+		};
+		let params = text
+			.lines()
+			.filter_map(|line| {
+				let mut parts = line.splitn(2, '=');
+				Some((parts.next()?.trim(), parts.next()?.trim().parse().ok()?))
+			})
+			.collect::<BTreeMap<_, u64>>();
+		match (params.get("local"), params.get("remote")) {
+			(Some(&local), Some(&remote)) => Ok((local, remote)),
+			_ => Err(util::corrupt_error(
+				util::invalid_data("invalid sync state"),
+				&path,
+			)),
+		}
+	}
+
+	fn write_sync_state(&self, peer: &str, local: u64, remote: u64) -> Result<()> {
+		let dir = self.path.join(SYNC_DIR);
+		let path = dir.join(peer);
+		let text = format!("local={}\nremote={}\n", local, remote);
+		fs::create_dir_all(&dir)
+			.and_then(|_| util::write_file(&path, text.as_bytes()))
+			.map_err(|err| write_error(err, &path))
+	}
+}
+
+/// Serialized form of a `SyncBatch`.
+#[derive(Serialize, Deserialize)]
+struct BatchJson {
+	seq: u64,
+	records: Vec<RecordJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordJson {
+	collection: String,
+	key: String,
+	version: u64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	data: Option<String>,
+}
+
+impl SyncBatch {
+	/// Serializes the batch as JSON, with values base64-encoded.
+	pub fn to_json(&self) -> String {
+		let json = BatchJson {
+			seq: self.seq,
+			records: self
+				.records
+				.iter()
+				.map(|record| RecordJson {
+					collection: record.collection.clone(),
+					key: record.key.clone(),
+					version: record.version,
+					data: record.value.as_ref().map(base64::encode),
+				})
+				.collect(),
+		};
+		serde_json::to_string(&json).unwrap()
+	}
+
+	/// Parses a batch serialized with `to_json`.
+	pub fn from_json(text: &str) -> Result<SyncBatch> {
+		let invalid = |reason: String| Error::InvalidJournal(reason);
+		let json: BatchJson = serde_json::from_str(text).map_err(|err| invalid(err.to_string()))?;
+		let mut records = Vec::new();
+		for record in json.records {
+			if !is_valid_name(&record.collection) || !is_valid_name(&record.key) {
+				return Err(Error::InvalidName(format!(
+					"{}/{}",
+					record.collection, record.key
+				)));
+			}
+			let value = match record.data {
+				Some(data) => Some(base64::decode(&data).map_err(|err| invalid(err.to_string()))?),
+				None => None,
+			};
+			records.push(SyncRecord {
+				collection: record.collection,
+				key: record.key,
+				version: record.version,
+				value,
+			});
+		}
+		Ok(SyncBatch {
+			seq: json.seq,
+			records,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_sync_databases() {
+		let (a, temp) = create_db(OpenFlags::default());
+		let b = open(temp.path().join("b"), OpenFlags::default()).unwrap();
+		let (a_notes, b_notes) = (
+			a.collection("notes").unwrap(),
+			b.collection("notes").unwrap(),
+		);
+		a_notes.put("1", b"A1").unwrap();
+		a_notes.put("2", b"A2").unwrap();
+		b_notes.put("3", b"B3").unwrap();
+
+		let summary = a.sync("b", &mut LocalTransport(&b)).unwrap();
+		assert_eq!((summary.pulled, summary.pushed), (1, 2));
+		assert!(summary.conflicts.is_empty());
+		assert_eq!(a_notes.keys().unwrap(), vec!["1", "2", "3"]);
+		assert_eq!(b_notes.keys().unwrap(), vec!["1", "2", "3"]);
+
+		// Nothing is exchanged again.
+		let summary = a.sync("b", &mut LocalTransport(&b)).unwrap();
+		assert_eq!(summary, SyncSummary::default());
+		let summary = b.sync("a", &mut LocalTransport(&a)).unwrap();
+		assert_eq!(summary, SyncSummary::default());
+
+		// Changes on both sides conflict, unless they are the same.
+		a_notes.put("1", b"A1-a").unwrap();
+		b_notes.put("1", b"A1-b").unwrap();
+		a_notes.delete("2").unwrap();
+		b_notes.delete("2").unwrap();
+		b_notes.delete("3").unwrap();
+		let summary = a.sync("b", &mut LocalTransport(&b)).unwrap();
+		assert_eq!(summary.pulled, 1);
+		assert_eq!(summary.pushed, 0);
+		assert_eq!(
+			summary.conflicts,
+			vec![SyncConflict {
+				collection: "notes".into(),
+				key: "1".into(),
+				local_version: 2,
+				remote_version: 2,
+			}]
+		);
+		assert_eq!(a_notes.keys().unwrap(), vec!["1"]);
+		assert_eq!(a_notes.get("1").unwrap().unwrap(), b"A1-a");
+		assert_eq!(b_notes.get("1").unwrap().unwrap(), b"A1-b");
+
+		// Resolving the conflict is sent with the next sync.
+		a_notes.put("1", b"merged").unwrap();
+		let summary = a.sync("b", &mut LocalTransport(&b)).unwrap();
+		assert_eq!((summary.pulled, summary.pushed), (0, 1));
+		assert_eq!(b_notes.get("1").unwrap().unwrap(), b"merged");
+
+		drop((a, b));
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_serialize_sync_batches() {
+		let batch = SyncBatch {
+			seq: 5,
+			records: vec![
+				SyncRecord {
+					collection: "notes".into(),
+					key: "a".into(),
+					version: 3,
+					value: Some(vec![0, 1, 2]),
+				},
+				SyncRecord {
+					collection: "notes".into(),
+					key: "b".into(),
+					version: 0,
+					value: None,
+				},
+			],
+		};
+		assert_eq!(SyncBatch::from_json(&batch.to_json()).unwrap(), batch);
+		let json = r#"{"seq":1,"records":[{"collection":"..","key":"a","version":1}]}"#;
+		assert!(SyncBatch::from_json(json).is_err());
+	}
+}
@@ -0,0 +1,277 @@
+//! Tags for records.
+//!
+//! Records can be tagged with any number of tags, which follow the same
+//! naming rules as keys. Tags are indexed per collection, with a file for
+//! each tag listing the keys of the tagged records, one per line, sorted:
+//!
+//! ```text
+//! tags/<collection>/<tag>
+//! ```
+//!
+//! The file for a tag is removed when no record has it. Deleting a record
+//! removes its tags, while expired records keep them until removed by
+//! `Database::compact`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::collection::{is_valid_name, list_names, Collection};
+use crate::error::Error;
+use crate::journal::Change;
+use crate::util::{self, read_error, write_error};
+use crate::Result;
+
+pub(crate) const TAGS_DIR: &str = "tags";
+
+impl<'a> Collection<'a> {
+	/// Adds a tag to a record. Returns false if the record already had it.
+	///
+	/// Fails with `Error::NotFound` if the record does not exist.
+	pub fn tag(&self, key: &str, tag: &str) -> Result<bool> {
+		self.db.check_writable()?;
+		self.record_path(key)?;
+		check_tag(tag)?;
+
+		let _guard = self.db.write_guard();
+		if !self.contains(key)? {
+			return Err(Error::NotFound(format!("record `{}/{}`", self.name, key)));
+		}
+		self.tag_locked(key, tag)
+	}
+
+	/// Adds a tag to a record, which must exist. Must be called with the
+	/// write lock held.
+	pub(crate) fn tag_locked(&self, key: &str, tag: &str) -> Result<bool> {
+		let mut keys = self.read_tag(tag)?;
+		if !keys.insert(key.to_string()) {
+			return Ok(false);
+		}
+		self.db.log_change(self.tag_change(key, tag, true))?;
+		self.write_tag(tag, keys)?;
+		Ok(true)
+	}
+
+	/// Removes a tag from a record. Returns false if the record didn't have
+	/// it.
+	pub fn untag(&self, key: &str, tag: &str) -> Result<bool> {
+		self.db.check_writable()?;
+		self.record_path(key)?;
+		check_tag(tag)?;
+		let _guard = self.db.write_guard();
+		self.untag_locked(key, tag)
+	}
+
+	/// Returns the keys of the records with a tag, sorted. Expired records
+	/// are not included.
+	pub fn find_by_tag(&self, tag: &str) -> Result<Vec<String>> {
+		check_tag(tag)?;
+		let mut keys = Vec::new();
+		for key in self.read_tag(tag)? {
+			if self.contains(&key)? {
+				keys.push(key);
+			}
+		}
+		Ok(keys)
+	}
+
+	/// Returns the tags of a record, sorted.
+	pub fn tags(&self, key: &str) -> Result<Vec<String>> {
+		self.record_path(key)?;
+		let mut tags = Vec::new();
+		for tag in list_names(&self.tags_dir(), false)? {
+			if self.read_tag(&tag)?.contains(key) {
+				tags.push(tag);
+			}
+		}
+		Ok(tags)
+	}
+
+	/// Returns the tags of several records, sorted, reading each tag only
+	/// once. Every key is in the result, with no tags if it has none.
+	pub fn tags_of<S: AsRef<str>>(&self, keys: &[S]) -> Result<HashMap<String, Vec<String>>> {
+		let mut result = HashMap::new();
+		for key in keys {
+			self.record_path(key.as_ref())?;
+			result.insert(key.as_ref().to_string(), Vec::new());
+		}
+		for tag in list_names(&self.tags_dir(), false)? {
+			for key in self.read_tag(&tag)? {
+				if let Some(tags) = result.get_mut(&key) {
+					tags.push(tag.clone());
+				}
+			}
+		}
+		Ok(result)
+	}
+
+	/// Returns all tags in the collection with the number of records having
+	/// each, sorted by tag. Expired records are not counted.
+	pub fn tag_counts(&self) -> Result<Vec<(String, usize)>> {
+		let mut counts = Vec::new();
+		for tag in list_names(&self.tags_dir(), false)? {
+			let mut count = 0;
+			for key in self.read_tag(&tag)? {
+				if self.contains(&key)? {
+					count += 1;
+				}
+			}
+			if count > 0 {
+				counts.push((tag, count));
+			}
+		}
+		Ok(counts)
+	}
+
+	/// Returns the changes that remove the tags from a record, which are
+	/// made when it is deleted.
+	pub(crate) fn untag_changes(&self, key: &str) -> Result<Vec<Change>> {
+		let tags = self.tags(key)?;
+		Ok(tags
+			.into_iter()
+			.map(|tag| self.tag_change(key, &tag, false))
+			.collect())
+	}
+
+	/// Removes all tags from a record that is being deleted. Must be called
+	/// with the write lock held.
+	pub(crate) fn untag_for_delete(&self, key: &str) -> Result<()> {
+		for tag in self.tags(key)? {
+			self.untag_locked(key, &tag)?;
+		}
+		Ok(())
+	}
+
+	/// Removes a tag from a record. Must be called with the write lock held.
+	pub(crate) fn untag_locked(&self, key: &str, tag: &str) -> Result<bool> {
+		let mut keys = self.read_tag(tag)?;
+		if !keys.remove(key) {
+			return Ok(false);
+		}
+		self.db.log_change(self.tag_change(key, tag, false))?;
+		self.write_tag(tag, keys)?;
+		Ok(true)
+	}
+
+	fn tag_change(&self, key: &str, tag: &str, add: bool) -> Change {
+		let (collection, key, tag) = (self.name.clone(), key.to_string(), tag.to_string());
+		if add {
+			Change::Tag {
+				collection,
+				key,
+				tag,
+			}
+		} else {
+			Change::Untag {
+				collection,
+				key,
+				tag,
+			}
+		}
+	}
+
+	fn tags_dir(&self) -> PathBuf {
+		self.db.path.join(TAGS_DIR).join(&self.name)
+	}
+
+	pub(crate) fn read_tag(&self, tag: &str) -> Result<BTreeSet<String>> {
+		let path = self.tags_dir().join(tag);
+		let data = match self.db.read_file(&path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+			Err(err) => return Err(read_error(err, &path)),
+		};
+		Ok(parse_tag(&data))
+	}
+
+	pub(crate) fn write_tag(&self, tag: &str, keys: BTreeSet<String>) -> Result<()> {
+		let path = self.tags_dir().join(tag);
+		if keys.is_empty() {
+			return match fs::remove_file(&path) {
+				Err(err) if err.kind() != io::ErrorKind::NotFound => Err(write_error(err, &path)),
+				_ => Ok(()),
+			};
+		}
+		let mut text = String::new();
+		for key in keys {
+			text.push_str(&key);
+			text.push('\n');
+		}
+		fs::create_dir_all(self.tags_dir())
+			.and_then(|_| util::write_file(&path, text.as_bytes()))
+			.map_err(|err| write_error(err, &path))
+	}
+}
+
+/// Parses the keys listed in a tag file.
+pub(crate) fn parse_tag(data: &[u8]) -> BTreeSet<String> {
+	let text = String::from_utf8_lossy(data);
+	text.lines()
+		.filter(|key| is_valid_name(key))
+		.map(|key| key.to_string())
+		.collect()
+}
+
+pub(crate) fn check_tag(tag: &str) -> Result<()> {
+	if is_valid_name(tag) {
+		Ok(())
+	} else {
+		Err(Error::InvalidName(tag.to_string()))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::testing::create_db;
+	use crate::{Error, OpenFlags};
+
+	#[test]
+	fn should_tag_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		for key in &["a", "b", "c"] {
+			notes.put(key, b"").unwrap();
+		}
+
+		assert!(notes.tag("a", "todo").unwrap());
+		assert!(!notes.tag("a", "todo").unwrap());
+		assert!(notes.tag("c", "todo").unwrap());
+		assert!(notes.tag("a", "work").unwrap());
+		assert!(notes.tag("a", "bad tag").is_err());
+		match notes.tag("missing", "todo") {
+			Err(Error::NotFound(_)) => (),
+			other => panic!("expected Error::NotFound, got {:?}", other),
+		}
+
+		assert_eq!(notes.find_by_tag("todo").unwrap(), vec!["a", "c"]);
+		assert_eq!(notes.tags("a").unwrap(), vec!["todo", "work"]);
+		let tags = notes.tags_of(&["a", "b", "c"]).unwrap();
+		assert_eq!(tags["a"], vec!["todo", "work"]);
+		assert!(tags["b"].is_empty());
+		assert_eq!(tags["c"], vec!["todo"]);
+		assert!(notes.find_by_tag("other").unwrap().is_empty());
+		let counts = notes.tag_counts().unwrap();
+		assert_eq!(
+			counts,
+			vec![("todo".to_string(), 2), ("work".to_string(), 1)]
+		);
+
+		assert!(notes.untag("c", "todo").unwrap());
+		assert!(!notes.untag("c", "todo").unwrap());
+		assert_eq!(notes.find_by_tag("todo").unwrap(), vec!["a"]);
+
+		// Deleting a record removes its tags, in a transaction as well.
+		notes.delete("a").unwrap();
+		assert!(notes.find_by_tag("todo").unwrap().is_empty());
+		assert!(notes.find_by_tag("work").unwrap().is_empty());
+		notes.tag("b", "todo").unwrap();
+		let mut tx = db.transaction();
+		tx.delete("notes", "b").unwrap();
+		tx.commit().unwrap();
+		assert!(notes.find_by_tag("todo").unwrap().is_empty());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
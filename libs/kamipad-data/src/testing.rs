@@ -0,0 +1,14 @@
+//! Shared helpers for the library tests.
+
+use tempdir::TempDir;
+
+use crate::{open, Database, OpenFlags};
+
+/// Opens a new database in a temporary directory. The directory is removed
+/// once the returned `TempDir` is dropped.
+pub fn create_db(flags: OpenFlags) -> (Database, TempDir) {
+	let temp = TempDir::new("kamipad-data").unwrap();
+	let path = temp.path().join("db");
+	let db = open(&path, flags).unwrap();
+	(db, temp)
+}
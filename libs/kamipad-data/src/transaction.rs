@@ -0,0 +1,640 @@
+//! Transactions.
+//!
+//! A `Transaction` buffers writes to any number of collections in memory and
+//! applies them all at once on `Transaction::commit`, while holding the write
+//! lock. Other writers in the process and readers (see the `mvcc` module)
+//! never see the transaction partially applied. Dropping a transaction
+//! without committing discards its writes.
+//!
+//! Besides writing records, a transaction can add and remove tags, so that
+//! a record and its tags can be changed together.
+//!
+//! Savepoints mark a point in the transaction that it can be rolled back to
+//! with `Transaction::rollback_to`, undoing only the writes made after it.
+//!
+//! Committing is atomic through the journal. Before applying any write, the
+//! files affected by the transaction are copied to the `transaction`
+//! directory, and the sequence number of its first change is written to the
+//! `transaction/begin` file:
+//!
+//! ```text
+//! transaction/begin
+//! transaction/data/<collection>/<key>
+//! ```
+//!
+//! The changes are then logged and applied as usual, and the directory is
+//! removed once done. If the commit is interrupted, the next time the
+//! database is opened for writing the files for every change in the journal
+//! since the `begin` sequence number are restored from the saved copies, or
+//! removed if there is no copy. A commit that fails while applying its
+//! changes is rolled back the same way before returning. As with any
+//! interrupted write, the journal then lists changes that never happened.
+//!
+//! Blobs and their reference counts are changed the same way, with
+//! `Database::apply_atomically`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::collection::is_valid_name;
+use crate::database::Database;
+use crate::error::Error;
+use crate::journal::{self, Change};
+use crate::links::RecordId;
+use crate::record::Header;
+use crate::tags::check_tag;
+use crate::util::{self, read_error, write_error};
+use crate::Result;
+
+pub(crate) const TRANSACTION_DIR: &str = "transaction";
+
+const BEGIN_FILENAME: &str = "begin";
+
+/// A set of writes to the database that are applied together, created with
+/// `Database::transaction`.
+pub struct Transaction<'a> {
+	db: &'a Database,
+	writes: Vec<Write>,
+	savepoints: Vec<usize>,
+}
+
+/// A point in a transaction that can be rolled back to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Savepoint(usize);
+
+enum Write {
+	Put {
+		collection: String,
+		key: String,
+		value: Vec<u8>,
+	},
+	Delete {
+		collection: String,
+		key: String,
+	},
+	Tag {
+		collection: String,
+		key: String,
+		tag: String,
+		add: bool,
+	},
+}
+
+impl Write {
+	fn change(&self) -> Change {
+		match self {
+			Write::Put {
+				collection, key, ..
+			} => Change::Put {
+				collection: collection.clone(),
+				key: key.clone(),
+			},
+			Write::Delete { collection, key } => Change::Delete {
+				collection: collection.clone(),
+				key: key.clone(),
+			},
+			Write::Tag {
+				collection,
+				key,
+				tag,
+				add,
+			} => {
+				let (collection, key, tag) = (collection.clone(), key.clone(), tag.clone());
+				if *add {
+					Change::Tag {
+						collection,
+						key,
+						tag,
+					}
+				} else {
+					Change::Untag {
+						collection,
+						key,
+						tag,
+					}
+				}
+			}
+		}
+	}
+
+	fn is_record(&self, collection: &str, key: &str) -> bool {
+		match self {
+			Write::Put {
+				collection: c,
+				key: k,
+				..
+			}
+			| Write::Delete {
+				collection: c,
+				key: k,
+			} => c == collection && k == key,
+			Write::Tag { .. } => false,
+		}
+	}
+}
+
+impl Database {
+	/// Starts a new transaction.
+	pub fn transaction(&self) -> Transaction<'_> {
+		Transaction {
+			db: self,
+			writes: Vec::new(),
+			savepoints: Vec::new(),
+		}
+	}
+}
+
+impl<'a> Transaction<'a> {
+	/// Reads a record, including the writes made in the transaction.
+	pub fn get(&self, collection: &str, key: &str) -> Result<Option<Vec<u8>>> {
+		check_names(collection, key)?;
+		let write = self
+			.writes
+			.iter()
+			.rev()
+			.find(|write| write.is_record(collection, key));
+		match write {
+			Some(Write::Put { value, .. }) => Ok(Some(value.clone())),
+			Some(Write::Delete { .. }) => Ok(None),
+			Some(Write::Tag { .. }) | None => self.db.collection(collection)?.get(key),
+		}
+	}
+
+	/// Writes a record when the transaction is committed.
+	pub fn put(&mut self, collection: &str, key: &str, value: &[u8]) -> Result<()> {
+		check_names(collection, key)?;
+		self.writes.push(Write::Put {
+			collection: collection.to_string(),
+			key: key.to_string(),
+			value: value.to_vec(),
+		});
+		Ok(())
+	}
+
+	/// Deletes a record when the transaction is committed.
+	pub fn delete(&mut self, collection: &str, key: &str) -> Result<()> {
+		check_names(collection, key)?;
+		self.writes.push(Write::Delete {
+			collection: collection.to_string(),
+			key: key.to_string(),
+		});
+		Ok(())
+	}
+
+	/// Adds a tag to a record when the transaction is committed. The record
+	/// must exist by then, or the commit fails with `Error::NotFound`.
+	pub fn tag(&mut self, collection: &str, key: &str, tag: &str) -> Result<()> {
+		self.push_tag(collection, key, tag, true)
+	}
+
+	/// Removes a tag from a record when the transaction is committed.
+	pub fn untag(&mut self, collection: &str, key: &str, tag: &str) -> Result<()> {
+		self.push_tag(collection, key, tag, false)
+	}
+
+	fn push_tag(&mut self, collection: &str, key: &str, tag: &str, add: bool) -> Result<()> {
+		check_names(collection, key)?;
+		check_tag(tag)?;
+		self.writes.push(Write::Tag {
+			collection: collection.to_string(),
+			key: key.to_string(),
+			tag: tag.to_string(),
+			add,
+		});
+		Ok(())
+	}
+
+	/// Creates a savepoint at the current point of the transaction.
+	pub fn savepoint(&mut self) -> Savepoint {
+		self.savepoints.push(self.writes.len());
+		Savepoint(self.savepoints.len() - 1)
+	}
+
+	/// Undoes the writes made after the savepoint was created. The savepoint
+	/// remains valid, but any savepoint created after it is released.
+	///
+	/// Fails with `Error::NotFound` if the savepoint was released.
+	pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<()> {
+		let Savepoint(index) = savepoint;
+		let len = match self.savepoints.get(index) {
+			Some(&len) => len,
+			None => return Err(Error::NotFound("savepoint".into())),
+		};
+		self.writes.truncate(len);
+		self.savepoints.truncate(index + 1);
+		Ok(())
+	}
+
+	/// Applies all writes in the transaction atomically.
+	pub fn commit(self) -> Result<()> {
+		let db = self.db;
+		db.check_writable()?;
+		if self.writes.is_empty() {
+			return Ok(());
+		}
+
+		// Check everything before applying anything, adding the deletes from
+		// cascade rules. Records can be deleted if they are only linked from
+		// records deleted before them.
+		let _guard = db.write_guard();
+		let mut writes = Vec::new();
+		let mut deleted = Vec::new();
+		for write in self.writes {
+			let id = match &write {
+				Write::Put {
+					collection,
+					key,
+					value,
+				} => {
+					db.validate(collection, key, value)?;
+					writes.push(write);
+					continue;
+				}
+				Write::Tag {
+					collection,
+					key,
+					add: true,
+					..
+				} => {
+					let last = writes.iter().rev().find(|it| it.is_record(collection, key));
+					let exists = match last {
+						Some(Write::Put { .. }) => true,
+						Some(_) => false,
+						None => db.collection(collection.as_str())?.contains(key)?,
+					};
+					if !exists {
+						let id = format!("{}/{}", collection, key);
+						return Err(Error::NotFound(format!("record `{}`", id)));
+					}
+					writes.push(write);
+					continue;
+				}
+				Write::Tag { .. } => {
+					writes.push(write);
+					continue;
+				}
+				Write::Delete { collection, key } => {
+					RecordId::new(collection.as_str(), key.as_str())
+				}
+			};
+			let cascaded = db.cascaded_deletes(&id, &deleted)?;
+			for id in std::iter::once(id).chain(cascaded) {
+				db.check_unlinked(&id, &deleted)?;
+				writes.push(Write::Delete {
+					collection: id.collection.clone(),
+					key: id.key.clone(),
+				});
+				deleted.push(id);
+			}
+		}
+
+		let (mut bytes, mut records) = (0, 0);
+		for write in writes.iter() {
+			let (b, r) = match write {
+				Write::Put {
+					collection,
+					key,
+					value,
+				} => db
+					.collection(collection.as_str())?
+					.put_usage(key, value.len())?,
+				Write::Delete { collection, key } => {
+					db.collection(collection.as_str())?.delete_usage(key)?
+				}
+				Write::Tag { .. } => (0, 0),
+			};
+			bytes += b;
+			records += r;
+		}
+		db.check_quota(bytes, records)?;
+
+		let mut changes = writes
+			.iter()
+			.map(|write| write.change())
+			.collect::<Vec<_>>();
+		for id in deleted.iter() {
+			let collection = db.collection(id.collection.as_str())?;
+			changes.extend(db.unlink_changes(id)?);
+			changes.extend(collection.untag_changes(&id.key)?);
+		}
+		db.apply_atomically(&changes, || apply(db, writes))
+	}
+
+	/// Discards all writes in the transaction. This is the same as dropping
+	/// it.
+	pub fn rollback(self) {}
+}
+
+impl Database {
+	/// Runs `apply`, which must log and make the given changes, atomically
+	/// through the journal the same as for committing a transaction. If it
+	/// fails, the changes are rolled back before returning. Must be called
+	/// with the write lock held.
+	pub(crate) fn apply_atomically<T, F>(&self, changes: &[Change], apply: F) -> Result<T>
+	where
+		F: FnOnce() -> Result<T>,
+	{
+		// Copies left by a commit that failed before writing the begin file
+		// are stale, and must not be restored if this one is rolled back.
+		let dir = self.path.join(TRANSACTION_DIR);
+		recover(&self.path)?;
+		for change in changes {
+			self.save_files(&dir, change)?;
+		}
+		let begin_path = dir.join(BEGIN_FILENAME);
+		let begin = self.sequence() + 1;
+		util::write_file(&begin_path, begin.to_string().as_bytes())
+			.map_err(|err| write_error(err, &begin_path))?;
+
+		let result = match apply() {
+			Ok(result) => result,
+			Err(err) => {
+				recover(&self.path)?;
+				self.cache().clear();
+				self.reset_usage();
+				return Err(err);
+			}
+		};
+
+		// Removing the begin file is what completes the transaction.
+		fs::remove_file(&begin_path).map_err(|err| write_error(err, &begin_path))?;
+		fs::remove_dir_all(&dir).map_err(|err| write_error(err, &dir))?;
+		Ok(result)
+	}
+}
+
+/// Applies the writes of a transaction being committed.
+fn apply(db: &Database, writes: Vec<Write>) -> Result<()> {
+	for write in writes {
+		match write {
+			Write::Put {
+				collection,
+				key,
+				value,
+			} => {
+				let collection = db.collection(collection)?;
+				collection.put_locked(&key, &value, Header::default(), None)?;
+			}
+			Write::Delete { collection, key } => {
+				db.collection(collection)?.delete_locked(&key)?;
+			}
+			Write::Tag {
+				collection,
+				key,
+				tag,
+				add,
+			} => {
+				let collection = db.collection(collection)?;
+				if add {
+					collection.tag_locked(&key, &tag)?;
+				} else {
+					collection.untag_locked(&key, &tag)?;
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Rolls back a transaction interrupted while being committed, if any. Must
+/// be called with the database locked for writing.
+pub(crate) fn recover(root: &Path) -> Result<()> {
+	let dir = root.join(TRANSACTION_DIR);
+	let begin_path = dir.join(BEGIN_FILENAME);
+	let begin = match fs::read_to_string(&begin_path) {
+		Ok(text) => Some(text.trim().parse::<u64>().map_err(|_| {
+			read_error(
+				io::Error::new(io::ErrorKind::InvalidData, "invalid sequence number"),
+				&begin_path,
+			)
+		})?),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+		Err(err) => return Err(read_error(err, &begin_path)),
+	};
+
+	// Without the begin file, no change was applied.
+	if let Some(begin) = begin {
+		for entry in journal::read_journal(root)? {
+			if entry.seq < begin {
+				continue;
+			}
+			for relative in entry.change.paths() {
+				let saved = dir.join(&relative);
+				let target = root.join(&relative);
+				let result = match fs::read(&saved) {
+					Ok(data) => util::write_file(&target, &data),
+					Err(err) if err.kind() == io::ErrorKind::NotFound => {
+						match fs::remove_file(&target) {
+							Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+							result => result,
+						}
+					}
+					Err(err) => return Err(read_error(err, &saved)),
+				};
+				result.map_err(|err| write_error(err, &target))?;
+			}
+		}
+		fs::remove_file(&begin_path).map_err(|err| write_error(err, &begin_path))?;
+	}
+
+	match fs::remove_dir_all(&dir) {
+		Err(err) if err.kind() != io::ErrorKind::NotFound => Err(write_error(err, &dir)),
+		_ => Ok(()),
+	}
+}
+
+fn check_names(collection: &str, key: &str) -> Result<()> {
+	if is_valid_name(collection) && is_valid_name(key) {
+		Ok(())
+	} else {
+		Err(Error::InvalidName(format!("{}/{}", collection, key)))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_commit_transactions() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		let tags = db.collection("tags").unwrap();
+		notes.put("a", b"A").unwrap();
+
+		let mut tx = db.transaction();
+		tx.put("notes", "b", b"B").unwrap();
+		tx.delete("notes", "a").unwrap();
+		tx.put("tags", "x", b"b").unwrap();
+		assert!(tx.get("notes", "a").unwrap().is_none());
+		assert_eq!(tx.get("notes", "b").unwrap().unwrap(), b"B");
+		assert!(tx.put("notes", "../x", b"").is_err());
+
+		// Nothing is written until committed.
+		assert_eq!(notes.keys().unwrap(), vec!["a"]);
+		tx.commit().unwrap();
+		assert_eq!(notes.keys().unwrap(), vec!["b"]);
+		assert_eq!(tags.get("x").unwrap().unwrap(), b"b");
+		assert_eq!(db.sequence(), 4);
+		assert!(!db.path.join(TRANSACTION_DIR).exists());
+
+		let mut tx = db.transaction();
+		tx.put("notes", "c", b"C").unwrap();
+		tx.rollback();
+		assert!(notes.get("c").unwrap().is_none());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_rollback_to_savepoint() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+
+		let mut tx = db.transaction();
+		tx.put("notes", "a", b"A1").unwrap();
+		let first = tx.savepoint();
+		tx.put("notes", "a", b"A2").unwrap();
+		let second = tx.savepoint();
+		tx.put("notes", "b", b"B").unwrap();
+
+		tx.rollback_to(second).unwrap();
+		assert!(tx.get("notes", "b").unwrap().is_none());
+		assert_eq!(tx.get("notes", "a").unwrap().unwrap(), b"A2");
+
+		// Rolling back to the first savepoint releases the second.
+		tx.put("notes", "c", b"C").unwrap();
+		tx.rollback_to(first).unwrap();
+		assert_eq!(tx.get("notes", "a").unwrap().unwrap(), b"A1");
+		assert!(tx.get("notes", "c").unwrap().is_none());
+		match tx.rollback_to(second) {
+			Err(Error::NotFound(_)) => (),
+			other => panic!("expected Error::NotFound, got {:?}", other),
+		}
+
+		tx.put("notes", "d", b"D").unwrap();
+		tx.commit().unwrap();
+		assert_eq!(notes.keys().unwrap(), vec!["a", "d"]);
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A1");
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_recover_interrupted_transaction() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A1").unwrap();
+		notes.put("b", b"B1").unwrap();
+
+		// Simulate a commit interrupted after applying some of its changes,
+		// by saving the files and making the changes by hand.
+		let dir = db.path.join(TRANSACTION_DIR);
+		let change = |key: &str| notes.change(key, true);
+		for key in &["a", "b", "c"] {
+			db.save_files(&dir, &change(key)).unwrap();
+		}
+		fs::write(dir.join(BEGIN_FILENAME), "3").unwrap();
+		notes.put("a", b"A2").unwrap();
+		notes.delete("b").unwrap();
+		notes.put("c", b"C").unwrap();
+
+		let path = db.path.clone();
+		drop(db);
+		let db = open(&path, OpenFlags::default()).unwrap();
+		let notes = db.collection("notes").unwrap();
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A1");
+		assert_eq!(notes.get("b").unwrap().unwrap(), b"B1");
+		assert!(notes.get("c").unwrap().is_none());
+		assert!(!dir.exists());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_rollback_failed_commit() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A1").unwrap();
+
+		// Fail the second validation of `c`, which happens while applying.
+		let calls = Arc::new(AtomicUsize::new(0));
+		let counter = calls.clone();
+		db.add_validator("notes", move |key, _| {
+			if key == "c" && counter.fetch_add(1, Ordering::SeqCst) == 1 {
+				Err("failed".to_string())
+			} else {
+				Ok(())
+			}
+		});
+
+		// A copy left by an earlier commit must not be restored.
+		let dir = db.path.join(TRANSACTION_DIR);
+		fs::create_dir_all(dir.join("data").join("notes")).unwrap();
+		fs::write(dir.join("data").join("notes").join("b"), b"stale").unwrap();
+
+		let mut tx = db.transaction();
+		tx.put("notes", "a", b"A2").unwrap();
+		tx.put("notes", "b", b"B").unwrap();
+		tx.put("notes", "c", b"C").unwrap();
+		assert!(tx.commit().is_err());
+		assert_eq!(calls.load(Ordering::SeqCst), 2);
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A1");
+		assert!(notes.get("b").unwrap().is_none());
+		assert!(notes.get("c").unwrap().is_none());
+		assert!(!dir.exists());
+
+		let mut tx = db.transaction();
+		tx.put("notes", "b", b"B").unwrap();
+		tx.commit().unwrap();
+		assert_eq!(notes.keys().unwrap(), vec!["a", "b"]);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_commit_tags() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.tag("a", "old").unwrap();
+
+		let mut tx = db.transaction();
+		tx.put("notes", "b", b"B").unwrap();
+		tx.tag("notes", "a", "new").unwrap();
+		tx.tag("notes", "b", "new").unwrap();
+		tx.untag("notes", "a", "old").unwrap();
+		assert!(tx.tag("notes", "a", "../x").is_err());
+
+		// Nothing is written until committed.
+		assert_eq!(notes.tags("a").unwrap(), vec!["old"]);
+		tx.commit().unwrap();
+		assert_eq!(notes.tags("a").unwrap(), vec!["new"]);
+		assert_eq!(notes.find_by_tag("new").unwrap(), vec!["a", "b"]);
+		assert!(notes.find_by_tag("old").unwrap().is_empty());
+
+		// Tagging a missing record fails the whole transaction.
+		let mut tx = db.transaction();
+		tx.untag("notes", "a", "new").unwrap();
+		tx.delete("notes", "b").unwrap();
+		tx.tag("notes", "b", "other").unwrap();
+		match tx.commit() {
+			Err(Error::NotFound(_)) => (),
+			other => panic!("expected Error::NotFound, got {:?}", other),
+		}
+		assert_eq!(notes.find_by_tag("new").unwrap(), vec!["a", "b"]);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,168 @@
+//! Trash for deleted records.
+//!
+//! With `OpenFlags::soft_delete`, deleted records are moved to the `trash`
+//! directory instead of being removed, using the same layout as the `data`
+//! directory. The record file is kept as is, with a `deleted` attribute
+//! added to its header with the deletion time.
+//!
+//! Deleting a record that is already in the trash replaces the previous
+//! version in the trash.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::collection::{list_names, Collection};
+use crate::database::Database;
+use crate::journal::now;
+use crate::record;
+use crate::util::{self, corrupt_error, read_error, write_error};
+use crate::Result;
+
+pub(crate) const TRASH_DIR: &str = "trash";
+
+impl<'a> Collection<'a> {
+	/// Restores a deleted record from the trash, replacing any existing
+	/// record with the same key. Returns false if the record is not in the
+	/// trash.
+	pub fn restore(&self, key: &str) -> Result<bool> {
+		self.db.check_writable()?;
+		let path = self.record_path(key)?;
+		let trash_path = self.trash_path().join(key);
+
+		let _guard = self.db.write_guard();
+		let data = match fs::read(&trash_path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+			Err(err) => return Err(read_error(err, &trash_path)),
+		};
+		let data = record::rewrite_header(&data, |header| header.deleted = None)
+			.map_err(|err| corrupt_error(err, &trash_path))?;
+		let (bytes, records) = self.put_usage(key, data.len())?;
+		self.db.check_quota(bytes, records)?;
+
+		self.db.log_change(self.change(key, true))?;
+		fs::create_dir_all(&self.path)
+			.and_then(|_| util::write_file(&path, &data))
+			.map_err(|err| write_error(err, &path))?;
+		self.db.update_usage(bytes, records);
+		fs::remove_file(&trash_path).map_err(|err| write_error(err, &trash_path))?;
+		Ok(true)
+	}
+
+	/// Returns the keys for all records of the collection in the trash,
+	/// sorted.
+	pub fn trashed(&self) -> Result<Vec<String>> {
+		list_names(&self.trash_path(), false)
+	}
+
+	/// Moves a record to the trash. Must be called with the write lock held.
+	pub(crate) fn move_to_trash(&self, key: &str) -> Result<()> {
+		let path = self.record_path(key)?;
+		let trash_path = self.trash_path().join(key);
+		let data = fs::read(&path).map_err(|err| read_error(err, &path))?;
+		let data = record::rewrite_header(&data, |header| header.deleted = Some(now()))
+			.map_err(|err| corrupt_error(err, &path))?;
+
+		fs::create_dir_all(self.trash_path())
+			.and_then(|_| util::write_file(&trash_path, &data))
+			.map_err(|err| write_error(err, &trash_path))?;
+		fs::remove_file(&path).map_err(|err| write_error(err, &path))
+	}
+
+	fn trash_path(&self) -> PathBuf {
+		self.db.path.join(TRASH_DIR).join(&self.name)
+	}
+}
+
+impl Database {
+	/// Permanently removes records deleted more than `older_than` ago from
+	/// the trash, returning the number of records removed.
+	pub fn purge_trash(&self, older_than: Duration) -> Result<usize> {
+		self.check_writable()?;
+		let _guard = self.write_guard();
+
+		let cutoff = now().saturating_sub(older_than.as_millis() as u64);
+		let trash_dir = self.path.join(TRASH_DIR);
+		let mut count = 0;
+		for collection in list_names(&trash_dir, true)? {
+			let collection_dir = trash_dir.join(&collection);
+			for key in list_names(&collection_dir, false)? {
+				let path = collection_dir.join(&key);
+				let data = fs::read(&path).map_err(|err| read_error(err, &path))?;
+				// Damaged entries in the trash are purged right away.
+				let deleted = record::read_header(&data)
+					.ok()
+					.and_then(|header| header.deleted)
+					.unwrap_or(0);
+				if deleted <= cutoff {
+					fs::remove_file(&path).map_err(|err| write_error(err, &path))?;
+					count += 1;
+				}
+			}
+		}
+		Ok(count)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_move_deleted_records_to_trash() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.soft_delete = true));
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.put("b", b"B").unwrap();
+
+		assert!(notes.delete("a").unwrap());
+		assert!(notes.get("a").unwrap().is_none());
+		assert_eq!(notes.keys().unwrap(), vec!["b"]);
+		assert_eq!(notes.trashed().unwrap(), vec!["a"]);
+
+		let raw = fs::read(db.path.join(TRASH_DIR).join("notes").join("a")).unwrap();
+		assert!(raw.starts_with(b"#record deleted="));
+
+		assert!(notes.restore("a").unwrap());
+		assert!(!notes.restore("a").unwrap());
+		assert_eq!(notes.get("a").unwrap().unwrap(), b"A");
+		assert!(notes.trashed().unwrap().is_empty());
+		assert!(db.check().unwrap().is_ok());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_purge_trash() {
+		let (db, temp) = create_db(OpenFlags::config(|f| f.soft_delete = true));
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.delete("a").unwrap();
+
+		assert_eq!(db.purge_trash(Duration::from_secs(3600)).unwrap(), 0);
+		assert_eq!(notes.trashed().unwrap(), vec!["a"]);
+		assert_eq!(db.purge_trash(Duration::from_secs(0)).unwrap(), 1);
+		assert!(notes.trashed().unwrap().is_empty());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_remove_records_without_soft_delete() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		notes.delete("a").unwrap();
+		assert!(notes.trashed().unwrap().is_empty());
+		assert!(!notes.restore("a").unwrap());
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,109 @@
+//! Internal file system helpers.
+
+use memmap::Mmap;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+use crate::error::{Error, IOError};
+
+use crate::fs::TEMP_EXTENSION;
+
+/// Writes a file by first writing to a temporary file alongside it and then
+/// renaming it into place, so that readers never see a partially written
+/// file.
+///
+/// The file is not synced, see `Database::flush`. Use `fs::atomic_write`
+/// for files that must survive a crash once written.
+pub(crate) fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+	crate::fs::write_replacing(path.as_ref(), data, false)
+}
+
+/// Contents of a file, either read into memory or memory-mapped.
+pub(crate) enum FileData {
+	Buffer(Vec<u8>),
+	Mapped(Mmap),
+}
+
+impl Deref for FileData {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match self {
+			FileData::Buffer(data) => data,
+			FileData::Mapped(map) => map,
+		}
+	}
+}
+
+/// Reads a file, optionally memory-mapping it instead of reading it into
+/// memory.
+pub(crate) fn read_file<P: AsRef<Path>>(path: P, mmap: bool) -> io::Result<FileData> {
+	if !mmap {
+		return fs::read(path).map(FileData::Buffer);
+	}
+
+	let file = fs::File::open(path)?;
+	// Empty files cannot be mapped.
+	if file.metadata()?.len() == 0 {
+		return Ok(FileData::Buffer(Vec::new()));
+	}
+
+	// Safety: database files are never modified in place, since writes
+	// always replace the file with `write_file`. A replaced file stays
+	// mapped until dropped, so the mapping never changes under us.
+	let map = unsafe { Mmap::map(&file)? };
+	Ok(FileData::Mapped(map))
+}
+
+/// Wraps an IO error from reading the given path into an `Error::Read`.
+pub(crate) fn read_error(err: io::Error, path: &Path) -> Error {
+	Error::Read(IOError::new(
+		err,
+		format!("reading `{}`", path.to_string_lossy()),
+	))
+}
+
+/// Wraps an IO error from writing the given path into an `Error::Write`.
+pub(crate) fn write_error(err: io::Error, path: &Path) -> Error {
+	Error::Write(IOError::new(
+		err,
+		format!("writing `{}`", path.to_string_lossy()),
+	))
+}
+
+/// Wraps an error from decoding the file at the given path into an
+/// `Error::Corrupt`.
+pub(crate) fn corrupt_error(err: io::Error, path: &Path) -> Error {
+	Error::Corrupt(IOError::new(
+		err,
+		format!("decoding `{}`", path.to_string_lossy()),
+	))
+}
+
+/// Creates an `InvalidData` IO error with the given message.
+pub(crate) fn invalid_data<S: Into<String>>(message: S) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Encodes bytes as a lowercase hex string.
+pub(crate) fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a hex string. Returns `None` if the string is not valid hex.
+pub(crate) fn from_hex(text: &str) -> Option<Vec<u8>> {
+	if text.len() % 2 != 0 || !text.is_ascii() {
+		return None;
+	}
+	(0..text.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+		.collect()
+}
+
+/// Returns true if the path is a temporary file left by `write_file`.
+pub(crate) fn is_temp_file<P: AsRef<Path>>(path: P) -> bool {
+	path.as_ref().extension() == Some(TEMP_EXTENSION.as_ref())
+}
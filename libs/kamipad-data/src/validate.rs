@@ -0,0 +1,114 @@
+//! Validation of records before they are written.
+//!
+//! Validators are registered per collection with `Database::add_validator`
+//! and run on every record written to the collection, including writes from
+//! a `Transaction`. A record rejected by any validator is not written, and
+//! the write fails with `Error::Validation`.
+//!
+//! Validators are kept in memory only, so they must be registered every time
+//! the database is opened.
+
+use std::collections::HashMap;
+
+use crate::database::Database;
+use crate::error::{Error, ValidationError};
+use crate::Result;
+
+type Validator = Box<dyn Fn(&str, &[u8]) -> std::result::Result<(), String> + Send + Sync>;
+
+/// Validators registered on a database, by collection.
+#[derive(Default)]
+pub(crate) struct Validators {
+	by_collection: HashMap<String, Vec<Validator>>,
+}
+
+impl Database {
+	/// Registers a validator for records in a collection.
+	///
+	/// The validator is called with the key and value of each record before
+	/// it is written, and returns a message describing the problem to reject
+	/// it.
+	pub fn add_validator<F>(&self, collection: &str, validator: F)
+	where
+		F: Fn(&str, &[u8]) -> std::result::Result<(), String> + Send + Sync + 'static,
+	{
+		self.validators()
+			.by_collection
+			.entry(collection.to_string())
+			.or_default()
+			.push(Box::new(validator));
+	}
+
+	/// Runs the validators for a collection on a record.
+	pub(crate) fn validate(&self, collection: &str, key: &str, value: &[u8]) -> Result<()> {
+		let validators = self.validators();
+		let validators = match validators.by_collection.get(collection) {
+			Some(validators) => validators,
+			None => return Ok(()),
+		};
+		for validator in validators {
+			if let Err(message) = validator(key, value) {
+				return Err(Error::Validation(ValidationError {
+					collection: collection.to_string(),
+					key: key.to_string(),
+					message,
+				}));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Validator that only accepts values that are valid JSON.
+pub fn validate_json(_key: &str, value: &[u8]) -> std::result::Result<(), String> {
+	serde_json::from_slice::<serde_json::Value>(value)
+		.map(|_| ())
+		.map_err(|err| format!("invalid JSON: {}", err))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::OpenFlags;
+
+	#[test]
+	fn should_validate_records() {
+		let (db, temp) = create_db(OpenFlags::default());
+		db.add_validator("notes", validate_json);
+		db.add_validator("notes", |key, _| {
+			if key.starts_with("tmp") {
+				Err("temporary keys are not allowed".into())
+			} else {
+				Ok(())
+			}
+		});
+
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", br#"{"title":"A"}"#).unwrap();
+		match notes.put("b", b"not json") {
+			Err(Error::Validation(err)) => {
+				assert_eq!(err.collection, "notes");
+				assert_eq!(err.key, "b");
+				assert!(err.message.starts_with("invalid JSON"));
+			}
+			other => panic!("expected Error::Validation, got {:?}", other),
+		}
+		assert!(notes.put("tmp1", b"{}").is_err());
+		db.collection("tags")
+			.unwrap()
+			.put("x", b"not json")
+			.unwrap();
+
+		// Nothing from a transaction is written if a record is rejected.
+		let mut tx = db.transaction();
+		tx.put("notes", "c", b"{}").unwrap();
+		tx.put("notes", "d", b"not json").unwrap();
+		assert!(tx.commit().is_err());
+		assert_eq!(notes.keys().unwrap(), vec!["a"]);
+		assert_eq!(db.sequence(), 2);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,247 @@
+//! Sanity checks when opening the database.
+//!
+//! With `OpenFlags::verify`, opening a database checks its structure and
+//! fails with `Error::Verify` if anything is wrong:
+//!
+//! - Every known entry at the root has the expected type, and the `data`
+//!   directory only contains collection directories.
+//! - The manifest can be read and is not at a format version newer than
+//!   the library. Older versions are still migrated as usual.
+//! - The lock file is a regular file, with readable owner information if
+//!   any.
+//!
+//! Unlike `Database::check`, the contents of records and blobs are not
+//! checked, so this is cheap enough to run on every open. Unknown entries
+//! are also left for `Database::check` to report.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::blob::BLOBS_DIR;
+use crate::check::QUARANTINE_DIR;
+use crate::collection::DATA_DIR;
+use crate::crypto::ENCRYPTION_FILENAME;
+use crate::error::Error;
+use crate::history::HISTORY_DIR;
+use crate::journal::JOURNAL_FILENAME;
+use crate::links::{BACKLINKS_DIR, LINKS_DIR};
+use crate::lock::LockInfo;
+use crate::manifest::{self, MANIFEST_FILENAME};
+use crate::mvcc::{COMMIT_LOCK_FILENAME, READERS_DIR};
+use crate::open::DB_LOCK_FILENAME;
+use crate::snapshot::UNDO_DIR;
+use crate::sync::SYNC_DIR;
+use crate::tags::TAGS_DIR;
+use crate::transaction::TRANSACTION_DIR;
+use crate::trash::TRASH_DIR;
+use crate::util::{is_temp_file, read_error};
+use crate::Result;
+
+const ROOT_DIRS: &[&str] = &[
+	DATA_DIR,
+	BLOBS_DIR,
+	HISTORY_DIR,
+	UNDO_DIR,
+	TRASH_DIR,
+	QUARANTINE_DIR,
+	READERS_DIR,
+	TRANSACTION_DIR,
+	LINKS_DIR,
+	BACKLINKS_DIR,
+	TAGS_DIR,
+	SYNC_DIR,
+];
+
+const ROOT_FILES: &[&str] = &[
+	DB_LOCK_FILENAME,
+	COMMIT_LOCK_FILENAME,
+	JOURNAL_FILENAME,
+	MANIFEST_FILENAME,
+	ENCRYPTION_FILENAME,
+];
+
+/// Problem found when opening a database with `OpenFlags::verify`, used in
+/// `Error::Verify`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum VerifyError {
+	/// An entry has the wrong type, such as a file where a directory is
+	/// expected. Has the path relative to the database root.
+	Layout(PathBuf),
+	/// The manifest can't be read or is at an unsupported format version.
+	Manifest(String),
+	/// The lock file is not a regular file or its owner information can't
+	/// be read.
+	LockFile(String),
+}
+
+impl fmt::Display for VerifyError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			VerifyError::Layout(path) => {
+				write!(f, "unexpected entry type for `{}`", path.to_string_lossy())
+			}
+			VerifyError::Manifest(reason) => write!(f, "invalid manifest: {}", reason),
+			VerifyError::LockFile(reason) => write!(f, "invalid lock file: {}", reason),
+		}
+	}
+}
+
+/// Verifies the database at the given root. Must be called before preparing
+/// the manifest, so that problems with it are reported here.
+pub(crate) fn verify(root: &Path) -> Result<()> {
+	verify_layout(root)?;
+	verify_manifest(root)?;
+	verify_lock_file(root)
+}
+
+fn verify_layout(root: &Path) -> Result<()> {
+	for (names, dirs) in &[(ROOT_DIRS, true), (ROOT_FILES, false)] {
+		for name in names.iter() {
+			check_type(root, Path::new(name), *dirs)?;
+		}
+	}
+
+	let data_dir = root.join(DATA_DIR);
+	let entries = match fs::read_dir(&data_dir) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(read_error(err, &data_dir)),
+	};
+	for entry in entries {
+		let path = entry.map_err(|err| read_error(err, &data_dir))?.path();
+		if !is_temp_file(&path) {
+			check_type(root, path.strip_prefix(root).unwrap(), true)?;
+		}
+	}
+	Ok(())
+}
+
+/// Checks that an entry is a directory or a regular file, if it exists.
+fn check_type(root: &Path, relative: &Path, dir: bool) -> Result<()> {
+	let path = root.join(relative);
+	let metadata = match fs::symlink_metadata(&path) {
+		Ok(metadata) => metadata,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(read_error(err, &path)),
+	};
+	let valid = if dir {
+		metadata.is_dir()
+	} else {
+		metadata.is_file()
+	};
+	if valid {
+		Ok(())
+	} else {
+		Err(Error::Verify(VerifyError::Layout(relative.to_path_buf())))
+	}
+}
+
+fn verify_manifest(root: &Path) -> Result<()> {
+	let invalid = |reason: String| Err(Error::Verify(VerifyError::Manifest(reason)));
+	match manifest::read_version(root) {
+		Ok(Some(version)) if version > crate::FORMAT_VERSION => {
+			invalid(format!("unsupported format version {}", version))
+		}
+		Ok(_) => Ok(()),
+		Err(Error::Open(err)) => invalid(err.to_string()),
+		Err(err) => Err(err),
+	}
+}
+
+fn verify_lock_file(root: &Path) -> Result<()> {
+	let path = root.join(DB_LOCK_FILENAME);
+	let invalid = |reason: &str| Err(Error::Verify(VerifyError::LockFile(reason.to_string())));
+	match fs::symlink_metadata(&path) {
+		Ok(metadata) if metadata.is_file() => {}
+		Ok(_) => return invalid("not a regular file"),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return invalid("missing"),
+		Err(err) => return Err(read_error(err, &path)),
+	}
+
+	let text = match fs::read(&path) {
+		Ok(data) => data,
+		Err(err) => return Err(read_error(err, &path)),
+	};
+	let text = match String::from_utf8(text) {
+		Ok(text) => text,
+		Err(_) => return invalid("owner information is not valid text"),
+	};
+	if !text.trim().is_empty() && LockInfo::parse(&text).is_none() {
+		return invalid("unreadable owner information");
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	fn verify_flags() -> OpenFlags {
+		OpenFlags::config(|f| f.verify = true)
+	}
+
+	fn verify_error(path: &Path) -> VerifyError {
+		match open(path, verify_flags()) {
+			Err(Error::Verify(err)) => err,
+			other => panic!("unexpected result: {:?}", other.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn should_verify_on_open() {
+		let (db, temp) = create_db(verify_flags());
+		db.collection("notes").unwrap().put("a", b"A").unwrap();
+		let path = db.path.clone();
+		drop(db);
+		assert!(open(&path, verify_flags()).is_ok());
+
+		// Layout
+		fs::write(path.join(DATA_DIR).join("stray"), b"").unwrap();
+		assert_eq!(
+			verify_error(&path),
+			VerifyError::Layout(Path::new(DATA_DIR).join("stray"))
+		);
+		fs::remove_file(path.join(DATA_DIR).join("stray")).unwrap();
+		fs::remove_file(path.join(JOURNAL_FILENAME)).unwrap();
+		fs::create_dir(path.join(JOURNAL_FILENAME)).unwrap();
+		assert_eq!(
+			verify_error(&path),
+			VerifyError::Layout(PathBuf::from(JOURNAL_FILENAME))
+		);
+		fs::remove_dir(path.join(JOURNAL_FILENAME)).unwrap();
+
+		// Manifest
+		fs::write(path.join(MANIFEST_FILENAME), "garbage").unwrap();
+		match verify_error(&path) {
+			VerifyError::Manifest(_) => {}
+			other => panic!("unexpected error: {:?}", other),
+		}
+		manifest::write_version(&path, crate::FORMAT_VERSION + 1).unwrap();
+		match verify_error(&path) {
+			VerifyError::Manifest(_) => {}
+			other => panic!("unexpected error: {:?}", other),
+		}
+		manifest::write_version(&path, crate::FORMAT_VERSION).unwrap();
+		assert!(open(&path, verify_flags()).is_ok());
+
+		// Lock file
+		fs::write(path.join(DB_LOCK_FILENAME), "garbage").unwrap();
+		let flags = OpenFlags::config(|f| {
+			f.verify = true;
+			f.read_only = true;
+		});
+		match open(&path, flags) {
+			Err(Error::Verify(VerifyError::LockFile(_))) => {}
+			other => panic!("unexpected result: {:?}", other.map(|_| ())),
+		}
+
+		// Unverified opening is not affected.
+		assert!(open(&path, OpenFlags::read_only()).is_ok());
+
+		temp.close().unwrap();
+	}
+}
@@ -0,0 +1,234 @@
+//! Change notifications.
+//!
+//! `Database::watch` returns a channel that receives every change made
+//! through the database instance from then on, as journal entries.
+//!
+//! Changes are delivered once the write that made them is done, which is
+//! when the write lock is released. Watchers are dropped as soon as their
+//! receiver is.
+//!
+//! Changes made by other processes are picked up from the journal file by
+//! `Database::poll_changes`. This is mostly useful for read-only instances,
+//! which can poll periodically to learn about changes made by the writer.
+//!
+//! Callbacks registered with `Database::on_write` and `Database::on_commit`
+//! are called at the same point as watchers are notified. Callbacks run
+//! while the write lock is still held, so they can read from the database
+//! but must not write to it.
+
+use std::sync::mpsc::{self, Receiver};
+
+use crate::database::Database;
+use crate::journal::{self, JournalEntry};
+use crate::Result;
+
+type WriteHook = Box<dyn Fn(&JournalEntry) + Send + Sync>;
+type CommitHook = Box<dyn Fn(&[JournalEntry]) + Send + Sync>;
+
+/// Callbacks registered on a database.
+#[derive(Default)]
+pub(crate) struct Hooks {
+	on_write: Vec<WriteHook>,
+	on_commit: Vec<CommitHook>,
+}
+
+impl Database {
+	/// Subscribes to changes to the database.
+	///
+	/// The returned receiver gets a `JournalEntry` for every change, in
+	/// sequence order. Changes made by other processes are not reported.
+	pub fn watch(&self) -> Receiver<JournalEntry> {
+		let (sender, receiver) = mpsc::channel();
+		self.watchers().push(sender);
+		receiver
+	}
+
+	/// Registers a callback called for every change to the database, once
+	/// the write that made it is done.
+	pub fn on_write<F: Fn(&JournalEntry) + Send + Sync + 'static>(&self, callback: F) {
+		self.hooks().on_write.push(Box::new(callback));
+	}
+
+	/// Registers a callback called with all the changes made by a write,
+	/// once it is done. A committed `Transaction` is reported as a single
+	/// call.
+	pub fn on_commit<F: Fn(&[JournalEntry]) + Send + Sync + 'static>(&self, callback: F) {
+		self.hooks().on_commit.push(Box::new(callback));
+	}
+
+	/// Checks the journal for changes made by other processes since the last
+	/// call, returning them and sending them to watchers.
+	///
+	/// This is cheap when there are no changes, since only the new part of
+	/// the journal is read.
+	///
+	/// For a read-only instance, this also moves its view of the database
+	/// forward to include the changes.
+	pub fn poll_changes(&self) -> Result<Vec<JournalEntry>> {
+		let _guard = self.write_guard();
+		let mut offset = self.journal_offset();
+		let (mut entries, next_offset) = journal::read_journal_from(&self.path, *offset)?;
+		*offset = next_offset;
+
+		// Skip changes we already know about, including our own.
+		let mut last_seq = self.last_seq();
+		entries.retain(|entry| entry.seq > *last_seq);
+		if let Some(entry) = entries.last() {
+			*last_seq = entry.seq;
+		}
+		if let Some(registration) = self.registration() {
+			registration.update(*last_seq)?;
+		}
+		for entry in entries.iter() {
+			self.queue_notification(entry.clone());
+		}
+		Ok(entries)
+	}
+
+	/// Queues a change to be sent to watchers once the write lock is
+	/// released.
+	pub(crate) fn queue_notification(&self, entry: JournalEntry) {
+		self.pending_notifications().push(entry);
+	}
+
+	/// Sends any queued change to watchers.
+	pub(crate) fn send_notifications(&self) {
+		let pending = std::mem::take(&mut *self.pending_notifications());
+		if pending.is_empty() {
+			return;
+		}
+
+		let hooks = self.hooks();
+		for entry in pending.iter() {
+			for hook in hooks.on_write.iter() {
+				hook(entry);
+			}
+		}
+		for hook in hooks.on_commit.iter() {
+			hook(&pending);
+		}
+		drop(hooks);
+
+		self.watchers().retain(|sender| {
+			pending
+				.iter()
+				.all(|entry| sender.send(entry.clone()).is_ok())
+		});
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::journal::Change;
+	use crate::testing::create_db;
+	use crate::{open, OpenFlags};
+
+	#[test]
+	fn should_notify_watchers() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+
+		let watcher = db.watch();
+		let dropped = db.watch();
+		drop(dropped);
+
+		notes.put("b", b"B").unwrap();
+		notes.delete("a").unwrap();
+		let blob = db.put_blob(b"blob").unwrap();
+
+		let changes = watcher
+			.try_iter()
+			.map(|entry| (entry.seq, entry.change))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			changes,
+			vec![
+				(
+					2,
+					Change::Put {
+						collection: "notes".into(),
+						key: "b".into()
+					}
+				),
+				(
+					3,
+					Change::Delete {
+						collection: "notes".into(),
+						key: "a".into()
+					}
+				),
+				(4, Change::PutBlob(blob)),
+			]
+		);
+		assert_eq!(db.watchers().len(), 1);
+
+		drop(watcher);
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_call_hooks() {
+		use std::sync::{Arc, Mutex};
+
+		let (db, temp) = create_db(OpenFlags::default());
+		let writes = Arc::new(Mutex::new(Vec::new()));
+		let commits = Arc::new(Mutex::new(Vec::new()));
+		{
+			let writes = writes.clone();
+			db.on_write(move |entry| writes.lock().unwrap().push(entry.seq));
+			let commits = commits.clone();
+			db.on_commit(move |entries| commits.lock().unwrap().push(entries.len()));
+		}
+
+		db.collection("notes").unwrap().put("a", b"A").unwrap();
+		let mut tx = db.transaction();
+		tx.put("notes", "b", b"B").unwrap();
+		tx.put("tags", "x", b"b").unwrap();
+		tx.commit().unwrap();
+
+		assert_eq!(*writes.lock().unwrap(), vec![1, 2, 3]);
+		assert_eq!(*commits.lock().unwrap(), vec![1, 2]);
+
+		drop(db);
+		temp.close().unwrap();
+	}
+
+	#[test]
+	fn should_poll_changes_from_other_processes() {
+		let (db, temp) = create_db(OpenFlags::default());
+		let notes = db.collection("notes").unwrap();
+		notes.put("a", b"A").unwrap();
+		let path = db.path.clone();
+		drop(db);
+
+		// The lock is per process, so we simulate the other process by
+		// writing to the journal directly.
+		let reader = open(&path, OpenFlags::read_only()).unwrap();
+		let watcher = reader.watch();
+		assert!(reader.poll_changes().unwrap().is_empty());
+
+		let entry = |seq, key: &str| JournalEntry {
+			seq,
+			time: 0,
+			change: Change::Put {
+				collection: "notes".into(),
+				key: key.into(),
+			},
+		};
+		journal::append_journal(&path, &[entry(2, "b"), entry(3, "c")]).unwrap();
+
+		assert_eq!(
+			reader.poll_changes().unwrap(),
+			vec![entry(2, "b"), entry(3, "c")]
+		);
+		assert_eq!(reader.sequence(), 3);
+		assert_eq!(watcher.try_iter().count(), 2);
+		assert!(reader.poll_changes().unwrap().is_empty());
+
+		drop(reader);
+		temp.close().unwrap();
+	}
+}